@@ -1,18 +1,55 @@
-use crate::config::{AIProvider, Config};
+use crate::config::{is_placeholder_api_key, AIProvider, Config, LlamaCppApi};
+use crate::error::SprenError;
 #[cfg(feature = "local")]
 use crate::local_llm::LocalSpren;
 use crate::shell::ShellType;
 use anyhow::{anyhow, Result};
+use base64::Engine;
 use reqwest::header::{HeaderMap, HeaderValue, AUTHORIZATION, CONTENT_TYPE};
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::LazyLock;
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc::UnboundedSender;
 #[cfg(feature = "local")]
-use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, Ordering};
+#[cfg(feature = "local")]
+use std::sync::Arc;
 
 #[cfg(feature = "local")]
 use once_cell::sync::Lazy;
 
 #[cfg(feature = "local")]
-static LOCAL_LLM: Lazy<Mutex<Option<LocalSpren>>> = Lazy::new(|| Mutex::new(None));
+use crate::pool::{Checkout, Pool};
+
+/// Loaded local models, up to `ai.local_workers` of them so concurrent
+/// queries (serve mode) don't queue behind one generation
+#[cfg(feature = "local")]
+static LOCAL_LLM: Lazy<Pool<LocalSpren>> = Lazy::new(Pool::default);
+
+/// Shared with the loaded model so an interrupt can stop generation
+#[cfg(feature = "local")]
+static LOCAL_CANCEL: Lazy<Arc<AtomicBool>> = Lazy::new(|| Arc::new(AtomicBool::new(false)));
+
+/// Receives model output as it streams in, for live display
+static STREAM_SINK: std::sync::Mutex<Option<UnboundedSender<String>>> =
+    std::sync::Mutex::new(None);
+
+/// Route streamed output to `sink` (or stop with `None`)
+pub fn set_stream_sink(sink: Option<UnboundedSender<String>>) {
+    if let Ok(mut current) = STREAM_SINK.lock() {
+        *current = sink;
+    }
+}
+
+fn emit_partial(text: &str) {
+    if let Ok(sink) = STREAM_SINK.lock() {
+        if let Some(sink) = sink.as_ref() {
+            let _ = sink.send(text.to_string());
+        }
+    }
+}
 
 // ============================================================================
 // Anthropic Types
@@ -22,6 +59,9 @@ static LOCAL_LLM: Lazy<Mutex<Option<LocalSpren>>> = Lazy::new(|| Mutex::new(None
 struct AnthropicResponse {
     content: Option<Vec<AnthropicContent>>,
     error: Option<AnthropicError>,
+    /// `max_tokens` when the reply was cut off
+    #[serde(default)]
+    stop_reason: Option<String>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -56,11 +96,16 @@ struct OpenAIError {
 #[derive(Debug, Serialize, Deserialize)]
 struct OpenAIChoice {
     message: OpenAIMessage,
+    /// `length` when the reply was cut off
+    #[serde(default)]
+    finish_reason: Option<String>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 struct OpenAIMessage {
-    content: String,
+    /// Null when the reply was filtered
+    #[serde(default)]
+    content: Option<String>,
 }
 
 // ============================================================================
@@ -75,7 +120,12 @@ struct GeminiResponse {
 
 #[derive(Debug, Serialize, Deserialize)]
 struct GeminiCandidate {
-    content: GeminiContent,
+    /// Missing when the reply was blocked (e.g. finishReason SAFETY)
+    #[serde(default)]
+    content: Option<GeminiContent>,
+    /// `MAX_TOKENS` when the reply was cut off
+    #[serde(default, rename = "finishReason")]
+    finish_reason: Option<String>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -98,96 +148,861 @@ struct GeminiError {
 // Public API
 // ============================================================================
 
-pub async fn get_command_suggestion(query: &str, config: &Config) -> Result<(String, bool)> {
-    match config.ai.provider {
-        AIProvider::Anthropic => get_anthropic_command(query, config).await,
-        AIProvider::OpenAI => get_openai_command(query, config).await,
-        AIProvider::Gemini => get_gemini_command(query, config).await,
-        #[cfg(feature = "local")]
-        AIProvider::Local => get_local_command(query, config).await,
+const COMMAND_SYSTEM_PROMPT: &str =
+    "You are Spren, a helpful command-line assistant. Respond only in the specified format.";
+const STRUCTURED_COMMAND_SYSTEM_PROMPT: &str =
+    "You are Spren, a helpful command-line assistant. Respond with JSON: the shell command \
+     and whether it is dangerous.";
+const EXPLAIN_SYSTEM_PROMPT: &str =
+    "You are Spren, a helpful command-line assistant. Provide clear and concise explanations.";
+const PLAN_SYSTEM_PROMPT: &str =
+    "You are Spren, a helpful command-line assistant. Plan tasks as short steps in plain \
+     language; never write commands.";
+
+/// A suggested command and what the model said about it
+#[derive(Debug, Clone, PartialEq)]
+pub struct Suggestion {
+    pub command: String,
+    /// The model's own danger flag
+    pub dangerous: bool,
+    /// Prose the model gave besides the command, if any
+    pub explanation: Option<String>,
+    /// How the command was found in the reply
+    pub extraction: Extraction,
+    /// The reply hit the token limit, so the command may be cut off
+    pub truncated: bool,
+    /// The cloud provider that answered after the configured model's output
+    /// couldn't be used (`ai.escalate_on_parse_failure`)
+    pub escalated_to: Option<AIProvider>,
+    /// `provider/model` that produced the command, after any escalation
+    pub answered_by: String,
+    /// Output tokens the request was repeated with after the first reply was
+    /// cut off (`ai.truncation_retry_max_tokens`)
+    pub retried_with_tokens: Option<u32>,
+}
+
+/// Which `extract_command` pattern found the command, in the order tried
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Extraction {
+    /// A JSON structured-output reply; nothing to extract
+    Structured,
+    CommandLine,
+    CommandInline,
+    CodeFence,
+    Backticks,
+    SecondLine,
+    SingleLine,
+    Heuristic,
+}
+
+impl Extraction {
+    pub fn name(self) -> &'static str {
+        match self {
+            Extraction::Structured => "structured output",
+            Extraction::CommandLine => "COMMAND: line",
+            Extraction::CommandInline => "COMMAND: within a line",
+            Extraction::CodeFence => "code fence",
+            Extraction::Backticks => "inline backticks",
+            Extraction::SecondLine => "second of two lines",
+            Extraction::SingleLine => "single-line command heuristic",
+            Extraction::Heuristic => "fallback command heuristic",
+        }
     }
+
+    /// Whether the reply ignored the requested format and a guess was needed
+    pub fn is_fallback(self) -> bool {
+        matches!(self, Extraction::SingleLine | Extraction::Heuristic)
+    }
+}
+
+pub async fn get_command_suggestion(query: &str, config: &Config) -> Result<Suggestion> {
+    get_image_command_suggestion(query, None, config).await
+}
+
+/// Like `get_command_suggestion`, with a screenshot sent along for vision models
+pub async fn get_image_command_suggestion(
+    query: &str,
+    image: Option<&Image>,
+    config: &Config,
+) -> Result<Suggestion> {
+    if image.is_some() {
+        check_vision_support(config)?;
+    }
+    check_sensitive_query(query, config)?;
+    let result = match request_command(query, image, false, config).await {
+        // Junk output gets one retry with a stricter prompt before giving up
+        Err(e) if is_invalid_command(&e) => request_command(query, image, true, config).await,
+        result => result,
+    };
+    match result {
+        Err(e) if is_invalid_command(&e) => escalate(query, image, e, config).await,
+        result => result,
+    }
+}
+
+/// With `ai.escalate_on_parse_failure`, hand a query the local model couldn't
+/// answer to the first cloud provider with an API key; otherwise return `err`
+async fn escalate(
+    query: &str,
+    image: Option<&Image>,
+    err: anyhow::Error,
+    config: &Config,
+) -> Result<Suggestion> {
+    if !config.ai.escalate_on_parse_failure || config.ai.provider.is_cloud() {
+        return Err(err);
+    }
+    let Some(provider) = [AIProvider::Anthropic, AIProvider::OpenAI, AIProvider::Gemini]
+        .into_iter()
+        .find(|provider| has_api_key(config, provider))
+    else {
+        return Err(err);
+    };
+    let cloud_config = provider_config(config, &provider);
+    // The query was only cleared for a local model
+    if sensitive_query(query, &cloud_config).is_some() {
+        return Err(err);
+    }
+
+    let mut suggestion = request_command(query, image, false, &cloud_config).await?;
+    suggestion.escalated_to = Some(provider);
+    Ok(suggestion)
 }
 
+/// The kind of secret `query` seems to contain, if it is headed for a cloud
+/// provider and `security.confirm_sensitive_queries` is on
+///
+/// Callers that can ask the user do so first and, once they agree, send the
+/// query with the setting switched off.
+pub fn sensitive_query(query: &str, config: &Config) -> Option<&'static str> {
+    if !config.security.confirm_sensitive_queries || !config.ai.provider.is_cloud() {
+        return None;
+    }
+    crate::secrets::find_secret(query)
+}
+
+/// Refuse to send a query that `sensitive_query` flags
+fn check_sensitive_query(query: &str, config: &Config) -> Result<()> {
+    match sensitive_query(query, config) {
+        Some(kind) => Err(SprenError::PolicyViolation(format!(
+            "Query appears to contain {}; not sending it to {} (security.confirm_sensitive_queries)",
+            kind,
+            config.ai.provider.name()
+        ))
+        .into()),
+        None => Ok(()),
+    }
+}
+
+/// Ask the configured provider for a command and reject degenerate output
+///
+/// A reply cut off by the token limit is requested once more with room to finish.
+async fn request_command(
+    query: &str,
+    image: Option<&Image>,
+    strict: bool,
+    config: &Config,
+) -> Result<Suggestion> {
+    let mut suggestion = fetch_command(query, image, strict, config).await?;
+    if let Some(tokens) = retry_output_tokens(config).filter(|_| suggestion.truncated) {
+        let mut roomier = config.clone();
+        roomier.ai.max_output_tokens = Some(tokens);
+        suggestion = fetch_command(query, image, strict, &roomier).await?;
+        suggestion.retried_with_tokens = Some(tokens);
+    }
+    suggestion.answered_by = model_label(config);
+
+    if config.ai.normalize_command {
+        suggestion.command = normalize_command(&suggestion.command);
+    }
+    suggestion.command = validate_command(query, &suggestion.command)?;
+    Ok(suggestion)
+}
+
+/// One request to the configured provider for a command
+async fn fetch_command(
+    query: &str,
+    image: Option<&Image>,
+    strict: bool,
+    config: &Config,
+) -> Result<Suggestion> {
+    check_circuit(&config.ai.provider)?;
+    let result = match config.ai.provider {
+        AIProvider::Anthropic => get_anthropic_command(query, strict, config).await,
+        AIProvider::OpenAI => get_openai_command(query, image, strict, config).await,
+        AIProvider::Gemini => get_gemini_command(query, image, strict, config).await,
+        AIProvider::LlamaCpp => get_llamacpp_command(query, strict, config).await,
+        #[cfg(feature = "local")]
+        AIProvider::Local => get_local_command(query, strict, config).await,
+    };
+    record_outcome(&config.ai.provider, &result);
+    result
+}
+
+/// Explain why `command` failed, given what it printed
 pub async fn get_error_suggestion(
     command: &str,
     stdout: &str,
     stderr: &str,
     config: &Config,
 ) -> Result<String> {
-    match config.ai.provider {
+    // Failure output often echoes tokens and connection strings
+    check_sensitive_query(&format!("{}\n{}\n{}", command, stdout, stderr), config)?;
+    check_circuit(&config.ai.provider)?;
+    let result = match config.ai.provider {
         AIProvider::Anthropic => get_anthropic_error(command, stdout, stderr, config).await,
         AIProvider::OpenAI => get_openai_error(command, stdout, stderr, config).await,
         AIProvider::Gemini => get_gemini_error(command, stdout, stderr, config).await,
+        AIProvider::LlamaCpp => get_llamacpp_error(command, stdout, stderr, config).await,
         #[cfg(feature = "local")]
         AIProvider::Local => get_local_error(command, stdout, stderr, config).await,
+    };
+    record_outcome(&config.ai.provider, &result);
+    result
+}
+
+/// Get a plain-English explanation of what a shell command does
+pub async fn get_command_explanation(command: &str, config: &Config) -> Result<String> {
+    check_circuit(&config.ai.provider)?;
+    let result = match config.ai.provider {
+        AIProvider::Anthropic => get_anthropic_explanation(command, config).await,
+        AIProvider::OpenAI => get_openai_explanation(command, config).await,
+        AIProvider::Gemini => get_gemini_explanation(command, config).await,
+        AIProvider::LlamaCpp => get_llamacpp_explanation(command, config).await,
+        #[cfg(feature = "local")]
+        AIProvider::Local => get_local_explanation(command, config).await,
+    };
+    record_outcome(&config.ai.provider, &result);
+    result
+}
+
+/// A one-line explanation of `command`, for a comment above it in exports
+pub async fn get_command_annotation(command: &str, config: &Config) -> Result<String> {
+    let explanation = get_command_explanation(command, config).await?;
+    one_line(&explanation).ok_or_else(|| anyhow!("The model gave no explanation for {}", command))
+}
+
+/// Longest annotation kept, in characters
+const MAX_ANNOTATION_CHARS: usize = 100;
+
+/// The first sentence of an explanation, cut to fit on one comment line
+fn one_line(explanation: &str) -> Option<String> {
+    let line = explanation
+        .lines()
+        .map(|l| l.trim().trim_start_matches(['-', '*', '#', ' ']).replace("**", "").replace('`', ""))
+        .find(|l| !l.is_empty())?;
+    let sentence = match line.find(". ") {
+        Some(end) => &line[..=end],
+        None => line.as_str(),
+    };
+
+    if sentence.chars().count() <= MAX_ANNOTATION_CHARS {
+        return Some(sentence.to_string());
+    }
+    let cut: String = sentence.chars().take(MAX_ANNOTATION_CHARS - 1).collect();
+    Some(format!("{}…", cut.trim_end()))
+}
+
+// ============================================================================
+// Images
+// ============================================================================
+
+/// Inline images larger than this are rejected by both vision APIs
+const MAX_IMAGE_BYTES: u64 = 20 * 1024 * 1024;
+
+/// A screenshot attached to a query with `--image`
+#[derive(Debug, Clone)]
+pub struct Image {
+    pub mime_type: &'static str,
+    /// Base64 of the file contents
+    pub data: String,
+}
+
+impl Image {
+    pub fn load(path: &Path) -> Result<Self> {
+        let extension = path
+            .extension()
+            .and_then(|e| e.to_str())
+            .unwrap_or_default()
+            .to_lowercase();
+        let mime_type = match extension.as_str() {
+            "png" => "image/png",
+            "jpg" | "jpeg" => "image/jpeg",
+            "webp" => "image/webp",
+            "gif" => "image/gif",
+            _ => {
+                return Err(anyhow!(
+                    "Unsupported image type for {} (use PNG, JPEG, WebP or GIF)",
+                    path.display()
+                ))
+            }
+        };
+
+        let size = std::fs::metadata(path)
+            .map_err(|e| anyhow!("Could not read image {}: {}", path.display(), e))?
+            .len();
+        if size > MAX_IMAGE_BYTES {
+            return Err(anyhow!(
+                "Image {} is {} MB; the limit is {} MB",
+                path.display(),
+                size / (1024 * 1024),
+                MAX_IMAGE_BYTES / (1024 * 1024)
+            ));
+        }
+
+        let bytes = std::fs::read(path)?;
+        Ok(Self {
+            mime_type,
+            data: base64::engine::general_purpose::STANDARD.encode(bytes),
+        })
+    }
+
+    fn data_url(&self) -> String {
+        format!("data:{};base64,{}", self.mime_type, self.data)
+    }
+}
+
+/// Error unless the configured provider and model accept image input
+fn check_vision_support(config: &Config) -> Result<()> {
+    let provider = &config.ai.provider;
+    let model = match provider {
+        AIProvider::OpenAI => get_model_or_default(config, "gpt-4o-mini"),
+        AIProvider::Gemini => get_model_or_default(config, "gemini-2.0-flash"),
+        _ => {
+            return Err(SprenError::NotConfigured(format!(
+                "--image needs the openai or gemini provider; {} doesn't take images here",
+                provider.name()
+            ))
+            .into())
+        }
+    };
+    if supports_vision(provider, model) {
+        Ok(())
+    } else {
+        Err(SprenError::NotConfigured(format!(
+            "--image needs a vision model; {} model '{}' doesn't accept images",
+            provider.name(),
+            model
+        ))
+        .into())
+    }
+}
+
+/// Whether a model accepts images, judged by its name
+fn supports_vision(provider: &AIProvider, model: &str) -> bool {
+    let model = model.to_lowercase();
+    match provider {
+        AIProvider::OpenAI => {
+            if ["o1-mini", "o1-preview", "o3-mini", "gpt-4o-audio"]
+                .iter()
+                .any(|prefix| model.starts_with(prefix))
+            {
+                return false;
+            }
+            ["gpt-4o", "gpt-4.1", "gpt-4-turbo", "gpt-5", "o1", "o3", "o4"]
+                .iter()
+                .any(|prefix| model.starts_with(prefix))
+        }
+        // Only the original text-only Gemini 1.0 Pro lacks vision
+        AIProvider::Gemini => !matches!(model.as_str(), "gemini-pro" | "gemini-1.0-pro"),
+        _ => false,
+    }
+}
+
+// ============================================================================
+// Empty Responses
+// ============================================================================
+
+/// Pause before re-asking after an empty reply, growing with each attempt
+const EMPTY_RETRY_DELAY: Duration = Duration::from_millis(500);
+
+/// A successful reply with no text (content filtering or a provider hiccup)
+///
+/// Unlike an error object in the reply, asking again usually works.
+#[derive(Debug)]
+struct EmptyResponse(&'static str);
+
+impl std::fmt::Display for EmptyResponse {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} API returned empty content", self.0)
+    }
+}
+
+impl std::error::Error for EmptyResponse {}
+
+/// A provider's reply and whether the token limit cut it off
+struct Reply {
+    text: String,
+    truncated: bool,
+}
+
+/// The reply's text, or `EmptyResponse` if there is none
+fn non_empty(provider: &'static str, text: Option<String>) -> Result<String> {
+    match text {
+        Some(text) if !text.trim().is_empty() => Ok(text),
+        _ => Err(EmptyResponse(provider).into()),
+    }
+}
+
+/// Send `request` again while it comes back empty, up to `ai.empty_response_retries` times
+async fn retry_empty<T, F, Fut>(config: &Config, mut request: F) -> Result<T>
+where
+    F: FnMut() -> Fut,
+    Fut: std::future::Future<Output = Result<T>>,
+{
+    let mut retries = 0;
+    loop {
+        match request().await {
+            Err(e)
+                if e.downcast_ref::<EmptyResponse>().is_some()
+                    && retries < config.ai.empty_response_retries =>
+            {
+                retries += 1;
+                tokio::time::sleep(EMPTY_RETRY_DELAY * retries).await;
+            }
+            result => return result,
+        }
+    }
+}
+
+// ============================================================================
+// Circuit Breaker
+// ============================================================================
+
+/// Consecutive auth/connection failures that open the circuit
+const CIRCUIT_FAILURES: u32 = 3;
+/// Failures further apart than this don't count as consecutive
+const CIRCUIT_WINDOW: Duration = Duration::from_secs(120);
+/// How long requests are short-circuited once the circuit opens
+const CIRCUIT_COOLDOWN: Duration = Duration::from_secs(60);
+
+static CIRCUITS: LazyLock<std::sync::Mutex<HashMap<AIProvider, CircuitBreaker>>> =
+    LazyLock::new(Default::default);
+
+/// Stops calling a provider that keeps failing for reasons a retry won't fix
+#[derive(Debug, Default)]
+struct CircuitBreaker {
+    failures: u32,
+    last_failure: Option<Instant>,
+    open_until: Option<Instant>,
+    last_error: String,
+}
+
+impl CircuitBreaker {
+    /// The cached error while the circuit is open
+    fn check(&self, now: Instant) -> Option<String> {
+        let open_until = self.open_until.filter(|until| now < *until)?;
+        Some(format!(
+            "{} (skipping requests for {}s after repeated failures; check your config)",
+            self.last_error,
+            (open_until - now).as_secs().max(1)
+        ))
+    }
+
+    fn record_failure(&mut self, now: Instant, error: String) {
+        let recent = self
+            .last_failure
+            .is_some_and(|last| now.duration_since(last) <= CIRCUIT_WINDOW);
+        self.failures = if recent { self.failures + 1 } else { 1 };
+        self.last_failure = Some(now);
+        self.last_error = error;
+
+        if self.failures >= CIRCUIT_FAILURES {
+            self.open_until = Some(now + CIRCUIT_COOLDOWN);
+        }
+    }
+
+    fn record_success(&mut self) {
+        *self = Self::default();
+    }
+}
+
+fn check_circuit(provider: &AIProvider) -> Result<()> {
+    let circuits = CIRCUITS.lock().map_err(|_| anyhow!("Circuit breaker state poisoned"))?;
+    match circuits.get(provider).and_then(|c| c.check(Instant::now())) {
+        Some(message) => Err(anyhow!(message)),
+        None => Ok(()),
     }
 }
 
+fn record_outcome<T>(provider: &AIProvider, result: &Result<T>) {
+    let Ok(mut circuits) = CIRCUITS.lock() else {
+        return;
+    };
+    let circuit = circuits.entry(provider.clone()).or_default();
+    match result {
+        Ok(_) => circuit.record_success(),
+        Err(e) if is_persistent_failure(e) => circuit.record_failure(Instant::now(), e.to_string()),
+        // Bad model output and the like say nothing about the provider's health
+        Err(_) => {}
+    }
+}
+
+/// Auth and connection errors, which fail the same way until something changes
+fn is_persistent_failure(error: &anyhow::Error) -> bool {
+    // Missing config and bad output already fail fast without a round-trip
+    if error.downcast_ref::<SprenError>().is_some() {
+        return false;
+    }
+    if let Some(e) = error.downcast_ref::<reqwest::Error>() {
+        return e.is_connect() || e.is_timeout();
+    }
+
+    let message = error.to_string().to_lowercase();
+    [
+        "api key",
+        "api_key",
+        "unauthorized",
+        "authentication",
+        "permission",
+        "could not reach",
+        "401",
+        "403",
+    ]
+    .iter()
+    .any(|needle| message.contains(needle))
+}
+
 /// Get a fixed command based on the error output
 /// Returns (fixed_command, is_dangerous)
-#[cfg(feature = "local")]
+///
+/// `tried` lists earlier commands that also failed, so the model is steered
+/// away from suggesting them again. `query` is what the user originally asked
+/// for, so the fix corrects toward that rather than the literal command.
 pub async fn get_fix_command(
+    query: &str,
     original_command: &str,
     stdout: &str,
     stderr: &str,
+    tried: &[String],
     config: &Config,
 ) -> Result<(String, bool)> {
-    get_local_fix(original_command, stdout, stderr, config).await
+    // Failure output often echoes tokens and connection strings
+    check_sensitive_query(&format!("{}\n{}\n{}", original_command, stdout, stderr), config)?;
+    check_circuit(&config.ai.provider)?;
+    let result = match config.ai.provider {
+        AIProvider::Anthropic => {
+            get_anthropic_fix(query, original_command, stdout, stderr, tried, config).await
+        }
+        AIProvider::OpenAI => get_openai_fix(query, original_command, stdout, stderr, tried, config).await,
+        AIProvider::Gemini => get_gemini_fix(query, original_command, stdout, stderr, tried, config).await,
+        AIProvider::LlamaCpp => {
+            get_llamacpp_fix(query, original_command, stdout, stderr, tried, config).await
+        }
+        #[cfg(feature = "local")]
+        AIProvider::Local => get_local_fix(query, original_command, stdout, stderr, tried, config).await,
+    };
+    record_outcome(&config.ai.provider, &result);
+    result
+}
+
+/// The prompt asking for a fix of `command`, which was generated for `query`
+fn build_fix_prompt(query: &str, command: &str, stdout: &str, stderr: &str, tried: &[String]) -> String {
+    let mut prompt = String::new();
+    if !query.trim().is_empty() {
+        prompt.push_str(&format!("The user asked: {}\n", query.trim()));
+    }
+    prompt.push_str(&format!(
+        "Command '{}' failed.\nOutput: {}\nError: {}\n",
+        command, stdout, stderr
+    ));
+    if query.trim().is_empty() {
+        prompt.push_str("Provide a fixed command.");
+    } else {
+        prompt.push_str("Provide a fixed command that still does what the user asked.");
+    }
+    let earlier: Vec<&str> = tried
+        .iter()
+        .map(String::as_str)
+        .filter(|t| !already_tried(t, &[command.to_string()]))
+        .collect();
+    if !earlier.is_empty() {
+        prompt.push_str(&format!(
+            "\nThese also failed; you already suggested them, so try a different approach: {}",
+            earlier.join(" | ")
+        ));
+    }
+    prompt
+}
+
+/// `build_fix_prompt` for providers that don't get the reply format in their
+/// system prompt the way the local model does
+fn build_remote_fix_prompt(
+    shell_name: &str,
+    query: &str,
+    command: &str,
+    stdout: &str,
+    stderr: &str,
+    tried: &[String],
+) -> String {
+    format!(
+        "Shell: {}\n{}\n\nReply ONLY in this exact format (2 lines, no explanation):\n\
+         DANGEROUS:false\nCOMMAND:{}",
+        shell_name,
+        build_fix_prompt(query, command, stdout, stderr, tried),
+        COMMAND_PLACEHOLDER
+    )
+}
+
+/// The fixed command in a reply, and whether the model called it dangerous
+fn parse_fix_reply(text: &str, truncated: bool, max_tokens: u32, config: &Config) -> Result<(String, bool)> {
+    // Nobody reviews a fix as closely as the first suggestion, so don't offer a cut-off one
+    if truncated {
+        return Err(SprenError::InvalidCommand(format!(
+            "the fix hit the {}-token limit and may be cut off (raise ai.max_output_tokens)",
+            max_tokens
+        ))
+        .into());
+    }
+    let suggestion = parse_ai_response(text)?;
+    let command = if config.ai.normalize_command {
+        normalize_command(&suggestion.command)
+    } else {
+        suggestion.command
+    };
+    Ok((command, suggestion.dangerous))
+}
+
+/// Whether `command` repeats one of `tried`, ignoring differences in spacing
+pub fn already_tried(command: &str, tried: &[String]) -> bool {
+    let words = |c: &str| c.split_whitespace().collect::<Vec<_>>().join(" ");
+    let command = words(command);
+    tried.iter().any(|t| words(t) == command)
 }
 
 // ============================================================================
-// Anthropic Implementation
+// Planning
 // ============================================================================
 
-async fn get_anthropic_command(query: &str, config: &Config) -> Result<(String, bool)> {
-    let api_key = config
-        .ai
-        .anthropic_api_key
-        .as_ref()
-        .ok_or_else(|| anyhow!("Anthropic API key not configured. Set 'anthropic_api_key' in config."))?;
+/// Break a bigger task into plain-language steps, with no commands in them
+///
+/// Each step reads like a query, so it can be turned into a command later.
+pub async fn get_plan(task: &str, config: &Config) -> Result<Vec<String>> {
+    check_sensitive_query(task, config)?;
+    check_circuit(&config.ai.provider)?;
+    let prompt = build_plan_prompt(ShellType::detect().get_shell_name(), task, config);
+    let result = match config.ai.provider {
+        AIProvider::Anthropic => anthropic_complete(PLAN_SYSTEM_PROMPT, &prompt, &[], config).await.map(|reply| reply.text),
+        AIProvider::OpenAI => openai_complete(PLAN_SYSTEM_PROMPT, &prompt, None, None, config).await.map(|reply| reply.text),
+        AIProvider::Gemini => gemini_complete(PLAN_SYSTEM_PROMPT, &prompt, None, config).await.map(|reply| reply.text),
+        AIProvider::LlamaCpp => llamacpp_complete(PLAN_SYSTEM_PROMPT, &prompt, config).await.map(|reply| reply.text),
+        #[cfg(feature = "local")]
+        AIProvider::Local => get_local_plan(&prompt, config).await,
+    };
+    record_outcome(&config.ai.provider, &result);
 
-    let client = reqwest::Client::new();
-    let mut headers = HeaderMap::new();
-    headers.insert("anthropic-version", HeaderValue::from_static("2023-06-01"));
-    headers.insert("x-api-key", HeaderValue::from_str(api_key)?);
-    headers.insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+    let steps = parse_plan(&result?);
+    if steps.is_empty() {
+        return Err(SprenError::InvalidCommand("Model did not produce a plan".to_string()).into());
+    }
+    Ok(steps)
+}
 
-    let shell_type = ShellType::detect();
-    let shell_name = shell_type.get_shell_name();
+fn build_plan_prompt(shell_name: &str, task: &str, config: &Config) -> String {
+    format!(
+        "Plan this task for a {} user: {}\n\
+         Reply with a numbered list of 2-8 steps, one line each, in plain language. \
+         Each step must be a single action that one shell command could do. \
+         Do not write any commands.{}",
+        shell_name,
+        task,
+        language_instruction(config)
+    )
+}
 
-    let prompt = build_command_prompt(shell_name, query);
-    let model = get_model_or_default(config, "claude-3-5-haiku-20241022");
+/// The numbered or bulleted lines of a plan, without their markers
+fn parse_plan(response: &str) -> Vec<String> {
+    response
+        .lines()
+        .map(str::trim)
+        .filter_map(|line| {
+            let number = line.trim_start_matches(|c: char| c.is_ascii_digit());
+            let unmarked = if number.len() < line.len() {
+                number.strip_prefix(['.', ')', ':'])?
+            } else {
+                line.strip_prefix("- ").or_else(|| line.strip_prefix("* "))?
+            };
+            let step = unmarked.trim().trim_matches('*').trim();
+            (!step.is_empty()).then(|| step.to_string())
+        })
+        .collect()
+}
 
-    let response = client
-        .post("https://api.anthropic.com/v1/messages")
-        .headers(headers)
-        .json(&serde_json::json!({
-            "model": model,
-            "max_tokens": config.ai.max_tokens,
-            "system": "You are Spren, a helpful command-line assistant. Respond only in the specified format.",
-            "messages": [{
-                "role": "user",
-                "content": prompt
-            }]
-        }))
-        .send()
-        .await?
-        .json::<AnthropicResponse>()
-        .await?;
+// ============================================================================
+// Provider Comparison
+// ============================================================================
+
+/// One provider's answer in a comparison
+pub struct Comparison {
+    pub provider: AIProvider,
+    pub latency: Duration,
+    pub result: Result<Suggestion>,
+}
+
+/// Ask every cloud provider with an API key for a command, all at once
+///
+/// Nothing is executed, and the circuit breaker is left alone so a provider
+/// that fails here stays usable for normal queries.
+pub async fn compare_providers(query: &str, config: &Config) -> Vec<Comparison> {
+    let run = |provider: AIProvider| {
+        let config = provider_config(config, &provider);
+        async move {
+            if !has_api_key(&config, &provider) {
+                return None;
+            }
+            let start = Instant::now();
+            let result = match provider {
+                AIProvider::Anthropic => get_anthropic_command(query, false, &config).await,
+                AIProvider::OpenAI => get_openai_command(query, None, false, &config).await,
+                AIProvider::Gemini => get_gemini_command(query, None, false, &config).await,
+                _ => return None,
+            }
+            .and_then(|mut suggestion| {
+                suggestion.command = validate_command(query, &suggestion.command)?;
+                Ok(suggestion)
+            });
+            Some(Comparison {
+                provider,
+                latency: start.elapsed(),
+                result,
+            })
+        }
+    };
 
-    if let Some(error) = response.error {
-        return Err(anyhow!("Anthropic API error: {}", error.message));
+    let (anthropic, openai, gemini) = tokio::join!(
+        run(AIProvider::Anthropic),
+        run(AIProvider::OpenAI),
+        run(AIProvider::Gemini)
+    );
+    [anthropic, openai, gemini].into_iter().flatten().collect()
+}
+
+/// The config as if `provider` were selected; `ai.model` only carries over
+/// to the provider it was chosen for
+pub fn provider_config(config: &Config, provider: &AIProvider) -> Config {
+    let mut config = config.clone();
+    if config.ai.provider != *provider {
+        config.ai.provider = provider.clone();
+        config.ai.model.clear();
     }
+    config
+}
 
-    let content = response
-        .content
-        .ok_or_else(|| anyhow!("Anthropic API returned no content"))?;
+fn has_api_key(config: &Config, provider: &AIProvider) -> bool {
+    let key = match provider {
+        AIProvider::Anthropic => &config.ai.anthropic_api_key,
+        AIProvider::OpenAI => &config.ai.openai_api_key,
+        AIProvider::Gemini => &config.ai.gemini_api_key,
+        _ => return false,
+    };
+    key.as_deref().is_some_and(|k| !k.trim().is_empty() && !is_placeholder_api_key(k))
+}
 
-    if content.is_empty() {
-        return Err(anyhow!("Anthropic API returned empty content"));
+/// A cloud provider's API key, or a setup error for a missing key or the
+/// placeholder from the default config, which would only get a 401 back
+fn cloud_api_key<'a>(key: Option<&'a String>, provider: &str, setting: &str) -> Result<&'a str> {
+    match key {
+        None => Err(SprenError::NotConfigured(format!(
+            "{} API key not configured. Set '{}' in config.",
+            provider, setting
+        ))
+        .into()),
+        Some(key) if is_placeholder_api_key(key) => {
+            let path = crate::config::get_config_path()
+                .map(|p| p.display().to_string())
+                .unwrap_or_else(|_| "your config file".to_string());
+            Err(SprenError::NotConfigured(format!(
+                "You haven't set your {} API key yet: '{}' is still the placeholder. Edit {}",
+                provider, setting, path
+            ))
+            .into())
+        }
+        Some(key) => Ok(key),
     }
+}
+
+// ============================================================================
+// Model limits
+// ============================================================================
+
+/// Most output tokens each cloud model accepts, by model name prefix (the
+/// longest matching prefix wins). Models not listed get `ai.max_tokens` as is.
+const MODEL_OUTPUT_LIMITS: &[(&str, u32)] = &[
+    ("claude-3-haiku", 4096),
+    ("claude-3-sonnet", 4096),
+    ("claude-3-opus", 4096),
+    ("claude-3-5-haiku", 8192),
+    ("claude-3-5-sonnet", 8192),
+    ("claude-3-7-sonnet", 64000),
+    ("claude-sonnet-4", 64000),
+    ("claude-opus-4", 32000),
+    ("gpt-3.5-turbo", 4096),
+    ("gpt-4-turbo", 4096),
+    ("gpt-4o", 16384),
+    ("gpt-4o-2024-05-13", 4096),
+    ("gpt-4.1", 32768),
+    ("gemini-pro", 2048),
+    ("gemini-1.0-pro", 2048),
+    ("gemini-1.5", 8192),
+    ("gemini-2.0", 8192),
+    ("gemini-2.5", 65536),
+];
+
+/// The output token ceiling of `model`, if it's a model we know
+fn model_output_limit(model: &str) -> Option<u32> {
+    let model = model.to_lowercase();
+    MODEL_OUTPUT_LIMITS
+        .iter()
+        .filter(|(prefix, _)| model.starts_with(prefix))
+        .max_by_key(|(prefix, _)| prefix.len())
+        .map(|(_, limit)| *limit)
+}
+
+/// `ai.output_tokens()`, clamped to what `model` can produce
+fn output_tokens_for(model: &str, config: &Config) -> u32 {
+    let requested = config.ai.output_tokens();
+    model_output_limit(model).map_or(requested, |limit| requested.min(limit))
+}
 
-    parse_ai_response(&content[0].text)
+/// Output tokens for a second try after a cut-off reply: twice what the first
+/// got, within `ai.truncation_retry_max_tokens` and the model's own limit
+fn retry_output_tokens(config: &Config) -> Option<u32> {
+    let model = get_model_or_default(config, config.get_default_model_for_provider());
+    let current = output_tokens_for(model, config);
+    let doubled = current.saturating_mul(2).min(config.ai.truncation_retry_max_tokens);
+    let tokens = model_output_limit(model).map_or(doubled, |limit| doubled.min(limit));
+    (tokens > current).then_some(tokens)
+}
+
+/// The configured model and its limit, when requests to it get fewer output
+/// tokens than configured
+pub fn output_token_clamp(config: &Config) -> Option<(String, u32)> {
+    if !config.ai.provider.is_cloud() {
+        return None;
+    }
+    let model = get_model_or_default(config, config.get_default_model_for_provider());
+    model_output_limit(model)
+        .filter(|limit| config.ai.output_tokens() > *limit)
+        .map(|limit| (model.to_string(), limit))
+}
+
+// ============================================================================
+// Anthropic Implementation
+// ============================================================================
+
+async fn get_anthropic_command(query: &str, strict: bool, config: &Config) -> Result<Suggestion> {
+    let shell_type = ShellType::detect();
+    let prompt = build_command_prompt(
+        shell_type.get_shell_name(),
+        query,
+        &prompt_environment(query, config),
+        &config.ai.style_guidelines,
+        strict,
+    );
+
+    let reply =
+        anthropic_complete(COMMAND_SYSTEM_PROMPT, &prompt, &config.ai.stop_sequences, config)
+            .await?;
+    parse_command_reply(reply)
 }
 
 async fn get_anthropic_error(
@@ -196,11 +1011,46 @@ async fn get_anthropic_error(
     stderr: &str,
     config: &Config,
 ) -> Result<String> {
-    let api_key = config
-        .ai
-        .anthropic_api_key
-        .as_ref()
-        .ok_or_else(|| anyhow!("Anthropic API key not configured. Set 'anthropic_api_key' in config."))?;
+    let shell_type = ShellType::detect();
+    let prompt = build_error_prompt(shell_type.get_shell_name(), command, stdout, stderr, config);
+
+    let text = anthropic_complete(EXPLAIN_SYSTEM_PROMPT, &prompt, &[], config).await?.text;
+    Ok(text.trim().to_string())
+}
+
+async fn get_anthropic_fix(
+    query: &str,
+    command: &str,
+    stdout: &str,
+    stderr: &str,
+    tried: &[String],
+    config: &Config,
+) -> Result<(String, bool)> {
+    let shell_type = ShellType::detect();
+    let prompt = build_remote_fix_prompt(shell_type.get_shell_name(), query, command, stdout, stderr, tried);
+
+    let reply =
+        anthropic_complete(COMMAND_SYSTEM_PROMPT, &prompt, &config.ai.stop_sequences, config)
+            .await?;
+    parse_fix_reply(&reply.text, reply.truncated, config.ai.output_tokens(), config)
+}
+
+async fn get_anthropic_explanation(command: &str, config: &Config) -> Result<String> {
+    let shell_type = ShellType::detect();
+    let prompt = build_explain_prompt(shell_type.get_shell_name(), command, config);
+
+    let text = anthropic_complete(EXPLAIN_SYSTEM_PROMPT, &prompt, &[], config).await?.text;
+    Ok(text.trim().to_string())
+}
+
+/// Send a single-turn request to the Anthropic messages API and return the text
+async fn anthropic_complete(
+    system: &str,
+    prompt: &str,
+    stop_sequences: &[String],
+    config: &Config,
+) -> Result<Reply> {
+    let api_key = cloud_api_key(config.ai.anthropic_api_key.as_ref(), "Anthropic", "anthropic_api_key")?;
 
     let client = reqwest::Client::new();
     let mut headers = HeaderMap::new();
@@ -208,190 +1058,353 @@ async fn get_anthropic_error(
     headers.insert("x-api-key", HeaderValue::from_str(api_key)?);
     headers.insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
 
-    let shell_type = ShellType::detect();
-    let shell_name = shell_type.get_shell_name();
-
-    let prompt = build_error_prompt(shell_name, command, stdout, stderr);
     let model = get_model_or_default(config, "claude-3-5-haiku-20241022");
 
-    let response = client
-        .post("https://api.anthropic.com/v1/messages")
-        .headers(headers)
-        .json(&serde_json::json!({
-            "model": model,
-            "max_tokens": config.ai.max_tokens,
-            "system": "You are Spren, a helpful command-line assistant. Provide clear and concise explanations.",
-            "messages": [{
-                "role": "user",
-                "content": prompt
-            }]
-        }))
-        .send()
-        .await?
-        .json::<AnthropicResponse>()
-        .await?;
-
-    if let Some(error) = response.error {
-        return Err(anyhow!("Anthropic API error: {}", error.message));
+    let mut body = serde_json::json!({
+        "model": model,
+        "max_tokens": output_tokens_for(model, config),
+        "system": system,
+        "messages": [{
+            "role": "user",
+            "content": prompt
+        }]
+    });
+    // The API rejects whitespace-only stop sequences
+    let stop_sequences: Vec<&String> =
+        stop_sequences.iter().filter(|s| !s.trim().is_empty()).collect();
+    if !stop_sequences.is_empty() {
+        body["stop_sequences"] = serde_json::json!(stop_sequences);
     }
 
-    let content = response
-        .content
-        .ok_or_else(|| anyhow!("Anthropic API returned no content"))?;
-
-    if content.is_empty() {
-        return Err(anyhow!("Anthropic API returned empty content"));
-    }
+    retry_empty(config, || async {
+        let response = client
+            .post("https://api.anthropic.com/v1/messages")
+            .headers(headers.clone())
+            .json(&body)
+            .send()
+            .await?
+            .json::<AnthropicResponse>()
+            .await?;
+
+        if let Some(error) = response.error {
+            return Err(anyhow!("Anthropic API error: {}", error.message));
+        }
 
-    Ok(content[0].text.trim().to_string())
+        let truncated = response.stop_reason.as_deref() == Some("max_tokens");
+        let text = response.content.and_then(|c| c.into_iter().next()).map(|c| c.text);
+        non_empty("Anthropic", text).map(|text| Reply { text, truncated })
+    })
+    .await
 }
 
 // ============================================================================
 // OpenAI Implementation
 // ============================================================================
 
-async fn get_openai_command(query: &str, config: &Config) -> Result<(String, bool)> {
-    let api_key = config
-        .ai
-        .openai_api_key
-        .as_ref()
-        .ok_or_else(|| anyhow!("OpenAI API key not configured. Set 'openai_api_key' in config."))?;
-
-    let client = reqwest::Client::new();
-    let mut headers = HeaderMap::new();
-    headers.insert(
-        AUTHORIZATION,
-        HeaderValue::from_str(&format!("Bearer {}", api_key))?,
+async fn get_openai_command(
+    query: &str,
+    image: Option<&Image>,
+    strict: bool,
+    config: &Config,
+) -> Result<Suggestion> {
+    let shell_type = ShellType::detect();
+    let prompt = build_command_prompt(
+        shell_type.get_shell_name(),
+        query,
+        &prompt_environment(query, config),
+        &config.ai.style_guidelines,
+        strict,
     );
-    headers.insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
 
+    let model = get_model_or_default(config, "gpt-4o-mini");
+    if supports_structured_outputs(model) {
+        return get_openai_command_structured(&prompt, image, config).await;
+    }
+
+    let reply = openai_complete(COMMAND_SYSTEM_PROMPT, &prompt, image, None, config).await?;
+    parse_command_reply(reply)
+}
+
+/// Command and danger flag as returned in OpenAI's JSON mode
+#[derive(Debug, Deserialize)]
+struct StructuredCommand {
+    command: String,
+    dangerous: bool,
+}
+
+/// Whether an OpenAI model accepts `response_format` with a JSON schema
+fn supports_structured_outputs(model: &str) -> bool {
+    let model = model.to_lowercase();
+    // Older snapshots of otherwise supported families reject json_schema
+    if ["o1-mini", "o1-preview", "gpt-4o-2024-05-13"]
+        .iter()
+        .any(|prefix| model.starts_with(prefix))
+    {
+        return false;
+    }
+    ["gpt-4o", "gpt-4.1", "gpt-5", "o1", "o3", "o4"]
+        .iter()
+        .any(|prefix| model.starts_with(prefix))
+}
+
+/// Ask for the command as schema-checked JSON instead of parsing free text
+async fn get_openai_command_structured(
+    prompt: &str,
+    image: Option<&Image>,
+    config: &Config,
+) -> Result<Suggestion> {
+    let response_format = serde_json::json!({
+        "type": "json_schema",
+        "json_schema": {
+            "name": "shell_command",
+            "strict": true,
+            "schema": {
+                "type": "object",
+                "properties": {
+                    "command": { "type": "string" },
+                    "dangerous": { "type": "boolean" }
+                },
+                "required": ["command", "dangerous"],
+                "additionalProperties": false
+            }
+        }
+    });
+
+    let reply = openai_complete(
+        STRUCTURED_COMMAND_SYSTEM_PROMPT,
+        prompt,
+        image,
+        Some(response_format),
+        config,
+    )
+    .await?;
+    let mut suggestion = parse_structured_command(&reply.text)?;
+    suggestion.truncated = reply.truncated;
+    Ok(suggestion)
+}
+
+fn parse_structured_command(text: &str) -> Result<Suggestion> {
+    let parsed: StructuredCommand = serde_json::from_str(text.trim()).map_err(|e| {
+        SprenError::InvalidCommand(format!("Invalid structured response: {}", e))
+    })?;
+    let command = parsed.command.trim();
+    if command.is_empty() {
+        return Err(SprenError::InvalidCommand("Model returned an empty command".into()).into());
+    }
+    Ok(Suggestion {
+        command: command.to_string(),
+        dangerous: parsed.dangerous,
+        explanation: None,
+        extraction: Extraction::Structured,
+        truncated: false,
+        escalated_to: None,
+        answered_by: String::new(),
+        retried_with_tokens: None,
+    })
+}
+
+async fn get_openai_error(
+    command: &str,
+    stdout: &str,
+    stderr: &str,
+    config: &Config,
+) -> Result<String> {
+    let shell_type = ShellType::detect();
+    let prompt = build_error_prompt(shell_type.get_shell_name(), command, stdout, stderr, config);
+
+    let text = openai_complete(EXPLAIN_SYSTEM_PROMPT, &prompt, None, None, config).await?.text;
+    Ok(text.trim().to_string())
+}
+
+async fn get_openai_fix(
+    query: &str,
+    command: &str,
+    stdout: &str,
+    stderr: &str,
+    tried: &[String],
+    config: &Config,
+) -> Result<(String, bool)> {
+    let shell_type = ShellType::detect();
+    let prompt = build_remote_fix_prompt(shell_type.get_shell_name(), query, command, stdout, stderr, tried);
+
+    let reply = openai_complete(COMMAND_SYSTEM_PROMPT, &prompt, None, None, config).await?;
+    parse_fix_reply(&reply.text, reply.truncated, config.ai.output_tokens(), config)
+}
+
+async fn get_openai_explanation(command: &str, config: &Config) -> Result<String> {
     let shell_type = ShellType::detect();
-    let shell_name = shell_type.get_shell_name();
+    let prompt = build_explain_prompt(shell_type.get_shell_name(), command, config);
+
+    let text = openai_complete(EXPLAIN_SYSTEM_PROMPT, &prompt, None, None, config).await?.text;
+    Ok(text.trim().to_string())
+}
+
+/// Send a single-turn request to the OpenAI chat completions API and return the text
+async fn openai_complete(
+    system: &str,
+    prompt: &str,
+    image: Option<&Image>,
+    response_format: Option<serde_json::Value>,
+    config: &Config,
+) -> Result<Reply> {
+    let api_key = cloud_api_key(config.ai.openai_api_key.as_ref(), "OpenAI", "openai_api_key")?;
 
-    let prompt = build_command_prompt(shell_name, query);
     let model = get_model_or_default(config, "gpt-4o-mini");
 
-    // Use max_completion_tokens for newer models, fall back to max_tokens for compatibility
-    let response = client
-        .post("https://api.openai.com/v1/chat/completions")
-        .headers(headers)
-        .json(&serde_json::json!({
-            "model": model,
-            "max_completion_tokens": config.ai.max_tokens,
-            "messages": [
-                {
-                    "role": "system",
-                    "content": "You are Spren, a helpful command-line assistant. Respond only in the specified format."
-                },
-                {
-                    "role": "user",
-                    "content": prompt
-                }
-            ]
-        }))
-        .send()
-        .await?
-        .json::<OpenAIResponse>()
-        .await?;
+    openai_chat(
+        "https://api.openai.com/v1/chat/completions",
+        Some(api_key),
+        model,
+        system,
+        openai_user_content(prompt, image),
+        response_format,
+        config,
+    )
+    .await
+}
 
-    if let Some(error) = response.error {
-        return Err(anyhow!("OpenAI API error: {}", error.message));
+/// The user message: plain text, or text and image parts for vision models
+fn openai_user_content(prompt: &str, image: Option<&Image>) -> serde_json::Value {
+    match image {
+        None => prompt.into(),
+        Some(image) => serde_json::json!([
+            { "type": "text", "text": prompt },
+            { "type": "image_url", "image_url": { "url": image.data_url() } }
+        ]),
     }
+}
 
-    let choices = response
-        .choices
-        .ok_or_else(|| anyhow!("OpenAI API returned no choices"))?;
+/// Chat completion against any OpenAI-compatible endpoint
+async fn openai_chat(
+    url: &str,
+    api_key: Option<&str>,
+    model: &str,
+    system: &str,
+    content: serde_json::Value,
+    response_format: Option<serde_json::Value>,
+    config: &Config,
+) -> Result<Reply> {
+    let client = reqwest::Client::new();
+    let mut headers = HeaderMap::new();
+    if let Some(api_key) = api_key {
+        headers.insert(
+            AUTHORIZATION,
+            HeaderValue::from_str(&format!("Bearer {}", api_key))?,
+        );
+    }
+    headers.insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
 
-    if choices.is_empty() {
-        return Err(anyhow!("OpenAI API returned empty choices"));
+    // Use max_completion_tokens for newer models, fall back to max_tokens for compatibility
+    let mut body = serde_json::json!({
+        "model": model,
+        "max_completion_tokens": output_tokens_for(model, config),
+        "messages": [
+            {
+                "role": "system",
+                "content": system
+            },
+            {
+                "role": "user",
+                "content": content
+            }
+        ]
+    });
+    if let Some(response_format) = response_format {
+        body["response_format"] = response_format;
     }
 
-    parse_ai_response(&choices[0].message.content)
+    retry_empty(config, || async {
+        let response = client
+            .post(url)
+            .headers(headers.clone())
+            .json(&body)
+            .send()
+            .await?
+            .json::<OpenAIResponse>()
+            .await?;
+
+        if let Some(error) = response.error {
+            return Err(anyhow!("OpenAI API error: {}", error.message));
+        }
+
+        let choice = response.choices.and_then(|c| c.into_iter().next());
+        let truncated = choice.as_ref().is_some_and(|c| c.finish_reason.as_deref() == Some("length"));
+        let text = choice.and_then(|c| c.message.content);
+        non_empty("OpenAI", text).map(|text| Reply { text, truncated })
+    })
+    .await
+}
+
+// ============================================================================
+// Gemini Implementation
+// ============================================================================
+
+async fn get_gemini_command(
+    query: &str,
+    image: Option<&Image>,
+    strict: bool,
+    config: &Config,
+) -> Result<Suggestion> {
+    let shell_type = ShellType::detect();
+    let prompt = build_command_prompt(
+        shell_type.get_shell_name(),
+        query,
+        &prompt_environment(query, config),
+        &config.ai.style_guidelines,
+        strict,
+    );
+
+    let reply = gemini_complete(COMMAND_SYSTEM_PROMPT, &prompt, image, config).await?;
+    parse_command_reply(reply)
 }
 
-async fn get_openai_error(
+async fn get_gemini_error(
     command: &str,
     stdout: &str,
     stderr: &str,
     config: &Config,
 ) -> Result<String> {
-    let api_key = config
-        .ai
-        .openai_api_key
-        .as_ref()
-        .ok_or_else(|| anyhow!("OpenAI API key not configured. Set 'openai_api_key' in config."))?;
-
-    let client = reqwest::Client::new();
-    let mut headers = HeaderMap::new();
-    headers.insert(
-        AUTHORIZATION,
-        HeaderValue::from_str(&format!("Bearer {}", api_key))?,
-    );
-    headers.insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
-
     let shell_type = ShellType::detect();
-    let shell_name = shell_type.get_shell_name();
+    let prompt = build_error_prompt(shell_type.get_shell_name(), command, stdout, stderr, config);
 
-    let prompt = build_error_prompt(shell_name, command, stdout, stderr);
-    let model = get_model_or_default(config, "gpt-4o-mini");
-
-    let response = client
-        .post("https://api.openai.com/v1/chat/completions")
-        .headers(headers)
-        .json(&serde_json::json!({
-            "model": model,
-            "max_completion_tokens": config.ai.max_tokens,
-            "messages": [
-                {
-                    "role": "system",
-                    "content": "You are Spren, a helpful command-line assistant. Provide clear and concise explanations."
-                },
-                {
-                    "role": "user",
-                    "content": prompt
-                }
-            ]
-        }))
-        .send()
-        .await?
-        .json::<OpenAIResponse>()
-        .await?;
+    let text = gemini_complete(EXPLAIN_SYSTEM_PROMPT, &prompt, None, config).await?.text;
+    Ok(text.trim().to_string())
+}
 
-    if let Some(error) = response.error {
-        return Err(anyhow!("OpenAI API error: {}", error.message));
-    }
+async fn get_gemini_fix(
+    query: &str,
+    command: &str,
+    stdout: &str,
+    stderr: &str,
+    tried: &[String],
+    config: &Config,
+) -> Result<(String, bool)> {
+    let shell_type = ShellType::detect();
+    let prompt = build_remote_fix_prompt(shell_type.get_shell_name(), query, command, stdout, stderr, tried);
 
-    let choices = response
-        .choices
-        .ok_or_else(|| anyhow!("OpenAI API returned no choices"))?;
+    let reply = gemini_complete(COMMAND_SYSTEM_PROMPT, &prompt, None, config).await?;
+    parse_fix_reply(&reply.text, reply.truncated, config.ai.output_tokens(), config)
+}
 
-    if choices.is_empty() {
-        return Err(anyhow!("OpenAI API returned empty choices"));
-    }
+async fn get_gemini_explanation(command: &str, config: &Config) -> Result<String> {
+    let shell_type = ShellType::detect();
+    let prompt = build_explain_prompt(shell_type.get_shell_name(), command, config);
 
-    Ok(choices[0].message.content.trim().to_string())
+    let text = gemini_complete(EXPLAIN_SYSTEM_PROMPT, &prompt, None, config).await?.text;
+    Ok(text.trim().to_string())
 }
 
-// ============================================================================
-// Gemini Implementation
-// ============================================================================
-
-async fn get_gemini_command(query: &str, config: &Config) -> Result<(String, bool)> {
-    let api_key = config
-        .ai
-        .gemini_api_key
-        .as_ref()
-        .ok_or_else(|| anyhow!("Gemini API key not configured. Set 'gemini_api_key' in config."))?;
+/// Send a single-turn request to the Gemini generateContent API and return the text
+async fn gemini_complete(
+    system: &str,
+    prompt: &str,
+    image: Option<&Image>,
+    config: &Config,
+) -> Result<Reply> {
+    let api_key = cloud_api_key(config.ai.gemini_api_key.as_ref(), "Gemini", "gemini_api_key")?;
 
     let client = reqwest::Client::new();
 
-    let shell_type = ShellType::detect();
-    let shell_name = shell_type.get_shell_name();
-
-    let prompt = format!(
-        "You are Spren, a helpful command-line assistant. Respond only in the specified format.\n\n{}",
-        build_command_prompt(shell_name, query)
-    );
     let model = get_model_or_default(config, "gemini-2.0-flash");
 
     let url = format!(
@@ -399,108 +1412,206 @@ async fn get_gemini_command(query: &str, config: &Config) -> Result<(String, boo
         model, api_key
     );
 
-    let response = client
-        .post(&url)
-        .header(CONTENT_TYPE, "application/json")
-        .json(&serde_json::json!({
-            "contents": [{
-                "parts": [{
-                    "text": prompt
-                }]
-            }],
-            "generationConfig": {
-                "temperature": config.ai.temperature,
-                "maxOutputTokens": config.ai.max_tokens
-            }
-        }))
-        .send()
-        .await?
-        .json::<GeminiResponse>()
-        .await?;
+    let body = gemini_request_body(model, system, prompt, image, config);
 
-    if let Some(error) = response.error {
-        return Err(anyhow!("Gemini API error: {}", error.message));
-    }
+    retry_empty(config, || async {
+        let response = client
+            .post(&url)
+            .header(CONTENT_TYPE, "application/json")
+            .json(&body)
+            .send()
+            .await?
+            .json::<GeminiResponse>()
+            .await?;
 
-    let candidates = response
-        .candidates
-        .ok_or_else(|| anyhow!("Gemini API returned no candidates"))?;
+        if let Some(error) = response.error {
+            return Err(anyhow!("Gemini API error: {}", error.message));
+        }
 
-    if candidates.is_empty() {
-        return Err(anyhow!("Gemini API returned empty candidates"));
-    }
+        let candidate = response.candidates.and_then(|c| c.into_iter().next());
+        let truncated =
+            candidate.as_ref().is_some_and(|c| c.finish_reason.as_deref() == Some("MAX_TOKENS"));
+        let text = candidate
+            .and_then(|c| c.content)
+            .and_then(|c| c.parts.into_iter().next())
+            .map(|p| p.text);
+        non_empty("Gemini", text).map(|text| Reply { text, truncated })
+    })
+    .await
+}
 
-    if candidates[0].content.parts.is_empty() {
-        return Err(anyhow!("Gemini API returned empty parts"));
+/// generateContent body with the system prompt in `systemInstruction`,
+/// leaving only the user's request (and any image) in `contents`
+fn gemini_request_body(
+    model: &str,
+    system: &str,
+    prompt: &str,
+    image: Option<&Image>,
+    config: &Config,
+) -> serde_json::Value {
+    let mut parts = vec![serde_json::json!({ "text": prompt })];
+    if let Some(image) = image {
+        parts.push(serde_json::json!({
+            "inlineData": {
+                "mimeType": image.mime_type,
+                "data": image.data
+            }
+        }));
     }
+    serde_json::json!({
+        "systemInstruction": {
+            "parts": [{
+                "text": system
+            }]
+        },
+        "contents": [{
+            "role": "user",
+            "parts": parts
+        }],
+        "generationConfig": {
+            "temperature": config.ai.temperature,
+            "maxOutputTokens": output_tokens_for(model, config)
+        }
+    })
+}
+
+// ============================================================================
+// llama.cpp Server Implementation
+// ============================================================================
 
-    parse_ai_response(&candidates[0].content.parts[0].text)
+/// Streamed chunk from llama.cpp's native `/completion` endpoint
+#[derive(Debug, Deserialize)]
+struct LlamaCppChunk {
+    #[serde(default)]
+    content: String,
+    #[serde(default)]
+    stop: bool,
+    /// Generation ended at `n_predict`
+    #[serde(default)]
+    stopped_limit: bool,
 }
 
-async fn get_gemini_error(
+async fn get_llamacpp_command(query: &str, strict: bool, config: &Config) -> Result<Suggestion> {
+    let shell_type = ShellType::detect();
+    let prompt = build_command_prompt(
+        shell_type.get_shell_name(),
+        query,
+        &prompt_environment(query, config),
+        &config.ai.style_guidelines,
+        strict,
+    );
+
+    let reply = llamacpp_complete(COMMAND_SYSTEM_PROMPT, &prompt, config).await?;
+    parse_command_reply(reply)
+}
+
+async fn get_llamacpp_error(
     command: &str,
     stdout: &str,
     stderr: &str,
     config: &Config,
 ) -> Result<String> {
-    let api_key = config
-        .ai
-        .gemini_api_key
-        .as_ref()
-        .ok_or_else(|| anyhow!("Gemini API key not configured. Set 'gemini_api_key' in config."))?;
+    let shell_type = ShellType::detect();
+    let prompt = build_error_prompt(shell_type.get_shell_name(), command, stdout, stderr, config);
 
-    let client = reqwest::Client::new();
+    let text = llamacpp_complete(EXPLAIN_SYSTEM_PROMPT, &prompt, config).await?.text;
+    Ok(text.trim().to_string())
+}
+
+async fn get_llamacpp_fix(
+    query: &str,
+    command: &str,
+    stdout: &str,
+    stderr: &str,
+    tried: &[String],
+    config: &Config,
+) -> Result<(String, bool)> {
+    let shell_type = ShellType::detect();
+    let prompt = build_remote_fix_prompt(shell_type.get_shell_name(), query, command, stdout, stderr, tried);
+
+    let reply = llamacpp_complete(COMMAND_SYSTEM_PROMPT, &prompt, config).await?;
+    parse_fix_reply(&reply.text, reply.truncated, config.ai.output_tokens(), config)
+}
 
+async fn get_llamacpp_explanation(command: &str, config: &Config) -> Result<String> {
     let shell_type = ShellType::detect();
-    let shell_name = shell_type.get_shell_name();
+    let prompt = build_explain_prompt(shell_type.get_shell_name(), command, config);
 
-    let prompt = format!(
-        "You are Spren, a helpful command-line assistant. Provide clear and concise explanations.\n\n{}",
-        build_error_prompt(shell_name, command, stdout, stderr)
-    );
-    let model = get_model_or_default(config, "gemini-2.0-flash");
+    let text = llamacpp_complete(EXPLAIN_SYSTEM_PROMPT, &prompt, config).await?.text;
+    Ok(text.trim().to_string())
+}
 
-    let url = format!(
-        "https://generativelanguage.googleapis.com/v1beta/models/{}:generateContent?key={}",
-        model, api_key
-    );
+/// Send a request to a llama.cpp server using the configured API flavor
+async fn llamacpp_complete(system: &str, prompt: &str, config: &Config) -> Result<Reply> {
+    let base_url = config.ai.llamacpp_url.trim_end_matches('/');
+
+    match config.ai.llamacpp_api {
+        LlamaCppApi::OpenAI => {
+            let url = format!("{}/v1/chat/completions", base_url);
+            openai_chat(&url, None, &config.ai.model, system, prompt.into(), None, config).await
+        }
+        LlamaCppApi::Native => {
+            let url = format!("{}/completion", base_url);
+            llamacpp_native(&url, system, prompt, config).await
+        }
+    }
+}
 
-    let response = client
-        .post(&url)
+/// Stream a completion from the native `/completion` endpoint
+async fn llamacpp_native(url: &str, system: &str, prompt: &str, config: &Config) -> Result<Reply> {
+    let client = reqwest::Client::new();
+
+    let mut response = client
+        .post(url)
         .header(CONTENT_TYPE, "application/json")
         .json(&serde_json::json!({
-            "contents": [{
-                "parts": [{
-                    "text": prompt
-                }]
-            }],
-            "generationConfig": {
-                "temperature": config.ai.temperature,
-                "maxOutputTokens": config.ai.max_tokens
-            }
+            "prompt": format!("{}\n\n{}\n", system, prompt),
+            "n_predict": config.ai.output_tokens(),
+            "temperature": config.ai.temperature,
+            "stop": ["<|im_end|>", "<|endoftext|>", "<|eot_id|>", "</s>"],
+            "stream": true
         }))
         .send()
-        .await?
-        .json::<GeminiResponse>()
-        .await?;
+        .await
+        .map_err(|e| anyhow!("Could not reach llama.cpp server at {}: {}", url, e))?;
 
-    if let Some(error) = response.error {
-        return Err(anyhow!("Gemini API error: {}", error.message));
+    if !response.status().is_success() {
+        let status = response.status();
+        let body = response.text().await.unwrap_or_default();
+        return Err(anyhow!("llama.cpp server error ({}): {}", status, body.trim()));
     }
 
-    let candidates = response
-        .candidates
-        .ok_or_else(|| anyhow!("Gemini API returned no candidates"))?;
+    // Server-sent events: one `data: {json}` line per generated chunk
+    let mut text = String::new();
+    let mut truncated = false;
+    let mut buffer = String::new();
+
+    'stream: while let Some(chunk) = response.chunk().await? {
+        buffer.push_str(&String::from_utf8_lossy(&chunk));
 
-    if candidates.is_empty() {
-        return Err(anyhow!("Gemini API returned empty candidates"));
+        while let Some(newline) = buffer.find('\n') {
+            let line: String = buffer.drain(..=newline).collect();
+            let Some(data) = line.trim().strip_prefix("data:") else {
+                continue;
+            };
+
+            let chunk: LlamaCppChunk = serde_json::from_str(data.trim())
+                .map_err(|e| anyhow!("Invalid llama.cpp stream data: {}", e))?;
+            text.push_str(&chunk.content);
+            emit_partial(&chunk.content);
+
+            if chunk.stop {
+                truncated = chunk.stopped_limit;
+                break 'stream;
+            }
+        }
     }
 
-    if candidates[0].content.parts.is_empty() {
-        return Err(anyhow!("Gemini API returned empty parts"));
+    if text.trim().is_empty() {
+        return Err(anyhow!("llama.cpp server returned empty content"));
     }
 
-    Ok(candidates[0].content.parts[0].text.trim().to_string())
+    Ok(Reply { text, truncated })
 }
 
 // ============================================================================
@@ -515,27 +1626,141 @@ fn get_model_or_default<'a>(config: &'a Config, default: &'a str) -> &'a str {
     }
 }
 
-fn build_command_prompt(shell_name: &str, query: &str) -> String {
-    format!(
+/// `provider/model` for display, or just the provider when the model is
+/// whatever the server has loaded (llama.cpp)
+fn model_label(config: &Config) -> String {
+    match get_model_or_default(config, config.get_default_model_for_provider()) {
+        "" => config.ai.provider.name().to_string(),
+        model => format!("{}/{}", config.ai.provider.name(), model),
+    }
+}
+
+/// Facts about this system for cloud prompts, unless context is turned off
+fn prompt_environment(query: &str, config: &Config) -> Vec<String> {
+    if !config.ai.include_context {
+        return Vec::new();
+    }
+    let manager = crate::context::detect_package_manager()
+        .map(|manager| format!("This system installs packages with {}.", manager));
+    manager.into_iter().chain(subproject_hint(query, config)).collect()
+}
+
+/// With `ai.subproject_hints`, point the model at the subdirectory the query names
+///
+/// Nothing is read in a directory excluded by `ai.context_exclude_dirs`.
+fn subproject_hint(query: &str, config: &Config) -> Option<String> {
+    if !config.ai.subproject_hints || !config.ai.include_context {
+        return None;
+    }
+    let cwd = std::env::current_dir().ok()?;
+    if crate::context::is_excluded(&cwd, &config.ai.context_exclude_dirs) {
+        return None;
+    }
+    let project = crate::context::find_subproject(&cwd, query)?;
+    Some(format!(
+        "The query refers to the {} project in ./{}; cd into it first (cd {} && ...).",
+        project.kind, project.name, project.name
+    ))
+}
+
+/// Stands in for the command in the reply format; a model sometimes copies it verbatim
+const COMMAND_PLACEHOLDER: &str = "your_command_here";
+
+fn build_command_prompt(
+    shell_name: &str,
+    query: &str,
+    environment: &[String],
+    style_guidelines: &[String],
+    strict: bool,
+) -> String {
+    let mut prompt = format!(
         r#"Convert to a {} command: {}
 
 Reply ONLY in this exact format (2 lines, no explanation):
 DANGEROUS:false
-COMMAND:your_command_here
+COMMAND:{}
 
 Set DANGEROUS:true only for destructive commands (rm -rf, format, dd, etc)."#,
-        shell_name, query
+        shell_name, query, COMMAND_PLACEHOLDER
+    );
+
+    if !environment.is_empty() {
+        prompt.push_str(&format!("\n\n{}", environment.join("\n")));
+    }
+    prompt.push_str(&format_style_guidelines(style_guidelines));
+
+    if strict {
+        prompt.push_str(
+            "\n\nYour previous reply was not a usable command. Reply with exactly those 2 lines. \
+             The COMMAND line must contain a complete, runnable command.",
+        );
+        prompt.push_str(&format!(" Replace {} with the actual command.", COMMAND_PLACEHOLDER));
+    }
+
+    prompt
+}
+
+/// User style preferences as a bullet list, or empty if there are none
+fn format_style_guidelines(style_guidelines: &[String]) -> String {
+    let bullets: Vec<String> = style_guidelines
+        .iter()
+        .map(|g| g.trim())
+        .filter(|g| !g.is_empty())
+        .map(|g| format!("- {}", g))
+        .collect();
+
+    if bullets.is_empty() {
+        String::new()
+    } else {
+        format!("\n\nFollow these style preferences:\n{}", bullets.join("\n"))
+    }
+}
+
+fn build_error_prompt(
+    shell_name: &str,
+    command: &str,
+    stdout: &str,
+    stderr: &str,
+    config: &Config,
+) -> String {
+    format!(
+        "Analyze briefly. {} command: {}\nOutput: {}\nError: {}\nOne short paragraph max.{}",
+        shell_name,
+        command,
+        stdout,
+        stderr,
+        language_instruction(config)
     )
 }
 
-fn build_error_prompt(shell_name: &str, command: &str, stdout: &str, stderr: &str) -> String {
+fn build_explain_prompt(shell_name: &str, command: &str, config: &Config) -> String {
     format!(
-        "Analyze briefly. {} command: {}\nOutput: {}\nError: {}\nOne short paragraph max.",
-        shell_name, command, stdout, stderr
+        "Explain what this {} command does in plain language: {}\nDescribe each part briefly. A few sentences max.{}",
+        shell_name,
+        command,
+        language_instruction(config)
     )
 }
 
-fn parse_ai_response(response: &str) -> Result<(String, bool)> {
+/// `ai.response_language` as a prompt suffix; commands themselves stay as they are
+fn language_instruction(config: &Config) -> String {
+    match config.ai.response_language.as_deref().map(str::trim) {
+        Some(language) if !language.is_empty() => format!(
+            "\nRespond in {}. Keep commands, flags and file names exactly as written.",
+            language
+        ),
+        _ => String::new(),
+    }
+}
+
+/// Parse a cloud or llama.cpp reply, noting whether it was cut off
+fn parse_command_reply(reply: Reply) -> Result<Suggestion> {
+    let mut suggestion = parse_ai_response(&reply.text)?;
+    suggestion.truncated = reply.truncated;
+    Ok(suggestion)
+}
+
+fn parse_ai_response(response: &str) -> Result<Suggestion> {
     let response = response.trim();
 
     // Try to find DANGEROUS line
@@ -543,17 +1768,51 @@ fn parse_ai_response(response: &str) -> Result<(String, bool)> {
         || response.to_lowercase().contains("dangerous: true");
 
     // Try multiple patterns to extract the command
-    let command = extract_command(response)?;
+    let (command, extraction) = extract_command(response)?;
+    let explanation = extract_explanation(response, &command);
+
+    Ok(Suggestion {
+        command,
+        dangerous: is_dangerous,
+        explanation,
+        extraction,
+        truncated: false,
+        escalated_to: None,
+        answered_by: String::new(),
+        retried_with_tokens: None,
+    })
+}
 
-    Ok((command, is_dangerous))
+/// Whatever prose is left once the command and format lines are removed
+fn extract_explanation(response: &str, command: &str) -> Option<String> {
+    let prose: Vec<&str> = response
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with("```"))
+        .filter(|line| {
+            let lower = line.to_lowercase();
+            !lower.starts_with("dangerous") && !lower.contains("command:")
+        })
+        .filter(|line| !line.contains(command) && strip_backticks(line) != command)
+        .map(|line| {
+            let lower = line.to_lowercase();
+            ["explanation:", "reason:", "rationale:"]
+                .iter()
+                .find(|label| lower.starts_with(*label))
+                .map_or(line, |label| line[label.len()..].trim())
+        })
+        .filter(|line| !line.is_empty())
+        .collect();
+
+    (!prose.is_empty()).then(|| prose.join(" "))
 }
 
-fn extract_command(response: &str) -> Result<String> {
+fn extract_command(response: &str) -> Result<(String, Extraction)> {
     let response = response.trim();
 
     // Handle empty response
     if response.is_empty() {
-        return Err(anyhow!("Empty response from AI"));
+        return Err(SprenError::InvalidCommand("Empty response from AI".to_string()).into());
     }
 
     // Pattern 1: COMMAND:xxx or COMMAND: xxx (case insensitive)
@@ -562,7 +1821,7 @@ fn extract_command(response: &str) -> Result<String> {
         if lower.starts_with("command:") {
             let cmd = line[8..].trim();
             if !cmd.is_empty() {
-                return Ok(strip_backticks(cmd));
+                return Ok((strip_backticks(cmd), Extraction::CommandLine));
             }
         }
     }
@@ -572,22 +1831,14 @@ fn extract_command(response: &str) -> Result<String> {
         if let Some(pos) = line.to_lowercase().find("command:") {
             let cmd = line[pos + 8..].trim();
             if !cmd.is_empty() {
-                return Ok(strip_backticks(cmd));
+                return Ok((strip_backticks(cmd), Extraction::CommandInline));
             }
         }
     }
 
     // Pattern 3: Look for ```bash or ``` code blocks
-    if let Some(start) = response.find("```") {
-        let after_fence = &response[start + 3..];
-        // Skip language identifier (bash, sh, etc.)
-        let code_start = after_fence.find('\n').map(|i| i + 1).unwrap_or(0);
-        if let Some(end) = after_fence[code_start..].find("```") {
-            let cmd = after_fence[code_start..code_start + end].trim();
-            if !cmd.is_empty() {
-                return Ok(cmd.to_string());
-            }
-        }
+    if let Some(cmd) = extract_fenced_command(response) {
+        return Ok((cmd, Extraction::CodeFence));
     }
 
     // Pattern 4: Look for single backtick-wrapped command
@@ -595,7 +1846,7 @@ fn extract_command(response: &str) -> Result<String> {
         if let Some(end) = response[start + 1..].find('`') {
             let cmd = &response[start + 1..start + 1 + end];
             if !cmd.is_empty() && !cmd.contains('\n') {
-                return Ok(cmd.to_string());
+                return Ok((cmd.to_string(), Extraction::Backticks));
             }
         }
     }
@@ -605,7 +1856,7 @@ fn extract_command(response: &str) -> Result<String> {
     if lines.len() == 2 {
         let second = lines[1].trim();
         if !second.to_lowercase().starts_with("dangerous") {
-            return Ok(strip_backticks(second));
+            return Ok((strip_backticks(second), Extraction::SecondLine));
         }
     }
 
@@ -613,7 +1864,7 @@ fn extract_command(response: &str) -> Result<String> {
     if lines.len() == 1 {
         let line = lines[0].trim();
         if looks_like_command(line) {
-            return Ok(strip_backticks(line));
+            return Ok((strip_backticks(line), Extraction::SingleLine));
         }
     }
 
@@ -621,11 +1872,147 @@ fn extract_command(response: &str) -> Result<String> {
     for line in response.lines() {
         let trimmed = line.trim();
         if looks_like_command(trimmed) && !trimmed.to_lowercase().contains("dangerous") {
-            return Ok(strip_backticks(trimmed));
+            return Ok((strip_backticks(trimmed), Extraction::Heuristic));
+        }
+    }
+
+    Err(SprenError::InvalidCommand(format!(
+        "Could not extract command from response:\n{}",
+        response
+    ))
+    .into())
+}
+
+/// Fence languages that hold shell commands
+const SHELL_LANGUAGES: &[&str] = &[
+    "bash", "sh", "shell", "zsh", "fish", "console", "shellsession", "terminal", "powershell",
+    "pwsh", "ps1", "ps", "cmd", "bat", "batch",
+];
+
+/// Fence languages that never hold a shell command
+const OTHER_LANGUAGES: &[&str] = &[
+    "python", "py", "javascript", "js", "typescript", "ts", "json", "yaml", "yml", "toml",
+    "rust", "rs", "go", "java", "c", "cpp", "ruby", "rb", "sql", "html", "css", "xml",
+    "text", "txt", "diff", "markdown", "md", "ini", "dockerfile",
+];
+
+/// The command in a fenced code block: the first shell-tagged block, else
+/// the first untagged one. Blocks tagged with another language are ignored.
+fn extract_fenced_command(response: &str) -> Option<String> {
+    let mut untagged = None;
+    let mut rest = response;
+
+    while let Some(start) = rest.find("```") {
+        let after_fence = &rest[start + 3..];
+        let end = after_fence.find("```")?;
+        let block = &after_fence[..end];
+        rest = &after_fence[end + 3..];
+
+        let (language, code) = split_fence_language(block);
+        let code = code
+            .lines()
+            .map(|l| l.trim().strip_prefix("$ ").unwrap_or(l.trim()))
+            .collect::<Vec<_>>()
+            .join("\n");
+        let code = code.trim();
+        if code.is_empty() {
+            continue;
+        }
+
+        match language {
+            Some(lang) if SHELL_LANGUAGES.contains(&lang.as_str()) => {
+                return Some(code.to_string())
+            }
+            Some(_) => {}
+            None => {
+                untagged.get_or_insert_with(|| code.to_string());
+            }
         }
     }
 
-    Err(anyhow!("Could not extract command from response:\n{}", response))
+    untagged
+}
+
+/// Split a fence's contents into its language tag (lowercased) and code
+///
+/// The tag may sit on its own line or share it with the code
+/// (```` ```bash ls -la``` ````). A leading word only counts as a tag if
+/// it's a language we know, since ```` ```ls -la``` ```` is just a command.
+fn split_fence_language(block: &str) -> (Option<String>, &str) {
+    let trimmed = block.trim_start_matches([' ', '\t']);
+    let word = trimmed
+        .split(char::is_whitespace)
+        .next()
+        .unwrap_or_default()
+        .to_lowercase();
+
+    if SHELL_LANGUAGES.contains(&word.as_str()) || OTHER_LANGUAGES.contains(&word.as_str()) {
+        (Some(word.clone()), &trimmed[word.len()..])
+    } else {
+        (None, block)
+    }
+}
+
+/// Final guard after extraction: reject empty, punctuation-only or echoed output
+fn validate_command(query: &str, command: &str) -> Result<String> {
+    let command = command.trim();
+
+    let invalid = |reason: &str| -> anyhow::Error {
+        SprenError::InvalidCommand(format!("Model did not produce a valid command ({})", reason))
+            .into()
+    };
+
+    if command.is_empty() {
+        return Err(invalid("empty output"));
+    }
+
+    if !command.chars().any(|c| c.is_alphanumeric()) {
+        return Err(invalid("only punctuation"));
+    }
+
+    if command.to_lowercase().contains(COMMAND_PLACEHOLDER) {
+        return Err(invalid("the prompt's placeholder echoed back"));
+    }
+
+    // A lone word lifted from a multi-word query ("files" for "list files")
+    let query_words: Vec<String> = query
+        .split(|c: char| !(c.is_alphanumeric() || c == '-' || c == '_'))
+        .filter(|w| !w.is_empty())
+        .map(|w| w.to_lowercase())
+        .collect();
+    let word = command.trim_matches(|c: char| !c.is_alphanumeric()).to_lowercase();
+    if query_words.len() > 1
+        && !command.contains(char::is_whitespace)
+        && query_words.contains(&word)
+        && !looks_like_command(command)
+    {
+        return Err(invalid("query word echoed back"));
+    }
+
+    Ok(command.to_string())
+}
+
+fn is_invalid_command(err: &anyhow::Error) -> bool {
+    matches!(err.downcast_ref::<SprenError>(), Some(SprenError::InvalidCommand(_)))
+}
+
+/// Strip artifacts models copy from examples into a command
+///
+/// A leading `$ `/`# ` prompt marker, carriage returns, one trailing `;` (but
+/// not `\;` or `;;`, which mean something) and surrounding whitespace.
+fn normalize_command(command: &str) -> String {
+    let command = command.replace('\r', "");
+    let mut command = command.trim();
+
+    if let Some(rest) = command.strip_prefix("$ ").or_else(|| command.strip_prefix("# ")) {
+        command = rest.trim_start();
+    }
+    if let Some(rest) = command.strip_suffix(';') {
+        if !rest.ends_with(['\\', ';']) {
+            command = rest.trim_end();
+        }
+    }
+    command.to_string()
 }
 
 fn strip_backticks(s: &str) -> String {
@@ -658,39 +2045,87 @@ fn looks_like_command(s: &str) -> bool {
 // Local LLM Implementation
 // ============================================================================
 
+/// Ask a running local generation to stop.
+/// Returns false if the model isn't busy, so the caller can handle Ctrl+C normally.
 #[cfg(feature = "local")]
-fn init_local_llm(_config: &Config) -> Result<()> {
-    let mut llm_guard = LOCAL_LLM.lock().map_err(|e| anyhow!("Lock error: {}", e))?;
+pub fn interrupt_local_generation() -> bool {
+    // A model is only checked out while loading or generating
+    let busy = LOCAL_LLM.busy();
+    if busy {
+        LOCAL_CANCEL.store(true, Ordering::Relaxed);
+    }
+    busy
+}
 
-    if llm_guard.is_none() {
+/// A local model to generate with, loading one if none is free and
+/// `ai.local_workers` allows another. Waits for a free one otherwise.
+#[cfg(feature = "local")]
+fn local_model(config: &Config) -> Result<Checkout<'static, LocalSpren>> {
+    LOCAL_LLM.checkout(config.ai.local_workers, || {
         println!("Loading local AI model...");
-        let spren = LocalSpren::load_default()?;
-        *llm_guard = Some(spren);
+        let mut spren =
+            LocalSpren::load_default(config.ai.local_chat_template, &config.ai.local_stop_tokens)?;
+        // Ctrl+C stops every generation in progress
+        spren.set_cancel_flag(LOCAL_CANCEL.clone());
         println!("Model loaded!");
-    }
+        Ok(spren)
+    })
+}
 
-    Ok(())
+/// Run `generate` on a local model, off the async runtime
+///
+/// Checking one out can wait for a free model and generation is CPU-bound,
+/// so both happen on a blocking thread rather than parking a runtime worker.
+#[cfg(feature = "local")]
+async fn with_local_model<T: Send + 'static>(
+    config: &Config,
+    generate: impl FnOnce(&mut LocalSpren) -> Result<T> + Send + 'static,
+) -> Result<T> {
+    let config = config.clone();
+    tokio::task::spawn_blocking(move || {
+        let mut llm = local_model(&config)?;
+        generate(&mut llm)
+    })
+    .await
+    .map_err(|e| anyhow!("Local model task failed: {}", e))?
 }
 
 #[cfg(feature = "local")]
-async fn get_local_command(query: &str, config: &Config) -> Result<(String, bool)> {
+async fn get_local_command(query: &str, strict: bool, config: &Config) -> Result<Suggestion> {
     use crate::context::LocalContext;
 
-    // Initialize LLM if not already done
-    init_local_llm(config)?;
-
     // Gather local context (current directory, files, git status)
-    let ctx = LocalContext::gather();
-    let context_str = ctx.format_for_prompt();
-
-    let max_tokens = config.ai.max_tokens.min(100);
-    let temperature = config.ai.temperature;
-
-    let mut llm_guard = LOCAL_LLM.lock().map_err(|e| anyhow!("Lock error: {}", e))?;
-    let llm = llm_guard.as_mut().ok_or_else(|| anyhow!("LLM not initialized"))?;
-
-    let response = llm.generate_with_context(query, Some(&context_str), max_tokens, temperature)?;
-    parse_ai_response(&response)
+    let mut context_str = if config.ai.include_context {
+        LocalContext::gather(query, config.ai.context_max_files, &config.ai.context_exclude_dirs)
+            .format_for_prompt()
+    } else {
+        String::new()
+    };
+    if let Some(hint) = subproject_hint(query, config) {
+        context_str.push_str(&format!("\n{}", hint));
+    }
+    context_str.push_str(&format_style_guidelines(&config.ai.style_guidelines));
+
+    let max_tokens = config.ai.output_tokens();
+    // The fine-tuned model ignores prompt wording, so be strict by decoding greedily
+    let temperature = if strict { 0.0 } else { config.ai.temperature };
+    let query = query.to_string();
+    let stop_sequences = config.ai.stop_sequences.clone();
+
+    let generation = with_local_model(config, move |llm| {
+        llm.generate_streaming(
+            &query,
+            Some(&context_str),
+            max_tokens,
+            temperature,
+            &stop_sequences,
+            emit_partial,
+        )
+    })
+    .await?;
+    let mut suggestion = parse_ai_response(&generation.text)?;
+    suggestion.truncated = generation.truncated;
+    Ok(suggestion)
 }
 
 #[cfg(feature = "local")]
@@ -700,41 +2135,510 @@ async fn get_local_error(
     stderr: &str,
     config: &Config,
 ) -> Result<String> {
-    // Initialize LLM if not already done
-    init_local_llm(config)?;
+    let (command, stdout, stderr) = (command.to_string(), stdout.to_string(), stderr.to_string());
+    let language = language_instruction(config);
+    with_local_model(config, move |llm| llm.analyze_error(&command, &stdout, &stderr, &language))
+        .await
+}
 
-    let mut llm_guard = LOCAL_LLM.lock().map_err(|e| anyhow!("Lock error: {}", e))?;
-    let llm = llm_guard.as_mut().ok_or_else(|| anyhow!("LLM not initialized"))?;
+#[cfg(feature = "local")]
+async fn get_local_explanation(command: &str, config: &Config) -> Result<String> {
+    let command = command.to_string();
+    let language = language_instruction(config);
+    with_local_model(config, move |llm| llm.explain_command(&command, &language)).await
+}
 
-    llm.analyze_error(command, stdout, stderr)
+#[cfg(feature = "local")]
+async fn get_local_plan(prompt: &str, config: &Config) -> Result<String> {
+    let prompt = prompt.to_string();
+    let (max_tokens, temperature) = (config.ai.output_tokens(), config.ai.temperature);
+    with_local_model(config, move |llm| llm.generate(&prompt, max_tokens, temperature)).await
 }
 
 #[cfg(feature = "local")]
 async fn get_local_fix(
+    query: &str,
     command: &str,
     stdout: &str,
     stderr: &str,
+    tried: &[String],
     config: &Config,
 ) -> Result<(String, bool)> {
     use crate::context::LocalContext;
 
-    init_local_llm(config)?;
-
     // Gather context for better fix suggestions
-    let ctx = LocalContext::gather();
-    let context_str = ctx.format_for_prompt();
+    let context_str = if config.ai.include_context {
+        LocalContext::gather(command, config.ai.context_max_files, &config.ai.context_exclude_dirs)
+            .format_for_prompt()
+    } else {
+        String::new()
+    };
 
-    let fix_prompt = format!(
-        "Command '{}' failed.\nOutput: {}\nError: {}\nProvide a fixed command.",
-        command, stdout, stderr
-    );
+    let fix_prompt = build_fix_prompt(query, command, stdout, stderr, tried);
 
-    let max_tokens = config.ai.max_tokens.min(100);
+    let max_tokens = config.ai.output_tokens();
     let temperature = config.ai.temperature;
+    let stop_sequences = config.ai.stop_sequences.clone();
+
+    let generation = with_local_model(config, move |llm| {
+        llm.generate_with_context(
+            &fix_prompt,
+            Some(&context_str),
+            max_tokens,
+            temperature,
+            &stop_sequences,
+        )
+    })
+    .await?;
+    parse_fix_reply(&generation.text, generation.truncated, max_tokens, config)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fix_prompt_keeps_the_query() {
+        let tried = vec!["find . -size +100M".to_string(), "du -a . | sort -n".to_string()];
+        let prompt = build_fix_prompt(
+            "find large files",
+            "du -a . | sort -n",
+            "",
+            "sort: invalid option",
+            &tried,
+        );
+        assert!(prompt.starts_with("The user asked: find large files\n"));
+        assert!(prompt.contains("Command 'du -a . | sort -n' failed."));
+        assert!(prompt.contains("still does what the user asked"));
+        assert!(prompt.ends_with("try a different approach: find . -size +100M"));
+
+        let prompt = build_fix_prompt("", "ls -Z", "", "bad option", &[]);
+        assert!(prompt.starts_with("Command 'ls -Z' failed."));
+        assert!(prompt.ends_with("Provide a fixed command."));
+    }
+
+    #[test]
+    fn test_remote_fixes_ask_for_the_reply_format() {
+        let prompt = build_remote_fix_prompt("bash", "list files", "ls -Z", "", "bad option", &[]);
+        assert!(prompt.starts_with("Shell: bash\nThe user asked: list files\n"));
+        assert!(prompt.ends_with(&format!("DANGEROUS:false\nCOMMAND:{}", COMMAND_PLACEHOLDER)));
 
-    let mut llm_guard = LOCAL_LLM.lock().map_err(|e| anyhow!("Lock error: {}", e))?;
-    let llm = llm_guard.as_mut().ok_or_else(|| anyhow!("LLM not initialized"))?;
+        let config = Config::default();
+        let reply = "DANGEROUS:false\nCOMMAND:ls -la";
+        assert_eq!(parse_fix_reply(reply, false, 256, &config).unwrap(), ("ls -la".to_string(), false));
+        assert!(parse_fix_reply(reply, true, 256, &config).is_err());
+    }
+
+    #[test]
+    fn test_one_line() {
+        assert_eq!(
+            one_line("Lists files by size. The -h flag prints sizes like 1K and 2M.").as_deref(),
+            Some("Lists files by size.")
+        );
+        assert_eq!(
+            one_line("\n**`du -sh *`** shows the size of each entry\n- sort -h orders them").as_deref(),
+            Some("du -sh * shows the size of each entry")
+        );
+        let long = one_line(&"word ".repeat(40)).unwrap();
+        assert_eq!(long.chars().count(), MAX_ANNOTATION_CHARS);
+        assert!(long.ends_with('…'));
+        assert_eq!(one_line("  \n"), None);
+    }
+
+    #[test]
+    fn test_parse_plan() {
+        let response = "Here is the plan:\n\
+                        1. Find the largest log files\n\
+                        2) Compress logs older than a week\n\
+                        \n\
+                        3. **Delete the compressed logs from last year**\n\
+                        Let me know if you need more.";
+        assert_eq!(
+            parse_plan(response),
+            [
+                "Find the largest log files",
+                "Compress logs older than a week",
+                "Delete the compressed logs from last year",
+            ]
+        );
+        assert_eq!(parse_plan("- Stop the service\n* Back up the database"), ["Stop the service", "Back up the database"]);
+        assert!(parse_plan("I can't plan that.\n2024 was a busy year").is_empty());
+    }
+
+    #[test]
+    fn test_normalize_command() {
+        assert_eq!(normalize_command("$ ls -la"), "ls -la");
+        assert_eq!(normalize_command("# apt update"), "apt update");
+        assert_eq!(normalize_command("ls -la\r"), "ls -la");
+        assert_eq!(normalize_command("cd src &&\r\nmake"), "cd src &&\nmake");
+        assert_eq!(normalize_command("git status;"), "git status");
+        assert_eq!(normalize_command("  df -h ;  "), "df -h");
+
+        // Meaningful semicolons and a `$` that isn't a prompt stay
+        assert_eq!(normalize_command("find . -name '*.tmp' -exec rm {} \\;"), "find . -name '*.tmp' -exec rm {} \\;");
+        assert_eq!(normalize_command("case $x in a) echo a;;"), "case $x in a) echo a;;");
+        assert_eq!(normalize_command("$HOME/bin/tool"), "$HOME/bin/tool");
+    }
+
+    #[test]
+    fn test_validate_rejects_degenerate_commands() {
+        for junk in ["", "   ", "...", "`", "-- ;"] {
+            let err = validate_command("list files", junk).unwrap_err();
+            assert!(is_invalid_command(&err), "accepted {:?}", junk);
+        }
+    }
+
+    #[test]
+    fn test_validate_rejects_the_prompt_placeholder() {
+        let suggestion = parse_ai_response("DANGEROUS:false\nCOMMAND:your_command_here").unwrap();
+        let err = validate_command("list files", &suggestion.command).unwrap_err();
+        assert!(is_invalid_command(&err));
+        assert!(validate_command("list files", "sudo YOUR_COMMAND_HERE --all").is_err());
+
+        let retry = build_command_prompt("bash", "list files", &[], &[], true);
+        assert!(retry.ends_with("Replace your_command_here with the actual command."));
+    }
+
+    #[test]
+    fn test_validate_rejects_echoed_query_word() {
+        let err = validate_command("show disk usage", "disk").unwrap_err();
+        assert!(is_invalid_command(&err));
+        assert!(validate_command("show disk usage", "usage.").is_err());
+    }
+
+    #[test]
+    fn test_validate_accepts_real_commands() {
+        assert_eq!(validate_command("list files", " ls -la ").unwrap(), "ls -la");
+        assert_eq!(validate_command("htop", "htop").unwrap(), "htop");
+        assert_eq!(validate_command("show git status", "git status").unwrap(), "git status");
+        assert_eq!(validate_command("list files", "ls").unwrap(), "ls");
+        assert_eq!(validate_command("what is my ip", "hostname").unwrap(), "hostname");
+    }
+
+    #[test]
+    fn test_style_guidelines_in_prompt() {
+        let prompt = build_command_prompt("Bash", "find todo comments", &[], &[], false);
+        assert!(!prompt.contains("style preferences"));
+
+        let guidelines = vec![
+            "Prefer long flags".to_string(),
+            " ".to_string(),
+            "Use rg instead of grep".to_string(),
+        ];
+        let environment = vec!["This system installs packages with dnf.".to_string()];
+        let prompt = build_command_prompt("Bash", "install ripgrep", &environment, &guidelines, false);
+        assert!(prompt.contains("\n\nThis system installs packages with dnf.\n\nFollow"));
+        assert!(prompt.ends_with(
+            "Follow these style preferences:\n- Prefer long flags\n- Use rg instead of grep"
+        ));
+    }
+
+    #[test]
+    fn test_unextractable_response_is_invalid_command() {
+        assert!(is_invalid_command(&parse_ai_response("").unwrap_err()));
+        assert!(is_invalid_command(
+            &parse_ai_response("Sorry, I can't help with that request.").unwrap_err()
+        ));
+    }
+
+    #[test]
+    fn test_structured_outputs() {
+        assert!(supports_structured_outputs("gpt-4o-mini"));
+        assert!(supports_structured_outputs("o3-mini"));
+        assert!(!supports_structured_outputs("o1-mini"));
+        assert!(supports_structured_outputs("gpt-4o-2024-08-06"));
+        assert!(!supports_structured_outputs("gpt-4o-2024-05-13"));
+        assert!(!supports_structured_outputs("gpt-3.5-turbo"));
+
+        let suggestion =
+            parse_structured_command(r#"{"command": "ls -la", "dangerous": false}"#).unwrap();
+        assert_eq!(suggestion.command, "ls -la");
+        assert!(!suggestion.dangerous);
+        assert!(parse_structured_command(r#"{"command": " ", "dangerous": false}"#).is_err());
+        assert!(parse_structured_command("COMMAND: ls").is_err());
+    }
+
+    #[test]
+    fn test_circuit_breaker() {
+        let mut circuit = CircuitBreaker::default();
+        let start = Instant::now();
+
+        circuit.record_failure(start, "invalid api key".into());
+        circuit.record_failure(start + Duration::from_secs(1), "invalid api key".into());
+        assert!(circuit.check(start + Duration::from_secs(1)).is_none());
+
+        circuit.record_failure(start + Duration::from_secs(2), "invalid api key".into());
+        let cached = circuit.check(start + Duration::from_secs(3)).unwrap();
+        assert!(cached.starts_with("invalid api key"));
+        assert!(circuit.check(start + CIRCUIT_COOLDOWN + Duration::from_secs(3)).is_none());
+
+        circuit.record_success();
+        circuit.record_failure(start + Duration::from_secs(4), "invalid api key".into());
+        assert!(circuit.check(start + Duration::from_secs(4)).is_none());
+    }
+
+    #[test]
+    fn test_persistent_failures() {
+        assert!(is_persistent_failure(&anyhow!("OpenAI API error: Incorrect API key provided")));
+        assert!(!is_persistent_failure(&SprenError::InvalidCommand("x".into()).into()));
+    }
+
+    #[test]
+    fn test_fenced_block_extraction() {
+        // Language on its own line
+        assert_eq!(extract_command("Here you go:\n```bash\nls -la\n```").unwrap().0, "ls -la");
+        // Language and command on one line
+        assert_eq!(extract_command("```bash du -sh * ```").unwrap().0, "du -sh *");
+        assert_eq!(extract_command("```ls -la```").unwrap().0, "ls -la");
+        // Non-shell blocks are skipped in favor of the shell one
+        let response = "```python\nprint('hi')\n```\nOr from the shell:\n```sh\n$ echo hi\n```";
+        assert_eq!(extract_command(response).unwrap(), ("echo hi".to_string(), Extraction::CodeFence));
+        // A shell block wins over an earlier untagged one
+        let response = "```\nsome output\n```\n```powershell\nGet-ChildItem\n```";
+        assert_eq!(extract_fenced_command(response).unwrap(), "Get-ChildItem");
+        // Only non-shell code: nothing to extract from fences
+        assert!(extract_fenced_command("```python\nimport os\n```").is_none());
+    }
+
+    #[test]
+    fn test_explanation_from_leftover_text() {
+        let response = "DANGEROUS: false\nCOMMAND: du -sh *\nExplanation: shows the size of each entry";
+        let suggestion = parse_ai_response(response).unwrap();
+        assert_eq!(suggestion.command, "du -sh *");
+        assert_eq!(suggestion.explanation.as_deref(), Some("shows the size of each entry"));
+
+        let response = "Use this:\n```bash\nls -la\n```\nIt includes hidden files.";
+        let suggestion = parse_ai_response(response).unwrap();
+        assert_eq!(suggestion.explanation.as_deref(), Some("Use this: It includes hidden files."));
+
+        assert_eq!(parse_ai_response("DANGEROUS: false\nCOMMAND: ls").unwrap().explanation, None);
+    }
+
+    #[test]
+    fn test_gemini_system_instruction() {
+        let body =
+            gemini_request_body("gemini-2.0-flash", COMMAND_SYSTEM_PROMPT, "list files", None, &Config::default());
+        assert_eq!(body["systemInstruction"]["parts"][0]["text"], COMMAND_SYSTEM_PROMPT);
+        assert_eq!(body["contents"][0]["parts"][0]["text"], "list files");
+        assert_eq!(body["contents"].as_array().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_output_tokens_clamped_to_model_limit() {
+        let mut config = Config::default();
+        config.ai.max_tokens = 10000;
+        assert_eq!(output_tokens_for("claude-3-5-haiku-20241022", &config), 8192);
+        assert_eq!(output_tokens_for("gpt-4o-mini", &config), 10000);
+        assert_eq!(output_tokens_for("gpt-4o-2024-05-13", &config), 4096);
+        assert_eq!(output_tokens_for("my-finetune", &config), 10000);
+        let body = gemini_request_body("gemini-1.5-flash", COMMAND_SYSTEM_PROMPT, "ls", None, &config);
+        assert_eq!(body["generationConfig"]["maxOutputTokens"], 8192);
+
+        config.ai.provider = AIProvider::Anthropic;
+        config.ai.model.clear();
+        assert_eq!(output_token_clamp(&config), Some(("claude-3-5-haiku-20241022".to_string(), 8192)));
+        config.ai.max_output_tokens = Some(500);
+        assert_eq!(output_token_clamp(&config), None);
+    }
+
+    #[test]
+    fn test_retry_output_tokens() {
+        let mut config = Config::default();
+        config.ai.provider = AIProvider::OpenAI;
+        config.ai.model = "gpt-4o-mini".to_string();
+        config.ai.max_output_tokens = Some(300);
+        assert_eq!(retry_output_tokens(&config), Some(600));
+
+        // Capped by the ceiling, then by the model
+        config.ai.max_output_tokens = Some(3000);
+        assert_eq!(retry_output_tokens(&config), Some(4096));
+        config.ai.model = "gpt-4o-2024-05-13".to_string();
+        assert_eq!(retry_output_tokens(&config), Some(4096));
+        config.ai.max_output_tokens = Some(4096);
+        assert_eq!(retry_output_tokens(&config), None);
+
+        config.ai.model = "gpt-4o-mini".to_string();
+        config.ai.truncation_retry_max_tokens = 0;
+        assert_eq!(retry_output_tokens(&config), None);
+    }
+
+    #[test]
+    fn test_cut_off_replies_are_detected() {
+        let openai: OpenAIResponse = serde_json::from_str(
+            r#"{"choices": [{"message": {"content": "COMMAND:find . -name"}, "finish_reason": "length"}]}"#,
+        )
+        .unwrap();
+        assert_eq!(openai.choices.unwrap()[0].finish_reason.as_deref(), Some("length"));
+
+        let gemini: GeminiResponse = serde_json::from_str(
+            r#"{"candidates": [{"content": {"parts": [{"text": "ls"}]}, "finishReason": "MAX_TOKENS"}]}"#,
+        )
+        .unwrap();
+        assert_eq!(gemini.candidates.unwrap()[0].finish_reason.as_deref(), Some("MAX_TOKENS"));
+
+        let reply = Reply { text: "DANGEROUS:false\nCOMMAND:find . -name".to_string(), truncated: true };
+        assert!(parse_command_reply(reply).unwrap().truncated);
+    }
+
+    #[test]
+    fn test_model_label() {
+        let mut config = Config::default();
+        config.ai.provider = AIProvider::Anthropic;
+        config.ai.model.clear();
+        assert_eq!(model_label(&config), "anthropic/claude-3-5-haiku-20241022");
+        config.ai.model = "claude-3-7-sonnet-latest".to_string();
+        assert_eq!(model_label(&config), "anthropic/claude-3-7-sonnet-latest");
+
+        config.ai.provider = AIProvider::LlamaCpp;
+        config.ai.model.clear();
+        assert_eq!(model_label(&config), "llamacpp");
+    }
+
+    #[tokio::test]
+    async fn test_escalation_needs_opt_in_and_a_key() {
+        let parse_error = || SprenError::InvalidCommand("no command".into()).into();
+        let mut config = Config::default();
+        config.ai.provider = AIProvider::LlamaCpp;
+        config.ai.anthropic_api_key = Some("sk-ant-test".to_string());
+
+        // Off by default
+        let err = escalate("list files", None, parse_error(), &config).await.unwrap_err();
+        assert!(is_invalid_command(&err));
+
+        // On, but no cloud provider has a key
+        config.ai.escalate_on_parse_failure = true;
+        config.ai.anthropic_api_key = None;
+        let err = escalate("list files", None, parse_error(), &config).await.unwrap_err();
+        assert!(is_invalid_command(&err));
+
+        // A cloud provider that failed is never escalated from
+        config.ai.provider = AIProvider::OpenAI;
+        config.ai.anthropic_api_key = Some("sk-ant-test".to_string());
+        let err = escalate("list files", None, parse_error(), &config).await.unwrap_err();
+        assert!(is_invalid_command(&err));
+    }
+
+    #[tokio::test]
+    async fn test_retry_empty_responses() {
+        let mut config = Config::default();
+        config.ai.empty_response_retries = 2;
+
+        let calls = std::cell::Cell::new(0);
+        let text = retry_empty(&config, || async {
+            calls.set(calls.get() + 1);
+            non_empty("OpenAI", (calls.get() == 2).then(|| "ls -la".to_string()))
+        })
+        .await;
+        assert_eq!(text.unwrap(), "ls -la");
+        assert_eq!(calls.get(), 2);
+
+        // Error objects and persistent emptiness are not retried forever
+        calls.set(0);
+        let err = retry_empty(&config, || async {
+            calls.set(calls.get() + 1);
+            non_empty("Gemini", Some("  ".to_string()))
+        })
+        .await
+        .unwrap_err();
+        assert_eq!(err.to_string(), "Gemini API returned empty content");
+        assert_eq!(calls.get(), 3);
+
+        calls.set(0);
+        let _: Result<String> = retry_empty(&config, || async {
+            calls.set(calls.get() + 1);
+            Err(anyhow!("OpenAI API error: invalid model"))
+        })
+        .await;
+        assert_eq!(calls.get(), 1);
+    }
+
+    #[test]
+    fn test_already_tried() {
+        let tried = vec!["ls -la /root".to_string(), "sudo ls /root".to_string()];
+        assert!(already_tried("ls  -la /root ", &tried));
+        assert!(!already_tried("ls -la /home", &tried));
+        assert!(!already_tried("ls -la /root", &[]));
+    }
+
+    #[test]
+    fn test_response_language() {
+        let mut config = Config::default();
+        assert!(!build_explain_prompt("bash", "ls -la", &config).contains("Respond in"));
+
+        config.ai.response_language = Some("Spanish".to_string());
+        let prompt = build_explain_prompt("bash", "ls -la", &config);
+        assert!(prompt.contains("ls -la"));
+        assert!(prompt.ends_with("Respond in Spanish. Keep commands, flags and file names exactly as written."));
+        assert!(build_error_prompt("bash", "ls", "", "denied", &config).contains("Respond in Spanish"));
+    }
 
-    let response = llm.generate_with_context(&fix_prompt, Some(&context_str), max_tokens, temperature)?;
-    parse_ai_response(&response)
+    #[test]
+    fn test_image_requests() {
+        let image = Image {
+            mime_type: "image/png",
+            data: "iVBORw0KGgo=".to_string(),
+        };
+        let body =
+            gemini_request_body("gemini-2.0-flash", COMMAND_SYSTEM_PROMPT, "fix this", Some(&image), &Config::default());
+        assert_eq!(body["contents"][0]["parts"][1]["inlineData"]["mimeType"], "image/png");
+        assert_eq!(body["contents"][0]["parts"][1]["inlineData"]["data"], "iVBORw0KGgo=");
+
+        let content = openai_user_content("fix this", Some(&image));
+        assert_eq!(content[0]["text"], "fix this");
+        assert_eq!(content[1]["image_url"]["url"], "data:image/png;base64,iVBORw0KGgo=");
+        assert_eq!(openai_user_content("fix this", None), "fix this");
+
+        assert!(supports_vision(&AIProvider::OpenAI, "gpt-4o-mini"));
+        assert!(!supports_vision(&AIProvider::OpenAI, "gpt-3.5-turbo"));
+        assert!(!supports_vision(&AIProvider::OpenAI, "o3-mini"));
+        assert!(supports_vision(&AIProvider::Gemini, "gemini-2.0-flash"));
+        assert!(!supports_vision(&AIProvider::Gemini, "gemini-pro"));
+
+        let mut config = Config::default();
+        config.ai.provider = AIProvider::Anthropic;
+        assert!(check_vision_support(&config).is_err());
+    }
+
+    #[test]
+    fn test_compare_provider_config() {
+        let mut config = Config::default();
+        config.ai.provider = AIProvider::Anthropic;
+        config.ai.model = "claude-3-5-haiku-20241022".to_string();
+        config.ai.openai_api_key = Some("sk-test".to_string());
+        config.ai.gemini_api_key = Some("  ".to_string());
+
+        assert_eq!(provider_config(&config, &AIProvider::Anthropic).ai.model, config.ai.model);
+        let openai = provider_config(&config, &AIProvider::OpenAI);
+        assert_eq!(openai.ai.provider, AIProvider::OpenAI);
+        assert!(openai.ai.model.is_empty());
+
+        assert!(has_api_key(&config, &AIProvider::OpenAI));
+        assert!(!has_api_key(&config, &AIProvider::Gemini));
+        assert!(!has_api_key(&config, &AIProvider::Anthropic));
+
+        config.ai.anthropic_api_key = Some(crate::config::ANTHROPIC_KEY_PLACEHOLDER.to_string());
+        assert!(!has_api_key(&config, &AIProvider::Anthropic));
+    }
+
+    #[tokio::test]
+    async fn test_placeholder_api_key_fails_before_the_request() {
+        let mut config = Config::default();
+        config.ai.provider = AIProvider::OpenAI;
+        config.ai.openai_api_key = Some(crate::config::OPENAI_KEY_PLACEHOLDER.to_string());
+
+        let err = get_command_explanation("ls", &config).await.unwrap_err();
+        assert!(matches!(err.downcast_ref(), Some(SprenError::NotConfigured(_))));
+        assert!(err.to_string().contains("You haven't set your OpenAI API key yet"));
+    }
+
+    #[test]
+    fn test_extraction_method() {
+        let via = |response: &str| extract_command(response).unwrap().1;
+        assert_eq!(via("DANGEROUS: false\nCOMMAND: ls -la"), Extraction::CommandLine);
+        assert_eq!(via("Sure! The command: ls -la"), Extraction::CommandInline);
+        assert_eq!(via("Run `ls -la` to see them"), Extraction::Backticks);
+        assert_eq!(via("ls -la"), Extraction::SingleLine);
+        assert!(via("Here are your files.\nThey are listed by:\nls -la").is_fallback());
+        assert!(!Extraction::CodeFence.is_fallback());
+    }
 }
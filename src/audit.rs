@@ -0,0 +1,79 @@
+//! Re-classify commands from a transcript with the current danger rules
+//!
+//! Read-only: useful after changing `security.dangerous_commands` to see
+//! which past commands the new rules would have flagged.
+
+use crate::config::SecurityConfig;
+use crate::danger::{self, DangerLevel};
+use crate::transcript::TranscriptEntry;
+use std::collections::BTreeMap;
+
+/// A past command whose classification differs under the current rules
+#[derive(Debug, PartialEq)]
+pub struct Reclassified<'a> {
+    pub entry: &'a TranscriptEntry,
+    pub now: DangerLevel,
+}
+
+/// Summary of a transcript under the current rules
+#[derive(Debug, Default, PartialEq)]
+pub struct AuditReport<'a> {
+    pub total: usize,
+    pub failed: usize,
+    /// Number of commands at each current classification
+    pub by_level: BTreeMap<DangerLevel, usize>,
+    /// Commands now rated more dangerous than when they ran
+    pub escalated: Vec<Reclassified<'a>>,
+}
+
+pub fn audit<'a>(entries: &'a [TranscriptEntry], security: &SecurityConfig) -> AuditReport<'a> {
+    let mut report = AuditReport {
+        total: entries.len(),
+        ..Default::default()
+    };
+
+    for entry in entries {
+        // Keep a model flag recorded at the time; only the rules can have changed
+        let now = danger::classify(&entry.command, false, security).max(entry.danger);
+
+        *report.by_level.entry(now).or_default() += 1;
+        if entry.exit_code != Some(0) {
+            report.failed += 1;
+        }
+        if now > entry.danger {
+            report.escalated.push(Reclassified { entry, now });
+        }
+    }
+
+    report
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(command: &str, danger: DangerLevel) -> TranscriptEntry {
+        TranscriptEntry::new("q", command, danger, Some(0))
+    }
+
+    #[test]
+    fn test_flags_newly_dangerous_commands() {
+        let entries = vec![
+            entry("ls -la", DangerLevel::Safe),
+            entry("git clean -fdx", DangerLevel::Safe),
+            entry("rm -rf build", DangerLevel::Destructive),
+        ];
+
+        let mut security = SecurityConfig::default();
+        security.dangerous_commands.insert("git clean".to_string());
+
+        let report = audit(&entries, &security);
+        assert_eq!(report.total, 3);
+        assert_eq!(report.failed, 0);
+        assert_eq!(report.by_level[&DangerLevel::Safe], 1);
+        assert_eq!(report.by_level[&DangerLevel::Destructive], 2);
+        assert_eq!(report.escalated.len(), 1);
+        assert_eq!(report.escalated[0].entry.command, "git clean -fdx");
+        assert_eq!(report.escalated[0].now, DangerLevel::Destructive);
+    }
+}
@@ -1,11 +1,21 @@
 use anyhow::Result;
 use dirs::home_dir;
 use serde::{Deserialize, Serialize};
-use std::collections::HashSet;
+use std::collections::{BTreeMap, HashSet};
 use std::fs;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
-#[derive(Debug, Serialize, Deserialize, Default)]
+/// API keys `Config::create_default` writes for the user to replace
+pub const ANTHROPIC_KEY_PLACEHOLDER: &str = "your-anthropic-api-key-here";
+pub const OPENAI_KEY_PLACEHOLDER: &str = "your-openai-api-key-here";
+pub const GEMINI_KEY_PLACEHOLDER: &str = "your-gemini-api-key-here";
+
+/// Whether `key` is one of the placeholders from the default config
+pub fn is_placeholder_api_key(key: &str) -> bool {
+    [ANTHROPIC_KEY_PLACEHOLDER, OPENAI_KEY_PLACEHOLDER, GEMINI_KEY_PLACEHOLDER].contains(&key.trim())
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
 pub struct Config {
     #[serde(default)]
     pub ai: AIConfig,
@@ -15,9 +25,35 @@ pub struct Config {
     pub display: DisplayConfig,
     #[serde(default)]
     pub shell: ShellConfig,
+    /// Named multi-step procedures for `spren run <name>`
+    #[serde(default)]
+    pub workflows: BTreeMap<String, Workflow>,
+    /// Vetted commands by name, pointed out when a suggestion is close to one
+    #[serde(default)]
+    pub pins: BTreeMap<String, String>,
+    /// File `save` writes to: the one this config was loaded from, or the
+    /// one it would have been loaded from
+    #[serde(skip)]
+    path: Option<PathBuf>,
+    /// The config as stored plus changes made through `change`; per-run
+    /// overrides like `--no-exec` only touch the live copy
+    #[serde(skip)]
+    stored: Option<Box<Config>>,
+    /// `stored` has changes waiting for `save_changes`
+    #[serde(skip)]
+    unsaved: bool,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+/// An ordered list of natural-language steps, each turned into a command
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Workflow {
+    pub steps: Vec<String>,
+    /// Keep going after a step fails instead of stopping
+    #[serde(default)]
+    pub continue_on_error: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct AIConfig {
     #[serde(default)]
     pub provider: AIProvider,
@@ -27,10 +63,19 @@ pub struct AIConfig {
     pub openai_api_key: Option<String>,
     #[serde(default)]
     pub gemini_api_key: Option<String>,
+    /// Base URL of a llama.cpp `llama-server` instance
+    #[serde(default = "default_llamacpp_url")]
+    pub llamacpp_url: String,
+    /// Which llama.cpp endpoint to use
+    #[serde(default)]
+    pub llamacpp_api: LlamaCppApi,
     #[serde(default = "default_model")]
     pub model: String,
     #[serde(default = "default_max_tokens")]
     pub max_tokens: u32,
+    /// Tokens the model may generate per reply; falls back to `max_tokens`
+    #[serde(default)]
+    pub max_output_tokens: Option<u32>,
     #[serde(default = "default_temperature")]
     pub temperature: f32,
     // Local LLM settings
@@ -38,6 +83,83 @@ pub struct AIConfig {
     pub local_model_path: Option<String>,
     #[serde(default = "default_local_model_repo")]
     pub local_model_repo: String,
+    /// Copies of the local model that may generate at once; each one loaded
+    /// costs its own memory, and extras only load when all are busy
+    #[serde(default = "default_local_workers")]
+    pub local_workers: usize,
+    /// Chat template for the local model; `auto` reads it from the GGUF
+    #[serde(default)]
+    pub local_chat_template: ChatTemplate,
+    /// Extra tokens that end the local model's reply, e.g. a fine-tune's own
+    /// end-of-turn marker
+    #[serde(default)]
+    pub local_stop_tokens: Vec<String>,
+    /// Send directory listings and git info along with queries
+    #[serde(default = "default_true")]
+    pub include_context: bool,
+    /// When a query names a subproject of the current directory (a monorepo
+    /// member like `frontend/`), have the command cd into it first
+    #[serde(default)]
+    pub subproject_hints: bool,
+    /// Maximum number of directory entries included in the prompt context
+    #[serde(default = "default_context_max_files")]
+    pub context_max_files: usize,
+    /// Directories (path prefixes or globs, `~` allowed) whose contents are
+    /// never gathered into the prompt context
+    #[serde(default)]
+    pub context_exclude_dirs: Vec<String>,
+    /// Extra style rules appended to the command prompt, e.g. "Prefer long flags"
+    #[serde(default)]
+    pub style_guidelines: Vec<String>,
+    /// Strings that end a command reply early (Anthropic and the local model)
+    #[serde(default)]
+    pub stop_sequences: Vec<String>,
+    /// Language for explanations and error analysis (e.g. "Spanish");
+    /// commands stay in shell syntax
+    #[serde(default)]
+    pub response_language: Option<String>,
+    /// Times to re-ask a cloud provider that replies with no content
+    #[serde(default = "default_empty_response_retries")]
+    pub empty_response_retries: u32,
+    /// Clean up suggested commands: drop a copied `$ ` prompt, stray `\r`,
+    /// a trailing `;` and surrounding whitespace
+    #[serde(default = "default_true")]
+    pub normalize_command: bool,
+    /// Whether a multi-line query is one task or a sequence split on blank lines
+    #[serde(default)]
+    pub multiline_mode: MultilineMode,
+    /// When the local model's reply can't be parsed even after the strict
+    /// retry, ask the first cloud provider with an API key instead
+    #[serde(default)]
+    pub escalate_on_parse_failure: bool,
+    /// Attach the previous command and its output (capped, secrets redacted)
+    /// to each follow-up query in the REPL and in workflows
+    #[serde(default)]
+    pub feed_output_to_context: bool,
+    /// When a reply hits the token limit, ask once more with twice the output
+    /// tokens, up to this many; 0 turns the retry off
+    #[serde(default = "default_truncation_retry_max_tokens")]
+    pub truncation_retry_max_tokens: u32,
+}
+
+fn default_context_max_files() -> usize {
+    20
+}
+
+fn default_empty_response_retries() -> u32 {
+    2
+}
+
+fn default_truncation_retry_max_tokens() -> u32 {
+    4096
+}
+
+fn default_llamacpp_url() -> String {
+    "http://127.0.0.1:8080".to_string()
+}
+
+fn default_local_workers() -> usize {
+    1
 }
 
 fn default_local_model_repo() -> String {
@@ -56,6 +178,13 @@ fn default_temperature() -> f32 {
     0.7
 }
 
+impl AIConfig {
+    /// Generation length for a single reply
+    pub fn output_tokens(&self) -> u32 {
+        self.max_output_tokens.unwrap_or(self.max_tokens)
+    }
+}
+
 impl Default for AIConfig {
     fn default() -> Self {
         Self {
@@ -63,40 +192,107 @@ impl Default for AIConfig {
             anthropic_api_key: None,
             openai_api_key: None,
             gemini_api_key: None,
+            llamacpp_url: default_llamacpp_url(),
+            llamacpp_api: LlamaCppApi::default(),
             model: default_model(),
             max_tokens: default_max_tokens(),
+            max_output_tokens: None,
             temperature: default_temperature(),
             local_model_path: None,
             local_model_repo: default_local_model_repo(),
+            local_workers: default_local_workers(),
+            local_chat_template: ChatTemplate::default(),
+            local_stop_tokens: Vec::new(),
+            include_context: true,
+            subproject_hints: false,
+            context_max_files: default_context_max_files(),
+            context_exclude_dirs: Vec::new(),
+            style_guidelines: Vec::new(),
+            stop_sequences: Vec::new(),
+            response_language: None,
+            empty_response_retries: default_empty_response_retries(),
+            normalize_command: true,
+            multiline_mode: MultilineMode::default(),
+            escalate_on_parse_failure: false,
+            feed_output_to_context: false,
+            truncation_retry_max_tokens: default_truncation_retry_max_tokens(),
         }
     }
 }
 
-#[derive(Debug, Serialize, Deserialize, PartialEq, Clone)]
+// Default to Local when compiled with local feature, otherwise Anthropic
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Hash, Clone, Default, clap::ValueEnum)]
 #[serde(rename_all = "lowercase")]
+#[value(rename_all = "lowercase")]
 pub enum AIProvider {
+    #[cfg_attr(not(feature = "local"), default)]
     Anthropic,
     OpenAI,
     Gemini,
+    /// Remote llama.cpp server (`llama-server`)
+    LlamaCpp,
     #[cfg(feature = "local")]
+    #[cfg_attr(feature = "local", default)]
     Local,
 }
 
-// Default to Local when compiled with local feature, otherwise Anthropic
-impl Default for AIProvider {
-    fn default() -> Self {
-        #[cfg(feature = "local")]
-        {
-            AIProvider::Local
-        }
-        #[cfg(not(feature = "local"))]
-        {
-            AIProvider::Anthropic
+impl AIProvider {
+    /// Name as written in the config file
+    pub fn name(&self) -> &'static str {
+        match self {
+            AIProvider::Anthropic => "anthropic",
+            AIProvider::OpenAI => "openai",
+            AIProvider::Gemini => "gemini",
+            AIProvider::LlamaCpp => "llamacpp",
+            #[cfg(feature = "local")]
+            AIProvider::Local => "local",
         }
     }
+
+    /// Whether queries leave for a third-party API (llama.cpp is self-hosted)
+    pub fn is_cloud(&self) -> bool {
+        matches!(self, AIProvider::Anthropic | AIProvider::OpenAI | AIProvider::Gemini)
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, PartialEq, Clone, Copy, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum LlamaCppApi {
+    /// Native `/completion` endpoint with streamed `data:` lines
+    #[default]
+    Native,
+    /// OpenAI-compatible `/v1/chat/completions` endpoint
+    OpenAI,
+}
+
+/// Prompt format and end-of-turn tokens for the local model
+#[derive(Debug, Serialize, Deserialize, PartialEq, Clone, Copy, Default)]
+#[serde(rename_all = "lowercase")]
+#[cfg_attr(not(feature = "local"), allow(dead_code))]
+pub enum ChatTemplate {
+    /// Follow the template in the GGUF metadata, else its architecture
+    #[default]
+    Auto,
+    /// `<|im_start|>` turns (Qwen)
+    Chatml,
+    /// `<|start_header_id|>` turns (Llama 3)
+    Llama3,
+    /// `<|system|>`/`<|user|>` turns (Phi-3)
+    Phi3,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+/// How a query typed or pasted over several lines is treated
+#[derive(Debug, Serialize, Deserialize, PartialEq, Clone, Copy, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum MultilineMode {
+    /// All the lines together are one query
+    #[default]
+    Single,
+    /// Blocks separated by blank lines are separate queries, run in order
+    Sequence,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct SecurityConfig {
     #[serde(default = "default_dangerous_commands")]
     pub dangerous_commands: HashSet<String>,
@@ -104,10 +300,77 @@ pub struct SecurityConfig {
     pub require_confirmation: bool,
     #[serde(default = "default_max_output_size")]
     pub max_output_size: usize,
+    /// Kill a command that is still running after this many seconds; 0 waits
+    /// forever. Interactive commands are never timed out.
+    #[serde(default = "default_command_timeout_secs")]
+    pub command_timeout_secs: u64,
     #[serde(default = "default_allowed_directories")]
     pub allowed_directories: Vec<String>,
     #[serde(default)]
     pub disable_dangerous_commands: bool,
+    /// How catastrophic commands (e.g. `rm -rf /`) must be confirmed
+    #[serde(default)]
+    pub catastrophic_confirm: CatastrophicConfirm,
+    /// Remove a leading `sudo` from suggested commands
+    #[serde(default)]
+    pub strip_sudo: bool,
+    /// Refuse any suggested command that uses `sudo`
+    #[serde(default)]
+    pub forbid_sudo: bool,
+    /// Log executed commands to `~/.config/spren/transcript.jsonl`
+    #[serde(default = "default_true")]
+    pub record_transcript: bool,
+    /// Environment variables (glob patterns) hidden from executed commands
+    #[serde(default = "default_scrub_env")]
+    pub scrub_env: Vec<String>,
+    /// Warn and ask separately before running commands that reach the network
+    #[serde(default)]
+    pub confirm_network_commands: bool,
+    /// Ask before sending a query that looks like it contains a secret to a
+    /// cloud provider
+    #[serde(default = "default_true")]
+    pub confirm_sensitive_queries: bool,
+    /// Only suggest and explain; never run anything, whatever is confirmed
+    #[serde(default)]
+    pub disable_execution: bool,
+    /// Let commands touch Spren's own config and model files (`--allow-self-modify`)
+    #[serde(default)]
+    pub allow_self_modify: bool,
+    /// Command prefixes (e.g. "git status") run with only the execute prompt,
+    /// skipping danger, path and network checks
+    #[serde(default)]
+    pub trusted_commands: Vec<String>,
+    /// Policy command run before every execute prompt with the candidate on
+    /// stdin; a non-zero exit blocks it, with stderr as the reason
+    #[serde(default)]
+    pub danger_check_command: Option<String>,
+    /// Run commands with a minimal environment, pinned to the current
+    /// directory; on Linux with bubblewrap, everything outside it is read-only
+    #[serde(default)]
+    pub jail_execution: bool,
+    /// What a confirmation prompt does when stdin ends before it is answered
+    #[serde(default)]
+    pub eof_confirmation: EofConfirmation,
+}
+
+#[derive(Debug, Serialize, Deserialize, PartialEq, Clone, Copy, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum CatastrophicConfirm {
+    /// Same y/N prompt as any other command
+    Prompt,
+    /// Retype a random token shown on screen
+    #[default]
+    RetypeToken,
+}
+
+#[derive(Debug, Serialize, Deserialize, PartialEq, Clone, Copy, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum EofConfirmation {
+    /// Treat it as declining
+    #[default]
+    No,
+    /// Fail, so a script that ran out of answers notices
+    Error,
 }
 
 fn default_true() -> bool {
@@ -118,6 +381,29 @@ fn default_max_output_size() -> usize {
     1024 * 1024 // 1MB
 }
 
+fn default_command_timeout_secs() -> u64 {
+    300
+}
+
+fn default_scrub_env() -> Vec<String> {
+    [
+        "AWS_SECRET_ACCESS_KEY",
+        "AWS_SESSION_TOKEN",
+        "GITHUB_TOKEN",
+        "GH_TOKEN",
+        "GITLAB_TOKEN",
+        "NPM_TOKEN",
+        "HF_TOKEN",
+        "*_API_KEY",
+        "*_SECRET",
+        "*_SECRET_KEY",
+        "*_PASSWORD",
+    ]
+    .iter()
+    .map(|&s| s.to_string())
+    .collect()
+}
+
 fn default_allowed_directories() -> Vec<String> {
     vec!["~".to_string(), "./".to_string()]
 }
@@ -155,13 +441,27 @@ impl Default for SecurityConfig {
             dangerous_commands: default_dangerous_commands(),
             require_confirmation: true,
             max_output_size: default_max_output_size(),
+            command_timeout_secs: default_command_timeout_secs(),
             allowed_directories: default_allowed_directories(),
             disable_dangerous_commands: false,
+            catastrophic_confirm: CatastrophicConfirm::default(),
+            strip_sudo: false,
+            forbid_sudo: false,
+            record_transcript: true,
+            scrub_env: default_scrub_env(),
+            confirm_network_commands: false,
+            confirm_sensitive_queries: true,
+            disable_execution: false,
+            allow_self_modify: false,
+            trusted_commands: Vec::new(),
+            danger_check_command: None,
+            jail_execution: false,
+            eof_confirmation: EofConfirmation::default(),
         }
     }
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct DisplayConfig {
     #[serde(default = "default_true")]
     pub show_execution_time: bool,
@@ -173,12 +473,64 @@ pub struct DisplayConfig {
     pub show_command_preview: bool,
     #[serde(default = "default_prompt_symbol")]
     pub prompt_symbol: String,
+    /// Queries starting with one of these words are answered, never executed
+    #[serde(default = "default_explain_only_prefixes")]
+    pub explain_only_prefixes: Vec<String>,
+    /// Characters of streamed model output shown while generating; 0 hides it
+    #[serde(default = "default_stream_preview_chars")]
+    pub stream_preview_chars: usize,
+    /// Show the model's explanation of a suggested command beneath it
+    #[serde(default)]
+    pub show_rationale: bool,
+    /// Re-indent command output that is JSON
+    #[serde(default)]
+    pub pretty_json: bool,
+    /// Put a one-line explanation comment above each command in exported
+    /// scripts and review files
+    #[serde(default)]
+    pub annotate_exports: bool,
+    /// Show which provider and model answered, dimmed beside the suggestion
+    #[serde(default)]
+    pub show_provider: bool,
+    /// Write interactive changes (like `:pin`) to the config file right away;
+    /// when off they are saved on exit
+    #[serde(default = "default_true")]
+    pub autosave: bool,
 }
 
 fn default_prompt_symbol() -> String {
     "❯".to_string()
 }
 
+fn default_stream_preview_chars() -> usize {
+    200
+}
+
+fn default_explain_only_prefixes() -> Vec<String> {
+    ["how", "what", "why", "explain"]
+        .iter()
+        .map(|s| s.to_string())
+        .collect()
+}
+
+impl DisplayConfig {
+    /// Whether a query is a question that should only be explained
+    pub fn is_explain_only(&self, query: &str) -> bool {
+        // Compare whole leading words so "whatever" or "how's" behave sensibly
+        let first_word = query
+            .trim_start()
+            .split(|c: char| !c.is_alphanumeric())
+            .next()
+            .unwrap_or_default();
+
+        !first_word.is_empty()
+            && self
+                .explain_only_prefixes
+                .iter()
+                .any(|p| p.trim().eq_ignore_ascii_case(first_word))
+    }
+}
+
 impl Default for DisplayConfig {
     fn default() -> Self {
         Self {
@@ -187,14 +539,25 @@ impl Default for DisplayConfig {
             verbose_mode: false,
             show_command_preview: true,
             prompt_symbol: default_prompt_symbol(),
+            explain_only_prefixes: default_explain_only_prefixes(),
+            stream_preview_chars: default_stream_preview_chars(),
+            show_rationale: false,
+            pretty_json: false,
+            annotate_exports: false,
+            show_provider: false,
+            autosave: true,
         }
     }
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ShellConfig {
     #[serde(default)]
     pub preferred_shell: Option<String>,
+    /// Binary that executes commands (e.g. "/bin/bash"), independent of the
+    /// shell commands are generated for
+    #[serde(default)]
+    pub exec_shell_path: Option<String>,
     #[serde(default)]
     pub shell_aliases: std::collections::HashMap<String, String>,
     #[serde(default)]
@@ -203,6 +566,13 @@ pub struct ShellConfig {
     pub history_size: usize,
     #[serde(default = "default_true")]
     pub enable_auto_correction: bool,
+    /// Append executed commands to the user's own shell history file
+    #[serde(default)]
+    pub write_to_shell_history: bool,
+    /// Encoding of command output (e.g. "windows-1252"); by default UTF-8,
+    /// or the console codepage on Windows
+    #[serde(default)]
+    pub output_encoding: Option<String>,
 }
 
 fn default_history_size() -> usize {
@@ -213,10 +583,13 @@ impl Default for ShellConfig {
     fn default() -> Self {
         Self {
             preferred_shell: None,
+            exec_shell_path: None,
             shell_aliases: std::collections::HashMap::new(),
             environment_variables: std::collections::HashMap::new(),
             history_size: default_history_size(),
             enable_auto_correction: true,
+            write_to_shell_history: false,
+            output_encoding: None,
         }
     }
 }
@@ -224,11 +597,22 @@ impl Default for ShellConfig {
 impl Config {
     pub fn load(config_path: &PathBuf) -> Result<Self> {
         let config_str = fs::read_to_string(config_path)?;
-        let config: Config = toml::from_str(&config_str)?;
+        let mut config: Config = toml::from_str(&config_str)?;
+        config.stored = Some(Box::new(config.clone()));
+        config.path = Some(config_path.clone());
         Ok(config)
     }
 
-    pub fn create_default(config_path: &PathBuf) -> Result<()> {
+    /// Defaults, saved to `config_path` once something changes
+    pub fn default_at(config_path: &Path) -> Self {
+        Config {
+            path: Some(config_path.to_path_buf()),
+            ..Default::default()
+        }
+    }
+
+    #[allow(dead_code)]
+    pub fn create_default(config_path: &Path) -> Result<()> {
         if let Some(dir) = config_path.parent() {
             fs::create_dir_all(dir)?;
         }
@@ -236,28 +620,79 @@ impl Config {
         let default_config = Config {
             ai: AIConfig {
                 provider: AIProvider::Anthropic,
-                anthropic_api_key: Some("your-anthropic-api-key-here".to_string()),
-                openai_api_key: Some("your-openai-api-key-here".to_string()),
-                gemini_api_key: Some("your-gemini-api-key-here".to_string()),
+                anthropic_api_key: Some(ANTHROPIC_KEY_PLACEHOLDER.to_string()),
+                openai_api_key: Some(OPENAI_KEY_PLACEHOLDER.to_string()),
+                gemini_api_key: Some(GEMINI_KEY_PLACEHOLDER.to_string()),
+                llamacpp_url: default_llamacpp_url(),
+                llamacpp_api: LlamaCppApi::default(),
                 model: "claude-3-5-haiku-20241022".to_string(),
                 max_tokens: 1024,
+                max_output_tokens: None,
                 temperature: 0.7,
                 local_model_path: None,
                 local_model_repo: "Qwen/Qwen2.5-0.5B-Instruct".to_string(),
+                local_workers: default_local_workers(),
+                local_chat_template: ChatTemplate::default(),
+                local_stop_tokens: Vec::new(),
+                include_context: true,
+                subproject_hints: false,
+                context_max_files: default_context_max_files(),
+                context_exclude_dirs: Vec::new(),
+                style_guidelines: Vec::new(),
+                stop_sequences: Vec::new(),
+                response_language: None,
+                empty_response_retries: default_empty_response_retries(),
+                normalize_command: true,
+                multiline_mode: MultilineMode::default(),
+                escalate_on_parse_failure: false,
+                feed_output_to_context: false,
+                truncation_retry_max_tokens: default_truncation_retry_max_tokens(),
             },
             security: SecurityConfig::default(),
             display: DisplayConfig::default(),
             shell: ShellConfig::default(),
+            workflows: BTreeMap::new(),
+            pins: BTreeMap::new(),
+            path: Some(config_path.to_path_buf()),
+            stored: None,
+            unsaved: false,
         };
+        default_config.save()
+    }
 
-        let toml_string = toml::to_string_pretty(&default_config)?;
-        fs::write(config_path, toml_string)?;
+    /// Write the stored config, with changes made through `change`, to the
+    /// file it came from (the active profile's, if one is selected)
+    pub fn save(&self) -> Result<()> {
+        let path = self.path.as_ref().ok_or_else(|| {
+            anyhow::anyhow!("Not saving: the config file couldn't be loaded, so it would be overwritten")
+        })?;
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let stored = self.stored.as_deref().unwrap_or(self);
+        fs::write(path, toml::to_string_pretty(stored)?)?;
         Ok(())
     }
 
-    pub fn update(&self, config_path: &PathBuf) -> Result<()> {
-        let toml_string = toml::to_string_pretty(&self)?;
-        fs::write(config_path, toml_string)?;
+    /// Apply a lasting change: to this session right away, and to the config
+    /// file now or on exit per `display.autosave`. Returns whether it was written.
+    pub fn change(&mut self, edit: impl Fn(&mut Config)) -> Result<bool> {
+        edit(self);
+        edit(self.stored.get_or_insert_with(Default::default));
+        if !self.display.autosave {
+            self.unsaved = true;
+            return Ok(false);
+        }
+        self.save()?;
+        Ok(true)
+    }
+
+    /// Write changes held back by `display.autosave = false`
+    pub fn save_changes(&mut self) -> Result<()> {
+        if self.unsaved {
+            self.save()?;
+            self.unsaved = false;
+        }
         Ok(())
     }
 
@@ -267,17 +702,127 @@ impl Config {
             AIProvider::Anthropic => "claude-3-5-haiku-20241022",
             AIProvider::OpenAI => "gpt-4o-mini",
             AIProvider::Gemini => "gemini-2.0-flash",
+            AIProvider::LlamaCpp => "",
             #[cfg(feature = "local")]
             AIProvider::Local => "Qwen/Qwen2.5-0.5B-Instruct",
         }
     }
 }
 
+/// One-off AI settings given as leading modifiers on a query,
+/// e.g. `--temp 0.9 --max-tokens 300 find something creative`
+#[derive(Debug, Default, PartialEq)]
+pub struct QueryOverrides {
+    pub temperature: Option<f32>,
+    pub max_tokens: Option<u32>,
+    /// `@nocontext`: send the bare query without directory or git context
+    pub no_context: bool,
+}
+
+impl QueryOverrides {
+    /// Split leading modifiers off `input`, returning them and the remaining query
+    pub fn parse(input: &str) -> Result<(Self, String)> {
+        let mut overrides = Self::default();
+        let mut rest = input.trim_start();
+
+        while let Some((flag, after)) = rest.split_once(char::is_whitespace) {
+            if flag == "@nocontext" {
+                overrides.no_context = true;
+                rest = after.trim_start();
+                continue;
+            }
+            if !matches!(flag, "--temp" | "--temperature" | "--max-tokens") {
+                break;
+            }
+
+            let after = after.trim_start();
+            let (value, remaining) = after.split_once(char::is_whitespace).unwrap_or((after, ""));
+
+            if flag == "--max-tokens" {
+                let tokens: u32 = value
+                    .parse()
+                    .ok()
+                    .filter(|&t| t > 0)
+                    .ok_or_else(|| anyhow::anyhow!("Invalid --max-tokens value: '{}'", value))?;
+                overrides.max_tokens = Some(tokens);
+            } else {
+                let temperature: f32 = value
+                    .parse()
+                    .ok()
+                    .filter(|t| (0.0..=2.0).contains(t))
+                    .ok_or_else(|| {
+                        anyhow::anyhow!("Invalid {} value: '{}' (expected 0.0-2.0)", flag, value)
+                    })?;
+                overrides.temperature = Some(temperature);
+            }
+
+            rest = remaining.trim_start();
+        }
+
+        Ok((overrides, rest.trim_end().to_string()))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.temperature.is_none() && self.max_tokens.is_none() && !self.no_context
+    }
+
+    /// A copy of `config` with these overrides applied
+    pub fn apply(&self, config: &Config) -> Config {
+        let mut config = config.clone();
+        if let Some(temperature) = self.temperature {
+            config.ai.temperature = temperature;
+        }
+        if let Some(max_tokens) = self.max_tokens {
+            config.ai.max_tokens = max_tokens;
+            config.ai.max_output_tokens = Some(max_tokens);
+        }
+        if self.no_context {
+            config.ai.include_context = false;
+        }
+        config
+    }
+}
+
 pub fn get_config_path() -> Result<PathBuf> {
     let home = home_dir().ok_or_else(|| anyhow::anyhow!("Could not find home directory"))?;
     Ok(home.join(".config").join("spren").join("config.toml"))
 }
 
+/// Directory holding named profiles (`<name>.toml`)
+pub fn get_profiles_dir() -> Result<PathBuf> {
+    let home = home_dir().ok_or_else(|| anyhow::anyhow!("Could not find home directory"))?;
+    Ok(home.join(".config").join("spren").join("profiles"))
+}
+
+/// Path of a named profile; names can't escape the profiles directory
+pub fn get_profile_path(name: &str) -> Result<PathBuf> {
+    if name.is_empty() || name.contains(['/', '\\']) || name.starts_with('.') {
+        return Err(anyhow::anyhow!("Invalid profile name '{}'", name));
+    }
+    Ok(get_profiles_dir()?.join(format!("{}.toml", name)))
+}
+
+/// Names of all profiles, sorted
+pub fn list_profiles() -> Result<Vec<String>> {
+    profile_names(&get_profiles_dir()?)
+}
+
+fn profile_names(dir: &Path) -> Result<Vec<String>> {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e.into()),
+    };
+
+    let mut names: Vec<String> = entries
+        .filter_map(|entry| entry.ok().map(|e| e.path()))
+        .filter(|path| path.extension().is_some_and(|ext| ext == "toml"))
+        .filter_map(|path| path.file_stem()?.to_str().map(String::from))
+        .collect();
+    names.sort();
+    Ok(names)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -355,4 +900,148 @@ model = "gemini-2.0-flash"
 
         Ok(())
     }
+
+    #[test]
+    fn test_llamacpp_provider() -> Result<()> {
+        let temp_dir = tempdir()?;
+        let config_path = temp_dir.path().join("config.toml");
+
+        fs::write(
+            &config_path,
+            r#"
+[ai]
+provider = "llamacpp"
+llamacpp_url = "http://gpu-box:8080"
+"#,
+        )?;
+
+        let config = Config::load(&config_path)?;
+        assert_eq!(config.ai.provider, AIProvider::LlamaCpp);
+        assert_eq!(config.ai.llamacpp_api, LlamaCppApi::Native);
+        assert_eq!(config.ai.llamacpp_url, "http://gpu-box:8080");
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_explain_only_prefixes() {
+        let display = DisplayConfig::default();
+        assert!(display.is_explain_only("How do I untar a file?"));
+        assert!(display.is_explain_only("what's using port 8080"));
+        assert!(display.is_explain_only("  explain git rebase"));
+        assert!(!display.is_explain_only("whatever is in /tmp, list it"));
+        assert!(!display.is_explain_only("list large files"));
+        assert!(!display.is_explain_only(""));
+    }
+
+    #[test]
+    fn test_query_overrides() -> Result<()> {
+        let (overrides, query) = QueryOverrides::parse("--temp 0.9 --max-tokens 300 find big files")?;
+        assert_eq!(overrides.temperature, Some(0.9));
+        assert_eq!(overrides.max_tokens, Some(300));
+        assert_eq!(query, "find big files");
+
+        let config = overrides.apply(&Config::default());
+        assert_eq!(config.ai.temperature, 0.9);
+        assert_eq!(config.ai.max_tokens, 300);
+        assert_eq!(config.ai.output_tokens(), 300);
+
+        // Only leading modifiers are parsed
+        let (overrides, query) = QueryOverrides::parse("grep for --temp in logs")?;
+        assert!(overrides.is_empty());
+        assert_eq!(query, "grep for --temp in logs");
+
+        let (overrides, query) = QueryOverrides::parse("@nocontext --temp 0.2 list files")?;
+        assert!(overrides.no_context);
+        assert_eq!(overrides.temperature, Some(0.2));
+        assert_eq!(query, "list files");
+        assert!(!overrides.apply(&Config::default()).ai.include_context);
+
+        assert!(QueryOverrides::parse("--temp hot list files").is_err());
+        assert!(QueryOverrides::parse("--max-tokens 0 list files").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_profiles() -> Result<()> {
+        let temp_dir = tempdir()?;
+        fs::write(temp_dir.path().join("work.toml"), "")?;
+        fs::write(temp_dir.path().join("home.toml"), "")?;
+        fs::write(temp_dir.path().join("notes.txt"), "")?;
+        assert_eq!(profile_names(temp_dir.path())?, vec!["home", "work"]);
+        assert!(profile_names(&temp_dir.path().join("missing"))?.is_empty());
+
+        assert!(get_profile_path("work")?.ends_with("profiles/work.toml"));
+        assert!(get_profile_path("../config").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_output_tokens() -> Result<()> {
+        let mut ai = AIConfig::default();
+        assert_eq!(ai.output_tokens(), ai.max_tokens);
+
+        ai = toml::from_str::<Config>("[ai]\nmax_tokens = 2048\nmax_output_tokens = 400\n")?.ai;
+        assert_eq!(ai.output_tokens(), 400);
+        Ok(())
+    }
+
+    #[test]
+    fn test_workflows() -> Result<()> {
+        let config: Config = toml::from_str(
+            r#"
+[workflows.deploy]
+steps = ["run the tests", "build a release binary", "copy it to @last"]
+
+[workflows.backup]
+steps = ["archive ~/notes"]
+continue_on_error = true
+"#,
+        )?;
+
+        assert_eq!(config.workflows["deploy"].steps.len(), 3);
+        assert!(!config.workflows["deploy"].continue_on_error);
+        assert!(config.workflows["backup"].continue_on_error);
+        Ok(())
+    }
+
+    #[test]
+    fn test_eof_confirmation() -> Result<()> {
+        assert_eq!(Config::default().security.eof_confirmation, EofConfirmation::No);
+        let config: Config = toml::from_str("[security]\neof_confirmation = \"error\"\n")?;
+        assert_eq!(config.security.eof_confirmation, EofConfirmation::Error);
+        Ok(())
+    }
+
+    #[test]
+    fn test_changes_are_saved_without_runtime_overrides() -> Result<()> {
+        let temp_dir = tempdir()?;
+        let config_path = temp_dir.path().join("config.toml");
+        fs::write(&config_path, "[ai]\nprovider = \"openai\"\n")?;
+
+        let mut config = Config::load(&config_path)?;
+        config.security.disable_execution = true;
+        assert!(config.change(|c| {
+            c.pins.insert("logs".into(), "tail -f app.log".into());
+        })?);
+        assert_eq!(config.pins["logs"], "tail -f app.log");
+
+        let saved = Config::load(&config_path)?;
+        assert_eq!(saved.ai.provider, AIProvider::OpenAI);
+        assert_eq!(saved.pins["logs"], "tail -f app.log");
+        assert!(!saved.security.disable_execution);
+
+        // Without autosave, changes wait for save_changes
+        let fresh_path = temp_dir.path().join("profiles").join("work.toml");
+        let mut config = Config::default_at(&fresh_path);
+        config.display.autosave = false;
+        assert!(!config.change(|c| c.shell.history_size = 50)?);
+        assert!(!fresh_path.exists());
+        config.save_changes()?;
+        assert_eq!(Config::load(&fresh_path)?.shell.history_size, 50);
+
+        // A config that failed to load has nowhere safe to go
+        assert!(Config::default().save().is_err());
+        Ok(())
+    }
 }
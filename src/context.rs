@@ -1,32 +1,61 @@
 //! Context gathering for intelligent command generation
 //!
-//! This module provides local context (current directory, files, git status)
-//! to help the LLM generate more accurate commands.
+//! This module provides local context (current directory, files, git status,
+//! package manager, subprojects) to help the LLM generate more accurate commands.
 
-use std::path::PathBuf;
+use crate::shell::expand_path;
+use std::path::{Path, PathBuf};
 use std::process::Command;
+use std::sync::OnceLock;
 
 /// Gathered context about the current environment
+///
+/// Only the local model's prompt includes it; cloud prompts get the package
+/// manager and subproject hint instead.
 #[derive(Debug, Default)]
+#[cfg_attr(not(feature = "local"), allow(dead_code))]
 pub struct LocalContext {
     pub cwd: PathBuf,
     pub files: Vec<String>,
+    /// Whether the directory had more entries than were listed
+    pub files_truncated: bool,
     pub git_branch: Option<String>,
     pub is_git_repo: bool,
+    /// apt, dnf, brew, winget, ... (see `detect_package_manager`)
+    pub package_manager: Option<String>,
+    /// The directory matched `ai.context_exclude_dirs`, so nothing was read
+    pub omitted: bool,
 }
 
+#[cfg_attr(not(feature = "local"), allow(dead_code))]
 impl LocalContext {
-    /// Gather context about the current directory
-    pub fn gather() -> Self {
+    /// Gather context about the current directory, listing at most `max_files`
+    /// entries. Files named in `query` are listed first. Directories matching
+    /// `exclude_dirs` are not read at all.
+    pub fn gather(query: &str, max_files: usize, exclude_dirs: &[String]) -> Self {
         let cwd = std::env::current_dir().unwrap_or_default();
-        let files = list_directory_fast(&cwd);
+        if is_excluded(&cwd, exclude_dirs) {
+            return Self {
+                cwd,
+                omitted: true,
+                ..Self::default()
+            };
+        }
+        Self::gather_in(cwd, query, max_files)
+    }
+
+    fn gather_in(cwd: PathBuf, query: &str, max_files: usize) -> Self {
+        let (files, files_truncated) = list_directory_fast(&cwd, query, max_files);
         let (is_git_repo, git_branch) = get_git_info(&cwd);
 
         Self {
             cwd,
             files,
+            files_truncated,
             git_branch,
             is_git_repo,
+            package_manager: detect_package_manager(),
+            omitted: false,
         }
     }
 
@@ -37,15 +66,15 @@ impl LocalContext {
         // Current directory
         parts.push(format!("CWD: {}", self.cwd.display()));
 
-        // File listing (limit to first 20 items to keep prompt small)
+        if self.omitted {
+            parts.push("[context omitted for this directory]".to_string());
+            return parts.join("\n");
+        }
+
+        // File listing (already capped by ai.context_max_files)
         if !self.files.is_empty() {
-            let files_preview: Vec<&str> = self.files.iter().take(20).map(|s| s.as_str()).collect();
-            let suffix = if self.files.len() > 20 {
-                format!(" (+{} more)", self.files.len() - 20)
-            } else {
-                String::new()
-            };
-            parts.push(format!("Files: [{}]{}", files_preview.join(", "), suffix));
+            let suffix = if self.files_truncated { " (+more)" } else { "" };
+            parts.push(format!("Files: [{}]{}", self.files.join(", "), suffix));
         }
 
         // Git info
@@ -57,32 +86,59 @@ impl LocalContext {
             }
         }
 
+        if let Some(ref manager) = self.package_manager {
+            parts.push(format!("Package manager: {}", manager));
+        }
+
         parts.join("\n")
     }
 }
 
-/// Fast directory listing using ls -F style output
-fn list_directory_fast(path: &PathBuf) -> Vec<String> {
+/// Whether `cwd` is, or is inside, one of the excluded directories
+///
+/// Entries are path prefixes (`~/.ssh`, `$PROJECTS/secret`) or globs
+/// (`~/work/*/secrets`), expanded like the shell would.
+pub(crate) fn is_excluded(cwd: &Path, exclude_dirs: &[String]) -> bool {
+    exclude_dirs.iter().any(|entry| {
+        let entry = entry.trim();
+        if entry.is_empty() {
+            return false;
+        }
+        let expanded = expand_path(entry).to_string_lossy().into_owned();
+
+        if expanded.contains(['*', '?', '[']) {
+            glob::Pattern::new(&expanded)
+                .is_ok_and(|pattern| cwd.ancestors().any(|dir| pattern.matches_path(dir)))
+        } else {
+            cwd.starts_with(&expanded)
+        }
+    })
+}
+
+/// Fast directory listing using ls -F style output.
+/// Returns at most `max_files` entries and whether any were left out.
+#[cfg_attr(not(feature = "local"), allow(dead_code))]
+fn list_directory_fast(path: &PathBuf, query: &str, max_files: usize) -> (Vec<String>, bool) {
+    // Files the query names directly always make the cut
+    let mut mentioned = mentioned_files(path, query);
+    mentioned.truncate(max_files);
+
     let mut entries = Vec::new();
+    let mut truncated = false;
+    let remaining = max_files - mentioned.len();
 
     if let Ok(read_dir) = std::fs::read_dir(path) {
-        for entry in read_dir.filter_map(|e| e.ok()).take(50) {
+        for entry in read_dir.filter_map(|e| e.ok()) {
             let name = entry.file_name().to_string_lossy().to_string();
-
-            // Add type indicator like ls -F
-            let indicator = if let Ok(ft) = entry.file_type() {
-                if ft.is_dir() {
-                    "/"
-                } else if ft.is_symlink() {
-                    "@"
-                } else {
-                    ""
-                }
-            } else {
-                ""
-            };
-
-            entries.push(format!("{}{}", name, indicator));
+            let entry = format!("{}{}", name, type_indicator(entry.file_type().ok()));
+            if mentioned.contains(&entry) {
+                continue;
+            }
+            if entries.len() == remaining {
+                truncated = true;
+                break;
+            }
+            entries.push(entry);
         }
     }
 
@@ -97,10 +153,194 @@ fn list_directory_fast(path: &PathBuf) -> Vec<String> {
         }
     });
 
-    entries
+    mentioned.append(&mut entries);
+    (mentioned, truncated)
+}
+
+/// Entries in `path` whose names appear as words in the query
+#[cfg_attr(not(feature = "local"), allow(dead_code))]
+fn mentioned_files(path: &Path, query: &str) -> Vec<String> {
+    let mut found: Vec<String> = Vec::new();
+
+    for word in query.split_whitespace() {
+        let name = word
+            .trim_matches(|c: char| matches!(c, '"' | '\'' | '`' | ',' | '?' | '!' | '(' | ')'))
+            .trim_end_matches(['.', ':']);
+
+        // Only plain names inside the directory, never absolute or parent paths
+        if name.is_empty() || name.starts_with('/') || name.contains("..") {
+            continue;
+        }
+
+        if let Ok(metadata) = std::fs::symlink_metadata(path.join(name)) {
+            let entry = format!("{}{}", name, type_indicator(Some(metadata.file_type())));
+            if !found.contains(&entry) {
+                found.push(entry);
+            }
+        }
+    }
+
+    found
+}
+
+/// Type indicator like ls -F
+#[cfg_attr(not(feature = "local"), allow(dead_code))]
+fn type_indicator(file_type: Option<std::fs::FileType>) -> &'static str {
+    match file_type {
+        Some(ft) if ft.is_dir() => "/",
+        Some(ft) if ft.is_symlink() => "@",
+        _ => "",
+    }
+}
+
+/// Files that mark a directory as a project, and what kind
+const PROJECT_MARKERS: &[(&str, &str)] = &[
+    ("package.json", "node"),
+    ("Cargo.toml", "rust"),
+    ("go.mod", "go"),
+    ("pyproject.toml", "python"),
+    ("requirements.txt", "python"),
+    ("setup.py", "python"),
+    ("pom.xml", "maven"),
+    ("build.gradle", "gradle"),
+    ("build.gradle.kts", "gradle"),
+    ("Gemfile", "ruby"),
+    ("composer.json", "php"),
+    ("mix.exs", "elixir"),
+    ("CMakeLists.txt", "cmake"),
+    ("Makefile", "make"),
+];
+
+/// Directories that hold dependencies or build output, never subprojects
+const SKIPPED_DIRS: &[&str] = &["node_modules", "target", "vendor", "dist", "build", "venv"];
+
+/// An immediate subdirectory that is a project of its own (monorepo member)
+#[derive(Debug, Clone, PartialEq)]
+pub struct Subproject {
+    pub name: String,
+    /// node, rust, python, ... from the first marker file found
+    pub kind: &'static str,
+}
+
+/// What kind of project `dir` is, judging by its marker files
+pub fn project_kind(dir: &Path) -> Option<&'static str> {
+    PROJECT_MARKERS
+        .iter()
+        .find(|(marker, _)| dir.join(marker).is_file())
+        .map(|(_, kind)| *kind)
+}
+
+/// Immediate subdirectories of `dir` that are projects, sorted by name
+pub fn subprojects(dir: &Path) -> Vec<Subproject> {
+    let Ok(read_dir) = std::fs::read_dir(dir) else {
+        return Vec::new();
+    };
+    let mut found: Vec<Subproject> = read_dir
+        .filter_map(|e| e.ok())
+        .filter(|e| e.file_type().is_ok_and(|t| t.is_dir()))
+        .filter_map(|e| {
+            let name = e.file_name().to_string_lossy().to_string();
+            if name.starts_with('.') || SKIPPED_DIRS.contains(&name.as_str()) {
+                return None;
+            }
+            project_kind(&e.path()).map(|kind| Subproject { name, kind })
+        })
+        .collect();
+    found.sort_by(|a, b| a.name.cmp(&b.name));
+    found
+}
+
+/// The one subproject of `dir` that `query` names ("build the frontend")
+///
+/// Names like `web-app` also match when all their parts are words of the
+/// query ("test the web app"). Ambiguous queries match nothing.
+pub fn find_subproject(dir: &Path, query: &str) -> Option<Subproject> {
+    let words: Vec<String> = query
+        .split(|c: char| !(c.is_alphanumeric() || c == '-' || c == '_'))
+        .filter(|w| !w.is_empty())
+        .map(str::to_lowercase)
+        .collect();
+
+    let mut matches = subprojects(dir).into_iter().filter(|project| {
+        let name = project.name.to_lowercase();
+        let mut parts = name.split(['-', '_', '.']).filter(|p| !p.is_empty());
+        words.contains(&name) || (name.contains(['-', '_', '.']) && parts.all(|p| words.iter().any(|w| w == p)))
+    });
+    match (matches.next(), matches.next()) {
+        (Some(project), None) => Some(project),
+        _ => None,
+    }
+}
+
+/// The system's package manager (apt, dnf, pacman, brew, winget, ...)
+///
+/// The distro named in `/etc/os-release` decides on Linux, as long as its
+/// manager is installed; otherwise the first known manager found on PATH wins.
+/// Detected once per run.
+pub fn detect_package_manager() -> Option<String> {
+    static DETECTED: OnceLock<Option<String>> = OnceLock::new();
+    DETECTED
+        .get_or_init(|| {
+            let os_release = std::fs::read_to_string("/etc/os-release").unwrap_or_default();
+            package_manager_candidates(std::env::consts::OS, &os_release)
+                .into_iter()
+                .find(|manager| on_path(manager))
+                .map(str::to_string)
+        })
+        .clone()
+}
+
+/// Package managers to look for, most likely first
+fn package_manager_candidates(os: &str, os_release: &str) -> Vec<&'static str> {
+    let mut candidates = match os {
+        "macos" => vec!["brew", "port"],
+        "windows" => vec!["winget", "choco", "scoop"],
+        _ => Vec::new(),
+    };
+
+    // ID and ID_LIKE name the distro and its family, e.g. ID_LIKE="rhel fedora"
+    let ids = os_release
+        .lines()
+        .filter_map(|line| line.strip_prefix("ID=").or_else(|| line.strip_prefix("ID_LIKE=")))
+        .flat_map(|value| value.trim_matches('"').split_whitespace());
+    for id in ids {
+        let manager = match id {
+            "debian" | "ubuntu" | "linuxmint" | "pop" | "raspbian" => "apt",
+            "fedora" | "rhel" | "centos" | "rocky" | "almalinux" | "amzn" => "dnf",
+            "arch" | "manjaro" | "endeavouros" => "pacman",
+            "opensuse" | "suse" | "sles" | "opensuse-leap" | "opensuse-tumbleweed" => "zypper",
+            "alpine" => "apk",
+            "void" => "xbps-install",
+            "gentoo" => "emerge",
+            "nixos" => "nix-env",
+            _ => continue,
+        };
+        if !candidates.contains(&manager) {
+            candidates.push(manager);
+        }
+    }
+
+    // Unknown distros, older ones with yum instead of dnf, and brew on Linux
+    for manager in ["apt", "dnf", "yum", "pacman", "zypper", "apk", "brew"] {
+        if !candidates.contains(&manager) {
+            candidates.push(manager);
+        }
+    }
+    candidates
+}
+
+/// Whether an executable named `program` is on PATH
+pub fn on_path(program: &str) -> bool {
+    let Some(path) = std::env::var_os("PATH") else {
+        return false;
+    };
+    std::env::split_paths(&path).any(|dir| {
+        dir.join(program).is_file() || (cfg!(windows) && dir.join(format!("{}.exe", program)).is_file())
+    })
 }
 
 /// Get git repository info (fast)
+#[cfg_attr(not(feature = "local"), allow(dead_code))]
 fn get_git_info(path: &PathBuf) -> (bool, Option<String>) {
     // Check if .git exists (faster than running git command)
     let git_dir = path.join(".git");
@@ -140,8 +380,82 @@ mod tests {
 
     #[test]
     fn test_gather_context() {
-        let ctx = LocalContext::gather();
+        let ctx = LocalContext::gather("", 20, &[]);
         assert!(!ctx.cwd.as_os_str().is_empty());
         println!("Context:\n{}", ctx.format_for_prompt());
     }
+
+    #[test]
+    fn test_max_files_prioritizes_mentioned() {
+        let dir = tempfile::tempdir().unwrap();
+        for i in 0..30 {
+            std::fs::write(dir.path().join(format!("file{:02}.txt", i)), "").unwrap();
+        }
+        std::fs::write(dir.path().join("target.log"), "").unwrap();
+
+        let ctx = LocalContext::gather_in(dir.path().to_path_buf(), "tail 'target.log'", 5);
+        assert_eq!(ctx.files.len(), 5);
+        assert_eq!(ctx.files[0], "target.log");
+        assert!(ctx.files_truncated);
+    }
+
+    #[test]
+    fn test_excluded_directories() {
+        let home = dirs::home_dir().unwrap();
+        let excludes = vec!["~/.ssh".to_string(), "/srv/*/secrets".to_string()];
+
+        assert!(is_excluded(&home.join(".ssh"), &excludes));
+        assert!(is_excluded(&home.join(".ssh").join("keys"), &excludes));
+        assert!(is_excluded(Path::new("/srv/app/secrets/prod"), &excludes));
+        assert!(!is_excluded(&home.join(".sshd"), &excludes));
+        assert!(!is_excluded(Path::new("/srv/app/public"), &excludes));
+
+        let ctx = LocalContext {
+            cwd: home.join(".ssh"),
+            omitted: true,
+            ..LocalContext::default()
+        };
+        assert!(ctx.format_for_prompt().ends_with("[context omitted for this directory]"));
+    }
+
+    #[test]
+    fn test_find_subproject() {
+        let dir = tempfile::tempdir().unwrap();
+        for (sub, marker) in [
+            ("frontend", "package.json"),
+            ("api", "Cargo.toml"),
+            ("web-admin", "package.json"),
+            ("node_modules", "package.json"),
+        ] {
+            std::fs::create_dir(dir.path().join(sub)).unwrap();
+            std::fs::write(dir.path().join(sub).join(marker), "").unwrap();
+        }
+        std::fs::create_dir(dir.path().join("docs")).unwrap();
+
+        let names: Vec<String> = subprojects(dir.path()).into_iter().map(|p| p.name).collect();
+        assert_eq!(names, ["api", "frontend", "web-admin"]);
+
+        let found = find_subproject(dir.path(), "build the frontend").unwrap();
+        assert_eq!((found.name.as_str(), found.kind), ("frontend", "node"));
+        assert_eq!(find_subproject(dir.path(), "run the web admin tests").unwrap().name, "web-admin");
+        assert_eq!(find_subproject(dir.path(), "list the docs"), None);
+        // Naming two subprojects is ambiguous
+        assert_eq!(find_subproject(dir.path(), "start the api and the frontend"), None);
+    }
+
+    #[test]
+    fn test_package_manager_candidates() {
+        let ubuntu = "NAME=\"Ubuntu\"\nID=ubuntu\nID_LIKE=debian\n";
+        assert_eq!(package_manager_candidates("linux", ubuntu)[0], "apt");
+
+        // Derivatives fall back on their family
+        let rocky = "ID=\"rocky\"\nID_LIKE=\"rhel centos fedora\"\n";
+        let candidates = package_manager_candidates("linux", rocky);
+        assert_eq!(candidates[0], "dnf");
+        assert!(candidates.contains(&"yum"));
+
+        assert_eq!(package_manager_candidates("macos", "")[0], "brew");
+        assert_eq!(package_manager_candidates("windows", "")[..2], ["winget", "choco"]);
+        assert_eq!(package_manager_candidates("linux", "ID=unknownix\n")[0], "apt");
+    }
 }
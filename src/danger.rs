@@ -0,0 +1,661 @@
+//! Command danger classification
+//!
+//! Combines the model's DANGEROUS flag with deterministic pattern checks so
+//! that confirmation friction scales with how bad a mistake could be.
+
+use crate::config::{get_config_path, CatastrophicConfirm, Config, SecurityConfig};
+use crate::error::SprenError;
+use crate::preview::{command_paths, written_paths};
+use crate::shell::expand_path;
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::path::{Component, Path, PathBuf};
+
+/// How much damage a command could do, in increasing order
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DangerLevel {
+    #[default]
+    Safe,
+    /// Harmless to data but can hang or fill the machine
+    Caution,
+    Destructive,
+    Catastrophic,
+}
+
+impl DangerLevel {
+    /// Whether the command should be flagged as dangerous to the user
+    pub fn is_dangerous(self) -> bool {
+        self >= DangerLevel::Destructive
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            DangerLevel::Safe => "safe",
+            DangerLevel::Caution => "caution",
+            DangerLevel::Destructive => "destructive",
+            DangerLevel::Catastrophic => "catastrophic",
+        }
+    }
+}
+
+/// How a pattern's end must line up with the command text
+#[derive(Clone, Copy)]
+enum Boundary {
+    /// Whole word: "dd" matches "dd if=..." but not "git add"
+    Word,
+    /// Path argument that must end there: "rm -rf /" but not "rm -rf /tmp"
+    Path,
+    /// Anything may follow: "of=/dev/sd" matches "of=/dev/sda"
+    Prefix,
+}
+
+/// Patterns that can wipe a system or disk in one go
+const CATASTROPHIC_PATTERNS: &[(&str, Boundary)] = &[
+    ("rm -rf /", Boundary::Path),
+    ("rm -rf /*", Boundary::Path),
+    ("rm -fr /", Boundary::Path),
+    ("rm -rf ~", Boundary::Path),
+    ("rm -rf ~/", Boundary::Path),
+    ("rm -rf $home", Boundary::Path),
+    ("--no-preserve-root", Boundary::Word),
+    ("mkfs", Boundary::Prefix),
+    ("of=/dev/sd", Boundary::Prefix),
+    ("of=/dev/nvme", Boundary::Prefix),
+    ("of=/dev/disk", Boundary::Prefix),
+    ("> /dev/sd", Boundary::Prefix),
+    (">/dev/sd", Boundary::Prefix),
+    ("chmod -r 777 /", Boundary::Path),
+    ("format-volume", Boundary::Word),
+    ("format c:", Boundary::Word),
+    ("remove-item -recurse -force c:\\", Boundary::Path),
+];
+
+/// Sources that never run dry
+const ENDLESS_SOURCES: &[&str] = &["if=/dev/zero", "if=/dev/urandom", "if=/dev/random"];
+
+/// Why a command could exhaust processes, memory or disk, and how bad that is
+///
+/// These delete nothing, so file-oriented patterns miss them, but they can
+/// still take a machine down until it is rebooted or cleaned up.
+pub fn resource_risk(command: &str) -> Option<(DangerLevel, &'static str)> {
+    let normalized = normalize(command);
+
+    if is_fork_bomb(&normalized) {
+        return Some((
+            DangerLevel::Destructive,
+            "This is a fork bomb: it spawns processes until the system stops responding.",
+        ));
+    }
+
+    let pipeline: Vec<&str> = normalized.split('|').map(str::trim).collect();
+    for (i, stage) in pipeline.iter().enumerate() {
+        let words: Vec<&str> = stage.split_whitespace().collect();
+        let program = words.iter().find(|w| **w != "sudo").copied();
+
+        if program == Some("yes") && (i + 1 < pipeline.len() || stage.contains('>')) {
+            return Some((
+                DangerLevel::Caution,
+                "`yes` never stops: it runs until its reader exits, or fills the disk if redirected to a file.",
+            ));
+        }
+        if program == Some("dd")
+            && words.iter().any(|w| ENDLESS_SOURCES.contains(w))
+            && !words.iter().any(|w| w.starts_with("count="))
+        {
+            return Some((
+                DangerLevel::Caution,
+                "`dd` from an endless device without count= writes until the disk is full.",
+            ));
+        }
+    }
+
+    None
+}
+
+/// Spot `f(){ f|f& };f` and its variants, plus the cmd.exe `%0|%0`
+fn is_fork_bomb(normalized: &str) -> bool {
+    let compact: String = normalized.chars().filter(|c| !c.is_whitespace()).collect();
+    if compact.contains("%0|%0") {
+        return true;
+    }
+    let recurses = |name: &str| !name.is_empty() && compact.contains(&format!("{0}|{0}&", name));
+
+    // `name(){ ... }`, possibly written `function name() { ... }`
+    let defined = compact.match_indices("(){").any(|(start, _)| {
+        let head = &compact[..start];
+        let name = head.rsplit(|c| ";&|{}()".contains(c)).next().unwrap_or_default();
+        recurses(name.strip_prefix("function").filter(|n| !n.is_empty()).unwrap_or(name))
+    });
+
+    // `function name { ... }`
+    defined
+        || normalized
+            .split_whitespace()
+            .skip_while(|w| *w != "function")
+            .nth(1)
+            .is_some_and(|name| recurses(name.trim_end_matches('{')))
+}
+
+/// Tools that send or fetch data over the network
+const NETWORK_COMMANDS: &[&str] = &[
+    "curl", "wget", "ssh", "scp", "sftp", "rsync", "ftp", "telnet", "nc", "ncat", "netcat",
+    "socat", "pip install", "pip3 install", "npm install", "git clone", "git push", "git pull",
+    "git fetch", "invoke-webrequest", "invoke-restmethod", "iwr", "irm",
+];
+
+/// Whether a command uses a known network tool
+///
+/// Separate from the danger level: this is about data leaving the machine,
+/// not data being destroyed.
+pub fn uses_network(command: &str) -> bool {
+    let normalized = normalize(command);
+    NETWORK_COMMANDS
+        .iter()
+        .any(|p| matches_pattern(&normalized, p, Boundary::Word))
+}
+
+/// Shell syntax that could smuggle a second command past a trusted prefix
+const CHAINING_CHARS: &[char] = &['|', ';', '&', '>', '<', '`', '$', '\n'];
+
+/// Whether the user vouched for `command` via `security.trusted_commands`
+///
+/// An entry matches the start of the command up to a word boundary, so
+/// "git status" trusts `git status -s` but not `git status-all`. Commands that
+/// chain, redirect or substitute are never trusted.
+pub fn is_trusted(command: &str, security: &SecurityConfig) -> bool {
+    let command = command.trim();
+    if command.contains(CHAINING_CHARS) {
+        return false;
+    }
+    security
+        .trusted_commands
+        .iter()
+        .map(|prefix| prefix.trim())
+        .filter(|prefix| !prefix.is_empty())
+        .any(|prefix| {
+            command
+                .strip_prefix(prefix)
+                .is_some_and(|rest| rest.is_empty() || rest.starts_with(char::is_whitespace))
+        })
+}
+
+/// Classify a command, taking the model's own danger flag into account
+///
+/// Catastrophic patterns and `security.dangerous_commands` apply to every
+/// command; past those, trusted commands are safe whatever the model thinks.
+pub fn classify(command: &str, model_flagged: bool, security: &SecurityConfig) -> DangerLevel {
+    let normalized = normalize(command);
+
+    if CATASTROPHIC_PATTERNS
+        .iter()
+        .any(|&(p, boundary)| matches_pattern(&normalized, p, boundary))
+    {
+        return DangerLevel::Catastrophic;
+    }
+
+    let configured = security
+        .dangerous_commands
+        .iter()
+        .any(|p| matches_pattern(&normalized, &p.to_lowercase(), Boundary::Word));
+
+    if configured {
+        return DangerLevel::Destructive;
+    }
+    if is_trusted(command, security) {
+        return DangerLevel::Safe;
+    }
+    if model_flagged {
+        return DangerLevel::Destructive;
+    }
+
+    resource_risk(command).map_or(DangerLevel::Safe, |(level, _)| level)
+}
+
+/// How a command must be confirmed before it runs
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Confirmation {
+    /// `--yes` answers for the user
+    Auto,
+    /// y/N prompt
+    Prompt,
+    /// Retype a random token
+    Token,
+}
+
+/// The confirmation tier for `command` at `level`
+///
+/// Applies to every command that is about to run, including auto-fix
+/// suggestions, so a retry can never skip the friction its level calls for.
+/// Trust only skips the warnings, so a trusted command is always prompted for.
+pub fn required_confirmation(
+    command: &str,
+    level: DangerLevel,
+    security: &SecurityConfig,
+    assume_yes: bool,
+) -> Confirmation {
+    if level == DangerLevel::Catastrophic
+        && security.catastrophic_confirm == CatastrophicConfirm::RetypeToken
+    {
+        Confirmation::Token
+    } else if level > DangerLevel::Safe || !assume_yes || is_trusted(command, security) {
+        Confirmation::Prompt
+    } else {
+        Confirmation::Auto
+    }
+}
+
+/// Apply `strip_sudo`/`forbid_sudo` to a suggested command
+///
+/// Returns the command to use and whether a leading `sudo` was stripped.
+pub fn apply_sudo_policy(command: &str, security: &SecurityConfig) -> Result<(String, bool)> {
+    let mut command = command.trim().to_string();
+    let mut stripped = false;
+
+    if security.strip_sudo {
+        // Only a plain `sudo cmd`; with options like `-u user` the intent is unclear
+        if let Some(rest) = command.strip_prefix("sudo ") {
+            let rest = rest.trim_start();
+            if !rest.is_empty() && !rest.starts_with('-') {
+                command = rest.to_string();
+                stripped = true;
+            }
+        }
+    }
+
+    if security.forbid_sudo && matches_pattern(&normalize(&command), "sudo", Boundary::Word) {
+        return Err(SprenError::PolicyViolation(format!(
+            "Refusing command that uses sudo (security.forbid_sudo): {}",
+            command
+        ))
+        .into());
+    }
+
+    Ok((command, stripped))
+}
+
+/// Spren's own files: the config directory (profiles, transcript, history)
+/// and the directories models are installed to
+pub fn protected_paths(config: &Config) -> Vec<PathBuf> {
+    let mut paths = Vec::new();
+    if let Some(dir) = get_config_path().ok().and_then(|p| p.parent().map(Path::to_path_buf)) {
+        paths.push(dir);
+    }
+    if let Some(model) = &config.ai.local_model_path {
+        paths.push(expand_path(model));
+    }
+    if let Some(data_dir) = dirs::data_local_dir() {
+        paths.push(data_dir.join("spren"));
+    }
+    if let Some(home) = dirs::home_dir() {
+        paths.push(home.join(".spren"));
+    }
+    paths
+}
+
+/// Refuse a command that would modify Spren's own config or model files
+///
+/// Reading them (`cat`, `ls`, `du`) is fine. A destructive command aimed at a
+/// parent directory (`rm -rf ~/.config`) counts too.
+/// `security.allow_self_modify` (`--allow-self-modify`) lifts this.
+pub fn check_self_modify(command: &str, cwd: &Path, config: &Config) -> Result<()> {
+    if config.security.allow_self_modify || is_trusted(command, &config.security) {
+        return Ok(());
+    }
+    let destructive = classify(command, false, &config.security).is_dangerous();
+    let targets = modified_targets(command, cwd, destructive);
+
+    match protected_target(&targets, &protected_paths(config), destructive) {
+        Some(path) => Err(SprenError::PolicyViolation(format!(
+            "Refusing command that touches Spren's own files ({}); pass --allow-self-modify to run it anyway: {}",
+            path.display(),
+            command
+        ))
+        .into()),
+        None => Ok(()),
+    }
+}
+
+/// Run `security.danger_check_command` on `command`, refusing it on a non-zero exit
+///
+/// This applies to trusted commands too: it is where org-wide policy lives.
+pub fn run_danger_check(command: &str, config: &Config) -> Result<()> {
+    let Some(checker) = &config.security.danger_check_command else {
+        return Ok(());
+    };
+    let check = crate::executor::run_reviewer(checker, command, config)?;
+    if check.success {
+        return Ok(());
+    }
+
+    let reason = if check.stderr.is_empty() {
+        let code = check.exit_code.map_or("a signal".to_string(), |c| format!("exit {}", c));
+        format!("`{}` failed ({})", checker, code)
+    } else {
+        check.stderr
+    };
+    Err(SprenError::PolicyViolation(format!("Blocked by danger check: {}", reason)).into())
+}
+
+/// Paths a command could change: the ones it writes, or with a destructive
+/// command every path it names
+fn modified_targets(command: &str, cwd: &Path, destructive: bool) -> Vec<PathBuf> {
+    if destructive {
+        command_paths(command, cwd)
+    } else {
+        written_paths(command, cwd)
+    }
+}
+
+/// The first protected path that one of `targets` is inside of (or, with
+/// `include_parents`, contains)
+fn protected_target(targets: &[PathBuf], protected: &[PathBuf], include_parents: bool) -> Option<PathBuf> {
+    let protected: Vec<PathBuf> = protected.iter().map(|p| clean_path(p)).collect();
+    targets.iter().map(|t| clean_path(t)).find_map(|target| {
+        protected
+            .iter()
+            .find(|p| target.starts_with(p) || (include_parents && p.starts_with(&target)))
+            .cloned()
+    })
+}
+
+/// Resolve symlinks where the path exists, and `.`/`..` lexically where it doesn't
+fn clean_path(path: &Path) -> PathBuf {
+    if let Ok(path) = std::fs::canonicalize(path) {
+        return path;
+    }
+    let mut clean = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir => {
+                clean.pop();
+            }
+            other => clean.push(other),
+        }
+    }
+    clean
+}
+
+/// Generate a short random token the user must retype to confirm
+pub fn confirmation_token() -> String {
+    // Unambiguous characters only (no 0/O, 1/l/I)
+    const ALPHABET: &[u8] = b"abcdefghjkmnpqrstuvwxyz23456789";
+
+    let mut hasher = RandomState::new().build_hasher();
+    hasher.write_u128(
+        std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|d| d.as_nanos())
+            .unwrap_or_default(),
+    );
+    let mut bits = hasher.finish();
+
+    (0..6)
+        .map(|_| {
+            let c = ALPHABET[(bits % ALPHABET.len() as u64) as usize];
+            bits /= ALPHABET.len() as u64;
+            c as char
+        })
+        .collect()
+}
+
+/// Lowercase, collapse whitespace and drop harmless /dev/null redirects
+fn normalize(command: &str) -> String {
+    command
+        .split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ")
+        .to_lowercase()
+        .replace("> /dev/null", "")
+        .replace(">/dev/null", "")
+}
+
+/// Match `pattern` where it starts a command word and ends per `boundary`
+fn matches_pattern(command: &str, pattern: &str, boundary: Boundary) -> bool {
+    let pattern = pattern.trim();
+    if pattern.is_empty() {
+        return false;
+    }
+
+    command.match_indices(pattern).any(|(start, _)| {
+        let before = command[..start].chars().next_back();
+        let after = command[start + pattern.len()..].chars().next();
+
+        let starts_word = before.is_none_or(|c| c.is_whitespace() || ";|&(`$".contains(c));
+        let ends_word = match (after, boundary) {
+            (None, _) | (_, Boundary::Prefix) => true,
+            (Some(c), Boundary::Path) => c.is_whitespace() || ";|&)".contains(c),
+            (Some(c), Boundary::Word) => !(c.is_alphanumeric() || c == '_' || c == '-'),
+        };
+
+        starts_word && ends_word
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn level(cmd: &str) -> DangerLevel {
+        classify(cmd, false, &SecurityConfig::default())
+    }
+
+    #[test]
+    fn test_catastrophic_commands() {
+        assert_eq!(level("rm -rf /"), DangerLevel::Catastrophic);
+        assert_eq!(level("sudo rm -rf /*"), DangerLevel::Catastrophic);
+        assert_eq!(level("mkfs.ext4 /dev/sda1"), DangerLevel::Catastrophic);
+        assert_eq!(level("dd if=/dev/zero of=/dev/sda bs=1M"), DangerLevel::Catastrophic);
+        assert_eq!(level("rm -rf $HOME"), DangerLevel::Catastrophic);
+    }
+
+    #[test]
+    fn test_destructive_is_not_catastrophic() {
+        assert_eq!(level("rm -rf /tmp/build"), DangerLevel::Destructive);
+        assert_eq!(level("rm -rf ./target"), DangerLevel::Destructive);
+        assert_eq!(level("rm -rf ~/projects/old"), DangerLevel::Destructive);
+        assert_eq!(level("rm -rf $HOME/.cache"), DangerLevel::Destructive);
+    }
+
+    #[test]
+    fn test_word_boundaries() {
+        assert_eq!(level("git add ."), DangerLevel::Safe);
+        assert_eq!(level("git log --format=%h"), DangerLevel::Safe);
+        assert_eq!(level("ls > /dev/null"), DangerLevel::Safe);
+        assert_eq!(level("dd if=a.img of=b.img"), DangerLevel::Destructive);
+    }
+
+    #[test]
+    fn test_fork_bombs() {
+        for bomb in [
+            ":(){ :|:& };:",
+            ":(){:|:&};:",
+            ": () { : | : & } ; :",
+            "bomb() { bomb | bomb & }; bomb",
+            "function f() { f|f& }; f",
+            "function f { f | f & }; f",
+            "%0|%0",
+        ] {
+            assert_eq!(level(bomb), DangerLevel::Destructive, "missed {:?}", bomb);
+            assert!(resource_risk(bomb).unwrap().1.contains("fork bomb"));
+        }
+        assert_eq!(level("greet() { echo hi; }; greet"), DangerLevel::Safe);
+    }
+
+    #[test]
+    fn test_resource_exhaustion() {
+        assert_eq!(level("yes | rm -rf build"), DangerLevel::Destructive);
+        assert_eq!(level("yes | head -n 5"), DangerLevel::Caution);
+        assert_eq!(level("yes > out.txt"), DangerLevel::Caution);
+        // `dd` is usually Destructive already; the specific reason still applies
+        assert!(resource_risk("dd if=/dev/urandom of=big.img bs=1M").is_some());
+        assert!(resource_risk("dd if=/dev/zero of=big.img bs=1M count=100").is_none());
+        assert_eq!(level("echo yes | tee answer.txt"), DangerLevel::Safe);
+
+        // --yes doesn't answer for them either
+        let security = SecurityConfig::default();
+        assert_eq!(
+            required_confirmation("yes | head -n 5", DangerLevel::Caution, &security, true),
+            Confirmation::Prompt
+        );
+    }
+
+    #[test]
+    fn test_model_flag() {
+        let security = SecurityConfig::default();
+        assert_eq!(classify("ls", true, &security), DangerLevel::Destructive);
+    }
+
+    #[test]
+    fn test_sudo_policy() {
+        let mut security = SecurityConfig::default();
+        assert_eq!(
+            apply_sudo_policy("sudo apt update", &security).unwrap(),
+            ("sudo apt update".to_string(), false)
+        );
+
+        security.strip_sudo = true;
+        assert_eq!(
+            apply_sudo_policy("sudo apt update", &security).unwrap(),
+            ("apt update".to_string(), true)
+        );
+        assert_eq!(
+            apply_sudo_policy("sudo -u postgres psql", &security).unwrap(),
+            ("sudo -u postgres psql".to_string(), false)
+        );
+
+        security.forbid_sudo = true;
+        assert!(apply_sudo_policy("sudo -u postgres psql", &security).is_err());
+        assert!(apply_sudo_policy("make && sudo make install", &security).is_err());
+        assert!(apply_sudo_policy("grep sudoers /etc/group", &security).is_ok());
+        assert!(apply_sudo_policy("sudo ls", &security).is_ok());
+    }
+
+    #[test]
+    fn test_confirmation_token() {
+        let token = confirmation_token();
+        assert_eq!(token.len(), 6);
+        assert!(token.chars().all(|c| c.is_ascii_alphanumeric()));
+    }
+
+    #[test]
+    fn test_network_commands() {
+        assert!(uses_network("curl -s ifconfig.me"));
+        assert!(uses_network("tar czf - . | ssh host 'cat > backup.tgz'"));
+        assert!(uses_network("pip install requests"));
+        assert!(!uses_network("pip list"));
+        assert!(!uses_network("ls ~/.ssh"));
+        assert!(!uses_network("ncdu /var"));
+    }
+
+    #[test]
+    fn test_dangerous_fix_needs_confirmation() {
+        let security = SecurityConfig::default();
+
+        // A fix the model calls safe is still classified by the rules, and
+        // --yes never answers for a destructive or catastrophic one
+        let tier = |fix: &str, assume_yes| {
+            let level = classify(fix, false, &security);
+            required_confirmation(fix, level, &security, assume_yes)
+        };
+        assert_eq!(tier("rm -rf build && make", true), Confirmation::Prompt);
+        assert_eq!(tier("sudo rm -rf /", true), Confirmation::Token);
+        assert_eq!(tier("make clean && make", true), Confirmation::Auto);
+        assert_eq!(tier("make clean && make", false), Confirmation::Prompt);
+    }
+
+    #[test]
+    fn test_protected_targets() {
+        let home = Path::new("/nonexistent-home");
+        let protected = vec![home.join(".config/spren"), home.join(".spren")];
+        let touches = |cmd: &str| {
+            let destructive = level(cmd).is_dangerous();
+            protected_target(&modified_targets(cmd, home, destructive), &protected, destructive)
+        };
+
+        assert!(touches("rm .config/spren/config.toml").is_some());
+        assert!(touches("echo '[ai]' > /nonexistent-home/.config/spren/config.toml").is_some());
+        assert!(touches("dd if=/dev/zero of=.spren/spren-model.gguf").is_some());
+        assert!(touches("cp evil.toml projects/../.config/spren/").is_some());
+        assert!(touches("rm -rf .config").is_some());
+        assert!(touches("nano .config/spren/config.toml").is_some());
+        assert!(touches("sed -i 's/openai/gemini/' .config/spren/config.toml").is_some());
+        assert!(touches("chmod 600 .spren/spren-model.gguf").is_some());
+        assert!(touches("mv .config/spren/config.toml /tmp/").is_some());
+
+        // Reading Spren's files is fine
+        assert!(touches("cat /nonexistent-home/.config/spren/config.toml").is_none());
+        assert!(touches("ls .config/spren").is_none());
+        assert!(touches("du -sh .spren").is_none());
+        assert!(touches("cp .config/spren/config.toml backup.toml").is_none());
+        assert!(touches("sed -n 1,5p .config/spren/config.toml").is_none());
+
+        // Listing a parent directory is fine; only destructive commands count there
+        assert!(touches("ls -la .config").is_none());
+        assert!(touches("rm -rf .config/spren-old").is_none());
+        assert!(touches("cat notes/spren.md").is_none());
+    }
+
+    #[test]
+    fn test_trusted_commands() {
+        let security = SecurityConfig {
+            trusted_commands: vec!["git status".to_string(), "rm -rf ./target".to_string()],
+            ..Default::default()
+        };
+        assert!(is_trusted("git status", &security));
+        assert!(is_trusted("git status -s", &security));
+        assert!(!is_trusted("git status-all", &security));
+        assert!(!is_trusted("git status; rm -rf ~", &security));
+        assert!(!is_trusted("git status > /etc/passwd", &security));
+        assert!(!is_trusted("git status $(rm -rf ~)", &security));
+
+        // Trust overrides the model's flag, but not the configured dangerous commands
+        assert_eq!(classify("git status", true, &security), DangerLevel::Safe);
+        assert_eq!(classify("rm -rf ./target", false, &security), DangerLevel::Destructive);
+        assert_eq!(
+            classify("rm -rf ./target && rm -rf /", false, &security),
+            DangerLevel::Catastrophic
+        );
+
+        // Extra arguments keep the prefix trusted, but not past the danger rules
+        let level = classify("rm -rf ./target /", false, &security);
+        assert!(level.is_dangerous());
+        assert_ne!(
+            required_confirmation("rm -rf ./target /", level, &security, true),
+            Confirmation::Auto
+        );
+
+        let security = SecurityConfig {
+            dangerous_commands: ["git push".to_string()].into(),
+            trusted_commands: vec!["git".to_string()],
+            ..Default::default()
+        };
+        assert_eq!(classify("git push --force", false, &security), DangerLevel::Destructive);
+        // Trusted commands still get their single confirmation under --yes
+        assert_eq!(
+            required_confirmation("git status", DangerLevel::Safe, &security, true),
+            Confirmation::Prompt
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_danger_check_command() {
+        let mut config = Config::default();
+        assert!(run_danger_check("kubectl delete ns prod", &config).is_ok());
+
+        config.security.danger_check_command =
+            Some("if grep -q 'ns prod'; then echo 'touches production' >&2; exit 1; fi".to_string());
+        assert!(run_danger_check("kubectl get pods", &config).is_ok());
+        let err = run_danger_check("kubectl delete ns prod", &config).unwrap_err();
+        assert!(err.to_string().contains("touches production"));
+
+        // A checker that says nothing still gets a reason
+        config.security.danger_check_command = Some("exit 4".to_string());
+        let err = run_danger_check("ls", &config).unwrap_err();
+        assert!(err.to_string().contains("exit 4"));
+    }
+}
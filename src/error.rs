@@ -0,0 +1,66 @@
+//! Error types that callers need to tell apart from generic failures
+
+use std::fmt;
+
+/// Exit code for configuration errors (sysexits.h EX_CONFIG)
+pub const EX_CONFIG: i32 = 78;
+
+/// Exit code for an interrupted run (128 + SIGINT)
+pub const EX_INTERRUPTED: i32 = 130;
+
+/// Exit code for a command killed by `security.command_timeout_secs` (as timeout(1))
+pub const EX_TIMED_OUT: i32 = 124;
+
+#[derive(Debug)]
+pub enum SprenError {
+    /// The selected provider is missing its API key or model files
+    NotConfigured(String),
+    /// The model's reply did not contain a usable command
+    InvalidCommand(String),
+    /// The suggested command breaks a configured security rule
+    PolicyViolation(String),
+    /// The user interrupted generation (only local inference can be interrupted)
+    #[cfg_attr(not(feature = "local"), allow(dead_code))]
+    Cancelled,
+    /// A command ran past `security.command_timeout_secs` and was killed
+    TimedOut(u64),
+}
+
+impl fmt::Display for SprenError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SprenError::NotConfigured(msg)
+            | SprenError::InvalidCommand(msg)
+            | SprenError::PolicyViolation(msg) => write!(f, "{}", msg),
+            SprenError::Cancelled => write!(f, "Generation cancelled"),
+            SprenError::TimedOut(secs) => write!(f, "command timed out after {} seconds", secs),
+        }
+    }
+}
+
+impl std::error::Error for SprenError {}
+
+/// Process exit code for an error: EX_CONFIG for setup problems, 1 otherwise
+pub fn exit_code(err: &anyhow::Error) -> i32 {
+    match err.downcast_ref::<SprenError>() {
+        Some(SprenError::NotConfigured(_)) => EX_CONFIG,
+        Some(SprenError::Cancelled) => EX_INTERRUPTED,
+        Some(SprenError::TimedOut(_)) => EX_TIMED_OUT,
+        Some(SprenError::InvalidCommand(_) | SprenError::PolicyViolation(_)) | None => 1,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_exit_codes() {
+        let not_configured: anyhow::Error = SprenError::NotConfigured("no key".into()).into();
+        assert_eq!(exit_code(&not_configured), EX_CONFIG);
+        assert_eq!(exit_code(&not_configured.context("while querying")), EX_CONFIG);
+        assert_eq!(exit_code(&anyhow::anyhow!("network down")), 1);
+        assert_eq!(exit_code(&SprenError::Cancelled.into()), EX_INTERRUPTED);
+        assert_eq!(exit_code(&SprenError::TimedOut(5).into()), EX_TIMED_OUT);
+    }
+}
@@ -1,17 +1,345 @@
 // src/executor.rs
 use anyhow::Result;
-use std::process::Command;
+use std::ffi::OsString;
+use std::io::Write;
+use std::path::Path;
+use std::process::{Command, ExitStatus, Stdio};
+use std::time::Duration;
+use crate::config::Config;
+use crate::error::SprenError;
 use crate::shell::ShellType;
 
+/// Programs that take over the terminal whatever their arguments
+const INTERACTIVE_PROGRAMS: &[&str] = &[
+    "vim", "vi", "nvim", "nano", "emacs", "pico", "micro", "hx", "less", "more", "most",
+    "man", "top", "htop", "btop", "atop", "iotop", "nmon", "ncdu", "mc", "ranger", "nnn",
+    "tmux", "screen", "watch", "fzf", "tig", "lazygit", "telnet", "ftp", "sftp", "su",
+    "passwd", "visudo", "crontab -e",
+];
+
+/// Interpreters and clients that start a REPL when given no arguments
+const REPL_PROGRAMS: &[&str] = &[
+    "python", "python3", "python2", "ipython", "node", "deno", "irb", "ghci", "lua", "R",
+    "psql", "mysql", "sqlite3", "redis-cli", "mongo", "mongosh", "bash", "zsh", "sh", "fish",
+    "pwsh", "powershell", "cmd", "scala", "clojure", "erl", "iex", "php", "bc",
+];
+
+/// Programs that read stdin when they get no file operand, with how many
+/// leading operands are something else (a pattern, script or filter)
+const STDIN_FILTERS: &[(&str, usize)] = &[
+    ("cat", 0), ("tac", 0), ("wc", 0), ("sort", 0), ("uniq", 0), ("head", 0), ("tail", 0),
+    ("cut", 0), ("nl", 0), ("rev", 0), ("fold", 0), ("fmt", 0), ("column", 0), ("base64", 0),
+    ("md5sum", 0), ("sha1sum", 0), ("sha256sum", 0), ("xxd", 0), ("od", 0), ("gzip", 0),
+    ("gunzip", 0), ("zcat", 0), ("bzip2", 0), ("xz", 0),
+    ("grep", 1), ("egrep", 1), ("fgrep", 1), ("sed", 1), ("awk", 1), ("jq", 1),
+    // Never take a file to read
+    ("tr", usize::MAX), ("tee", usize::MAX), ("xargs", usize::MAX),
+];
+
 pub struct CommandOutput {
     pub stdout: String,
     pub stderr: String,
     pub success: bool,
+    /// Process exit code (None if terminated by a signal)
+    pub exit_code: Option<i32>,
+}
+
+/// Whether a command needs the terminal (editors, pagers, REPLs, live views)
+/// and would hang or show nothing if its output were captured
+pub fn is_interactive(command: &str) -> bool {
+    command
+        .split(['|', ';', '&'])
+        .map(str::trim)
+        .filter(|segment| !segment.is_empty())
+        .any(segment_is_interactive)
+}
+
+fn segment_is_interactive(segment: &str) -> bool {
+    let words: Vec<&str> = segment
+        .split_whitespace()
+        // Skip privilege wrappers and VAR=value prefixes
+        .skip_while(|w| {
+            matches!(*w, "sudo" | "doas" | "env") || (w.contains('=') && !w.starts_with('-'))
+        })
+        .collect();
+    let Some((&program, args)) = words.split_first() else {
+        return false;
+    };
+    let program = program.rsplit(['/', '\\']).next().unwrap_or(program);
+    let has = |flag: &str| args.contains(&flag);
+
+    if INTERACTIVE_PROGRAMS.iter().any(|p| match p.split_once(' ') {
+        Some((name, flag)) => program == name && has(flag),
+        None => program == *p,
+    }) {
+        return true;
+    }
+
+    // Only flags, no script/file/-c: the interpreter starts a REPL
+    if REPL_PROGRAMS.contains(&program) {
+        return args
+            .iter()
+            .all(|a| a.starts_with('-') && !matches!(*a, "-c" | "-e" | "-m" | "--command"));
+    }
+
+    match program {
+        // `ssh host` opens a shell; `ssh host uptime` just runs a command
+        "ssh" | "mosh" => args.iter().filter(|a| !a.starts_with('-')).count() <= 1,
+        "tail" | "journalctl" => has("-f") || has("-F") || has("--follow"),
+        "git" => {
+            (has("commit") && !git_commit_has_message(args))
+                || (has("rebase") && (has("-i") || has("--interactive")))
+                || (has("add") && (has("-p") || has("--patch") || has("-i")))
+        }
+        "docker" | "podman" | "kubectl" => {
+            args.iter().any(|a| a.starts_with("-it") || a.starts_with("-ti"))
+        }
+        _ => false,
+    }
+}
+
+/// Whether `git commit` gets its message from the arguments, so no editor opens
+fn git_commit_has_message(args: &[&str]) -> bool {
+    args.iter().any(|a| {
+        matches!(*a, "--message" | "--file" | "--no-edit")
+            || a.starts_with("--message=")
+            || a.starts_with("--file=")
+            // Short flags combine (`-am`, `-aF msg.txt`); the first that takes a value ends the group
+            || a.strip_prefix('-').filter(|f| !f.starts_with('-')).is_some_and(|flags| {
+                flags.chars().find(|c| "mFCct".contains(*c)).is_some_and(|c| c == 'm' || c == 'F')
+            })
+    })
+}
+
+/// Whether a command will likely stop to ask for a password or a yes/no on
+/// the terminal (sudo, su, ssh host keys, ...), even though it isn't interactive
+///
+/// sudo with cached credentials won't ask, so it doesn't count.
+pub fn needs_tty(command: &str) -> bool {
+    command
+        .split(['|', ';', '&'])
+        .filter_map(prompting_program)
+        .any(|program| program != "sudo" || !sudo_credentials_cached())
 }
 
-pub async fn execute_command(command: &str) -> Result<CommandOutput> {
-    let shell_type = ShellType::detect();
-    let (shell, args) = shell_type.get_shell_command();
+/// The program in `segment` that prompts on the terminal, if any
+fn prompting_program(segment: &str) -> Option<&'static str> {
+    let words: Vec<&str> = segment
+        .split_whitespace()
+        .skip_while(|w| w.contains('=') && !w.starts_with('-'))
+        .collect();
+    let (&program, args) = words.split_first()?;
+    let program = program.rsplit(['/', '\\']).next().unwrap_or(program);
+    let has = |flag: &str| args.contains(&flag);
+
+    match program {
+        // -n never prompts (it fails instead); -S reads the password from stdin
+        "sudo" if !(has("-n") || has("--non-interactive") || has("-S")) => Some("sudo"),
+        "doas" if !has("-n") => Some("doas"),
+        "su" => Some("su"),
+        "passwd" => Some("passwd"),
+        "ssh" | "scp" | "sftp" if !args.iter().any(|a| a.contains("BatchMode=yes")) => Some("ssh"),
+        _ => None,
+    }
+}
+
+/// The program a pipeline starts with that would wait for input on stdin,
+/// because it got no file to read (`grep TODO`, `wc -l`, `tr a-z A-Z`)
+///
+/// Executed commands get an empty stdin, so these don't hang but finish with
+/// no output, which looks just as broken.
+pub fn stdin_reader(command: &str) -> Option<&'static str> {
+    command
+        .replace("&&", ";")
+        .replace("||", ";")
+        .split([';', '&', '\n'])
+        .filter_map(|pipeline| pipeline.split('|').next())
+        .find_map(reads_stdin)
+}
+
+fn reads_stdin(stage: &str) -> Option<&'static str> {
+    // Redirected input, or a heredoc
+    if stage.contains('<') {
+        return None;
+    }
+    let words = shlex::split(stage)?;
+    let words: Vec<&str> = words
+        .iter()
+        .map(String::as_str)
+        .skip_while(|w| {
+            matches!(*w, "sudo" | "doas" | "env") || (w.contains('=') && !w.starts_with('-'))
+        })
+        .collect();
+    let (&program, args) = words.split_first()?;
+    let program = program.rsplit(['/', '\\']).next().unwrap_or(program);
+    let &(name, non_files) = STDIN_FILTERS.iter().find(|(name, _)| *name == program)?;
+
+    let recursive = args.iter().any(|a| {
+        *a == "--recursive" || (a.starts_with('-') && !a.starts_with("--") && a.contains(['r', 'R']))
+    });
+    if name.ends_with("grep") && recursive {
+        // Searches the current directory
+        return None;
+    }
+
+    let mut operands = 0;
+    let mut after_flag = false;
+    for arg in args {
+        if *arg == "-" {
+            // Explicitly stdin
+            return Some(name);
+        }
+        if arg.starts_with('-') {
+            after_flag = true;
+            continue;
+        }
+        // A number after a flag is its value (`head -n 5`)
+        if !(after_flag && arg.parse::<i64>().is_ok()) {
+            operands += 1;
+        }
+        after_flag = false;
+    }
+    (non_files == usize::MAX || operands <= non_files).then_some(name)
+}
+
+/// Whether sudo would run without asking for a password right now
+fn sudo_credentials_cached() -> bool {
+    Command::new("sudo")
+        .args(["-n", "true"])
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status()
+        .is_ok_and(|status| status.success())
+}
+
+/// Run a command with the terminal handed over (inherited stdio)
+pub async fn execute_interactive(command: &str, config: &Config) -> Result<Option<i32>> {
+    check_before_run(command, config)?;
+    let (shell_type, shell) = exec_shell(config);
+    let (_, args) = shell_type.get_shell_command();
+
+    let mut cmd = shell_command(&shell, args, &shell_type.format_command(command), config)?;
+    cmd.stdin(Stdio::inherit())
+        .stdout(Stdio::inherit())
+        .stderr(Stdio::inherit());
+    scrub_env(&mut cmd, &config.security.scrub_env);
+
+    Ok(cmd.status()?.code())
+}
+
+/// Checks every command gets right before it starts, captured or interactive
+///
+/// This also covers fixes, workflows and re-runs that skip the suggestion prompt.
+fn check_before_run(command: &str, config: &Config) -> Result<()> {
+    if let Ok(cwd) = std::env::current_dir() {
+        crate::danger::check_self_modify(command, &cwd, config)?;
+    }
+    Ok(())
+}
+
+/// The shell that runs commands: `shell.exec_shell_path` if set, else the detected one
+fn exec_shell(config: &Config) -> (ShellType, String) {
+    match config.shell.exec_shell_path.as_deref().filter(|p| !p.trim().is_empty()) {
+        Some(path) => (ShellType::from_binary(path), path.to_string()),
+        None => {
+            let shell_type = ShellType::detect();
+            let shell = shell_type.get_shell_command().0.to_string();
+            (shell_type, shell)
+        }
+    }
+}
+
+/// Variables a jailed command still gets; everything else is cleared
+const JAIL_ENV: &[&str] = &[
+    "PATH", "HOME", "USER", "LOGNAME", "SHELL", "TERM", "LANG", "LC_ALL", "LC_CTYPE", "TZ",
+    // Windows can't start most programs without these
+    "SYSTEMROOT", "WINDIR", "COMSPEC", "PATHEXT", "TEMP", "TMP", "USERPROFILE",
+];
+
+/// `shell args command`, jailed to the current directory when
+/// `security.jail_execution` is set
+fn shell_command(shell: &str, args: &[&str], command: &str, config: &Config) -> Result<Command> {
+    if !config.security.jail_execution {
+        let mut cmd = Command::new(shell);
+        cmd.args(args).arg(command);
+        return Ok(cmd);
+    }
+
+    let cwd = std::env::current_dir()?;
+    let mut cmd = if cfg!(target_os = "linux") && crate::context::on_path("bwrap") {
+        let mut cmd = Command::new("bwrap");
+        cmd.args(bwrap_args(&cwd)).arg("--").arg(shell);
+        cmd
+    } else {
+        Command::new(shell)
+    };
+    cmd.args(args).arg(command).current_dir(&cwd).env_clear().env("PWD", &cwd);
+    for (name, value) in std::env::vars_os() {
+        if name.to_str().is_some_and(|name| JAIL_ENV.iter().any(|kept| kept.eq_ignore_ascii_case(name))) {
+            cmd.env(name, value);
+        }
+    }
+    Ok(cmd)
+}
+
+/// bubblewrap options: the filesystem read-only except `cwd`, a private
+/// /tmp, and fresh namespaces apart from the network
+fn bwrap_args(cwd: &Path) -> Vec<OsString> {
+    let mut args: Vec<OsString> = ["--ro-bind", "/", "/", "--dev", "/dev", "--proc", "/proc", "--tmpfs", "/tmp"]
+        .into_iter()
+        .map(OsString::from)
+        .collect();
+    // After /tmp, so a working directory under it stays visible
+    args.extend([OsString::from("--bind"), cwd.into(), cwd.into()]);
+    args.extend([OsString::from("--chdir"), cwd.into()]);
+    args.extend(["--unshare-all", "--share-net", "--die-with-parent"].map(OsString::from));
+    args
+}
+
+/// Remove secret-bearing variables from the child's environment
+fn scrub_env(cmd: &mut Command, patterns: &[String]) {
+    let patterns = compile_patterns(patterns);
+
+    for (name, _) in std::env::vars_os() {
+        if name.to_str().is_some_and(|name| should_scrub(name, &patterns)) {
+            cmd.env_remove(name);
+        }
+    }
+}
+
+fn compile_patterns(patterns: &[String]) -> Vec<glob::Pattern> {
+    patterns
+        .iter()
+        .filter_map(|p| glob::Pattern::new(p).ok())
+        .collect()
+}
+
+fn should_scrub(name: &str, patterns: &[glob::Pattern]) -> bool {
+    // Windows variable names are case-insensitive
+    let options = glob::MatchOptions {
+        case_sensitive: false,
+        ..Default::default()
+    };
+    patterns.iter().any(|p| p.matches_with(name, options))
+}
+
+pub async fn execute_command(command: &str, config: &Config) -> Result<CommandOutput> {
+    // A password or host-key prompt would never reach us through captured
+    // pipes, so let the user answer it; only the exit code comes back
+    if needs_tty(command) {
+        let exit_code = execute_interactive(command, config).await?;
+        return Ok(CommandOutput {
+            stdout: String::new(),
+            stderr: String::new(),
+            success: exit_code == Some(0),
+            exit_code,
+        });
+    }
+
+    check_before_run(command, config)?;
+    let (shell_type, shell) = exec_shell(config);
+    let (_, args) = shell_type.get_shell_command();
 
     let formatted_command = match shell_type {
         ShellType::PowerShell => {
@@ -33,13 +361,33 @@ pub async fn execute_command(command: &str) -> Result<CommandOutput> {
         _ => shell_type.format_command(command)
     };
 
-    let mut cmd = Command::new(shell);
-    cmd.args(args).arg(&formatted_command);
+    let mut cmd = shell_command(&shell, args, &formatted_command, config)?;
+    cmd.stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped());
+    scrub_env(&mut cmd, &config.security.scrub_env);
+    // Its own process group, so a timeout or Ctrl+C stops everything it started
+    #[cfg(unix)]
+    std::os::unix::process::CommandExt::process_group(&mut cmd, 0);
 
-    let output = cmd.output()?;
+    let mut child = tokio::process::Command::from(cmd).kill_on_drop(true).spawn()?;
+    let group = child.id();
+    #[cfg(unix)]
+    let _running = RunningGroup::track(group);
+    let run = run_captured(&mut child, config.security.max_output_size);
+    let (stdout, stderr, limit_reached, status) = match config.security.command_timeout_secs {
+        0 => run.await?,
+        secs => match tokio::time::timeout(Duration::from_secs(secs), run).await {
+            Ok(finished) => finished?,
+            Err(_) => {
+                stop_process_group(&mut child, group).await?;
+                return Err(SprenError::TimedOut(secs).into());
+            }
+        },
+    };
 
-    let stdout = String::from_utf8_lossy(&output.stdout).into_owned();
-    let stderr = String::from_utf8_lossy(&output.stderr).into_owned();
+    let stdout = decode_output(&stdout, config);
+    let stderr = decode_output(&stderr, config);
 
     // Clean up the output by removing excessive newlines and whitespace
     let stdout = stdout
@@ -50,13 +398,448 @@ pub async fn execute_command(command: &str) -> Result<CommandOutput> {
 
     // Note: PowerShell and CMD might write to stderr even on success
     let success = match shell_type {
-        ShellType::Bash => output.status.success() && stderr.is_empty(),
-        _ => output.status.success()
+        ShellType::Bash => status.success() && stderr.is_empty(),
+        _ => status.success()
     };
 
     Ok(CommandOutput {
         stdout: stdout.trim().to_string(),
         stderr: stderr.trim().to_string(),
-        success
+        success: success && !limit_reached,
+        exit_code: status.code(),
+    })
+}
+
+/// How long a timed-out command gets to exit after SIGTERM before SIGKILL
+const KILL_GRACE: Duration = Duration::from_secs(2);
+
+/// Stop a timed-out command and everything it started: SIGTERM to its
+/// process group, then SIGKILL for whatever is left after `KILL_GRACE`
+#[cfg_attr(not(unix), allow(unused_variables))]
+async fn stop_process_group(child: &mut tokio::process::Child, group: Option<u32>) -> Result<()> {
+    #[cfg(unix)]
+    if let Some(group) = group.and_then(|id| libc::pid_t::try_from(id).ok()) {
+        // SAFETY: killpg only sends a signal; a group that's gone gets ESRCH
+        unsafe { libc::killpg(group, libc::SIGTERM) };
+        let _ = tokio::time::timeout(KILL_GRACE, child.wait()).await;
+        unsafe { libc::killpg(group, libc::SIGKILL) };
+        child.wait().await?;
+        return Ok(());
+    }
+    child.kill().await?;
+    Ok(())
+}
+
+/// Process group of the captured command running now (0 for none)
+#[cfg(unix)]
+static RUNNING_GROUP: std::sync::atomic::AtomicI32 = std::sync::atomic::AtomicI32::new(0);
+
+/// Marks a command's process group as running until dropped
+#[cfg(unix)]
+struct RunningGroup;
+
+#[cfg(unix)]
+impl RunningGroup {
+    fn track(group: Option<u32>) -> Self {
+        let group = group.and_then(|id| i32::try_from(id).ok()).unwrap_or(0);
+        RUNNING_GROUP.store(group, std::sync::atomic::Ordering::SeqCst);
+        RunningGroup
+    }
+}
+
+#[cfg(unix)]
+impl Drop for RunningGroup {
+    fn drop(&mut self) {
+        RUNNING_GROUP.store(0, std::sync::atomic::Ordering::SeqCst);
+    }
+}
+
+/// Pass Ctrl+C on to the captured command running now, if any
+///
+/// It runs in its own process group, so the terminal's SIGINT doesn't reach it.
+pub fn interrupt_running_command() {
+    #[cfg(unix)]
+    {
+        let group = RUNNING_GROUP.load(std::sync::atomic::Ordering::SeqCst);
+        if group > 0 {
+            // SAFETY: killpg only sends a signal; a group that's gone gets ESRCH
+            unsafe { libc::killpg(group, libc::SIGINT) };
+        }
+    }
+}
+
+/// Capture a child's output and wait for it to exit
+///
+/// Returns (stdout, stderr, limit_reached, status), as for `read_capped`.
+async fn run_captured(
+    child: &mut tokio::process::Child,
+    limit: usize,
+) -> Result<(Vec<u8>, Vec<u8>, bool, ExitStatus)> {
+    let (stdout, mut stderr, limit_reached) = read_capped(child, limit).await?;
+    if limit_reached {
+        // Whatever the pipeline still runs gets SIGPIPE once our pipes close
+        child.kill().await?;
+        stderr.extend_from_slice(OUTPUT_LIMIT_REACHED.as_bytes());
+    }
+    let status = child.wait().await?;
+    Ok((stdout, stderr, limit_reached, status))
+}
+
+/// Appended to stderr when a command is killed for printing too much
+const OUTPUT_LIMIT_REACHED: &str = "\n[output limit reached, command terminated]";
+
+/// Collect a child's stdout and stderr as they arrive, stopping once together
+/// they pass `limit` bytes (`security.max_output_size`)
+///
+/// Returns (stdout, stderr, limit_reached); on the limit, both are cut so
+/// that no more than `limit` bytes are kept.
+async fn read_capped(
+    child: &mut tokio::process::Child,
+    limit: usize,
+) -> Result<(Vec<u8>, Vec<u8>, bool)> {
+    let mut stdout_pipe = child.stdout.take();
+    let mut stderr_pipe = child.stderr.take();
+    let (mut stdout, mut stderr) = (Vec::new(), Vec::new());
+    let (mut stdout_chunk, mut stderr_chunk) = ([0u8; 8192], [0u8; 8192]);
+
+    while stdout_pipe.is_some() || stderr_pipe.is_some() {
+        tokio::select! {
+            read = read_pipe(&mut stdout_pipe, &mut stdout_chunk) => match read? {
+                0 => stdout_pipe = None,
+                n => stdout.extend_from_slice(&stdout_chunk[..n]),
+            },
+            read = read_pipe(&mut stderr_pipe, &mut stderr_chunk) => match read? {
+                0 => stderr_pipe = None,
+                n => stderr.extend_from_slice(&stderr_chunk[..n]),
+            },
+        }
+
+        if stdout.len() + stderr.len() > limit {
+            stdout.truncate(limit);
+            stderr.truncate(limit - stdout.len());
+            return Ok((stdout, stderr, true));
+        }
+    }
+    Ok((stdout, stderr, false))
+}
+
+/// Read from a pipe that may already be closed (then never ready)
+async fn read_pipe<R: tokio::io::AsyncRead + Unpin>(
+    pipe: &mut Option<R>,
+    buf: &mut [u8],
+) -> std::io::Result<usize> {
+    use tokio::io::AsyncReadExt;
+    match pipe {
+        Some(pipe) => pipe.read(buf).await,
+        None => std::future::pending().await,
+    }
+}
+
+/// Run an external reviewer (linter, policy script) with `command` on its stdin
+///
+/// The reviewer is itself a shell command line, so it can carry arguments,
+/// e.g. `shellcheck -s bash -`.
+pub fn run_reviewer(reviewer: &str, command: &str, config: &Config) -> Result<CommandOutput> {
+    let (shell_type, shell) = exec_shell(config);
+    let (_, args) = shell_type.get_shell_command();
+
+    let mut cmd = Command::new(shell);
+    cmd.args(args)
+        .arg(shell_type.format_command(reviewer))
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped());
+    scrub_env(&mut cmd, &config.security.scrub_env);
+
+    let mut child = cmd.spawn()?;
+    if let Some(mut stdin) = child.stdin.take() {
+        // A reviewer may exit without reading everything; its exit code still counts
+        let _ = writeln!(stdin, "{}", command);
+    }
+    let output = child.wait_with_output()?;
+
+    Ok(CommandOutput {
+        stdout: decode_output(&output.stdout, config).trim().to_string(),
+        stderr: decode_output(&output.stderr, config).trim().to_string(),
+        success: output.status.success(),
+        exit_code: output.status.code(),
     })
-}
\ No newline at end of file
+}
+
+/// Turn a child's output into text
+///
+/// `shell.output_encoding` wins when set. Otherwise valid UTF-8 is taken as
+/// is, and on Windows anything else is read in the console codepage (e.g.
+/// CP-437 or CP-1252), which is what `cmd` built-ins and most native tools
+/// write when their output is piped.
+pub fn decode_output(bytes: &[u8], config: &Config) -> String {
+    if let Some(encoding) = config
+        .shell
+        .output_encoding
+        .as_deref()
+        .and_then(|label| encoding_rs::Encoding::for_label(label.trim().as_bytes()))
+    {
+        return encoding.decode(bytes).0.into_owned();
+    }
+    if let Ok(text) = std::str::from_utf8(bytes) {
+        return text.to_string();
+    }
+    #[cfg(windows)]
+    if let Some(text) = decode_console_codepage(bytes) {
+        return text;
+    }
+    String::from_utf8_lossy(bytes).into_owned()
+}
+
+/// Decode with the console's output codepage, or the OEM codepage when
+/// Spren has no console
+#[cfg(windows)]
+fn decode_console_codepage(bytes: &[u8]) -> Option<String> {
+    use windows_sys::Win32::Globalization::{GetOEMCP, MultiByteToWideChar};
+    use windows_sys::Win32::System::Console::GetConsoleOutputCP;
+
+    let codepage = match unsafe { GetConsoleOutputCP() } {
+        0 => unsafe { GetOEMCP() },
+        codepage => codepage,
+    };
+    let len = i32::try_from(bytes.len()).ok()?;
+    // SAFETY: the input pointer and length describe `bytes`; the first call
+    // only measures, the second writes at most `wide.len()` UTF-16 units
+    let needed = unsafe {
+        MultiByteToWideChar(codepage, 0, bytes.as_ptr(), len, std::ptr::null_mut(), 0)
+    };
+    if needed <= 0 {
+        return None;
+    }
+    let mut wide = vec![0u16; needed as usize];
+    let written = unsafe {
+        MultiByteToWideChar(codepage, 0, bytes.as_ptr(), len, wide.as_mut_ptr(), needed)
+    };
+    if written <= 0 {
+        return None;
+    }
+    Some(String::from_utf16_lossy(&wide[..written as usize]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_interactive_commands() {
+        assert!(is_interactive("vim notes.txt"));
+        assert!(is_interactive("sudo top"));
+        assert!(is_interactive("python3"));
+        assert!(is_interactive("ssh user@host"));
+        assert!(is_interactive("tail -f /var/log/syslog"));
+        assert!(is_interactive("git log | less"));
+        assert!(is_interactive("git commit"));
+        assert!(is_interactive("git commit -a"));
+        assert!(is_interactive("git commit -c HEAD"));
+        assert!(is_interactive("docker run -it ubuntu bash"));
+        assert!(is_interactive("crontab -e"));
+    }
+
+    #[test]
+    fn test_non_interactive_commands() {
+        assert!(!is_interactive("ls -la"));
+        assert!(!is_interactive("python3 script.py"));
+        assert!(!is_interactive("python3 -c 'print(1)'"));
+        assert!(!is_interactive("ssh host uptime"));
+        assert!(!is_interactive("git commit -m 'fix'"));
+        assert!(!is_interactive("git commit -am 'fix'"));
+        assert!(!is_interactive("git commit --message=fix"));
+        assert!(!is_interactive("git commit -F msg.txt"));
+        assert!(!is_interactive("git commit -aF msg.txt"));
+        assert!(!is_interactive("git commit --file=msg.txt"));
+        assert!(!is_interactive("git commit --amend --no-edit"));
+        assert!(!is_interactive("crontab -l"));
+        assert!(!is_interactive("FOO=1 cargo build"));
+        assert!(!is_interactive("bash -c 'echo hi'"));
+    }
+
+    #[test]
+    fn test_prompting_programs() {
+        assert_eq!(prompting_program("sudo apt install ripgrep"), Some("sudo"));
+        assert_eq!(prompting_program(" /usr/bin/sudo -u postgres psql -c 'select 1'"), Some("sudo"));
+        assert_eq!(prompting_program("su - admin"), Some("su"));
+        assert_eq!(prompting_program("ssh build@ci uptime"), Some("ssh"));
+        assert_eq!(prompting_program("LANG=C scp notes.txt host:"), Some("ssh"));
+
+        assert_eq!(prompting_program("sudo -n systemctl restart nginx"), None);
+        assert_eq!(prompting_program("ssh -o BatchMode=yes host uptime"), None);
+        assert_eq!(prompting_program("ls -la"), None);
+        assert_eq!(prompting_program("echo sudo"), None);
+        assert!(!needs_tty("grep -r sudo /etc | wc -l"));
+        assert!(needs_tty("make && su -c 'make install'"));
+    }
+
+    #[test]
+    fn test_stdin_readers() {
+        assert_eq!(stdin_reader("grep TODO"), Some("grep"));
+        assert_eq!(stdin_reader("grep -i 'not found'"), Some("grep"));
+        assert_eq!(stdin_reader("wc -l"), Some("wc"));
+        assert_eq!(stdin_reader("head -n 5"), Some("head"));
+        assert_eq!(stdin_reader("tr a-z A-Z"), Some("tr"));
+        assert_eq!(stdin_reader("cd src && sudo sort -u"), Some("sort"));
+        assert_eq!(stdin_reader("cat -"), Some("cat"));
+
+        assert_eq!(stdin_reader("grep TODO src/main.rs"), None);
+        assert_eq!(stdin_reader("grep -rn TODO"), None);
+        assert_eq!(stdin_reader("wc -l < notes.txt"), None);
+        assert_eq!(stdin_reader("head -n 5 log.txt"), None);
+        assert_eq!(stdin_reader("ls | wc -l"), None);
+        assert_eq!(stdin_reader("awk '{print $1}' access.log"), None);
+        assert_eq!(stdin_reader("cat <<EOF > notes.txt"), None);
+    }
+
+    #[test]
+    fn test_scrub_env_patterns() {
+        let patterns = compile_patterns(&crate::config::SecurityConfig::default().scrub_env);
+        assert!(should_scrub("GITHUB_TOKEN", &patterns));
+        assert!(should_scrub("OPENAI_API_KEY", &patterns));
+        assert!(should_scrub("Stripe_Secret_Key", &patterns));
+        assert!(!should_scrub("PATH", &patterns));
+        assert!(!should_scrub("HOME", &patterns));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_run_reviewer() -> Result<()> {
+        let config = Config::default();
+
+        let review = run_reviewer("grep -q 'ls'", "ls -la", &config)?;
+        assert!(review.success);
+
+        let review = run_reviewer("echo 'policy: no rm' >&2; exit 3", "rm -rf build", &config)?;
+        assert!(!review.success);
+        assert_eq!(review.exit_code, Some(3));
+        assert_eq!(review.stderr, "policy: no rm");
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_output_limit_stops_endless_commands() -> Result<()> {
+        let mut config = Config::default();
+        config.shell.exec_shell_path = Some("/bin/sh".to_string());
+        config.security.max_output_size = 64 * 1024;
+
+        let output = execute_command("yes", &config).await?;
+        assert!(!output.success);
+        assert!(output.stdout.len() <= 64 * 1024);
+        assert!(output.stderr.ends_with("[output limit reached, command terminated]"));
+
+        let output = execute_command("echo hello", &config).await?;
+        assert!(output.success);
+        assert_eq!(output.stdout, "hello");
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_hung_commands_time_out() -> Result<()> {
+        let mut config = Config::default();
+        config.shell.exec_shell_path = Some("/bin/sh".to_string());
+        config.security.command_timeout_secs = 1;
+
+        let start = std::time::Instant::now();
+        let Err(err) = execute_command("sleep 30", &config).await else {
+            panic!("sleep 30 finished within a 1s timeout");
+        };
+        assert!(start.elapsed() < Duration::from_secs(10));
+        assert_eq!(err.to_string(), "command timed out after 1 seconds");
+        assert_eq!(crate::error::exit_code(&err), crate::error::EX_TIMED_OUT);
+
+        assert!(execute_command("echo hello", &config).await?.success);
+        Ok(())
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn test_timeouts_stop_background_children() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let pid_file = dir.path().join("pid");
+        let mut config = Config::default();
+        config.shell.exec_shell_path = Some("/bin/sh".to_string());
+        config.security.command_timeout_secs = 1;
+
+        let command = format!("sleep 30 & echo $! > {}; wait", pid_file.display());
+        assert!(execute_command(&command, &config).await.is_err());
+
+        // Reparented orphans may linger as zombies until reaped; those count as gone
+        let pid = std::fs::read_to_string(&pid_file)?.trim().to_string();
+        let alive = || match std::fs::read_to_string(format!("/proc/{}/stat", pid)) {
+            Ok(stat) => stat.rsplit(')').next().is_some_and(|state| !state.trim_start().starts_with('Z')),
+            Err(_) => false,
+        };
+        for _ in 0..50 {
+            if !alive() {
+                return Ok(());
+            }
+            std::thread::sleep(Duration::from_millis(100));
+        }
+        panic!("sleep {} outlived the timed-out command", pid);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_interactive_commands_cannot_edit_spren_config() -> Result<()> {
+        let mut config = Config::default();
+        // Nothing would be edited even if the guard let it through
+        config.shell.exec_shell_path = Some("/bin/false".to_string());
+        let config_path = crate::config::get_config_path()?;
+
+        let command = format!("nano {}", config_path.display());
+        assert!(is_interactive(&command));
+        assert!(execute_interactive(&command, &config).await.is_err());
+
+        config.security.allow_self_modify = true;
+        assert_eq!(execute_interactive(&command, &config).await?, Some(1));
+        Ok(())
+    }
+
+    #[test]
+    fn test_bwrap_args_only_bind_the_cwd() {
+        let args = bwrap_args(Path::new("/tmp/project"));
+        let args: Vec<&str> = args.iter().map(|a| a.to_str().unwrap()).collect();
+        let joined = args.join(" ");
+        assert!(joined.starts_with("--ro-bind / / "));
+        assert!(joined.contains("--tmpfs /tmp --bind /tmp/project /tmp/project --chdir /tmp/project"));
+        assert_eq!(args.iter().filter(|a| **a == "--bind").count(), 1);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_jailed_commands_get_a_minimal_environment() -> Result<()> {
+        std::env::set_var("SPREN_JAIL_TEST", "leaked");
+        let mut config = Config::default();
+        config.shell.exec_shell_path = Some("/bin/sh".to_string());
+        config.security.jail_execution = true;
+
+        let output = execute_command("echo \"[$SPREN_JAIL_TEST] $PWD\"", &config).await?;
+        let cwd = std::env::current_dir()?;
+        assert_eq!(output.stdout, format!("[] {}", cwd.display()));
+
+        config.security.jail_execution = false;
+        let output = execute_command("echo \"[$SPREN_JAIL_TEST]\"", &config).await?;
+        assert_eq!(output.stdout, "[leaked]");
+        Ok(())
+    }
+
+    #[test]
+    fn test_decode_output() {
+        let mut config = Config::default();
+        assert_eq!(decode_output("café ─".as_bytes(), &config), "café ─");
+
+        // "café" and a box-drawing line as written by a CP-1252/CP-866 console
+        config.shell.output_encoding = Some("windows-1252".to_string());
+        assert_eq!(decode_output(b"caf\xe9", &config), "café");
+        config.shell.output_encoding = Some("cp866".to_string());
+        assert_eq!(decode_output(b"\xc4\xc4", &config), "──");
+
+        // An unknown label falls back to the default handling
+        config.shell.output_encoding = Some("no-such-encoding".to_string());
+        assert_eq!(decode_output(b"ok", &config), "ok");
+    }
+}
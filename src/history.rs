@@ -0,0 +1,586 @@
+//! Persistent query history
+//!
+//! Spren keeps one entry per line in `~/.config/spren/history.txt`: a bare
+//! query, or a [`HistoryEntry`] as JSON when it knows where the query was
+//! asked. The file can be seeded from the user's own shell history with
+//! `spren history import`, and executed commands can be written back to it
+//! (`shell.write_to_shell_history`).
+
+use crate::shell::ShellType;
+use anyhow::{anyhow, Result};
+use dirs::home_dir;
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+/// A query and the directory it was asked in
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct HistoryEntry {
+    pub query: String,
+    /// Working directory at the time (missing for imported and older entries)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cwd: Option<PathBuf>,
+    /// Root of the git repository `cwd` was in
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub repo: Option<PathBuf>,
+}
+
+impl HistoryEntry {
+    /// An entry for `query` asked in `cwd`
+    pub fn new(query: &str, cwd: Option<PathBuf>) -> Self {
+        let repo = cwd.as_deref().and_then(repo_root);
+        Self {
+            query: query.to_string(),
+            cwd,
+            repo,
+        }
+    }
+
+    /// One line of the history file; entries without a directory stay bare
+    fn to_line(&self) -> String {
+        if self.cwd.is_none() && self.repo.is_none() {
+            return self.query.clone();
+        }
+        serde_json::to_string(self).unwrap_or_else(|_| self.query.clone())
+    }
+
+    fn from_line(line: &str) -> Self {
+        if line.starts_with('{') {
+            if let Ok(entry) = serde_json::from_str(line) {
+                return entry;
+            }
+        }
+        Self::new(line, None)
+    }
+
+    /// Whether the query was asked in `dir` or below it
+    pub fn is_in(&self, dir: &Path) -> bool {
+        self.cwd.as_deref().is_some_and(|cwd| cwd.starts_with(dir))
+    }
+}
+
+/// The enclosing git repository's root, if any
+fn repo_root(dir: &Path) -> Option<PathBuf> {
+    dir.ancestors()
+        .find(|ancestor| ancestor.join(".git").exists())
+        .map(Path::to_path_buf)
+}
+
+/// Path of Spren's history file
+pub fn history_path() -> Result<PathBuf> {
+    let home = home_dir().ok_or_else(|| anyhow!("Could not find home directory"))?;
+    Ok(home.join(".config").join("spren").join("history.txt"))
+}
+
+/// Load history queries, oldest first; a missing file is an empty history
+pub fn load(path: &Path) -> Result<Vec<String>> {
+    Ok(load_entries(path)?.into_iter().map(|entry| entry.query).collect())
+}
+
+/// Load queries for Up-arrow recall: consecutive repeats collapsed and only
+/// the newest `max_entries` kept
+pub fn load_recent(path: &Path, max_entries: usize) -> Result<Vec<String>> {
+    let mut queries = load(path)?;
+    queries.dedup();
+    queries.drain(..queries.len().saturating_sub(max_entries));
+    Ok(queries)
+}
+
+/// Collapse consecutive repeats in the history file and trim it to the
+/// newest `max_entries`; the file is only rewritten if that changes it
+pub fn compact(path: &Path, max_entries: usize) -> Result<()> {
+    let mut history = load_entries(path)?;
+    let before = history.len();
+    history.dedup_by(|later, earlier| later.query == earlier.query);
+    history.drain(..history.len().saturating_sub(max_entries));
+    if history.len() == before {
+        return Ok(());
+    }
+    write_entries(path, &history)
+}
+
+/// Load history entries with their directories, oldest first
+pub fn load_entries(path: &Path) -> Result<Vec<HistoryEntry>> {
+    match fs::read_to_string(path) {
+        Ok(contents) => Ok(contents
+            .lines()
+            .filter(|l| !l.trim().is_empty())
+            .map(HistoryEntry::from_line)
+            .collect()),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Vec::new()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Append a query asked in the current directory to the history file
+pub fn append(path: &Path, entry: &str) -> Result<()> {
+    append_entry(path, &HistoryEntry::new(entry.trim(), std::env::current_dir().ok()))
+}
+
+fn append_entry(path: &Path, entry: &HistoryEntry) -> Result<()> {
+    if entry.query.is_empty() || entry.query.contains('\n') {
+        return Ok(());
+    }
+
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+    writeln!(file, "{}", entry.to_line())?;
+    Ok(())
+}
+
+/// Merge entries not already present, keeping at most `max_entries` newest
+///
+/// Returns how many new entries were added.
+pub fn merge(path: &Path, entries: &[String], max_entries: usize) -> Result<usize> {
+    let mut history = load_entries(path)?;
+    let mut seen: HashSet<String> = history.iter().map(|e| e.query.clone()).collect();
+
+    let before = history.len();
+    for entry in entries {
+        let entry = entry.trim();
+        // The history file is line-based, so multi-line commands are skipped
+        if entry.is_empty() || entry.contains('\n') {
+            continue;
+        }
+        if seen.insert(entry.to_string()) {
+            history.push(HistoryEntry::new(entry, None));
+        }
+    }
+    let added = history.len() - before;
+
+    if history.len() > max_entries {
+        history.drain(..history.len() - max_entries);
+    }
+    write_entries(path, &history)?;
+
+    Ok(added)
+}
+
+/// Replace the history file with `history`
+fn write_entries(path: &Path, history: &[HistoryEntry]) -> Result<()> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let mut contents = history.iter().map(HistoryEntry::to_line).collect::<Vec<_>>().join("\n");
+    contents.push('\n');
+    fs::write(path, contents)?;
+    Ok(())
+}
+
+// ============================================================================
+// Shell History Import
+// ============================================================================
+
+/// On-disk history formats of the shells we can import from
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ShellHistory {
+    Bash,
+    Zsh,
+    Fish,
+    PowerShell,
+}
+
+impl ShellHistory {
+    /// Pick the history format for the user's shell
+    pub fn detect(shell_type: &ShellType) -> Option<Self> {
+        match shell_type {
+            ShellType::PowerShell => Some(ShellHistory::PowerShell),
+            ShellType::Cmd => None,
+            ShellType::Bash => {
+                // ShellType doesn't distinguish POSIX shells, so ask $SHELL
+                let shell = std::env::var("SHELL").unwrap_or_default();
+                Some(match shell.rsplit('/').next().unwrap_or_default() {
+                    "zsh" => ShellHistory::Zsh,
+                    "fish" => ShellHistory::Fish,
+                    _ => ShellHistory::Bash,
+                })
+            }
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        match self {
+            ShellHistory::Bash => "bash",
+            ShellHistory::Zsh => "zsh",
+            ShellHistory::Fish => "fish",
+            ShellHistory::PowerShell => "PowerShell",
+        }
+    }
+
+    /// Default location of the shell's history file
+    pub fn default_path(&self) -> Option<PathBuf> {
+        let home = home_dir()?;
+        let histfile = std::env::var("HISTFILE").ok().filter(|f| !f.is_empty());
+
+        match self {
+            ShellHistory::Bash => {
+                Some(histfile.map_or_else(|| home.join(".bash_history"), PathBuf::from))
+            }
+            ShellHistory::Zsh => {
+                Some(histfile.map_or_else(|| home.join(".zsh_history"), PathBuf::from))
+            }
+            ShellHistory::Fish => {
+                let data = dirs::data_dir().unwrap_or_else(|| home.join(".local").join("share"));
+                Some(data.join("fish").join("fish_history"))
+            }
+            ShellHistory::PowerShell => Some(
+                dirs::config_dir()?
+                    .join("Microsoft")
+                    .join("Windows")
+                    .join("PowerShell")
+                    .join("PSReadLine")
+                    .join("ConsoleHost_history.txt"),
+            ),
+        }
+    }
+
+    /// Parse raw history file bytes into commands, oldest first
+    pub fn parse(&self, bytes: &[u8]) -> Vec<String> {
+        match self {
+            ShellHistory::Bash | ShellHistory::PowerShell => {
+                parse_bash(&String::from_utf8_lossy(bytes))
+            }
+            ShellHistory::Zsh => parse_zsh(&unmetafy(bytes)),
+            ShellHistory::Fish => parse_fish(&String::from_utf8_lossy(bytes)),
+        }
+    }
+
+    /// Encode one command as a history record, `timestamp` in Unix seconds
+    ///
+    /// Returns None for commands the format can't hold safely (multi-line
+    /// commands in bash history would be read back as separate entries).
+    pub fn format_entry(&self, command: &str, timestamp: u64) -> Option<Vec<u8>> {
+        let command = command.trim();
+        if command.is_empty() {
+            return None;
+        }
+
+        match self {
+            ShellHistory::Bash => {
+                if command.contains('\n') {
+                    return None;
+                }
+                // Bash only writes timestamps when HISTTIMEFORMAT is set
+                let stamp = std::env::var_os("HISTTIMEFORMAT")
+                    .map_or(String::new(), |_| format!("#{}\n", timestamp));
+                Some(format!("{}{}\n", stamp, command).into_bytes())
+            }
+            ShellHistory::Zsh => {
+                let record = format!(": {}:0;{}\n", timestamp, command.replace('\n', "\\\n"));
+                Some(metafy(record.as_bytes()))
+            }
+            ShellHistory::Fish => Some(
+                format!("- cmd: {}\n  when: {}\n", escape_fish(command), timestamp).into_bytes(),
+            ),
+            // PSReadLine continues multi-line entries with a trailing backtick
+            ShellHistory::PowerShell => {
+                Some(format!("{}\n", command.replace('\n', "`\n")).into_bytes())
+            }
+        }
+    }
+}
+
+/// Append an executed command to the user's own shell history file
+///
+/// The file is locked for the write so a shell saving its history at the
+/// same moment can't interleave with the record.
+pub fn append_to_shell_history(command: &str) -> Result<()> {
+    let shell = ShellHistory::detect(&ShellType::detect())
+        .ok_or_else(|| anyhow!("Command Prompt does not keep a history file"))?;
+    let path = shell
+        .default_path()
+        .ok_or_else(|| anyhow!("Could not locate {} history file", shell.name()))?;
+
+    let timestamp = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default();
+    let Some(record) = shell.format_entry(command, timestamp) else {
+        return Ok(());
+    };
+
+    write_locked(&path, &record)
+}
+
+fn write_locked(path: &Path, record: &[u8]) -> Result<()> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+    file.lock()?;
+    // One write, so even an unlocked reader never sees half a record
+    let result = file.write_all(record);
+    file.unlock()?;
+    Ok(result?)
+}
+
+/// Import the detected shell's history into Spren's history file
+///
+/// Returns the shell that was imported from and the number of new entries.
+pub fn import_shell_history(max_entries: usize) -> Result<(ShellHistory, usize)> {
+    let shell = ShellHistory::detect(&ShellType::detect())
+        .ok_or_else(|| anyhow!("Command Prompt does not keep a history file to import"))?;
+    let source = shell
+        .default_path()
+        .ok_or_else(|| anyhow!("Could not locate {} history file", shell.name()))?;
+
+    let bytes =
+        fs::read(&source).map_err(|e| anyhow!("Could not read {}: {}", source.display(), e))?;
+    let entries = shell.parse(&bytes);
+
+    let added = merge(&history_path()?, &entries, max_entries)?;
+    Ok((shell, added))
+}
+
+/// Plain one-command-per-line history, skipping `HISTTIMEFORMAT` stamps
+fn parse_bash(contents: &str) -> Vec<String> {
+    contents
+        .lines()
+        .filter(|l| !l.trim().is_empty())
+        .filter(|l| !(l.starts_with('#') && l[1..].chars().all(|c| c.is_ascii_digit())))
+        .map(String::from)
+        .collect()
+}
+
+/// Zsh history, in plain or `EXTENDED_HISTORY` (`: <time>:<secs>;cmd`) form
+///
+/// A trailing backslash continues the command on the next line.
+fn parse_zsh(contents: &str) -> Vec<String> {
+    let mut entries = Vec::new();
+    let mut current: Option<String> = None;
+
+    for line in contents.lines() {
+        let line = match current.take() {
+            Some(mut pending) => {
+                pending.push('\n');
+                pending.push_str(line);
+                pending
+            }
+            None => strip_zsh_timestamp(line).to_string(),
+        };
+
+        match line.strip_suffix('\\') {
+            Some(continued) => current = Some(continued.to_string()),
+            None => entries.push(line),
+        }
+    }
+    entries.extend(current);
+
+    entries.retain(|e| !e.trim().is_empty());
+    entries
+}
+
+fn strip_zsh_timestamp(line: &str) -> &str {
+    line.strip_prefix(": ")
+        .and_then(|rest| rest.split_once(';'))
+        .filter(|(stamp, _)| stamp.chars().all(|c| c.is_ascii_digit() || c == ':'))
+        .map_or(line, |(_, cmd)| cmd)
+}
+
+/// Undo zsh's "metafied" encoding: 0x83 marks a byte XORed with 0x20
+fn unmetafy(bytes: &[u8]) -> String {
+    const META: u8 = 0x83;
+
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut iter = bytes.iter();
+    while let Some(&b) = iter.next() {
+        if b == META {
+            if let Some(&next) = iter.next() {
+                out.push(next ^ 0x20);
+            }
+        } else {
+            out.push(b);
+        }
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+/// Fish's YAML-like `- cmd: ...` records
+fn parse_fish(contents: &str) -> Vec<String> {
+    contents
+        .lines()
+        .filter_map(|l| l.strip_prefix("- cmd: "))
+        .map(unescape_fish)
+        .collect()
+}
+
+/// Inverse of `unmetafy`: NUL, META and zsh's token bytes are escaped
+fn metafy(bytes: &[u8]) -> Vec<u8> {
+    const META: u8 = 0x83;
+    // zsh's Pound through Nularg token range
+    const TOKENS: std::ops::RangeInclusive<u8> = 0x84..=0xA1;
+
+    let mut out = Vec::with_capacity(bytes.len());
+    for &b in bytes {
+        if b == 0 || b == META || TOKENS.contains(&b) {
+            out.push(META);
+            out.push(b ^ 0x20);
+        } else {
+            out.push(b);
+        }
+    }
+    out
+}
+
+fn escape_fish(cmd: &str) -> String {
+    cmd.replace('\\', "\\\\").replace('\n', "\\n")
+}
+
+fn unescape_fish(cmd: &str) -> String {
+    let mut out = String::with_capacity(cmd.len());
+    let mut chars = cmd.chars();
+    while let Some(c) = chars.next() {
+        if c == '\\' {
+            match chars.next() {
+                Some('n') => out.push('\n'),
+                Some('\\') => out.push('\\'),
+                Some(other) => {
+                    out.push('\\');
+                    out.push(other);
+                }
+                None => out.push('\\'),
+            }
+        } else {
+            out.push(c);
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_entries_record_their_directory() -> Result<()> {
+        let dir = tempdir()?;
+        let path = dir.path().join("history.txt");
+        let repo = dir.path().join("api");
+        fs::create_dir_all(repo.join(".git"))?;
+        fs::create_dir_all(repo.join("src"))?;
+
+        fs::write(&path, "old bare query\n")?;
+        append_entry(&path, &HistoryEntry::new("run the tests", Some(repo.join("src"))))?;
+        // Merging rewrites the file and must keep the directories
+        merge(&path, &["git status".to_string()], 100)?;
+
+        assert_eq!(load(&path)?, ["old bare query", "run the tests", "git status"]);
+        let entries = load_entries(&path)?;
+        assert_eq!(entries[1].repo.as_deref(), Some(repo.as_path()));
+        assert!(entries[1].is_in(&repo));
+        assert!(!entries[0].is_in(&repo));
+        assert!(!entries[1].is_in(&dir.path().join("web")));
+        Ok(())
+    }
+
+    #[test]
+    fn test_compact_drops_repeats_and_old_entries() -> Result<()> {
+        let dir = tempdir()?;
+        let path = dir.path().join("history.txt");
+        for query in ["old", "git status", "git status", "ls", "git status", "ls", "ls"] {
+            append(&path, query)?;
+        }
+
+        assert_eq!(load_recent(&path, 3)?, ["ls", "git status", "ls"]);
+        compact(&path, 4)?;
+        assert_eq!(load(&path)?, ["git status", "ls", "git status", "ls"]);
+
+        // Already compact: left untouched
+        let modified = fs::metadata(&path)?.modified()?;
+        compact(&path, 4)?;
+        assert_eq!(fs::metadata(&path)?.modified()?, modified);
+
+        compact(&dir.path().join("missing.txt"), 4)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_bash() {
+        let history = "ls -la\n#1700000000\ngit status\n\n# a comment\n";
+        assert_eq!(
+            ShellHistory::Bash.parse(history.as_bytes()),
+            vec!["ls -la", "git status", "# a comment"]
+        );
+    }
+
+    #[test]
+    fn test_parse_zsh_extended() {
+        let history = b": 1700000000:0;ls -la\n: 1700000001:3;echo a \\\nb\ngit log\n";
+        assert_eq!(
+            ShellHistory::Zsh.parse(history),
+            vec!["ls -la", "echo a \nb", "git log"]
+        );
+    }
+
+    #[test]
+    fn test_unmetafy_zsh() {
+        // "ē" is 0xC4 0x93; zsh stores 0x93 as META, 0xB3
+        let history = [b'e', b'c', b'h', b'o', b' ', 0xC4, 0x83, 0xB3, b'\n'];
+        assert_eq!(ShellHistory::Zsh.parse(&history), vec!["echo ē"]);
+    }
+
+    #[test]
+    fn test_parse_fish() {
+        let history = "- cmd: ls -la\n  when: 1700000000\n- cmd: echo a\\\\b\n  when: 1700000001\n  paths:\n    - foo\n";
+        assert_eq!(
+            ShellHistory::Fish.parse(history.as_bytes()),
+            vec!["ls -la", "echo a\\b"]
+        );
+    }
+
+    #[test]
+    fn test_format_entries_round_trip() {
+        let commands = ["ls -la", "echo ē \\ done", "for f in *; do\n  echo $f\ndone"];
+        for shell in [ShellHistory::Zsh, ShellHistory::Fish] {
+            let mut bytes = Vec::new();
+            for cmd in commands {
+                bytes.extend(shell.format_entry(cmd, 1700000000).unwrap());
+            }
+            assert_eq!(shell.parse(&bytes), commands, "{} round trip", shell.name());
+        }
+
+        let zsh = ShellHistory::Zsh.format_entry("git status", 1700000000).unwrap();
+        assert_eq!(zsh, b": 1700000000:0;git status\n");
+
+        assert!(ShellHistory::Bash.format_entry("a\nb", 0).is_none());
+        assert_eq!(
+            ShellHistory::PowerShell.format_entry("Get-Date |\n  Out-String", 0).unwrap(),
+            b"Get-Date |`\n  Out-String\n"
+        );
+    }
+
+    #[test]
+    fn test_write_locked_appends() -> Result<()> {
+        let dir = tempdir()?;
+        let path = dir.path().join("nested").join(".zsh_history");
+        write_locked(&path, b": 1:0;ls\n")?;
+        write_locked(&path, b": 2:0;pwd\n")?;
+        assert_eq!(ShellHistory::Zsh.parse(&fs::read(&path)?), vec!["ls", "pwd"]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_merge_dedups_and_caps() -> Result<()> {
+        let dir = tempdir()?;
+        let path = dir.path().join("history.txt");
+        append(&path, "ls")?;
+
+        let entries = vec![
+            "ls".to_string(),
+            "pwd".to_string(),
+            "a\nb".to_string(),
+            "pwd".to_string(),
+        ];
+        assert_eq!(merge(&path, &entries, 10)?, 1);
+        assert_eq!(load(&path)?, vec!["ls", "pwd"]);
+
+        merge(&path, &["whoami".to_string()], 2)?;
+        assert_eq!(load(&path)?, vec!["pwd", "whoami"]);
+        Ok(())
+    }
+}
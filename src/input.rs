@@ -0,0 +1,159 @@
+//! Multi-line queries in the REPL
+//!
+//! A line ending in `\` continues on the next one, and a line starting with
+//! `"""` collects everything up to the closing `"""`, so a pasted error
+//! message arrives as one query. Anything else is a single-line query.
+//!
+//! With `ai.multiline_mode = "sequence"`, blank lines split such a query
+//! into several, run one after another.
+
+use crate::config::MultilineMode;
+
+const QUOTE: &str = "\"\"\"";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Mode {
+    Backslash,
+    Quoted,
+}
+
+/// Lines of a query that isn't finished yet
+#[derive(Debug, Default)]
+pub struct QueryBuffer {
+    lines: Vec<String>,
+    mode: Option<Mode>,
+}
+
+impl QueryBuffer {
+    /// Whether earlier lines are waiting for the rest of the query
+    pub fn is_pending(&self) -> bool {
+        self.mode.is_some()
+    }
+
+    /// Drop a half-typed query
+    pub fn clear(&mut self) {
+        self.lines.clear();
+        self.mode = None;
+    }
+
+    /// Add a line, returning the whole query once it is complete
+    pub fn push(&mut self, line: &str) -> Option<String> {
+        match self.mode {
+            None => {
+                if let Some(rest) = line.trim_start().strip_prefix(QUOTE) {
+                    self.mode = Some(Mode::Quoted);
+                    return self.push_quoted(rest);
+                }
+                self.push_backslash(line)
+            }
+            Some(Mode::Backslash) => self.push_backslash(line),
+            Some(Mode::Quoted) => self.push_quoted(line),
+        }
+    }
+
+    fn push_backslash(&mut self, line: &str) -> Option<String> {
+        match line.strip_suffix('\\') {
+            Some(start) => {
+                self.lines.push(start.to_string());
+                self.mode = Some(Mode::Backslash);
+                None
+            }
+            None => {
+                self.lines.push(line.to_string());
+                Some(self.finish())
+            }
+        }
+    }
+
+    fn push_quoted(&mut self, line: &str) -> Option<String> {
+        match line.split_once(QUOTE) {
+            Some((end, _)) => {
+                self.lines.push(end.to_string());
+                Some(self.finish())
+            }
+            None => {
+                self.lines.push(line.to_string());
+                None
+            }
+        }
+    }
+
+    fn finish(&mut self) -> String {
+        let query = self.lines.join("\n").trim().to_string();
+        self.clear();
+        query
+    }
+}
+
+/// The queries in `input`: all of it, or in sequence mode each block
+/// between blank lines
+pub fn split_queries(input: &str, mode: MultilineMode) -> Vec<String> {
+    match mode {
+        MultilineMode::Single => vec![input.trim().to_string()],
+        MultilineMode::Sequence => {
+            let mut queries = Vec::new();
+            let mut block: Vec<&str> = Vec::new();
+            for line in input.lines().chain([""]) {
+                if line.trim().is_empty() {
+                    if !block.is_empty() {
+                        queries.push(block.join("\n").trim().to_string());
+                        block.clear();
+                    }
+                } else {
+                    block.push(line);
+                }
+            }
+            queries
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_single_line_is_unchanged() {
+        let mut buffer = QueryBuffer::default();
+        assert_eq!(buffer.push("list files").as_deref(), Some("list files"));
+        assert!(!buffer.is_pending());
+    }
+
+    #[test]
+    fn test_backslash_continuation() {
+        let mut buffer = QueryBuffer::default();
+        assert_eq!(buffer.push("find large files \\"), None);
+        assert!(buffer.is_pending());
+        assert_eq!(buffer.push("in /var \\"), None);
+        assert_eq!(
+            buffer.push("and sort them").as_deref(),
+            Some("find large files \nin /var \nand sort them")
+        );
+        assert!(!buffer.is_pending());
+    }
+
+    #[test]
+    fn test_quoted_block() {
+        let mut buffer = QueryBuffer::default();
+        assert_eq!(buffer.push("\"\"\"why does this fail:"), None);
+        // Backslashes inside a quoted block are literal
+        assert_eq!(buffer.push("error: C:\\temp not found \\"), None);
+        assert_eq!(
+            buffer.push("\"\"\"").as_deref(),
+            Some("why does this fail:\nerror: C:\\temp not found \\")
+        );
+
+        assert_eq!(buffer.push("\"\"\"one line\"\"\"").as_deref(), Some("one line"));
+    }
+
+    #[test]
+    fn test_split_queries() {
+        let input = "back up the database\nto /backups\n\n  \nrestart postgres";
+        assert_eq!(split_queries(input, MultilineMode::Single), [input]);
+        assert_eq!(
+            split_queries(input, MultilineMode::Sequence),
+            ["back up the database\nto /backups", "restart postgres"]
+        );
+        assert_eq!(split_queries("list files", MultilineMode::Sequence), ["list files"]);
+    }
+}
@@ -1,8 +1,12 @@
 //! Local LLM inference using Candle with quantized GGUF models
 //!
 //! This module provides CPU-based inference for the fine-tuned Qwen 0.5B model,
-//! allowing Spren to work without cloud API calls.
+//! allowing Spren to work without cloud API calls. Llama and Phi-3 GGUFs load
+//! too, with their own chat template and end-of-turn tokens.
 
+use crate::config::ChatTemplate;
+#[cfg(feature = "local")]
+use crate::error::SprenError;
 #[cfg(feature = "local")]
 use anyhow::{anyhow, Result};
 #[cfg(feature = "local")]
@@ -12,12 +16,20 @@ use candle_core::{Device, Tensor};
 #[cfg(feature = "local")]
 use candle_transformers::generation::LogitsProcessor;
 #[cfg(feature = "local")]
+use candle_transformers::models::quantized_llama::ModelWeights as Llama;
+#[cfg(feature = "local")]
+use candle_transformers::models::quantized_phi3::ModelWeights as Phi3;
+#[cfg(feature = "local")]
 use candle_transformers::models::quantized_qwen2::ModelWeights as Qwen2;
 #[cfg(feature = "local")]
 use std::fs::File;
 #[cfg(feature = "local")]
 use std::path::{Path, PathBuf};
 #[cfg(feature = "local")]
+use std::sync::atomic::{AtomicBool, Ordering};
+#[cfg(feature = "local")]
+use std::sync::Arc;
+#[cfg(feature = "local")]
 use tokenizers::Tokenizer;
 
 /// Model and tokenizer filenames
@@ -26,74 +38,260 @@ const MODEL_FILENAME: &str = "spren-model.gguf";
 #[cfg(feature = "local")]
 const TOKENIZER_FILENAME: &str = "tokenizer.json";
 
+/// Unused embedding rows a model may carry beyond its tokenizer's vocab
+/// (Qwen2.5 pads 151,665 tokens to 151,936)
+#[cfg(feature = "local")]
+const MAX_VOCAB_PADDING: usize = 1024;
+
+/// Text produced by one generation
+#[cfg(feature = "local")]
+pub struct Generation {
+    pub text: String,
+    /// `max_tokens` ran out before an end-of-turn token or stop sequence
+    pub truncated: bool,
+}
+
+/// Quantized weights for the architectures Candle can run from a GGUF
+#[cfg(feature = "local")]
+enum Weights {
+    Qwen2(Qwen2),
+    Llama(Llama),
+    Phi3(Phi3),
+}
+
+#[cfg(feature = "local")]
+impl Weights {
+    /// Load by `general.architecture`; anything unrecognized is tried as Qwen2
+    fn from_gguf(
+        architecture: &str,
+        content: gguf_file::Content,
+        file: &mut File,
+        device: &Device,
+    ) -> candle_core::Result<Self> {
+        match architecture {
+            "llama" => Llama::from_gguf(content, file, device).map(Weights::Llama),
+            "phi3" => Phi3::from_gguf(false, content, file, device).map(Weights::Phi3),
+            _ => Qwen2::from_gguf(content, file, device).map(Weights::Qwen2),
+        }
+    }
+
+    fn forward(&mut self, input: &Tensor, start_pos: usize) -> candle_core::Result<Tensor> {
+        match self {
+            Weights::Qwen2(model) => model.forward(input, start_pos),
+            Weights::Llama(model) => model.forward(input, start_pos),
+            Weights::Phi3(model) => model.forward(input, start_pos),
+        }
+    }
+}
+
+#[cfg(feature = "local")]
+impl ChatTemplate {
+    /// The template to use: this one, or for `Auto` a guess from the chat
+    /// template embedded in the GGUF, then from its architecture
+    pub fn resolve(self, embedded: Option<&str>, architecture: &str) -> ChatTemplate {
+        if self != ChatTemplate::Auto {
+            return self;
+        }
+        match embedded {
+            Some(t) if t.contains("<|im_start|>") => ChatTemplate::Chatml,
+            Some(t) if t.contains("<|start_header_id|>") => ChatTemplate::Llama3,
+            Some(t) if t.contains("<|assistant|>") => ChatTemplate::Phi3,
+            _ => match architecture {
+                "llama" => ChatTemplate::Llama3,
+                "phi3" => ChatTemplate::Phi3,
+                _ => ChatTemplate::Chatml,
+            },
+        }
+    }
+
+    /// A system and a user turn, leaving the model to write the assistant's
+    ///
+    /// A leading BOS token is left to the tokenizer.
+    pub fn format(self, system: &str, user: &str) -> String {
+        match self {
+            ChatTemplate::Auto | ChatTemplate::Chatml => format!(
+                "<|im_start|>system\n{}<|im_end|>\n<|im_start|>user\n{}<|im_end|>\n<|im_start|>assistant\n",
+                system, user
+            ),
+            ChatTemplate::Llama3 => format!(
+                "<|start_header_id|>system<|end_header_id|>\n\n{}<|eot_id|>\
+                 <|start_header_id|>user<|end_header_id|>\n\n{}<|eot_id|>\
+                 <|start_header_id|>assistant<|end_header_id|>\n\n",
+                system, user
+            ),
+            ChatTemplate::Phi3 => {
+                format!("<|system|>\n{}<|end|>\n<|user|>\n{}<|end|>\n<|assistant|>\n", system, user)
+            }
+        }
+    }
+
+    /// Special tokens that end the model's turn
+    pub fn stop_tokens(self) -> &'static [&'static str] {
+        match self {
+            ChatTemplate::Auto | ChatTemplate::Chatml => &["<|im_end|>", "<|endoftext|>"],
+            ChatTemplate::Llama3 => &["<|eot_id|>", "<|end_of_text|>"],
+            ChatTemplate::Phi3 => &["<|end|>", "<|endoftext|>"],
+        }
+    }
+}
+
 /// Local Spren model for shell command generation
 #[cfg(feature = "local")]
 pub struct LocalSpren {
-    model: Qwen2,
+    model: Weights,
     tokenizer: Tokenizer,
     device: Device,
+    template: ChatTemplate,
+    /// IDs that end generation: the template's end-of-turn tokens, the GGUF's
+    /// EOS token and `ai.local_stop_tokens`
+    stop_ids: Vec<u32>,
+    /// The same tokens as text, scrubbed from replies
+    stop_tokens: Vec<String>,
+    /// Checked every token; set from another thread to stop generation early
+    cancel: Arc<AtomicBool>,
 }
 
 #[cfg(feature = "local")]
 impl LocalSpren {
     /// Load model from default locations (searches relative to executable, then standard paths)
-    pub fn load_default() -> Result<Self> {
+    pub fn load_default(template: ChatTemplate, extra_stop: &[String]) -> Result<Self> {
         let (model_path, tokenizer_path) = find_model_files()?;
         Self::new(
             &model_path.to_string_lossy(),
             &tokenizer_path.to_string_lossy(),
+            template,
+            extra_stop,
         )
     }
 
     /// Load the GGUF model and tokenizer from specific paths
-    pub fn new(model_path: &str, tokenizer_path: &str) -> Result<Self> {
+    ///
+    /// `template` may be `Auto` to follow the GGUF metadata; `extra_stop` adds
+    /// end-of-turn tokens the template doesn't know about.
+    pub fn new(
+        model_path: &str,
+        tokenizer_path: &str,
+        template: ChatTemplate,
+        extra_stop: &[String],
+    ) -> Result<Self> {
         let device = Device::Cpu;
 
         // Verify files exist
         if !Path::new(model_path).exists() {
-            return Err(anyhow!(
+            return Err(SprenError::NotConfigured(format!(
                 "Model file not found: {}\n\nThe model should be at one of:\n  - Next to the spren executable\n  - ~/.local/share/spren/\n  - /usr/share/spren/",
                 model_path
-            ));
+            ))
+            .into());
         }
         if !Path::new(tokenizer_path).exists() {
-            return Err(anyhow!(
+            return Err(SprenError::NotConfigured(format!(
                 "Tokenizer file not found: {}\n\nDownload from: https://huggingface.co/Qwen/Qwen2.5-0.5B-Instruct",
                 tokenizer_path
-            ));
+            ))
+            .into());
         }
 
         // Load the GGUF file
         let mut file = File::open(model_path)?;
         let content = gguf_file::Content::read(&mut file)
             .map_err(|e| anyhow!("Failed to read GGUF: {}", e))?;
-        let model = Qwen2::from_gguf(content, &mut file, &device)
+        let model_vocab = content
+            .tensor_infos
+            .get("token_embd.weight")
+            .map(|info| info.shape.dims()[0]);
+        let metadata = |key: &str| content.metadata.get(key).and_then(|v| v.to_string().ok()).cloned();
+        let architecture = metadata("general.architecture").unwrap_or_default();
+        let template = template.resolve(metadata("tokenizer.chat_template").as_deref(), &architecture);
+        let eos_id = content
+            .metadata
+            .get("tokenizer.ggml.eos_token_id")
+            .and_then(|v| v.to_u32().ok());
+        let model = Weights::from_gguf(&architecture, content, &mut file, &device)
             .map_err(|e| anyhow!("Failed to load model: {}", e))?;
 
         // Load the Tokenizer
         let tokenizer = Tokenizer::from_file(tokenizer_path)
             .map_err(|e| anyhow!("Failed to load tokenizer: {}", e))?;
 
+        // A mismatched pair loads fine but fails mid-generation, so check now
+        if let Some(model_vocab) = model_vocab {
+            check_vocab(tokenizer.get_vocab_size(true), model_vocab)?;
+        }
+        let mut stop_tokens: Vec<String> = template
+            .stop_tokens()
+            .iter()
+            .map(|token| token.to_string())
+            .chain(extra_stop.iter().filter(|token| !token.is_empty()).cloned())
+            .filter(|token| tokenizer.token_to_id(token).is_some())
+            .collect();
+        if stop_tokens.is_empty() {
+            return Err(SprenError::NotConfigured(format!(
+                "tokenizer/model mismatch: {} has none of the {:?} end-of-turn tokens ({}); \
+                 set ai.local_chat_template or ai.local_stop_tokens",
+                tokenizer_path,
+                template,
+                template.stop_tokens().join(", ")
+            ))
+            .into());
+        }
+        if let Some(eos) = eos_id.and_then(|id| tokenizer.id_to_token(id)) {
+            if !stop_tokens.contains(&eos) {
+                stop_tokens.push(eos);
+            }
+        }
+        let stop_ids = stop_tokens.iter().filter_map(|token| tokenizer.token_to_id(token)).collect();
+
         Ok(Self {
             model,
             tokenizer,
             device,
+            template,
+            stop_ids,
+            stop_tokens,
+            cancel: Arc::new(AtomicBool::new(false)),
         })
     }
 
+    /// Share a cancellation flag with e.g. a Ctrl+C handler
+    pub fn set_cancel_flag(&mut self, cancel: Arc<AtomicBool>) {
+        self.cancel = cancel;
+    }
+
     /// Generate a shell command from natural language input
     pub fn generate(&mut self, prompt: &str, max_tokens: u32, temperature: f32) -> Result<String> {
-        self.generate_with_context(prompt, None, max_tokens, temperature)
+        self.generate_with_context(prompt, None, max_tokens, temperature, &[])
+            .map(|generation| generation.text)
     }
 
     /// Generate a shell command with local context
+    ///
+    /// Generation also ends at the first of `stop`, which is left out of the result.
     pub fn generate_with_context(
         &mut self,
         prompt: &str,
         context: Option<&str>,
         max_tokens: u32,
         temperature: f32,
-    ) -> Result<String> {
+        stop: &[String],
+    ) -> Result<Generation> {
+        self.generate_streaming(prompt, context, max_tokens, temperature, stop, |_| {})
+    }
+
+    /// Like `generate_with_context`, handing each piece of text to `on_text`
+    /// as soon as it decodes
+    ///
+    /// Pieces never split a character. Text from a stop sequence onwards may
+    /// or may not have been passed on before generation ends.
+    pub fn generate_streaming(
+        &mut self,
+        prompt: &str,
+        context: Option<&str>,
+        max_tokens: u32,
+        temperature: f32,
+        stop: &[String],
+        mut on_text: impl FnMut(&str),
+    ) -> Result<Generation> {
         // Build system prompt with optional context
         let system_prompt = if let Some(ctx) = context {
             format!(
@@ -104,11 +302,7 @@ impl LocalSpren {
             "You are Spren, a terminal assistant. Convert natural language to shell commands. Reply with DANGEROUS:true/false and COMMAND:the_command".to_string()
         };
 
-        // Format prompt using ChatML format for Qwen Instruct models
-        let formatted_prompt = format!(
-            "<|im_start|>system\n{}<|im_end|>\n<|im_start|>user\n{}<|im_end|>\n<|im_start|>assistant\n",
-            system_prompt, prompt
-        );
+        let formatted_prompt = self.template.format(&system_prompt, prompt);
 
         // Encode tokens
         let encoding = self
@@ -126,13 +320,15 @@ impl LocalSpren {
             Some(temperature as f64)
         };
         let mut logits_processor = LogitsProcessor::new(299792458, temp, None);
-
-        // Qwen2.5 special tokens
-        const EOS_TOKEN: u32 = 151643; // <|endoftext|>
-        const EOT_TOKEN: u32 = 151645; // <|im_end|>
+        let mut stream = TokenStream::default();
 
         // Inference loop
         for i in 0..max_tokens {
+            // Partial output is never a safe command, so abort rather than return it
+            if self.cancel.swap(false, Ordering::Relaxed) {
+                return Err(SprenError::Cancelled.into());
+            }
+
             let context_size = if i == 0 { all_tokens.len() } else { 1 };
             let start_pos = all_tokens.len().saturating_sub(context_size);
             let context = &all_tokens[start_pos..];
@@ -144,45 +340,117 @@ impl LocalSpren {
             let next_token = logits_processor.sample(&logits)?;
 
             // Stop on End-of-Turn or End-of-Text tokens
-            if next_token == EOS_TOKEN || next_token == EOT_TOKEN {
+            if self.stop_ids.contains(&next_token) {
                 break;
             }
 
             all_tokens.push(next_token);
             output_tokens.push(next_token);
+
+            if !stop.is_empty() {
+                let text = self.decode(&output_tokens)?;
+                if let Some(end) = find_stop(&text, stop) {
+                    self.cancel.store(false, Ordering::Relaxed);
+                    return Ok(Generation {
+                        text: text[..end].trim().to_string(),
+                        truncated: false,
+                    });
+                }
+            }
+
+            if let Some(text) = stream.next(&output_tokens, |tokens| self.decode(tokens))? {
+                on_text(&text);
+            }
         }
+        // Running out of tokens rather than stopping means the reply is cut off
+        let truncated = output_tokens.len() >= max_tokens as usize;
+
+        // Drop an interrupt that arrived after the last token
+        self.cancel.store(false, Ordering::Relaxed);
 
         // Decode output tokens
-        let result = self
-            .tokenizer
-            .decode(&output_tokens, true)
-            .map_err(|e| anyhow!("Decoding failed: {}", e))?;
+        let result = self.decode(&output_tokens)?;
 
         // Clean up the result
-        let clean_result = result
-            .trim()
-            .replace("<|im_end|>", "")
-            .replace("<|endoftext|>", "")
+        let clean_result = self
+            .stop_tokens
+            .iter()
+            .fold(result, |text, token| text.replace(token.as_str(), ""))
             .trim()
             .to_string();
 
-        Ok(clean_result)
+        Ok(Generation {
+            text: clean_result,
+            truncated,
+        })
     }
 
-    /// Generate a command suggestion (convenience wrapper)
-    pub fn get_command(&mut self, query: &str) -> Result<(String, bool)> {
-        let response = self.generate(query, 100, 0.1)?;
-        parse_response(&response)
+    fn decode(&self, tokens: &[u32]) -> Result<String> {
+        self.tokenizer
+            .decode(tokens, true)
+            .map_err(|e| anyhow!("Decoding failed: {}", e))
     }
 
-    /// Analyze an error (convenience wrapper)
-    pub fn analyze_error(&mut self, command: &str, stdout: &str, stderr: &str) -> Result<String> {
+    /// Analyze an error (convenience wrapper); `instruction` is appended to the prompt
+    pub fn analyze_error(
+        &mut self,
+        command: &str,
+        stdout: &str,
+        stderr: &str,
+        instruction: &str,
+    ) -> Result<String> {
         let prompt = format!(
-            "Command '{}' produced:\nOutput: {}\nError: {}\nExplain briefly.",
-            command, stdout, stderr
+            "Command '{}' produced:\nOutput: {}\nError: {}\nExplain briefly.{}",
+            command, stdout, stderr, instruction
         );
         self.generate(&prompt, 150, 0.3)
     }
+
+    /// Explain what a command does (convenience wrapper); `instruction` is appended to the prompt
+    pub fn explain_command(&mut self, command: &str, instruction: &str) -> Result<String> {
+        let prompt = format!("Explain what this command does: {}{}", command, instruction);
+        self.generate(&prompt, 150, 0.3)
+    }
+}
+
+/// Turns a growing list of tokens into text pieces as they become printable
+///
+/// A token can hold part of a multi-byte character, which decodes as U+FFFD
+/// until the rest arrives, and a tokenizer may join tokens differently than
+/// it decodes them alone. So the unsent tokens are decoded together, with
+/// those already sent in front for context, and only the new, complete
+/// tail is handed out.
+#[cfg(feature = "local")]
+#[derive(Default)]
+struct TokenStream {
+    /// Start of the tokens used as context for the next decode
+    prev: usize,
+    /// Tokens up to here have been handed out
+    sent: usize,
+}
+
+#[cfg(feature = "local")]
+impl TokenStream {
+    fn next(
+        &mut self,
+        tokens: &[u32],
+        decode: impl Fn(&[u32]) -> Result<String>,
+    ) -> Result<Option<String>> {
+        let sent_text = decode(&tokens[self.prev..self.sent])?;
+        let text = decode(&tokens[self.prev..])?;
+        if text.ends_with('\u{FFFD}') {
+            return Ok(None);
+        }
+        match text.get(sent_text.len()..).filter(|new| !new.is_empty()) {
+            Some(new) => {
+                let new = new.to_string();
+                self.prev = self.sent;
+                self.sent = tokens.len();
+                Ok(Some(new))
+            }
+            None => Ok(None),
+        }
+    }
 }
 
 /// Find model files in standard locations
@@ -205,12 +473,13 @@ fn find_model_files() -> Result<(PathBuf, PathBuf)> {
         .map(|p| format!("  - {}", p.display()))
         .collect();
 
-    Err(anyhow!(
+    Err(SprenError::NotConfigured(format!(
         "Could not find model files ({} and {})\n\nSearched in:\n{}\n\nPlease place the model files in one of these locations.",
         MODEL_FILENAME,
         TOKENIZER_FILENAME,
         paths_tried.join("\n")
     ))
+    .into())
 }
 
 /// Get list of paths to search for model files
@@ -252,60 +521,30 @@ fn get_search_paths() -> Vec<PathBuf> {
     paths
 }
 
-/// Parse the model response to extract command and danger flag
+/// Check that a tokenizer fits a model's embedding table
+///
+/// The model may have a few padding rows more than the tokenizer has tokens,
+/// but a tokenizer with more tokens produces out-of-range IDs, and one far
+/// smaller belongs to a different model family.
 #[cfg(feature = "local")]
-fn parse_response(response: &str) -> Result<(String, bool)> {
-    let response = response.trim();
-
-    // Check for dangerous flag
-    let is_dangerous = response.to_lowercase().contains("dangerous:true")
-        || response.to_lowercase().contains("dangerous: true");
-
-    // Extract command
-    let command = extract_command(response)?;
-
-    Ok((command, is_dangerous))
+fn check_vocab(tokenizer_vocab: usize, model_vocab: usize) -> Result<()> {
+    if tokenizer_vocab > model_vocab || model_vocab - tokenizer_vocab > MAX_VOCAB_PADDING {
+        return Err(SprenError::NotConfigured(format!(
+            "tokenizer/model mismatch: tokenizer has {} tokens, model expects {}",
+            tokenizer_vocab, model_vocab
+        ))
+        .into());
+    }
+    Ok(())
 }
 
-/// Extract the command from the response
+/// Byte offset of the earliest stop sequence in `text`
 #[cfg(feature = "local")]
-fn extract_command(response: &str) -> Result<String> {
-    // Try COMMAND: pattern first
-    for line in response.lines() {
-        let lower = line.to_lowercase();
-        if lower.starts_with("command:") {
-            let cmd = line[8..].trim();
-            if !cmd.is_empty() {
-                return Ok(cmd.to_string());
-            }
-        }
-    }
-
-    // Try to find command anywhere in line
-    for line in response.lines() {
-        if let Some(pos) = line.to_lowercase().find("command:") {
-            let cmd = line[pos + 8..].trim();
-            if !cmd.is_empty() {
-                return Ok(cmd.to_string());
-            }
-        }
-    }
-
-    // If only one line and looks like a command, use it
-    let lines: Vec<&str> = response.lines().collect();
-    if lines.len() == 1 {
-        return Ok(lines[0].trim().to_string());
-    }
-
-    // Last resort: find a line that looks like a shell command
-    for line in response.lines() {
-        let trimmed = line.trim();
-        if !trimmed.to_lowercase().starts_with("dangerous") && !trimmed.is_empty() {
-            return Ok(trimmed.to_string());
-        }
-    }
-
-    Err(anyhow!("Could not extract command from: {}", response))
+fn find_stop(text: &str, stop: &[String]) -> Option<usize> {
+    stop.iter()
+        .filter(|s| !s.is_empty())
+        .filter_map(|s| text.find(s.as_str()))
+        .min()
 }
 
 // ============================================================================
@@ -317,11 +556,16 @@ pub struct LocalSpren;
 
 #[cfg(not(feature = "local"))]
 impl LocalSpren {
-    pub fn load_default() -> anyhow::Result<Self> {
+    pub fn load_default(_template: ChatTemplate, _extra_stop: &[String]) -> anyhow::Result<Self> {
         anyhow::bail!("Local LLM support not compiled. Rebuild with: cargo build --features local")
     }
 
-    pub fn new(_model_path: &str, _tokenizer_path: &str) -> anyhow::Result<Self> {
+    pub fn new(
+        _model_path: &str,
+        _tokenizer_path: &str,
+        _template: ChatTemplate,
+        _extra_stop: &[String],
+    ) -> anyhow::Result<Self> {
         anyhow::bail!("Local LLM support not compiled. Rebuild with: cargo build --features local")
     }
 
@@ -334,16 +578,69 @@ impl LocalSpren {
         anyhow::bail!("Local LLM support not compiled")
     }
 
-    pub fn get_command(&mut self, _query: &str) -> anyhow::Result<(String, bool)> {
-        anyhow::bail!("Local LLM support not compiled")
-    }
-
     pub fn analyze_error(
         &mut self,
         _command: &str,
         _stdout: &str,
         _stderr: &str,
+        _instruction: &str,
     ) -> anyhow::Result<String> {
         anyhow::bail!("Local LLM support not compiled")
     }
+
+    pub fn explain_command(&mut self, _command: &str, _instruction: &str) -> anyhow::Result<String> {
+        anyhow::bail!("Local LLM support not compiled")
+    }
+}
+
+#[cfg(all(test, feature = "local"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_check_vocab() {
+        // Qwen2.5's own tokenizer against its padded embedding table
+        assert!(check_vocab(151_665, 151_936).is_ok());
+        assert!(check_vocab(151_936, 151_936).is_ok());
+
+        let err = check_vocab(152_064, 151_936).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "tokenizer/model mismatch: tokenizer has 152064 tokens, model expects 151936"
+        );
+        // A Llama tokenizer with a Qwen model
+        assert!(check_vocab(32_000, 151_936).is_err());
+    }
+
+    #[test]
+    fn test_token_stream_waits_for_whole_characters() {
+        // Byte-level tokens, as a BPE tokenizer may split "é" (0xC3 0xA9)
+        let decode = |tokens: &[u32]| -> Result<String> {
+            let bytes: Vec<u8> = tokens.iter().map(|&t| t as u8).collect();
+            Ok(String::from_utf8_lossy(&bytes).into_owned())
+        };
+        let tokens = [b'c', 0xC3, 0xA9, b' ', b'x'].map(u32::from);
+        let mut stream = TokenStream::default();
+        let pieces: Vec<Option<String>> =
+            (1..=tokens.len()).map(|n| stream.next(&tokens[..n], decode).unwrap()).collect();
+        assert_eq!(
+            pieces,
+            [Some("c".into()), None, Some("é".into()), Some(" ".into()), Some("x".into())]
+        );
+    }
+
+    #[test]
+    fn test_chat_template_resolution() {
+        let llama3 = "{% for m in messages %}<|start_header_id|>{{ m.role }}<|end_header_id|>";
+        assert_eq!(ChatTemplate::Auto.resolve(Some(llama3), "llama"), ChatTemplate::Llama3);
+        assert_eq!(ChatTemplate::Auto.resolve(Some("<|im_start|>"), "qwen2"), ChatTemplate::Chatml);
+        assert_eq!(ChatTemplate::Auto.resolve(None, "phi3"), ChatTemplate::Phi3);
+        assert_eq!(ChatTemplate::Auto.resolve(None, "qwen2"), ChatTemplate::Chatml);
+        // An explicit setting wins over the metadata
+        assert_eq!(ChatTemplate::Chatml.resolve(Some(llama3), "llama"), ChatTemplate::Chatml);
+
+        let prompt = ChatTemplate::Phi3.format("Be brief.", "list files");
+        assert_eq!(prompt, "<|system|>\nBe brief.<|end|>\n<|user|>\nlist files<|end|>\n<|assistant|>\n");
+        assert!(ChatTemplate::Llama3.format("s", "u").ends_with("assistant<|end_header_id|>\n\n"));
+    }
 }
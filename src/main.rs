@@ -1,17 +1,36 @@
 use anyhow::Result;
-use clap::Parser;
+use clap::{Parser, Subcommand};
 use colored::*;
-use std::io::{self, Write};
+use danger::DangerLevel;
+use rustyline::error::ReadlineError;
+use rustyline::DefaultEditor;
+use std::io::{self, IsTerminal, Read, Write};
+use std::path::PathBuf;
 use std::time::Instant;
 
 mod ai;
+mod audit;
 mod config;
-#[cfg(feature = "local")]
 mod context;
+mod danger;
+mod error;
 mod executor;
+mod history;
+mod input;
 #[cfg(feature = "local")]
 mod local_llm;
+mod pins;
+#[cfg(feature = "local")]
+mod pool;
+mod preview;
+mod pretty;
+mod recall;
+mod review;
+mod secrets;
+mod server;
 mod shell;
+mod stats;
+mod transcript;
 #[cfg(feature = "tui")]
 mod tui;
 
@@ -25,36 +44,331 @@ struct Args {
     /// Single query mode (non-interactive)
     #[arg(short, long)]
     query: Option<String>,
+
+    /// Attach a screenshot (PNG, JPEG, WebP, GIF) to the query; needs an OpenAI or Gemini vision model
+    #[arg(long, value_name = "PATH", requires = "query")]
+    image: Option<PathBuf>,
+
+    /// Print a step-by-step plan for the query in plain language, then offer
+    /// to turn each step into a command
+    #[arg(long, requires = "query", conflicts_with = "image")]
+    plan: bool,
+
+    /// Execute without asking for confirmation (dangerous commands still prompt)
+    #[arg(short, long)]
+    yes: bool,
+
+    /// Preview in-place file edits (sed -i, awk -i inplace) as a diff before confirming
+    #[arg(long)]
+    diff: bool,
+
+    /// Write suggestions to FILE for human review instead of executing
+    /// (queries from --query, or one per line on stdin)
+    #[arg(long, value_name = "FILE")]
+    review_out: Option<PathBuf>,
+
+    /// Pipe each suggested command to CMD (e.g. "shellcheck -s bash -") and only
+    /// offer to run it if CMD exits zero
+    #[arg(long, value_name = "CMD")]
+    review_with: Option<String>,
+
+    /// Suggest and explain only; never execute anything (security.disable_execution)
+    #[arg(long)]
+    no_exec: bool,
+
+    /// Show the suggested command and stop: no execute prompt, nothing runs
+    #[arg(long)]
+    dry_run: bool,
+
+    /// Allow commands that touch Spren's own config or model files (security.allow_self_modify)
+    #[arg(long)]
+    allow_self_modify: bool,
+
+    /// Send only the query, without directory or git context (ai.include_context)
+    #[arg(long)]
+    no_context: bool,
+
+    /// Put a one-line explanation comment above each command in exported
+    /// scripts and review files (display.annotate_exports)
+    #[arg(long)]
+    annotate: bool,
+
+    /// Re-run the last executed command exactly as it ran, without asking the AI
+    #[arg(long)]
+    rerun_last: bool,
+
+    /// Explain why CMD failed, reading its output from stdin
+    /// (e.g. `make 2>&1 | spren --analyze-error make`); never executes anything
+    #[arg(long, value_name = "CMD", conflicts_with_all = ["query", "tui", "rerun_last"])]
+    analyze_error: Option<String>,
+
+    /// Explain what COMMAND does in plain English; never executes anything
+    #[arg(long, value_name = "COMMAND", conflicts_with_all = ["query", "tui", "rerun_last", "analyze_error"])]
+    explain: Option<String>,
+
+    /// Load ~/.config/spren/profiles/<name>.toml instead of config.toml (or set SPREN_PROFILE)
+    #[arg(long, value_name = "NAME")]
+    profile: Option<String>,
+
+    /// Use this provider for this run (ai.provider); its default model unless --model is given
+    #[arg(long, value_name = "PROVIDER")]
+    provider: Option<config::AIProvider>,
+
+    /// Use this model for this run (ai.model)
+    #[arg(long, value_name = "NAME")]
+    model: Option<String>,
+
+    #[command(subcommand)]
+    command: Option<Commands>,
+}
+
+#[derive(Subcommand)]
+enum Commands {
+    /// Serve suggestions over HTTP for editor integrations (never executes)
+    Serve {
+        /// Port to listen on
+        #[arg(long, default_value_t = 7878)]
+        port: u16,
+
+        /// Address to bind to (localhost only by default)
+        #[arg(long, default_value = "127.0.0.1")]
+        host: String,
+    },
+
+    /// Re-check executed commands from a transcript against the current danger rules
+    Audit {
+        /// Transcript to read (defaults to ~/.config/spren/transcript.jsonl)
+        transcript: Option<PathBuf>,
+    },
+
+    /// Run a named workflow from the [workflows] config section, step by step
+    Run {
+        /// Workflow name
+        name: String,
+    },
+
+    /// Ask every cloud provider with an API key for the same command (never executes)
+    Compare {
+        /// The request to send to each provider
+        #[arg(short, long)]
+        query: String,
+    },
+
+    /// Summarize your most common queries and commands from local logs
+    Stats {
+        /// How many queries and commands to list
+        #[arg(long, default_value_t = 10)]
+        top: usize,
+    },
+
+    /// Manage Spren's query history
+    History {
+        #[command(subcommand)]
+        action: Option<HistoryAction>,
+        /// Only list queries asked in this directory or below it
+        #[arg(long = "in", value_name = "PATH")]
+        in_dir: Option<PathBuf>,
+    },
+
+    /// Manage named config profiles
+    Profile {
+        #[command(subcommand)]
+        action: ProfileAction,
+    },
+}
+
+#[derive(Subcommand)]
+enum HistoryAction {
+    /// Seed history from your shell's history file (bash, zsh, fish, PowerShell)
+    Import,
+}
+
+#[derive(Subcommand)]
+enum ProfileAction {
+    /// List profiles in ~/.config/spren/profiles
+    List,
+}
+
+/// Per-invocation options that affect how a query is confirmed and executed
+#[derive(Debug, Default, Clone)]
+struct QueryOptions {
+    /// Auto-confirm non-dangerous commands
+    yes: bool,
+    /// Show a diff of in-place file edits before confirming
+    diff: bool,
+    /// External reviewer that must approve a command before the execute prompt
+    review_with: Option<String>,
+    /// Screenshot sent along with the query (`--image`)
+    image: Option<ai::Image>,
+    /// Stop after showing the suggested command (`--dry-run`)
+    dry_run: bool,
 }
 
 #[tokio::main]
-async fn main() -> Result<()> {
+async fn main() {
     let args = Args::parse();
-    let config = load_or_default_config();
 
-    // Single query mode
-    if let Some(query) = args.query {
-        return process_query(&query, &config).await;
+    match run(args).await {
+        Ok(code) => std::process::exit(code),
+        Err(e) => {
+            // Setup problems get a distinct exit code so scripts can detect them
+            eprintln!("{}: {}", "Error".red().bold(), e);
+            std::process::exit(error::exit_code(&e));
+        }
+    }
+}
+
+/// Dispatch to the selected mode and return the process exit code
+async fn run(args: Args) -> Result<i32> {
+    let profile = args
+        .profile
+        .clone()
+        .or_else(|| std::env::var("SPREN_PROFILE").ok())
+        .filter(|p| !p.is_empty());
+
+    // Listing must work even when the selected profile is missing
+    if let Some(Commands::Profile { action: ProfileAction::List }) = args.command {
+        print_profiles(profile.as_deref())?;
+        return Ok(0);
+    }
+
+    let mut config = load_or_default_config(profile.as_deref())?;
+    if args.no_exec {
+        config.security.disable_execution = true;
+    }
+    if args.allow_self_modify {
+        config.security.allow_self_modify = true;
+    }
+    if args.no_context {
+        config.ai.include_context = false;
+    }
+    if args.annotate {
+        config.display.annotate_exports = true;
+    }
+    // Like the flags above, these only last for this run and are never saved
+    if let Some(provider) = &args.provider {
+        config = ai::provider_config(&config, provider);
+    }
+    if let Some(model) = &args.model {
+        config.ai.model = model.clone();
+    }
+    if let Some((model, limit)) = ai::output_token_clamp(&config) {
+        eprintln!(
+            "{} {} produces at most {} output tokens; using that instead of the configured {}",
+            "Note:".yellow().bold(),
+            model,
+            limit,
+            config.ai.output_tokens()
+        );
+    }
+
+    // Ctrl+C stops a slow local generation instead of killing Spren. Otherwise
+    // it stops Spren and the captured command, which has its own process group.
+    let _ = ctrlc::set_handler(|| {
+        #[cfg(feature = "local")]
+        if ai::interrupt_local_generation() {
+            return;
+        }
+        executor::interrupt_running_command();
+        std::process::exit(error::EX_INTERRUPTED);
+    });
+
+    let opts = QueryOptions {
+        yes: args.yes,
+        diff: args.diff,
+        review_with: args.review_with,
+        image: args.image.as_deref().map(ai::Image::load).transpose()?,
+        dry_run: args.dry_run,
+    };
+
+    if let Some(command) = args.command {
+        match command {
+            Commands::Serve { port, host } => server::serve(config, &host, port).await?,
+            Commands::Audit { transcript } => {
+                let path = match transcript {
+                    Some(path) => path,
+                    None => transcript::transcript_path()?,
+                };
+                print_audit(&path, &config)?;
+            }
+            Commands::History { action: Some(HistoryAction::Import), .. } => {
+                let (shell, added) = history::import_shell_history(config.shell.history_size)?;
+                println!("Imported {} new entries from {} history", added, shell.name());
+            }
+            Commands::History { action: None, in_dir } => print_history(in_dir.as_deref())?,
+            Commands::Run { name } => return run_workflow(&name, &config, &opts).await,
+            Commands::Compare { query } => compare_providers(&query, &config).await?,
+            Commands::Stats { top } => print_stats(top)?,
+            Commands::Profile { .. } => unreachable!("handled before loading config"),
+        }
+        return Ok(0);
+    }
+
+    // Review mode never executes anything
+    if let Some(path) = &args.review_out {
+        return run_review(path, args.query.as_deref(), &config).await;
+    }
+
+    if args.rerun_last {
+        return rerun_last(&config, &opts).await;
+    }
+
+    if let Some(command) = args.analyze_error.as_deref() {
+        return analyze_error(command, &config).await;
+    }
+
+    if let Some(command) = args.explain.as_deref() {
+        return explain_command(command, &config, &opts).await;
+    }
+
+    // `echo "list big files" | spren` reads the query from stdin
+    let query = match args.query {
+        Some(query) => Some(query),
+        None if !args.tui && !io::stdin().is_terminal() => Some(read_piped_query()?),
+        None => None,
+    };
+
+    if let Some(task) = query.as_deref().filter(|_| args.plan) {
+        return run_plan(task, &config, &opts).await;
+    }
+
+    // Single query mode: exit with the executed command's status
+    if let Some(query) = query {
+        let steps = input::split_queries(&query, config.ai.multiline_mode);
+        if steps.len() > 1 {
+            let mut recent = recall::RecentOutputs::default();
+            return run_steps(&steps, false, &config, &opts, &mut recent).await;
+        }
+        let code = process_query(&query, &config, &opts, None).await?;
+        return Ok(code.unwrap_or(0));
     }
 
     // TUI mode
     if args.tui {
+        // Terminals without raw mode or an alternate screen get the plain REPL instead
         #[cfg(feature = "tui")]
-        return run_tui(config).await;
+        match run_tui(config.clone(), opts.dry_run).await {
+            Ok(()) => return Ok(0),
+            Err(e) if e.downcast_ref::<tui::TerminalUnsupported>().is_some() => {
+                eprintln!("{} {}; falling back to the REPL", "Note:".yellow().bold(), e);
+            }
+            Err(e) => return Err(e),
+        }
 
         #[cfg(not(feature = "tui"))]
         {
             eprintln!("TUI mode not available. Rebuild with: cargo build --features tui");
-            return Ok(());
+            return Ok(0);
         }
     }
 
     // Default: simple REPL mode
-    run_repl(config).await
+    run_repl(config, opts).await?;
+    Ok(0)
 }
 
 /// Run the simple REPL interface
-async fn run_repl(config: config::Config) -> Result<()> {
+async fn run_repl(mut config: config::Config, opts: QueryOptions) -> Result<()> {
     let shell_type = shell::ShellType::detect();
 
     println!("{}", "Spren - Your AI Shell Assistant".green().bold());
@@ -68,15 +382,38 @@ async fn run_repl(config: config::Config) -> Result<()> {
     #[cfg(feature = "tui")]
     println!("Tip: Run with {} for interactive mode", "--tui".cyan());
 
-    println!("Type 'exit' to quit\n");
+    println!("Type 'exit' to quit, ':pin NAME COMMAND' to pin a command\n");
 
-    loop {
-        print!("spren> ");
-        io::stdout().flush()?;
+    let history_path = history::history_path().ok();
+    let mut recent = recall::RecentOutputs::default();
 
-        let mut query = String::new();
-        io::stdin().read_line(&mut query)?;
-        let query = query.trim();
+    // Readline editing, with earlier queries available from the history file
+    let mut editor = DefaultEditor::new()?;
+    if let Some(path) = &history_path {
+        for entry in history::load_recent(path, config.shell.history_size).unwrap_or_default() {
+            let _ = editor.add_history_entry(entry);
+        }
+    }
+
+    let mut pending = input::QueryBuffer::default();
+
+    loop {
+        let prompt = if pending.is_pending() { "  ...> " } else { "spren> " };
+        let line = match editor.readline(prompt) {
+            Ok(line) => line,
+            // Ctrl+C abandons the query being typed; Ctrl+D leaves
+            Err(ReadlineError::Interrupted) => {
+                pending.clear();
+                continue;
+            }
+            Err(ReadlineError::Eof) => break,
+            Err(e) => return Err(e.into()),
+        };
+        // A trailing `\` or an opening `"""` continues the query on the next line
+        let Some(line) = pending.push(&line) else {
+            continue;
+        };
+        let query = line.trim();
 
         if query.is_empty() {
             continue;
@@ -86,171 +423,951 @@ async fn run_repl(config: config::Config) -> Result<()> {
             break;
         }
 
-        match process_query(query, &config).await {
+        let _ = editor.add_history_entry(query);
+        if query == ":pin" || query.starts_with(":pin ") {
+            if let Err(e) = pin_command(&query[4..], &mut config) {
+                eprintln!("{}: {}", "Error".red().bold(), e);
+            }
+            continue;
+        }
+        if let Some(path) = &history_path {
+            // History is a convenience; never fail a query over it
+            let _ = history::append(path, query);
+        }
+
+        // Leading `--temp`/`--max-tokens` modifiers apply to this query only
+        let (overrides, query) = match config::QueryOverrides::parse(query) {
+            Ok(parsed) => parsed,
+            Err(e) => {
+                eprintln!("{}: {}", "Error".red().bold(), e);
+                continue;
+            }
+        };
+        if query.is_empty() {
+            continue;
+        }
+        let query_config = if overrides.is_empty() {
+            None
+        } else {
+            Some(overrides.apply(&config))
+        };
+        let query_config = query_config.as_ref().unwrap_or(&config);
+
+        // In sequence mode, a pasted block split on blank lines runs as steps
+        let steps = input::split_queries(&query, query_config.ai.multiline_mode);
+        if steps.len() > 1 {
+            match run_steps(&steps, false, query_config, &opts, &mut recent).await {
+                Ok(_) => {}
+                Err(e) if error::exit_code(&e) == error::EX_INTERRUPTED => {
+                    println!("{}", "Cancelled.".dimmed())
+                }
+                Err(e) => eprintln!("{}: {}", "Error".red().bold(), e),
+            }
+            continue;
+        }
+
+        // `@last`, `@last2`, ... pull in earlier command output
+        let resolved = if query_config.ai.feed_output_to_context {
+            recent.resolve_with_last_result(&query)
+        } else {
+            recent.resolve(&query)
+        };
+        let query = match resolved {
+            Ok(query) => query,
+            Err(e) => {
+                eprintln!("{}: {}", "Error".red().bold(), e);
+                continue;
+            }
+        };
+        match process_query(&query, query_config, &opts, Some(&mut recent)).await {
             Ok(_) => continue,
+            Err(e) if error::exit_code(&e) == error::EX_INTERRUPTED => {
+                println!("{}", "Cancelled.".dimmed())
+            }
             Err(e) => eprintln!("{}: {}", "Error".red().bold(), e),
         }
     }
 
+    if let Some(path) = &history_path {
+        let _ = history::compact(path, config.shell.history_size);
+    }
+    config.save_changes()
+}
+
+/// Add a `[pins]` entry from `:pin NAME COMMAND` in the REPL
+fn pin_command(args: &str, config: &mut config::Config) -> Result<()> {
+    let Some((name, command)) = args.trim().split_once(char::is_whitespace) else {
+        return Err(anyhow::anyhow!("Usage: :pin NAME COMMAND"));
+    };
+    let command = command.trim();
+    let saved = config.change(|c| {
+        c.pins.insert(name.to_string(), command.to_string());
+    })?;
+    let when = if saved { "" } else { " (saved on exit)" };
+    println!("Pinned {} as '{}'{}", command.cyan(), name, when);
     Ok(())
 }
 
 /// Run the interactive TUI
 #[cfg(feature = "tui")]
-async fn run_tui(config: config::Config) -> Result<()> {
-    use crossterm::event::{Event, KeyCode, KeyEventKind};
+async fn run_tui(config: config::Config, dry_run: bool) -> Result<()> {
+    use crossterm::event::{Event, KeyCode, KeyEventKind, KeyModifiers};
+
+    tui::ensure_terminal()?;
+
+    // Offer to pick up where an interrupted session left off (asked before raw mode)
+    let resumed = match tui::take_session() {
+        Some(session) => {
+            let summary = session.command.as_deref().unwrap_or(&session.input);
+            println!("{} {}", "Previous session:".blue().bold(), summary);
+            let resume = confirm("Resume previous session? [y/N] ", false, &config, &QueryOptions::default())?;
+            resume.then_some(session)
+        }
+        None => None,
+    };
 
     let mut terminal = tui::init_terminal()?;
     let mut app = tui::App::new();
+    if let Some(session) = resumed {
+        let level = session
+            .command
+            .as_deref()
+            .map(|cmd| danger::classify(cmd, false, &config.security))
+            .unwrap_or_default();
+        app.restore_session(session, level);
+    }
+    app.dry_run = dry_run;
+    let history_path = history::history_path().ok();
+    if let Some(path) = &history_path {
+        app.history = history::load_recent(path, config.shell.history_size).unwrap_or_default();
+    }
+
+    // At most one query is in flight; its suggestion fills the single command pane
+    let mut in_flight: Option<InFlight> = None;
 
     loop {
+        if let Some(flight) = in_flight.as_mut() {
+            drain_stream(&mut app, flight);
+            app.tick();
+        }
+        if let Some(flight) = in_flight.take_if(|flight| flight.task.is_finished()) {
+            finish_query_in_tui(&mut app, flight).await;
+        }
+
         // Draw UI
         terminal.draw(|f| tui::draw(f, &app))?;
 
         // Handle events
-        if let Some(event) = tui::poll_event(100)? {
-            if let Event::Key(key) = event {
-                if key.kind != KeyEventKind::Press {
-                    continue;
-                }
+        if let Some(Event::Key(key)) = tui::poll_event(100)? {
+            if key.kind != KeyEventKind::Press {
+                continue;
+            }
 
-                match key.code {
-                    KeyCode::Enter if !app.edit_mode => {
-                        if app.command.is_some() {
-                            // We have a command, this is confirmation
-                            // Do nothing here, 'y' handles execution
-                        } else if !app.input.is_empty() {
-                            // Get command from AI
-                            app.loading = true;
-                            app.status = "Thinking...".to_string();
-                            terminal.draw(|f| tui::draw(f, &app))?;
-
-                            match ai::get_command_suggestion(&app.input, &config).await {
-                                Ok((cmd, dangerous)) => {
-                                    app.set_command(cmd, dangerous);
-                                }
-                                Err(e) => {
-                                    app.status = format!("Error: {}", e);
-                                }
-                            }
-                            app.loading = false;
-                        }
+            match key.code {
+                // Raw mode swallows SIGINT, so Ctrl+C (or Esc) cancels the query here
+                KeyCode::Esc | KeyCode::Char('c')
+                    if in_flight.is_some()
+                        && (key.code == KeyCode::Esc || key.modifiers.contains(KeyModifiers::CONTROL)) =>
+                {
+                    if let Some(flight) = in_flight.take() {
+                        cancel_query_in_tui(&mut app, flight);
                     }
-                    KeyCode::Char('y') | KeyCode::Char('Y') if app.command.is_some() && !app.edit_mode => {
-                        // Execute command - clone to avoid borrow issues
+                }
+                // Nothing else happens until the query in flight finishes
+                _ if in_flight.is_some() => {}
+                KeyCode::Enter if app.confirm_token.is_some() => {
+                    // Retyped token for a catastrophic command
+                    if app.token_matches() {
                         let cmd = app.get_command().map(|s| s.to_string());
                         if let Some(cmd) = cmd {
-                            app.status = "Executing...".to_string();
-                            terminal.draw(|f| tui::draw(f, &app))?;
-
-                            match executor::execute_command(&cmd).await {
-                                Ok(output) => {
-                                    let mut result = String::new();
-                                    if !output.stdout.is_empty() {
-                                        result.push_str(&output.stdout);
+                            execute_in_tui(&mut app, &mut terminal, &cmd, &config).await?;
+                        }
+                    } else {
+                        app.cancel_token_confirm();
+                        app.status = "Token did not match. Command not executed.".to_string();
+                    }
+                }
+                KeyCode::Enter if !app.edit_mode => {
+                    if app.command.is_some() {
+                        // We have a command, this is confirmation
+                        // Do nothing here, 'y' handles execution
+                    } else if app.input.trim().is_empty() {
+                        app.status = EMPTY_QUERY.to_string();
+                    } else {
+                        if let Some(path) = &history_path {
+                            // History is a convenience; never fail a query over it
+                            let _ = history::append(path, &app.input);
+                        }
+
+                        // Leading `--temp`/`--max-tokens` modifiers apply to this query only
+                        match config::QueryOverrides::parse(&app.input) {
+                            Ok((_, query)) if query.is_empty() => {
+                                app.status = EMPTY_QUERY.to_string();
+                            }
+                            Ok((overrides, query)) => {
+                                let mut query_config = overrides.apply(&config);
+                                match ai::sensitive_query(&query, &query_config) {
+                                    // A second Enter on the same input sends it anyway
+                                    Some(kind)
+                                        if app.sensitive_confirmed.as_deref()
+                                            != Some(app.input.as_str()) =>
+                                    {
+                                        app.sensitive_confirmed = Some(app.input.clone());
+                                        app.status = format!(
+                                            "Your query appears to contain {}. \
+                                             Press Enter again to send it to {} anyway",
+                                            kind,
+                                            query_config.ai.provider.name()
+                                        );
                                     }
-                                    if !output.stderr.is_empty() {
-                                        if !result.is_empty() {
-                                            result.push_str("\n");
+                                    sensitive => {
+                                        if sensitive.is_some() {
+                                            query_config.security.confirm_sensitive_queries = false;
                                         }
-                                        if output.success {
-                                            result.push_str(&format!("Note: {}", output.stderr));
-                                        } else {
-                                            result.push_str(&format!("Error: {}", output.stderr));
-                                        }
-                                    }
-                                    if result.is_empty() {
-                                        result = "Command completed successfully".to_string();
+                                        in_flight = Some(start_query_in_tui(&mut app, query, query_config));
                                     }
-                                    app.set_output(result);
-                                    app.status = "Done. Enter new query or Ctrl+C to quit".to_string();
-                                }
-                                Err(e) => {
-                                    app.set_output(format!("Execution error: {}", e));
-                                    app.status = "Command failed".to_string();
                                 }
                             }
-                            app.clear_for_new_query();
+                            Err(e) => {
+                                app.status = format!("Error: {}", e);
+                            }
                         }
                     }
-                    KeyCode::Char('n') | KeyCode::Char('N') if app.command.is_some() && !app.edit_mode => {
-                        // Cancel command
-                        app.clear_for_new_query();
-                        app.status = "Cancelled. Enter new query.".to_string();
-                    }
-                    _ => {
-                        app.handle_key(key.code, key.modifiers);
-                    }
                 }
+                KeyCode::Char('y') | KeyCode::Char('Y')
+                    if app.command.is_some() && !app.edit_mode && app.confirm_token.is_none() =>
+                {
+                    // Execute command - clone to avoid borrow issues
+                    let cmd = app.get_command().map(|s| s.to_string());
+                    if app.dry_run {
+                        app.status = DRY_RUN.to_string();
+                    } else if config.security.disable_execution {
+                        app.status = EXECUTION_DISABLED.to_string();
+                    } else if let Some(cmd) = cmd {
+                        // Re-classify in case the command was edited
+                        let level = danger::classify(&cmd, false, &config.security)
+                            .max(app.danger_level);
 
-                if app.should_quit {
-                    break;
+                        if config.security.confirm_network_commands
+                            && !app.network_confirmed
+                            && !danger::is_trusted(&cmd, &config.security)
+                            && danger::uses_network(&cmd)
+                        {
+                            // First 'y' acknowledges network access, the second runs it
+                            app.network_confirmed = true;
+                            app.status = "This command accesses the network. \
+                                Press 'y' again to allow, 'n' to cancel"
+                                .to_string();
+                        } else if danger::required_confirmation(
+                            &cmd,
+                            level,
+                            &config.security,
+                            false,
+                        ) == danger::Confirmation::Token
+                        {
+                            app.start_token_confirm(danger::confirmation_token());
+                        } else {
+                            execute_in_tui(&mut app, &mut terminal, &cmd, &config).await?;
+                        }
+                    }
+                }
+                KeyCode::Char('n') | KeyCode::Char('N')
+                    if app.command.is_some() && !app.edit_mode && app.confirm_token.is_none() =>
+                {
+                    // Cancel command
+                    app.clear_for_new_query();
+                    app.status = "Cancelled. Enter new query.".to_string();
+                }
+                // Explain each command before the export writes the script
+                KeyCode::Char('s')
+                    if key.modifiers.contains(KeyModifiers::CONTROL)
+                        && config.display.annotate_exports =>
+                {
+                    annotate_turns(&mut app, &mut terminal, &config).await?;
+                    app.handle_key(key.code, key.modifiers);
+                }
+                KeyCode::Tab | KeyCode::Enter if app.edit_mode => {
+                    confirm_edit_in_tui(&mut app, key.code, &config);
                 }
+                _ => {
+                    app.handle_key(key.code, key.modifiers);
+                }
+            }
+
+            if app.should_quit {
+                break;
             }
         }
     }
 
     tui::restore_terminal(&mut terminal)?;
+
+    // Saving is a convenience; a failure shouldn't turn a clean exit into an error
+    let _ = tui::save_session(app.session());
+    if let Some(path) = &history_path {
+        let _ = history::compact(path, config.shell.history_size);
+    }
+    Ok(())
+}
+
+/// Accept an edited command, resolved the way a suggestion is, so the command
+/// pane shows exactly what `y` will run
+#[cfg(feature = "tui")]
+fn confirm_edit_in_tui(app: &mut tui::App, key: crossterm::event::KeyCode, config: &config::Config) {
+    let (cmd, sudo_stripped) = match danger::apply_sudo_policy(&app.edited_command, &config.security) {
+        Ok(resolved) => resolved,
+        Err(e) => {
+            // Stay in edit mode so the command can be fixed
+            app.status = format!("Error: {}", e);
+            return;
+        }
+    };
+    app.edited_command = cmd;
+    app.handle_key(key, crossterm::event::KeyModifiers::NONE);
+    if let Some(cmd) = app.command.as_deref() {
+        app.danger_level = danger::classify(cmd, false, &config.security).max(app.danger_level);
+    }
+    if sudo_stripped {
+        app.status.push_str(" (removed leading sudo)");
+    }
+}
+
+/// Fill in the one-line notes of session commands that don't have one yet
+#[cfg(feature = "tui")]
+async fn annotate_turns(
+    app: &mut tui::App,
+    terminal: &mut ratatui::Terminal<ratatui::backend::CrosstermBackend<io::Stdout>>,
+    config: &config::Config,
+) -> Result<()> {
+    if app.turns.iter().all(|turn| turn.note.is_some()) {
+        return Ok(());
+    }
+    app.status = "Annotating commands...".to_string();
+    terminal.draw(|f| tui::draw(f, app))?;
+
+    for turn in app.turns.iter_mut().filter(|turn| turn.note.is_none()) {
+        // Commands the model can't explain are exported without a note
+        turn.note = ai::get_command_annotation(&turn.command, config).await.ok();
+    }
+    Ok(())
+}
+
+/// A TUI query whose AI call runs on its own task, so the UI keeps
+/// redrawing and can cancel it
+#[cfg(feature = "tui")]
+struct InFlight {
+    config: config::Config,
+    task: tokio::task::JoinHandle<TuiReply>,
+    /// Model output as it streams in
+    stream: tokio::sync::mpsc::UnboundedReceiver<String>,
+}
+
+/// What an in-flight TUI query produced
+#[cfg(feature = "tui")]
+enum TuiReply {
+    /// An explain-only query's answer
+    Answer(Result<String>),
+    Suggestion(Result<ai::Suggestion>, std::time::Duration),
+}
+
+/// Start asking the AI about `query` in the background
+#[cfg(feature = "tui")]
+fn start_query_in_tui(app: &mut tui::App, query: String, config: config::Config) -> InFlight {
+    app.loading = true;
+    app.status = "Thinking... (Esc to cancel)".to_string();
+    app.partial.clear();
+    app.partial_max = config.display.stream_preview_chars;
+
+    let (tx, stream) = tokio::sync::mpsc::unbounded_channel();
+    ai::set_stream_sink(Some(tx));
+    let task_config = config.clone();
+    let task = tokio::spawn(async move {
+        if task_config.display.is_explain_only(&query) {
+            TuiReply::Answer(explain_in_tui(&query, &task_config).await)
+        } else {
+            let start = std::time::Instant::now();
+            let suggestion = ai::get_command_suggestion(&query, &task_config).await;
+            TuiReply::Suggestion(suggestion, start.elapsed())
+        }
+    });
+
+    InFlight { config, task, stream }
+}
+
+/// Show the output streamed in so far
+#[cfg(feature = "tui")]
+fn drain_stream(app: &mut tui::App, flight: &mut InFlight) {
+    while let Ok(text) = flight.stream.try_recv() {
+        app.push_partial(&text);
+    }
+}
+
+/// Stop waiting for the in-flight query
+#[cfg(feature = "tui")]
+fn cancel_query_in_tui(app: &mut tui::App, flight: InFlight) {
+    // A local generation never yields to abort(), so stop it directly
+    #[cfg(feature = "local")]
+    ai::interrupt_local_generation();
+    flight.task.abort();
+    ai::set_stream_sink(None);
+    app.loading = false;
+    app.partial.clear();
+    app.status = "Cancelled. Enter new query.".to_string();
+}
+
+/// Stage the finished query's suggestion (or show its explanation)
+#[cfg(feature = "tui")]
+async fn finish_query_in_tui(app: &mut tui::App, flight: InFlight) {
+    ai::set_stream_sink(None);
+    app.loading = false;
+    app.partial.clear();
+    let config = &flight.config;
+
+    let reply = match flight.task.await {
+        Ok(reply) => reply,
+        Err(e) => {
+            app.status = format!("Error: {}", e);
+            return;
+        }
+    };
+    let (suggestion, latency) = match reply {
+        // Questions are answered in the output pane, never staged
+        TuiReply::Answer(Ok(answer)) => {
+            app.set_output(answer);
+            app.clear_for_new_query();
+            app.status = "Explain-only query: not executed".to_string();
+            return;
+        }
+        TuiReply::Answer(Err(e)) => {
+            app.status = format!("Error: {}", e);
+            return;
+        }
+        TuiReply::Suggestion(suggestion, latency) => (suggestion, latency),
+    };
+
+    let suggestion = suggestion.and_then(|s| {
+        danger::apply_sudo_policy(&s.command, &config.security).map(|(cmd, stripped)| (cmd, stripped, s))
+    });
+    match suggestion {
+        Ok((cmd, sudo_stripped, suggestion)) => {
+            let level = danger::classify(&cmd, suggestion.dangerous, &config.security);
+            let mut warnings: Vec<String> = if danger::is_trusted(&cmd, &config.security) {
+                Vec::new()
+            } else {
+                danger::resource_risk(&cmd)
+                    .map(|(_, reason)| reason.to_string())
+                    .into_iter()
+                    .chain(executor::stdin_reader(&cmd).map(stdin_warning))
+                    .chain(overwritten_files(&cmd).iter().map(|p| {
+                        format!("Warning: this will overwrite existing file {}", p.display())
+                    }))
+                    .collect()
+            };
+            if let Some(tokens) = suggestion.retried_with_tokens {
+                warnings.push(truncation_retry_notice(tokens));
+            }
+            if suggestion.truncated {
+                warnings.push(TRUNCATED_WARNING.to_string());
+            }
+            if let Some(provider) = &suggestion.escalated_to {
+                warnings.push(escalation_notice(provider, config));
+            }
+            warnings.extend(pin_note(&cmd, config));
+            if !warnings.is_empty() {
+                app.set_output(warnings.join("\n"));
+            }
+            app.set_command(cmd, level);
+            if config.display.show_rationale {
+                app.rationale = suggestion.explanation;
+            }
+            app.latency = Some(latency);
+            if config.display.show_provider {
+                app.answered_by = Some(suggestion.answered_by);
+            }
+            if sudo_stripped {
+                app.status.push_str(" (removed leading sudo)");
+            }
+        }
+        Err(e) => {
+            app.status = format!("Error: {}", e);
+        }
+    }
+}
+
+/// Build the output pane text for an explain-only query
+#[cfg(feature = "tui")]
+async fn explain_in_tui(query: &str, config: &config::Config) -> Result<String> {
+    let command = ai::get_command_suggestion(query, config).await?.command;
+    let explanation = ai::get_command_explanation(&command, config).await?;
+    Ok(format!("$ {}\n\n{}", command, explanation))
+}
+
+/// Execute a confirmed command and show its output in the TUI
+#[cfg(feature = "tui")]
+async fn execute_in_tui(
+    app: &mut tui::App,
+    terminal: &mut ratatui::Terminal<ratatui::backend::CrosstermBackend<io::Stdout>>,
+    cmd: &str,
+    config: &config::Config,
+) -> Result<()> {
+    let level = danger::classify(cmd, false, &config.security).max(app.danger_level);
+    if let Err(e) = danger::run_danger_check(cmd, config) {
+        app.status = format!("Error: {}", e);
+        return Ok(());
+    }
+
+    if executor::is_interactive(cmd) || executor::needs_tty(cmd) {
+        // Editors, pagers, REPLs and password prompts need the real terminal,
+        // not captured output
+        tui::restore_terminal(terminal)?;
+        let result = executor::execute_interactive(cmd, config).await;
+        tui::resume_terminal(terminal)?;
+        let exit_code = result.as_ref().ok().copied().flatten();
+        record_execution(config, &app.input, cmd, level, exit_code, app.latency);
+        if exit_code == Some(0) {
+            app.record_turn(cmd);
+        }
+
+        app.set_output(match result {
+            Ok(code) => format!(
+                "Interactive command exited with code {}",
+                code.map_or("unknown".to_string(), |c| c.to_string())
+            ),
+            Err(e) => format!("Error: {}", e),
+        });
+        app.clear_for_new_query();
+        return Ok(());
+    }
+
+    app.status = "Executing...".to_string();
+    terminal.draw(|f| tui::draw(f, app))?;
+
+    let result = executor::execute_command(cmd, config).await;
+    let exit_code = result.as_ref().ok().and_then(|output| output.exit_code);
+    record_execution(config, &app.input, cmd, level, exit_code, app.latency);
+
+    match result {
+        Ok(output) => {
+            if output.success {
+                app.record_turn(cmd);
+            }
+            if config.display.pretty_json && output.stderr.is_empty() {
+                if let Some(json) = pretty::parse_json(&output.stdout) {
+                    app.set_json_output(json);
+                    app.status = "Done. Enter new query or Ctrl+C to quit".to_string();
+                    app.clear_for_new_query();
+                    return Ok(());
+                }
+            }
+
+            let mut result = String::new();
+            if !output.stdout.is_empty() {
+                result.push_str(&output.stdout);
+            }
+            if !output.stderr.is_empty() {
+                if !result.is_empty() {
+                    result.push('\n');
+                }
+                if output.success {
+                    result.push_str(&format!("Note: {}", output.stderr));
+                } else {
+                    result.push_str(&format!("Error: {}", output.stderr));
+                }
+            }
+            if result.is_empty() {
+                result = "Command completed successfully".to_string();
+            }
+            app.set_output(result);
+            app.status = "Done. Enter new query or Ctrl+C to quit".to_string();
+        }
+        Err(e) => {
+            let label = if timed_out(&e) { "Error" } else { "Execution error" };
+            app.set_output(format!("{}: {}", label, e));
+            app.status = "Command failed".to_string();
+        }
+    }
+    app.clear_for_new_query();
+
+    Ok(())
+}
+
+/// Print the available profiles, marking the active one
+fn print_profiles(active: Option<&str>) -> Result<()> {
+    let profiles = config::list_profiles()?;
+    if profiles.is_empty() {
+        println!("No profiles in {}", config::get_profiles_dir()?.display());
+        return Ok(());
+    }
+
+    for name in profiles {
+        if Some(name.as_str()) == active {
+            println!("* {}", name.green());
+        } else {
+            println!("  {}", name);
+        }
+    }
     Ok(())
 }
 
 /// Load config from file, or return sensible defaults for zero-config operation
-fn load_or_default_config() -> config::Config {
+///
+/// A named profile must exist; only the default config falls back silently.
+fn load_or_default_config(profile: Option<&str>) -> Result<config::Config> {
+    if let Some(name) = profile {
+        let path = config::get_profile_path(name)?;
+        if !path.exists() {
+            return Err(error::SprenError::NotConfigured(format!(
+                "Profile '{}' not found at {}",
+                name,
+                path.display()
+            ))
+            .into());
+        }
+        return config::Config::load(&path)
+            .map_err(|e| anyhow::anyhow!("Invalid profile {}: {}", path.display(), e));
+    }
+
     // Try to load existing config
     if let Ok(config_path) = config::get_config_path() {
-        if config_path.exists() {
-            if let Ok(config) = config::Config::load(&config_path) {
-                return config;
+        if !config_path.exists() {
+            return Ok(config::Config::default_at(&config_path));
+        }
+        if let Ok(config) = config::Config::load(&config_path) {
+            return Ok(config);
+        }
+    }
+
+    // Return default config (local mode if compiled with local feature);
+    // an unreadable config file is never saved over
+    Ok(config::Config::default())
+}
+
+/// Print model output dimmed as it streams in (local model and llama.cpp),
+/// up to `display.stream_preview_chars`; off when stdout isn't a terminal
+fn start_stream_preview(config: &config::Config) -> Option<tokio::task::JoinHandle<bool>> {
+    let max = config.display.stream_preview_chars;
+    if max == 0 || !io::stdout().is_terminal() {
+        return None;
+    }
+
+    let (tx, mut stream) = tokio::sync::mpsc::unbounded_channel::<String>();
+    ai::set_stream_sink(Some(tx));
+    Some(tokio::spawn(async move {
+        let mut shown = 0;
+        while let Some(text) = stream.recv().await {
+            if shown > max {
+                continue;
+            }
+            let piece: String = text.chars().take(max - shown).collect();
+            shown += text.chars().count();
+            print!("{}", piece.dimmed());
+            if shown > max {
+                print!("{}", "…".dimmed());
             }
+            let _ = io::stdout().flush();
         }
+        shown > 0
+    }))
+}
+
+/// Stop the preview, ending its line if it printed anything
+async fn finish_stream_preview(preview: Option<tokio::task::JoinHandle<bool>>) {
+    let Some(preview) = preview else {
+        return;
+    };
+    // Dropping the sender lets the printer finish what is queued and stop
+    ai::set_stream_sink(None);
+    if preview.await.unwrap_or(false) {
+        println!();
+    }
+}
+
+/// Ask a yes/no question on stdin. `--yes` answers for non-dangerous commands.
+fn confirm(
+    prompt: &str,
+    is_dangerous: bool,
+    config: &config::Config,
+    opts: &QueryOptions,
+) -> Result<bool> {
+    if opts.yes && !is_dangerous {
+        return Ok(true);
     }
 
-    // Return default config (local mode if compiled with local feature)
-    config::Config::default()
+    print!("{}", prompt);
+    io::stdout().flush()?;
+
+    Ok(read_answer(config)?.trim().to_lowercase() == "y")
 }
 
-async fn process_query(query: &str, config: &config::Config) -> Result<()> {
+/// One line of stdin answering a prompt
+///
+/// Piped answers (`echo y | spren -q ...`) work like typed ones. Input that
+/// ends first is an empty answer, i.e. "no", unless `security.eof_confirmation`
+/// makes it an error.
+fn read_answer(config: &config::Config) -> Result<String> {
+    let mut response = String::new();
+    if io::stdin().read_line(&mut response)? == 0 {
+        println!();
+        if config.security.eof_confirmation == config::EofConfirmation::Error {
+            return Err(anyhow::anyhow!(
+                "Input ended before the prompt was answered (security.eof_confirmation)"
+            ));
+        }
+    }
+    Ok(response)
+}
+
+/// Ask before a query that looks like it contains a secret goes to a cloud provider
+///
+/// Returns the config to send it with, or None if the user declined.
+fn confirm_sensitive_query(
+    query: &str,
+    config: &config::Config,
+    opts: &QueryOptions,
+) -> Result<Option<config::Config>> {
+    let mut config = config.clone();
+    if let Some(kind) = ai::sensitive_query(query, &config) {
+        let prompt = format!(
+            "Your query appears to contain {}. Send it to {} anyway? [y/N] ",
+            kind,
+            config.ai.provider.name()
+        );
+        // Never auto-confirmed by --yes
+        if !confirm(&prompt.yellow().to_string(), true, &config, opts)? {
+            return Ok(None);
+        }
+        config.security.confirm_sensitive_queries = false;
+    }
+    Ok(Some(config))
+}
+
+/// Confirm a command with the friction its danger level calls for
+fn confirm_level(
+    prompt: &str,
+    command: &str,
+    level: DangerLevel,
+    config: &config::Config,
+    opts: &QueryOptions,
+) -> Result<bool> {
+    match danger::required_confirmation(command, level, &config.security, opts.yes) {
+        danger::Confirmation::Auto => Ok(true),
+        danger::Confirmation::Prompt => confirm(prompt, true, config, opts),
+        danger::Confirmation::Token => confirm_with_token(config),
+    }
+}
+
+/// The checks every command goes through right before it runs
+///
+/// Network access, `--review-with`, `security.danger_check_command` and the
+/// confirmation its danger level calls for. `command` must already have been
+/// through `danger::apply_sudo_policy`. Returns whether to run it.
+fn clear_to_run(
+    prompt: &str,
+    command: &str,
+    level: DangerLevel,
+    config: &config::Config,
+    opts: &QueryOptions,
+) -> Result<bool> {
+    // Network access is confirmed separately, and never auto-confirmed by --yes
+    if config.security.confirm_network_commands
+        && !danger::is_trusted(command, &config.security)
+        && danger::uses_network(command)
+    {
+        println!("\n{}", "This command accesses the network.".magenta().bold());
+        if !confirm("Allow network access? [y/N] ", true, config, opts)? {
+            return Ok(false);
+        }
+    }
+
+    if let Some(reviewer) = &opts.review_with {
+        review_with(reviewer, command, config)?;
+    }
+    danger::run_danger_check(command, config)?;
+
+    // Always ask for confirmation, with extra friction for catastrophic commands
+    confirm_level(prompt, command, level, config, opts)
+}
+
+/// Require the user to retype a random token before running a catastrophic command
+fn confirm_with_token(config: &config::Config) -> Result<bool> {
+    let token = danger::confirmation_token();
+    print!("\nType {} to execute (anything else cancels): ", token.bold());
+    io::stdout().flush()?;
+
+    let response = read_answer(config)?;
+
+    if response.trim() == token {
+        Ok(true)
+    } else {
+        println!("{}", "Token did not match. Command not executed.".yellow());
+        Ok(false)
+    }
+}
+
+/// Suggest, confirm and run a command for `query`.
+/// Returns the exit code of the last executed command (0 if nothing needed to run),
+/// or `None` if the user declined to run it.
+async fn process_query(
+    query: &str,
+    config: &config::Config,
+    opts: &QueryOptions,
+    mut recent: Option<&mut recall::RecentOutputs>,
+) -> Result<Option<i32>> {
+    let Some(config) = confirm_sensitive_query(query, config, opts)? else {
+        return Ok(None);
+    };
+    let config = &config;
+
+    // Questions get an answer, never an execute prompt
+    if config.display.is_explain_only(query) {
+        return explain_query(query, opts.image.as_ref(), config).await.map(Some);
+    }
+
     let start = Instant::now();
 
-    // Get command suggestion from AI
-    let (command, is_dangerous) = ai::get_command_suggestion(query, config).await?;
+    // Get command suggestion from AI, showing a streaming model's output as it arrives
+    let preview = start_stream_preview(config);
+    let suggestion = ai::get_image_command_suggestion(query, opts.image.as_ref(), config).await;
+    finish_stream_preview(preview).await;
+    let suggestion = suggestion?;
+    let (command, sudo_stripped) = danger::apply_sudo_policy(&suggestion.command, &config.security)?;
+    let trusted = danger::is_trusted(&command, &config.security);
+    let level = danger::classify(&command, suggestion.dangerous, &config.security);
+    let is_dangerous = level.is_dangerous();
 
     let inference_time = start.elapsed();
 
-    println!("\n{} {}", "Suggested command:".blue().bold(), format!("({:.0?})", inference_time).dimmed());
-    if is_dangerous {
-        println!("{} {}", command, "[DANGEROUS]".red().bold());
+    let mut timing = format!("({:.0?})", inference_time);
+    if config.display.show_provider {
+        timing.push_str(&format!(" via {}", suggestion.answered_by));
+    }
+    println!("\n{} {}", "Suggested command:".blue().bold(), timing.dimmed());
+    let shown = shell::format_command_for_display(&command, &shell::ShellType::detect());
+    if level == DangerLevel::Catastrophic {
+        println!("{} {}", shown, "[CATASTROPHIC]".red().bold());
+        println!("\n{}", "This command could destroy your system or data.".red().bold());
+    } else if is_dangerous {
+        println!("{} {}", shown, "[DANGEROUS]".red().bold());
         println!("\n{}", "This command has been identified as potentially dangerous.".yellow());
+    } else if level == DangerLevel::Caution {
+        println!("{} {}", shown, "[CAUTION]".yellow().bold());
+    } else if trusted {
+        println!("{} {}", shown, "[trusted]".dimmed());
     } else {
-        println!("{}", command);
+        println!("{}", shown);
+    }
+    if let Some((_, reason)) = danger::resource_risk(&command).filter(|_| !trusted) {
+        println!("{}", reason.yellow());
+    }
+    if let Some(program) = executor::stdin_reader(&command).filter(|_| !trusted) {
+        println!("{}", stdin_warning(program).yellow());
+    }
+    if let Some(tokens) = suggestion.retried_with_tokens {
+        println!("{}", truncation_retry_notice(tokens).dimmed());
+    }
+    if suggestion.truncated {
+        println!("{}", TRUNCATED_WARNING.yellow());
+    }
+    if let Some(provider) = &suggestion.escalated_to {
+        println!("{}", escalation_notice(provider, config).yellow());
+    }
+    if let Some(note) = pin_note(&command, config) {
+        println!("{}", note.cyan());
+    }
+    if config.display.show_rationale {
+        if let Some(explanation) = &suggestion.explanation {
+            println!("{}", explanation.dimmed());
+        }
+    }
+    if config.display.verbose_mode {
+        let via = format!("matched via: {}", suggestion.extraction.name());
+        if suggestion.extraction.is_fallback() {
+            println!("{}", via.yellow());
+        } else {
+            println!("{}", via.dimmed());
+        }
+    }
+    if sudo_stripped {
+        println!("{}", "Removed leading sudo (security.strip_sudo)".dimmed());
     }
 
-    // Always ask for confirmation
-    print!("\nExecute? [y/N] ");
-    io::stdout().flush()?;
+    if !trusted {
+        for path in overwritten_files(&command) {
+            println!(
+                "{}",
+                format!("Warning: this will overwrite existing file {}", path.display()).yellow()
+            );
+        }
+        if let Ok(cwd) = std::env::current_dir() {
+            danger::check_self_modify(&command, &cwd, config)?;
+        }
+    }
 
-    let mut response = String::new();
-    io::stdin().read_line(&mut response)?;
+    if opts.dry_run {
+        return Ok(None);
+    }
+    if config.security.disable_execution {
+        println!("\n{}", EXECUTION_DISABLED.yellow());
+        return Ok(None);
+    }
 
-    if response.trim().to_lowercase() != "y" {
-        return Ok(());
+    if opts.diff {
+        if let Some(diff) = preview::preview_file_changes(&command) {
+            println!("\n{}", "File changes preview:".blue().bold());
+            print_diff(&diff);
+        }
+    }
+
+    if !clear_to_run("\nExecute? [y/N] ", &command, level, config, opts)? {
+        return Ok(None);
+    }
+
+    // Interactive programs would hang with captured output, so give them the terminal
+    if executor::is_interactive(&command) {
+        println!("{}", "Interactive command: handing over the terminal".dimmed());
+        print_running(&command);
+        let code = executor::execute_interactive(&command, config).await?;
+        record_execution(config, query, &command, level, code, Some(inference_time));
+        return Ok(Some(code.unwrap_or(1)));
     }
 
     // Auto-fix loop: retry failed commands up to 3 times
     let mut current_command = command;
+    let mut current_level = level;
     let mut attempts = 0;
     const MAX_RETRIES: u32 = 3;
+    // Every command run so far, so a fix that repeats one can be caught
+    let mut tried: Vec<String> = Vec::new();
 
-    loop {
+    let exit_code = loop {
+        tried.push(current_command.clone());
+        print_running(&current_command);
         let exec_start = Instant::now();
-        match executor::execute_command(&current_command).await {
+        let result = executor::execute_command(&current_command, config).await;
+        let exit_code = result.as_ref().ok().and_then(|output| output.exit_code);
+        // Fixes are separate model calls, so only the first attempt records the suggestion latency
+        let latency = (attempts == 0).then_some(inference_time);
+        record_execution(config, query, &current_command, current_level, exit_code, latency);
+
+        match result {
             Ok(output) => {
                 println!("{}", format!("Execution time: {:?}", exec_start.elapsed()).dimmed());
+                if let Some(recent) = recent.as_deref_mut() {
+                    let combined = [output.stdout.as_str(), output.stderr.as_str()]
+                        .iter()
+                        .filter(|s| !s.is_empty())
+                        .copied()
+                        .collect::<Vec<_>>()
+                        .join("\n");
+                    recent.push(&current_command, combined);
+                }
 
                 if !output.stdout.is_empty() {
-                    println!("\n{}", output.stdout);
+                    println!("\n{}", display_stdout(&output.stdout, config));
                 }
 
+                let code = output.exit_code.unwrap_or(1);
+
                 if !output.stderr.is_empty() {
                     if output.success {
                         println!("{}: {}", "Note".yellow().bold(), output.stderr);
@@ -260,51 +1377,603 @@ async fn process_query(query: &str, config: &config::Config) -> Result<()> {
                         attempts += 1;
                         if attempts >= MAX_RETRIES {
                             println!("\n{}", "Max retries reached.".red());
-                            break;
+                            break code;
                         }
 
                         // Try to get a fixed command
-                        #[cfg(feature = "local")]
-                        {
-                            println!("\n{}", "Attempting to fix...".yellow());
-                            match ai::get_fix_command(
-                                &current_command,
-                                &output.stdout,
-                                &output.stderr,
-                                config
-                            ).await {
-                                Ok((fixed_cmd, is_dangerous)) => {
-                                    println!("{} {}", "Fixed command:".blue().bold(), &fixed_cmd);
-                                    if is_dangerous {
-                                        println!("{}", "[DANGEROUS]".red().bold());
+                        println!("\n{}", "Attempting to fix...".yellow());
+                        match ai::get_fix_command(
+                            query,
+                            &current_command,
+                            &output.stdout,
+                            &output.stderr,
+                            &tried,
+                            config
+                        ).await {
+                            Ok((fixed_cmd, _)) if ai::already_tried(&fixed_cmd, &tried) => {
+                                println!(
+                                    "{}",
+                                    format!(
+                                        "The fix repeats a command that already failed ({}); stopping.",
+                                        fixed_cmd.trim()
+                                    )
+                                    .red()
+                                );
+                            }
+                            Ok((fixed_cmd, is_dangerous)) => {
+                                // A fix is a new command: same policy, rules and confirmation tier
+                                let fixed_cmd = match danger::apply_sudo_policy(
+                                    &fixed_cmd,
+                                    &config.security,
+                                ) {
+                                    Ok((fixed_cmd, _)) => fixed_cmd,
+                                    Err(e) => {
+                                        println!("{}: {}", "Fix rejected".red(), e);
+                                        break code;
                                     }
+                                };
+                                let fix_level = danger::classify(
+                                    &fixed_cmd,
+                                    is_dangerous,
+                                    &config.security,
+                                );
 
-                                    print!("Try fixed command? [y/N] ");
-                                    io::stdout().flush()?;
-
-                                    let mut resp = String::new();
-                                    io::stdin().read_line(&mut resp)?;
+                                println!("{} {}", "Fixed command:".blue().bold(), &fixed_cmd);
+                                if fix_level > DangerLevel::Safe {
+                                    println!(
+                                        "{}",
+                                        format!("[{}]", fix_level.name().to_uppercase()).red().bold()
+                                    );
+                                }
 
-                                    if resp.trim().to_lowercase() == "y" {
+                                match clear_to_run(
+                                    "Try fixed command? [y/N] ",
+                                    &fixed_cmd,
+                                    fix_level,
+                                    config,
+                                    opts,
+                                ) {
+                                    Ok(true) => {
+                                        current_level = fix_level;
                                         current_command = fixed_cmd;
                                         continue;
                                     }
-                                }
-                                Err(e) => {
-                                    println!("{}: {}", "Could not generate fix".red(), e);
+                                    Ok(false) => {}
+                                    Err(e) => {
+                                        println!("{}: {}", "Fix rejected".red(), e);
+                                        break code;
+                                    }
                                 }
                             }
+                            Err(e) => {
+                                println!("{}: {}", "Could not generate fix".red(), e);
+                            }
                         }
                     }
                 }
-                break;
+                break code;
             }
             Err(e) => {
-                println!("\n{}: {}", "System Error".red().bold(), e);
-                break;
+                // A timeout is the command's failure, not Spren's
+                let label = if timed_out(&e) { "Error" } else { "System Error" };
+                println!("\n{}: {}", label.red().bold(), e);
+                break error::exit_code(&e);
+            }
+        }
+    };
+
+    Ok(Some(exit_code))
+}
+
+/// Answer an explain-only query with a command and its explanation, without executing
+async fn explain_query(
+    query: &str,
+    image: Option<&ai::Image>,
+    config: &config::Config,
+) -> Result<i32> {
+    let command = ai::get_image_command_suggestion(query, image, config).await?.command;
+    let explanation = ai::get_command_explanation(&command, config).await?;
+
+    println!("\n{}", "Command:".blue().bold());
+    println!("{}", command);
+    println!("\n{}", "Explanation:".blue().bold());
+    println!("{}", explanation);
+    println!("\n{}", "(Explain-only query: not executed)".dimmed());
+
+    Ok(0)
+}
+
+/// Run the `--review-with` reviewer on a command, showing what it said
+///
+/// Fails with a policy violation unless the reviewer exits zero.
+fn review_with(reviewer: &str, command: &str, config: &config::Config) -> Result<()> {
+    println!("\n{} {}", "Reviewing with:".blue().bold(), reviewer);
+    let review = executor::run_reviewer(reviewer, command, config)?;
+    for text in [&review.stdout, &review.stderr] {
+        if !text.is_empty() {
+            println!("{}", text);
+        }
+    }
+
+    if review.success {
+        println!("{}", "Reviewer approved.".green());
+        Ok(())
+    } else {
+        let code = review.exit_code.map_or("a signal".to_string(), |c| format!("exit {}", c));
+        Err(error::SprenError::PolicyViolation(format!(
+            "Rejected by reviewer `{}` ({})",
+            reviewer, code
+        ))
+        .into())
+    }
+}
+
+/// Command output as shown in the REPL: JSON re-indented if `display.pretty_json`
+fn display_stdout(stdout: &str, config: &config::Config) -> String {
+    config
+        .display
+        .pretty_json
+        .then(|| pretty::pretty_json(stdout))
+        .flatten()
+        .unwrap_or_else(|| stdout.to_string())
+}
+
+/// Show the exact command about to run, after sudo stripping, edits and fixes
+fn print_running(command: &str) {
+    println!("{} {}", "Running:".dimmed(), command);
+}
+
+/// Run each step of a workflow through the normal suggest/confirm/execute flow
+///
+/// Steps can refer to earlier steps' output with `@last`. Returns the exit
+/// code of the step that stopped the workflow, or 0 if all steps ran.
+async fn run_workflow(name: &str, config: &config::Config, opts: &QueryOptions) -> Result<i32> {
+    let workflow = config.workflows.get(name).ok_or_else(|| {
+        let known: Vec<&str> = config.workflows.keys().map(String::as_str).collect();
+        anyhow::anyhow!(
+            "No workflow named '{}' (configured: {})",
+            name,
+            if known.is_empty() { "none".to_string() } else { known.join(", ") }
+        )
+    })?;
+
+    let mut recent = recall::RecentOutputs::default();
+    run_steps(&workflow.steps, workflow.continue_on_error, config, opts, &mut recent).await
+}
+
+/// Suggest, confirm and run each step in order, stopping at the first failed
+/// or declined step unless `continue_on_error`
+///
+/// `@last` in a step refers to the output of earlier steps (or, before the
+/// first step's output, whatever `recent` already holds).
+async fn run_steps(
+    steps: &[String],
+    continue_on_error: bool,
+    config: &config::Config,
+    opts: &QueryOptions,
+    recent: &mut recall::RecentOutputs,
+) -> Result<i32> {
+    let mut last_failure = 0;
+    for (i, step) in steps.iter().enumerate() {
+        println!(
+            "\n{} {}",
+            format!("Step {}/{}:", i + 1, steps.len()).blue().bold(),
+            step
+        );
+
+        let query = if config.ai.feed_output_to_context {
+            recent.resolve_with_last_result(step)?
+        } else {
+            recent.resolve(step)?
+        };
+        match process_query(&query, config, opts, Some(&mut *recent)).await? {
+            Some(0) => {}
+            Some(code) if continue_on_error => {
+                println!("{}", format!("Step failed (exit {}), continuing", code).yellow());
+                last_failure = code;
+            }
+            Some(code) => {
+                println!("{}", format!("Step failed (exit {}), stopping", code).red());
+                return Ok(code);
+            }
+            // Nothing runs in a dry run, so every step is shown
+            None if opts.dry_run => {}
+            // Skipping a step could leave later steps acting on the wrong state
+            None => {
+                println!("{}", "Step declined, stopping".yellow());
+                return Ok(0);
+            }
+        }
+    }
+
+    Ok(last_failure)
+}
+
+/// Print a plain-language plan for `task`, then offer to work through it step by step
+async fn run_plan(task: &str, config: &config::Config, opts: &QueryOptions) -> Result<i32> {
+    let Some(plan_config) = confirm_sensitive_query(task, config, opts)? else {
+        return Ok(0);
+    };
+    let steps = ai::get_plan(task, &plan_config).await?;
+
+    println!("\n{}", "Plan:".blue().bold());
+    for (i, step) in steps.iter().enumerate() {
+        println!("  {}. {}", i + 1, step);
+    }
+
+    if config.security.disable_execution {
+        return Ok(0);
+    }
+    if !confirm("\nTurn these steps into commands one at a time? [y/N] ", false, config, opts)? {
+        return Ok(0);
+    }
+    run_steps(&steps, false, config, opts, &mut recall::RecentOutputs::default()).await
+}
+
+/// Describe an existing command for `--explain`, without executing it
+async fn explain_command(command: &str, config: &config::Config, opts: &QueryOptions) -> Result<i32> {
+    // A pasted command may carry a token or password
+    let Some(config) = confirm_sensitive_query(command, config, opts)? else {
+        return Ok(0);
+    };
+    let explanation = ai::get_command_explanation(command, &config).await?;
+
+    println!("\n{}", "Explanation:".blue().bold());
+    println!("{}", explanation);
+    Ok(0)
+}
+
+/// The whole of stdin as the query, when it's piped in rather than a terminal
+fn read_piped_query() -> Result<String> {
+    let mut query = String::new();
+    io::stdin().read_to_string(&mut query)?;
+    let query = query.trim();
+    if query.is_empty() {
+        return Err(anyhow::anyhow!("No query on stdin; pipe one in or pass --query"));
+    }
+    Ok(query.to_string())
+}
+
+/// Explain a failure from output piped in on stdin
+async fn analyze_error(command: &str, config: &config::Config) -> Result<i32> {
+    if io::stdin().is_terminal() {
+        return Err(anyhow::anyhow!(
+            "--analyze-error reads the failing output from stdin; pipe it in, e.g. `{} 2>&1 | spren --analyze-error \"{}\"`",
+            command,
+            command
+        ));
+    }
+    let mut output = String::new();
+    io::stdin().read_to_string(&mut output)?;
+    if output.trim().is_empty() {
+        return Err(anyhow::anyhow!("No output on stdin to analyze"));
+    }
+
+    // stdout and stderr arrive merged; the end of the output is where the error usually is
+    let explanation = ai::get_error_suggestion(command, "", &recall::cap(output.trim_end()), config).await?;
+    println!("{}", explanation);
+    Ok(0)
+}
+
+/// Generate commands for a batch of queries and append them to a review file
+async fn run_review(
+    path: &std::path::Path,
+    query: Option<&str>,
+    config: &config::Config,
+) -> Result<i32> {
+    let queries: Vec<String> = match query {
+        Some(query) => vec![query.to_string()],
+        None => io::stdin()
+            .lines()
+            .collect::<io::Result<Vec<_>>>()?
+            .into_iter()
+            .map(|q| q.trim().to_string())
+            .filter(|q| !q.is_empty() && !q.starts_with('#'))
+            .collect(),
+    };
+
+    let mut failed = 0;
+    for query in &queries {
+        let suggestion = ai::get_command_suggestion(query, config)
+            .await
+            .and_then(|s| {
+                danger::apply_sudo_policy(&s.command, &config.security)
+                    .map(|(cmd, _)| (cmd, s.dangerous))
+            });
+
+        match suggestion {
+            Ok((command, flagged)) => {
+                let level = danger::classify(&command, flagged, &config.security);
+                // A missing note shouldn't keep the command out of the review file
+                let note = if config.display.annotate_exports {
+                    ai::get_command_annotation(&command, config).await.ok()
+                } else {
+                    None
+                };
+                review::append(path, query, &command, level, note.as_deref())?;
+                println!("{} {}", "Queued:".blue().bold(), query);
+            }
+            Err(e) => {
+                failed += 1;
+                eprintln!("{} {}: {}", "Failed:".red().bold(), query, e);
+            }
+        }
+    }
+
+    println!(
+        "\n{} of {} suggestions written to {} (nothing was executed)",
+        queries.len() - failed,
+        queries.len(),
+        path.display()
+    );
+    Ok(if failed > 0 { 1 } else { 0 })
+}
+
+/// Point out a pinned command that is close to, but not the same as, `command`
+fn pin_note(command: &str, config: &config::Config) -> Option<String> {
+    pins::closest_pin(command, &config.pins)
+        .map(|(name, pinned)| format!("You have a pinned command for this ({}): {}", name, pinned))
+}
+
+/// TUI status for Enter with nothing to ask
+#[cfg(feature = "tui")]
+const EMPTY_QUERY: &str = "Type a query first";
+
+/// Says which cloud provider stepped in for the configured model
+fn escalation_notice(provider: &config::AIProvider, config: &config::Config) -> String {
+    format!(
+        "The {} model's reply couldn't be used; this command comes from {} (ai.escalate_on_parse_failure)",
+        config.ai.provider.name(),
+        provider.name()
+    )
+}
+
+/// Shown when a reply cut off by the token limit was requested again with more room
+fn truncation_retry_notice(tokens: u32) -> String {
+    format!(
+        "Note: the first reply hit the token limit; asked again with {} output tokens (ai.truncation_retry_max_tokens)",
+        tokens
+    )
+}
+
+/// Whether a command was killed by `security.command_timeout_secs`
+fn timed_out(err: &anyhow::Error) -> bool {
+    matches!(err.downcast_ref(), Some(error::SprenError::TimedOut(_)))
+}
+
+/// Shown for a command that would read its input from stdin
+fn stdin_warning(program: &str) -> String {
+    format!(
+        "Warning: {} reads from stdin here, which Spren doesn't connect, so it will get no input. Give it a file to read.",
+        program
+    )
+}
+
+/// Shown when the model ran out of tokens mid-reply
+const TRUNCATED_WARNING: &str =
+    "Warning: the model hit its token limit; this command may be cut off (ai.max_output_tokens)";
+
+/// Shown in the TUI when asked to run a command under `--dry-run`
+#[cfg(feature = "tui")]
+const DRY_RUN: &str = "Dry run (--dry-run): commands are shown but never executed.";
+
+/// Shown instead of running anything when `security.disable_execution` is set
+const EXECUTION_DISABLED: &str = "Execution is disabled (security.disable_execution / --no-exec); not running it.";
+
+/// Re-run the last command from the transcript, with the usual confirmation
+async fn rerun_last(config: &config::Config, opts: &QueryOptions) -> Result<i32> {
+    if config.security.disable_execution {
+        return Err(error::SprenError::PolicyViolation(EXECUTION_DISABLED.to_string()).into());
+    }
+    let path = transcript::transcript_path()?;
+    if !path.exists() {
+        return Err(anyhow::anyhow!(
+            "Nothing to re-run: no commands recorded yet (security.record_transcript)"
+        ));
+    }
+    let entry = transcript::read(&path)?
+        .pop()
+        .ok_or_else(|| anyhow::anyhow!("Nothing to re-run: {} is empty", path.display()))?;
+
+    // Rules may have changed since it ran: apply today's sudo policy, and
+    // never trust a lower recorded level
+    let (command, sudo_stripped) = danger::apply_sudo_policy(&entry.command, &config.security)?;
+    let level = danger::classify(&command, false, &config.security).max(entry.danger);
+    println!("{} {}", "Last command:".blue().bold(), command);
+    if level > DangerLevel::Safe {
+        println!("{}", format!("[{}]", level.name().to_uppercase()).red().bold());
+    }
+    if sudo_stripped {
+        println!("{}", "Removed leading sudo (security.strip_sudo)".dimmed());
+    }
+
+    if opts.dry_run {
+        return Ok(0);
+    }
+    if !clear_to_run("\nExecute again? [y/N] ", &command, level, config, opts)? {
+        return Ok(0);
+    }
+
+    print_running(&command);
+    let code = if executor::is_interactive(&command) {
+        executor::execute_interactive(&command, config).await?
+    } else {
+        let output = executor::execute_command(&command, config).await?;
+        if !output.stdout.is_empty() {
+            println!("\n{}", display_stdout(&output.stdout, config));
+        }
+        if !output.stderr.is_empty() {
+            let label = if output.success { "Note".yellow().bold() } else { "Error".red().bold() };
+            println!("{}: {}", label, output.stderr);
+        }
+        output.exit_code
+    };
+    record_execution(config, &entry.query, &command, level, code, None);
+
+    Ok(code.unwrap_or(1))
+}
+
+/// Print a unified diff with added/removed lines colored
+fn print_diff(diff: &str) {
+    for line in diff.lines() {
+        if line.starts_with("+++") || line.starts_with("---") {
+            println!("{}", line.bold());
+        } else if line.starts_with('+') {
+            println!("{}", line.green());
+        } else if line.starts_with('-') {
+            println!("{}", line.red());
+        } else if line.starts_with("@@") {
+            println!("{}", line.cyan());
+        } else {
+            println!("{}", line);
+        }
+    }
+}
+
+/// Log an executed command to the transcript and, if enabled, the shell's own
+/// history; both are best-effort and never fail a query
+fn record_execution(
+    config: &config::Config,
+    query: &str,
+    command: &str,
+    level: DangerLevel,
+    exit_code: Option<i32>,
+    latency: Option<std::time::Duration>,
+) {
+    if config.shell.write_to_shell_history {
+        let _ = history::append_to_shell_history(command);
+    }
+
+    if !config.security.record_transcript {
+        return;
+    }
+
+    if let Ok(path) = transcript::transcript_path() {
+        let mut entry = transcript::TranscriptEntry::new(query, command, level, exit_code);
+        // Only freshly suggested commands have a provider and latency to report
+        if let Some(latency) = latency {
+            entry.provider = Some(config.ai.provider.name().to_string());
+            entry.latency_ms = Some(latency.as_millis() as u64);
+        }
+        let _ = transcript::append(&path, &entry);
+    }
+}
+
+/// List saved queries with the directory each was asked in
+fn print_history(in_dir: Option<&std::path::Path>) -> Result<()> {
+    let in_dir = in_dir.map(|dir| std::fs::canonicalize(dir).unwrap_or_else(|_| dir.to_path_buf()));
+    let entries = history::load_entries(&history::history_path()?)?;
+
+    for entry in entries.iter().filter(|e| in_dir.as_deref().is_none_or(|dir| e.is_in(dir))) {
+        match &entry.cwd {
+            Some(cwd) => println!("{}  {}", entry.query, cwd.display().to_string().dimmed()),
+            None => println!("{}", entry.query),
+        }
+    }
+    Ok(())
+}
+
+/// Print an audit of a transcript under the current danger rules
+fn print_audit(path: &std::path::Path, config: &config::Config) -> Result<()> {
+    let entries = transcript::read(path)?;
+    let report = audit::audit(&entries, &config.security);
+
+    println!("{} {}", "Audited".blue().bold(), path.display());
+    println!("{} commands, {} failed or interrupted", report.total, report.failed);
+    for (level, count) in &report.by_level {
+        println!("  {:<13} {}", level.name(), count);
+    }
+
+    if report.escalated.is_empty() {
+        println!("\n{}", "No past commands are rated more dangerous under the current rules.".green());
+        return Ok(());
+    }
+
+    println!("\n{}", "Now rated more dangerous than when they ran:".yellow().bold());
+    for item in &report.escalated {
+        println!(
+            "  {} -> {}  {}",
+            item.entry.danger.name(),
+            item.now.name().red().bold(),
+            item.entry.command
+        );
+    }
+
+    Ok(())
+}
+
+/// Print each configured cloud provider's command for `query` side by side
+async fn compare_providers(query: &str, config: &config::Config) -> Result<()> {
+    let comparisons = ai::compare_providers(query, config).await;
+    if comparisons.is_empty() {
+        return Err(error::SprenError::NotConfigured(
+            "No cloud provider has an API key configured".to_string(),
+        )
+        .into());
+    }
+
+    println!("{} {}\n", "Comparing providers for:".blue().bold(), query);
+    for comparison in comparisons {
+        let name = format!("{:<10}", comparison.provider.name());
+        let latency = format!("{:>7.1?}", comparison.latency);
+        match comparison.result {
+            Ok(suggestion) => {
+                let flag = if suggestion.dangerous {
+                    format!(" {}", "[DANGEROUS]".red().bold())
+                } else {
+                    String::new()
+                };
+                println!("{} {}  {}{}", name.cyan(), latency.dimmed(), suggestion.command, flag);
+            }
+            Err(e) => {
+                let error = format!("error: {}", e);
+                println!("{} {}  {}", name.cyan(), latency.dimmed(), error.red());
             }
         }
     }
 
     Ok(())
 }
+
+/// Print usage statistics from the local history and transcript
+fn print_stats(top: usize) -> Result<()> {
+    let queries = history::load(&history::history_path()?)?;
+    let transcript_path = transcript::transcript_path()?;
+    let entries = if transcript_path.exists() {
+        transcript::read(&transcript_path)?
+    } else {
+        Vec::new()
+    };
+    let stats = stats::usage_stats(&queries, &entries, top);
+
+    println!("{}", "Most common queries".blue().bold());
+    for (query, count) in &stats.top_queries {
+        println!("  {:>5}  {}", count, query);
+    }
+
+    println!("\n{}", "Most run commands".blue().bold());
+    for (command, count) in &stats.top_commands {
+        println!("  {:>5}  {}", count, command);
+    }
+
+    println!("\n{}", "Summary".blue().bold());
+    println!("  {} queries, {} commands executed", stats.queries, stats.executed);
+    if let Some(rate) = stats.success_rate() {
+        println!("  {:.0}% exited successfully", rate);
+    }
+    for (provider, latency) in &stats.latency_by_provider {
+        println!(
+            "  {:<10} {:>6} ms average over {} suggestions",
+            provider, latency.average_ms, latency.samples
+        );
+    }
+
+    Ok(())
+}
+
+/// Existing files the command would clobber, relative to the current directory
+fn overwritten_files(command: &str) -> Vec<PathBuf> {
+    std::env::current_dir()
+        .map(|cwd| preview::detect_overwrites(command, &cwd))
+        .unwrap_or_default()
+}
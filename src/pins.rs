@@ -0,0 +1,85 @@
+//! Pinned commands: vetted versions of commands you run often
+//!
+//! Pins live in the `[pins]` config table as `name = "command"`. When the
+//! model suggests something close to a pin but not identical, the pin is
+//! pointed out so you can settle on the version you already trust.
+
+use std::collections::BTreeMap;
+
+/// How alike a suggestion and a pin must be (1.0 = identical) to mention the pin
+const MIN_SIMILARITY: f64 = 0.75;
+
+/// The pin most similar to `command`, as (name, pinned command)
+///
+/// An exact match returns nothing: there is nothing to point out.
+pub fn closest_pin<'a>(command: &str, pins: &'a BTreeMap<String, String>) -> Option<(&'a str, &'a str)> {
+    let command = normalize(command);
+    pins.iter()
+        .map(|(name, pinned)| (name, pinned, similarity(&command, &normalize(pinned))))
+        .filter(|(_, _, score)| *score >= MIN_SIMILARITY && *score < 1.0)
+        .max_by(|a, b| a.2.total_cmp(&b.2))
+        .map(|(name, pinned, _)| (name.as_str(), pinned.as_str()))
+}
+
+/// Collapse runs of whitespace so spacing alone never makes commands differ
+fn normalize(command: &str) -> String {
+    command.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+/// 1 minus the edit distance divided by the longer length
+fn similarity(a: &str, b: &str) -> f64 {
+    let longest = a.chars().count().max(b.chars().count());
+    if longest == 0 {
+        return 1.0;
+    }
+    1.0 - edit_distance(a, b) as f64 / longest as f64
+}
+
+/// Levenshtein distance over characters
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+    let mut current = vec![0; b.len() + 1];
+
+    for (i, ca) in a.chars().enumerate() {
+        current[0] = i + 1;
+        for (j, cb) in b.iter().enumerate() {
+            let substitution = previous[j] + usize::from(ca != *cb);
+            current[j + 1] = substitution.min(previous[j + 1] + 1).min(current[j] + 1);
+        }
+        std::mem::swap(&mut previous, &mut current);
+    }
+    previous[b.len()]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_edit_distance() {
+        assert_eq!(edit_distance("kitten", "sitting"), 3);
+        assert_eq!(edit_distance("", "ls"), 2);
+        assert_eq!(edit_distance("ls -la", "ls -la"), 0);
+    }
+
+    #[test]
+    fn test_closest_pin() {
+        let pins = BTreeMap::from([
+            ("disk".to_string(), "du -sh * | sort -h".to_string()),
+            ("logs".to_string(), "journalctl -u nginx --since today".to_string()),
+        ]);
+
+        assert_eq!(
+            closest_pin("du -sh * | sort -rh", &pins),
+            Some(("disk", "du -sh * | sort -h"))
+        );
+        assert_eq!(
+            closest_pin("journalctl -u nginx --since yesterday", &pins),
+            Some(("logs", "journalctl -u nginx --since today"))
+        );
+        // Identical (up to spacing) or unrelated commands don't get a note
+        assert_eq!(closest_pin("du  -sh * | sort -h", &pins), None);
+        assert_eq!(closest_pin("git status", &pins), None);
+    }
+}
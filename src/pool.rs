@@ -0,0 +1,154 @@
+//! A small pool of expensive, non-shareable resources
+//!
+//! The local model keeps its KV cache inside the weights and can't be cloned,
+//! so parallel generation needs separate instances. The pool loads them on
+//! demand, up to a limit, and callers wait for a free one beyond that. A
+//! single interactive user only ever loads one.
+
+use anyhow::{anyhow, Result};
+use std::ops::{Deref, DerefMut};
+use std::sync::{Condvar, Mutex};
+
+/// Instances loaded so far and the ones not checked out
+struct State<T> {
+    idle: Vec<T>,
+    loaded: usize,
+}
+
+pub struct Pool<T> {
+    state: Mutex<State<T>>,
+    returned: Condvar,
+}
+
+impl<T> Default for Pool<T> {
+    fn default() -> Self {
+        Self {
+            state: Mutex::new(State { idle: Vec::new(), loaded: 0 }),
+            returned: Condvar::new(),
+        }
+    }
+}
+
+impl<T> Pool<T> {
+    /// Take an idle instance, load a new one with `load` if fewer than `max`
+    /// exist, or else wait for one to be returned
+    pub fn checkout(&self, max: usize, load: impl FnOnce() -> Result<T>) -> Result<Checkout<'_, T>> {
+        let mut state = self.lock()?;
+        loop {
+            if let Some(item) = state.idle.pop() {
+                return Ok(Checkout { pool: self, item: Some(item) });
+            }
+            if state.loaded < max.max(1) {
+                break;
+            }
+            state = self
+                .returned
+                .wait(state)
+                .map_err(|e| anyhow!("Lock error: {}", e))?;
+        }
+
+        // Load without holding the lock so other callers can use idle instances meanwhile
+        state.loaded += 1;
+        drop(state);
+        match load() {
+            Ok(item) => Ok(Checkout { pool: self, item: Some(item) }),
+            Err(e) => {
+                self.lock()?.loaded -= 1;
+                self.returned.notify_one();
+                Err(e)
+            }
+        }
+    }
+
+    /// Whether any instance is checked out or being loaded
+    pub fn busy(&self) -> bool {
+        match self.state.try_lock() {
+            Ok(state) => state.loaded > state.idle.len(),
+            // Only held briefly, around checkouts
+            Err(_) => true,
+        }
+    }
+
+    fn lock(&self) -> Result<std::sync::MutexGuard<'_, State<T>>> {
+        self.state.lock().map_err(|e| anyhow!("Lock error: {}", e))
+    }
+}
+
+/// An instance in use; it goes back to the pool when dropped
+pub struct Checkout<'a, T> {
+    pool: &'a Pool<T>,
+    item: Option<T>,
+}
+
+impl<T> Deref for Checkout<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        self.item.as_ref().expect("present until dropped")
+    }
+}
+
+impl<T> DerefMut for Checkout<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        self.item.as_mut().expect("present until dropped")
+    }
+}
+
+impl<T> Drop for Checkout<'_, T> {
+    fn drop(&mut self) {
+        if let (Some(item), Ok(mut state)) = (self.item.take(), self.pool.state.lock()) {
+            state.idle.push(item);
+            self.pool.returned.notify_one();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn test_pool_loads_on_demand_up_to_max() {
+        let pool = Pool::default();
+        let loads = AtomicUsize::new(0);
+        let load = || Ok(loads.fetch_add(1, Ordering::SeqCst));
+
+        // One at a time reuses the first instance
+        for _ in 0..3 {
+            assert_eq!(*pool.checkout(2, load).unwrap(), 0);
+        }
+        assert!(!pool.busy());
+
+        let first = pool.checkout(2, load).unwrap();
+        let second = pool.checkout(2, load).unwrap();
+        assert_eq!((*first, *second), (0, 1));
+        assert!(pool.busy());
+        drop((first, second));
+        assert_eq!(loads.load(Ordering::SeqCst), 2);
+
+        // A failed load doesn't count against the limit
+        let fresh = Pool::<usize>::default();
+        assert!(fresh.checkout(1, || Err(anyhow!("no model"))).is_err());
+        assert_eq!(*fresh.checkout(1, || Ok(7)).unwrap(), 7);
+    }
+
+    #[test]
+    fn test_pool_waits_for_a_returned_instance() {
+        let pool = Arc::new(Pool::default());
+        let held = pool.checkout(1, || Ok("model")).unwrap();
+
+        let waiter = {
+            let pool = pool.clone();
+            thread::spawn(move || *pool.checkout(1, || panic!("over the limit")).unwrap())
+        };
+        thread::sleep(Duration::from_millis(50));
+        assert!(!waiter.is_finished());
+
+        drop(held);
+        assert_eq!(waiter.join().unwrap(), "model");
+    }
+}
@@ -0,0 +1,77 @@
+//! Readable display of JSON command output
+//!
+//! Tools like `kubectl -o json`, `gh api` and `aws --output json` print JSON
+//! that is often minified. With `display.pretty_json` it is re-indented, and
+//! the TUI can fold it down to its top-level keys.
+
+use serde_json::Value;
+
+/// Parse output that is a single JSON object or array
+///
+/// Scalars are left alone: `true` or `42` read fine as they are.
+pub fn parse_json(output: &str) -> Option<Value> {
+    let output = output.trim();
+    if !(output.starts_with('{') || output.starts_with('[')) {
+        return None;
+    }
+    serde_json::from_str(output).ok()
+}
+
+/// Re-indent JSON output, or None if it isn't JSON
+pub fn pretty_json(output: &str) -> Option<String> {
+    parse_json(output).and_then(|value| serde_json::to_string_pretty(&value).ok())
+}
+
+/// Show only the top level, summarizing nested objects and arrays
+#[cfg_attr(not(feature = "tui"), allow(dead_code))]
+pub fn fold_json(value: &Value) -> String {
+    match value {
+        Value::Object(map) if !map.is_empty() => {
+            let fields: Vec<String> = map
+                .iter()
+                .map(|(key, value)| format!("  {}: {}", Value::from(key.as_str()), summary(value)))
+                .collect();
+            format!("{{\n{}\n}}", fields.join(",\n"))
+        }
+        Value::Array(items) if !items.is_empty() => {
+            let items: Vec<String> = items.iter().map(|v| format!("  {}", summary(v))).collect();
+            format!("[\n{}\n]", items.join(",\n"))
+        }
+        other => other.to_string(),
+    }
+}
+
+#[cfg_attr(not(feature = "tui"), allow(dead_code))]
+fn summary(value: &Value) -> String {
+    match value {
+        Value::Object(map) if !map.is_empty() => format!("{{… {} keys}}", map.len()),
+        Value::Array(items) if !items.is_empty() => format!("[… {} items]", items.len()),
+        other => other.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pretty_json() {
+        assert_eq!(
+            pretty_json(r#"{"name":"web","replicas":3}"#).unwrap(),
+            "{\n  \"name\": \"web\",\n  \"replicas\": 3\n}"
+        );
+        assert!(pretty_json("total 8\ndrwxr-xr-x 2 me me 4096 .").is_none());
+        assert!(pretty_json("42").is_none());
+        // Several JSON documents in a row (JSON lines) aren't one value
+        assert!(pretty_json("{\"a\":1}\n{\"a\":2}").is_none());
+    }
+
+    #[test]
+    fn test_fold_json() {
+        let value = parse_json(r#"{"kind":"List","items":[{"a":1},{"b":2}],"metadata":{}}"#).unwrap();
+        assert_eq!(
+            fold_json(&value),
+            "{\n  \"items\": [… 2 items],\n  \"kind\": \"List\",\n  \"metadata\": {}\n}"
+        );
+    }
+}
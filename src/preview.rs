@@ -0,0 +1,431 @@
+//! Dry-run previews of commands that change files
+//!
+//! In-place editors are rerun without their in-place flag, and the output is
+//! diffed against the file on disk. Only simple, single commands are handled.
+//! Redirections and `cp`/`mv` targets are checked for files they would clobber.
+
+use crate::shell::expand_path;
+use similar::TextDiff;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+/// Shell syntax that would make a plain argv rerun misleading
+const SHELL_METACHARS: &[&str] = &["|", ";", "&", ">", "<", "`", "$("];
+
+/// Show a unified diff of what an in-place `sed`/`awk` edit would change
+///
+/// Returns `None` when the command isn't a previewable in-place edit.
+pub fn preview_file_changes(cmd: &str) -> Option<String> {
+    if SHELL_METACHARS.iter().any(|m| cmd.contains(m)) {
+        return None;
+    }
+
+    let words = shlex::split(cmd)?;
+    let (program, args) = words.split_first()?;
+    let program = program.rsplit('/').next().unwrap_or(program);
+
+    let (preview_args, files) = match program {
+        "sed" | "gsed" => sed_preview_args(args)?,
+        "awk" | "gawk" => awk_preview_args(args)?,
+        _ => return None,
+    };
+    if files.is_empty() {
+        return None;
+    }
+
+    let mut diffs = String::new();
+    for file in &files {
+        let original = fs::read_to_string(file).ok()?;
+
+        let output = Command::new(program)
+            .args(&preview_args)
+            .arg(file)
+            .output()
+            .ok()?;
+        if !output.status.success() {
+            return None;
+        }
+        let modified = String::from_utf8_lossy(&output.stdout);
+
+        if original != modified {
+            let diff = TextDiff::from_lines(original.as_str(), modified.as_ref());
+            diffs.push_str(&diff.unified_diff().header(file, file).to_string());
+        }
+    }
+
+    if diffs.is_empty() {
+        Some("No changes: the command would leave the files as they are.".to_string())
+    } else {
+        Some(diffs)
+    }
+}
+
+/// Existing files that `cmd` would overwrite via `>` redirection or a `cp`/`mv` target
+pub fn detect_overwrites(cmd: &str, cwd: &Path) -> Vec<PathBuf> {
+    let mut targets = Vec::new();
+
+    for segment in cmd.split(['|', ';', '&']).filter(|s| !s.trim().is_empty()) {
+        // `&&` and `2>&1` leave fragments that won't parse or are harmless
+        let Some(words) = shlex::split(segment) else {
+            continue;
+        };
+
+        targets.extend(redirect_targets(&words));
+        targets.extend(copy_targets(&words, cwd));
+    }
+
+    let mut existing: Vec<PathBuf> = targets
+        .into_iter()
+        .map(|t| resolve(&t, cwd))
+        .filter(|p| p.is_file())
+        .collect();
+    existing.dedup();
+    existing
+}
+
+/// Every path-like argument and redirection target of `cmd`, resolved against `cwd`
+///
+/// Options are skipped, but `--opt=value` and `of=value` contribute their value.
+/// Words that aren't really paths (patterns, scripts) just resolve to names
+/// under `cwd` that nothing cares about.
+pub fn command_paths(cmd: &str, cwd: &Path) -> Vec<PathBuf> {
+    let mut paths = Vec::new();
+
+    for segment in cmd.split(['|', ';', '&']).filter(|s| !s.trim().is_empty()) {
+        let Some(words) = shlex::split(segment) else {
+            continue;
+        };
+
+        paths.extend(redirect_targets(&words));
+        for word in words.iter().skip(1).filter(|w| !w.contains('>')) {
+            let arg = match word.split_once('=') {
+                Some((_, value)) => value,
+                None if word.starts_with('-') => continue,
+                None => word.as_str(),
+            };
+            if !arg.is_empty() {
+                paths.push(PathBuf::from(arg));
+            }
+        }
+    }
+
+    paths.into_iter().map(|p| resolve(&p, cwd)).collect()
+}
+
+/// Programs that change, move or delete the files they're given
+const MODIFYING_PROGRAMS: &[&str] = &[
+    "rm", "rmdir", "unlink", "shred", "truncate", "touch", "mv", "ln", "install", "tee",
+    "chmod", "chown", "chgrp", "chattr",
+    // Editors save wherever they're pointed
+    "nano", "pico", "vi", "vim", "nvim", "emacs", "micro", "ed", "code",
+];
+
+/// Paths `cmd` would write, create, move or delete, resolved against `cwd`
+///
+/// Redirections and `cp`/`mv` destinations always count; other arguments only
+/// for programs that modify them (`rm`, `chmod`, editors, `sed -i`, `dd of=`).
+/// Read-only commands like `cat` or `ls` name nothing here.
+pub fn written_paths(cmd: &str, cwd: &Path) -> Vec<PathBuf> {
+    let mut paths = Vec::new();
+
+    for segment in cmd.split(['|', ';', '&']).filter(|s| !s.trim().is_empty()) {
+        let Some(words) = shlex::split(segment) else {
+            continue;
+        };
+
+        paths.extend(redirect_targets(&words));
+        paths.extend(copy_targets(&words, cwd));
+        paths.extend(modified_operands(&words));
+    }
+
+    paths.into_iter().map(|p| resolve(&p, cwd)).collect()
+}
+
+/// Operands of a program that modifies them in place
+fn modified_operands(words: &[String]) -> Vec<PathBuf> {
+    let words: Vec<&String> = words
+        .iter()
+        .skip_while(|w| matches!(w.as_str(), "sudo" | "doas"))
+        .take_while(|w| !w.contains('>'))
+        .collect();
+    let Some((program, args)) = words.split_first() else {
+        return Vec::new();
+    };
+    let program = program.rsplit('/').next().unwrap_or(program);
+    let args: Vec<String> = args.iter().map(|a| a.to_string()).collect();
+
+    let operands: Vec<String> = match program {
+        "sed" => sed_preview_args(&args).map(|(_, files)| files).unwrap_or_default(),
+        "dd" => args.iter().filter_map(|a| a.strip_prefix("of=")).map(str::to_string).collect(),
+        _ if MODIFYING_PROGRAMS.contains(&program) => {
+            args.into_iter().filter(|a| !a.starts_with('-')).collect()
+        }
+        _ => Vec::new(),
+    };
+    operands.into_iter().map(PathBuf::from).collect()
+}
+
+/// Files written by `>`, `1>`, `2>` or `&>` (appending `>>` is left alone)
+fn redirect_targets(words: &[String]) -> Vec<PathBuf> {
+    let mut targets = Vec::new();
+
+    for (i, word) in words.iter().enumerate() {
+        let Some(pos) = word.find('>') else {
+            continue;
+        };
+        let (prefix, rest) = (&word[..pos], &word[pos + 1..]);
+        if rest.starts_with('>') || rest.starts_with('&') {
+            continue;
+        }
+        // `echo hi>out` has the command text before the `>`; only bare fd numbers are operators
+        if !(prefix.is_empty() || prefix == "&" || prefix.chars().all(|c| c.is_ascii_digit())) {
+            if let Some(target) = word
+                .rsplit_once('>')
+                .map(|(_, t)| t)
+                .filter(|t| !t.is_empty())
+            {
+                targets.push(PathBuf::from(target));
+            }
+            continue;
+        }
+
+        let target = if rest.is_empty() {
+            words.get(i + 1).map(String::as_str)
+        } else {
+            Some(rest)
+        };
+        if let Some(target) = target.filter(|t| !t.starts_with("/dev/")) {
+            targets.push(PathBuf::from(target));
+        }
+    }
+
+    targets
+}
+
+/// Destination files of `cp`/`mv`, looking inside a target directory when given one
+fn copy_targets(words: &[String], cwd: &Path) -> Vec<PathBuf> {
+    let words: Vec<&str> = words
+        .iter()
+        .map(String::as_str)
+        .skip_while(|w| *w == "sudo")
+        .take_while(|w| !w.contains('>'))
+        .collect();
+    let Some((&program, args)) = words.split_first() else {
+        return Vec::new();
+    };
+    if !matches!(program, "cp" | "mv") {
+        return Vec::new();
+    }
+
+    let mut target_dir = None;
+    let mut operands = Vec::new();
+    let mut iter = args.iter();
+    while let Some(&arg) = iter.next() {
+        match arg {
+            "-n" | "--no-clobber" | "--update=none" => return Vec::new(),
+            "-t" | "--target-directory" => target_dir = iter.next().copied(),
+            _ if arg.starts_with("--target-directory=") => {
+                target_dir = arg.split_once('=').map(|(_, d)| d);
+            }
+            _ if arg.starts_with('-') && arg.len() > 1 => {}
+            _ => operands.push(arg),
+        }
+    }
+
+    let (dir, sources) = match target_dir {
+        Some(dir) => (dir, operands.as_slice()),
+        None => match operands.split_last() {
+            Some((&dest, sources)) if !sources.is_empty() => {
+                if !resolve(Path::new(dest), cwd).is_dir() {
+                    return vec![PathBuf::from(dest)];
+                }
+                (dest, sources)
+            }
+            _ => return Vec::new(),
+        },
+    };
+
+    sources
+        .iter()
+        .filter_map(|src| Path::new(src).file_name())
+        .map(|name| Path::new(dir).join(name))
+        .collect()
+}
+
+/// Expand `~`/`$VAR` and resolve relative paths against the command's working directory
+fn resolve(path: &Path, cwd: &Path) -> PathBuf {
+    cwd.join(expand_path(&path.to_string_lossy()))
+}
+
+/// Rewrite `sed -i` arguments to print to stdout; returns (args, files)
+fn sed_preview_args(args: &[String]) -> Option<(Vec<String>, Vec<String>)> {
+    // --sandbox rejects the w/r/e commands, so the dry run can't touch anything
+    let mut preview = vec!["--sandbox".to_string()];
+    let mut files = Vec::new();
+    let mut in_place = false;
+    let mut have_script = false;
+
+    let mut iter = args.iter().peekable();
+    while let Some(arg) = iter.next() {
+        if arg == "-i" {
+            in_place = true;
+            // BSD sed takes the backup suffix as a separate, often empty, argument
+            if iter.peek().is_some_and(|next| next.is_empty()) {
+                iter.next();
+            }
+        } else if arg.starts_with("--in-place") || (arg.starts_with("-i") && !arg.starts_with("--"))
+        {
+            in_place = true;
+        } else if arg == "-e" || arg == "-f" || arg == "--expression" || arg == "--file" {
+            preview.push(arg.clone());
+            preview.push(iter.next()?.clone());
+            have_script = true;
+        } else if arg.starts_with('-') && arg.len() > 1 {
+            preview.push(arg.clone());
+        } else if !have_script {
+            preview.push(arg.clone());
+            have_script = true;
+        } else {
+            files.push(arg.clone());
+        }
+    }
+
+    in_place.then_some((preview, files))
+}
+
+/// Rewrite `gawk -i inplace` arguments to print to stdout; returns (args, files)
+fn awk_preview_args(args: &[String]) -> Option<(Vec<String>, Vec<String>)> {
+    let mut preview = vec!["--sandbox".to_string()];
+    let mut files = Vec::new();
+    let mut in_place = false;
+    let mut have_program = false;
+
+    let mut iter = args.iter();
+    while let Some(arg) = iter.next() {
+        match arg.as_str() {
+            "-i" | "--include" => {
+                let lib = iter.next()?;
+                if lib == "inplace" {
+                    in_place = true;
+                } else {
+                    preview.push(arg.clone());
+                    preview.push(lib.clone());
+                }
+            }
+            "-F" | "-v" | "-f" => {
+                preview.push(arg.clone());
+                preview.push(iter.next()?.clone());
+                have_program |= arg == "-f";
+            }
+            _ if arg.starts_with('-') && arg.len() > 1 => preview.push(arg.clone()),
+            _ if !have_program => {
+                preview.push(arg.clone());
+                have_program = true;
+            }
+            _ => files.push(arg.clone()),
+        }
+    }
+
+    in_place.then_some((preview, files))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn strings(args: &[&str]) -> Vec<String> {
+        args.iter().map(|s| s.to_string()).collect()
+    }
+
+    #[test]
+    fn test_sed_args() {
+        let (args, files) =
+            sed_preview_args(&strings(&["-i.bak", "-E", "s/a/b/", "x.txt", "y.txt"])).unwrap();
+        assert_eq!(args, strings(&["--sandbox", "-E", "s/a/b/"]));
+        assert_eq!(files, strings(&["x.txt", "y.txt"]));
+
+        let (args, files) =
+            sed_preview_args(&strings(&["-i", "", "-e", "s/a/b/", "x.txt"])).unwrap();
+        assert_eq!(args, strings(&["--sandbox", "-e", "s/a/b/"]));
+        assert_eq!(files, strings(&["x.txt"]));
+
+        // Not in place: nothing to preview
+        assert!(sed_preview_args(&strings(&["s/a/b/", "x.txt"])).is_none());
+    }
+
+    #[test]
+    fn test_awk_args() {
+        let (args, files) = awk_preview_args(&strings(&[
+            "-i",
+            "inplace",
+            "-F",
+            ",",
+            "{print $1}",
+            "a.csv",
+        ]))
+        .unwrap();
+        assert_eq!(args, strings(&["--sandbox", "-F", ",", "{print $1}"]));
+        assert_eq!(files, strings(&["a.csv"]));
+    }
+
+    #[test]
+    fn test_unsupported_commands() {
+        assert!(preview_file_changes("ls -la").is_none());
+        assert!(preview_file_changes("sed -i s/a/b/ x.txt && rm x.txt").is_none());
+        assert!(preview_file_changes("echo hi > x.txt").is_none());
+    }
+
+    #[test]
+    fn test_detect_overwrites() -> anyhow::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let cwd = dir.path();
+        fs::write(cwd.join("notes.txt"), "old")?;
+        fs::write(cwd.join("a.txt"), "a")?;
+        fs::create_dir(cwd.join("backup"))?;
+        fs::write(cwd.join("backup").join("a.txt"), "a")?;
+
+        let notes = vec![cwd.join("notes.txt")];
+        assert_eq!(detect_overwrites("echo hi > notes.txt", cwd), notes);
+        assert_eq!(detect_overwrites("echo hi>notes.txt", cwd), notes);
+        assert_eq!(
+            detect_overwrites("cat a.txt | sort > notes.txt", cwd),
+            notes
+        );
+        assert_eq!(detect_overwrites("cp a.txt notes.txt", cwd), notes);
+        assert_eq!(
+            detect_overwrites("mv a.txt backup/", cwd),
+            vec![cwd.join("backup/").join("a.txt")]
+        );
+
+        // Variables in the target are expanded before checking
+        std::env::set_var("SPREN_TEST_OUT", cwd);
+        assert_eq!(detect_overwrites("echo hi > $SPREN_TEST_OUT/notes.txt", cwd), notes);
+
+        assert!(detect_overwrites("echo hi >> notes.txt", cwd).is_empty());
+        assert!(detect_overwrites("ls > new.txt", cwd).is_empty());
+        assert!(detect_overwrites("ls 2>/dev/null", cwd).is_empty());
+        assert!(detect_overwrites("make 2>&1", cwd).is_empty());
+        assert!(detect_overwrites("cp -n a.txt notes.txt", cwd).is_empty());
+        Ok(())
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_sed_preview_diff() -> anyhow::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("notes.txt");
+        fs::write(&path, "foo\nkeep\n")?;
+
+        let cmd = format!("sed -i 's/foo/bar/' {}", path.display());
+        let Some(diff) = preview_file_changes(&cmd) else {
+            // sed without --sandbox support (e.g. busybox)
+            return Ok(());
+        };
+
+        assert!(diff.contains("-foo"));
+        assert!(diff.contains("+bar"));
+        assert_eq!(fs::read_to_string(&path)?, "foo\nkeep\n");
+        Ok(())
+    }
+}
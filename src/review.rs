@@ -0,0 +1,79 @@
+//! Review files: suggested commands written out for a human to approve
+//!
+//! Nothing here executes anything. Each suggestion is appended commented
+//! out, so a reviewer approves a command by uncommenting it and then runs
+//! the file themselves.
+
+use crate::danger::DangerLevel;
+use anyhow::Result;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::Path;
+
+const HEADER: &str = "\
+# Spren review file: commands suggested for human review, never executed.
+# Uncomment the commands you approve, then run this file.
+";
+
+/// Append a suggestion to the review file, creating it with a header if needed.
+/// `note` is a one-line explanation of the command (`--annotate`).
+pub fn append(
+    path: &Path,
+    query: &str,
+    command: &str,
+    level: DangerLevel,
+    note: Option<&str>,
+) -> Result<()> {
+    if let Some(parent) = path.parent().filter(|p| !p.as_os_str().is_empty()) {
+        fs::create_dir_all(parent)?;
+    }
+
+    let is_new = !path.exists() || fs::metadata(path)?.len() == 0;
+    let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+    if is_new {
+        write!(file, "{}", HEADER)?;
+    }
+    write!(file, "{}", format_entry(query, command, level, note))?;
+    Ok(())
+}
+
+fn format_entry(query: &str, command: &str, level: DangerLevel, note: Option<&str>) -> String {
+    let mut entry = format!("\n# Query: {}\n", query.replace('\n', " "));
+    if level.is_dangerous() {
+        entry.push_str(&format!("# Danger: {} - review carefully\n", level.name().to_uppercase()));
+    } else {
+        entry.push_str(&format!("# Danger: {}\n", level.name()));
+    }
+    if let Some(note) = note {
+        entry.push_str(&format!("# Note: {}\n", note));
+    }
+    for line in command.lines() {
+        entry.push_str(&format!("# {}\n", line));
+    }
+    entry
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_append_review_entries() -> Result<()> {
+        let dir = tempdir()?;
+        let path = dir.path().join("review.sh");
+
+        append(&path, "list files", "ls -la", DangerLevel::Safe, None)?;
+        append(&path, "clean up", "rm -rf build", DangerLevel::Destructive, None)?;
+        append(&path, "disk usage", "du -sh .", DangerLevel::Safe, Some("Shows the total size"))?;
+
+        let contents = fs::read_to_string(&path)?;
+        assert_eq!(contents.matches("Spren review file").count(), 1);
+        assert!(contents.contains("# Query: list files\n# Danger: safe\n# ls -la\n"));
+        assert!(contents.contains("# Danger: DESTRUCTIVE - review carefully\n# rm -rf build\n"));
+        assert!(contents.contains("# Danger: safe\n# Note: Shows the total size\n# du -sh .\n"));
+        // Nothing is runnable until a reviewer uncomments it
+        assert!(contents.lines().all(|l| l.is_empty() || l.starts_with('#')));
+        Ok(())
+    }
+}
@@ -0,0 +1,214 @@
+//! Minimal HTTP server for editor integrations
+//!
+//! Exposes generation-only endpoints so GUIs can reuse Spren's provider logic.
+//! There is deliberately no execution endpoint.
+//!
+//! ```text
+//! POST /suggest   {"query": "list large files"}
+//!              -> {"command": "du -ah . | sort -rh | head", "dangerous": false}
+//!
+//! POST /explain   {"command": "tar -xzf archive.tar.gz"}
+//!              -> {"explanation": "Extracts the gzip-compressed archive..."}
+//!
+//! Errors       -> {"error": "..."} with a 4xx/5xx status
+//! ```
+
+use crate::ai;
+use crate::config::Config;
+use crate::danger;
+use crate::error::SprenError;
+use anyhow::Result;
+use hyper::body::HttpBody;
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Method, Request, Response, Server, StatusCode};
+use serde::{Deserialize, Serialize};
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+/// Largest request body accepted (queries and commands are short)
+const MAX_BODY_SIZE: usize = 64 * 1024;
+
+#[derive(Debug, Deserialize)]
+struct SuggestRequest {
+    query: String,
+}
+
+#[derive(Debug, Serialize)]
+struct SuggestResponse {
+    command: String,
+    dangerous: bool,
+}
+
+#[derive(Debug, Deserialize)]
+struct ExplainRequest {
+    command: String,
+}
+
+#[derive(Debug, Serialize)]
+struct ExplainResponse {
+    explanation: String,
+}
+
+#[derive(Debug, Serialize)]
+struct ErrorResponse {
+    error: String,
+}
+
+/// Run the server until interrupted
+pub async fn serve(config: Config, host: &str, port: u16) -> Result<()> {
+    let addr: SocketAddr = format!("{}:{}", host, port).parse()?;
+    let config = Arc::new(config);
+
+    let make_svc = make_service_fn(move |_conn| {
+        let config = config.clone();
+        async move { Ok::<_, Infallible>(service_fn(move |req| handle(req, config.clone()))) }
+    });
+
+    let server = Server::try_bind(&addr)?.serve(make_svc);
+    println!("Spren server listening on http://{}", addr);
+
+    server.await?;
+    Ok(())
+}
+
+async fn handle(req: Request<Body>, config: Arc<Config>) -> Result<Response<Body>, Infallible> {
+    let result = match (req.method(), req.uri().path()) {
+        (&Method::POST, "/suggest") => suggest(req, &config).await,
+        (&Method::POST, "/explain") => explain(req, &config).await,
+        _ => Err((StatusCode::NOT_FOUND, "Not found".to_string())),
+    };
+
+    Ok(match result {
+        Ok(body) => json_response(StatusCode::OK, body),
+        Err((status, error)) => json_response(
+            status,
+            serde_json::to_string(&ErrorResponse { error }).unwrap_or_default(),
+        ),
+    })
+}
+
+async fn suggest(req: Request<Body>, config: &Config) -> Result<String, (StatusCode, String)> {
+    let request: SuggestRequest = read_json(req).await?;
+
+    let suggestion = ai::get_command_suggestion(&request.query, config)
+        .await
+        .map_err(provider_error)?;
+    let (command, _) =
+        danger::apply_sudo_policy(&suggestion.command, &config.security).map_err(provider_error)?;
+    // The same classification the CLI and TUI use, not just the model's flag
+    let dangerous =
+        danger::classify(&command, suggestion.dangerous, &config.security).is_dangerous();
+
+    serde_json::to_string(&SuggestResponse { command, dangerous }).map_err(internal_error)
+}
+
+async fn explain(req: Request<Body>, config: &Config) -> Result<String, (StatusCode, String)> {
+    let request: ExplainRequest = read_json(req).await?;
+
+    let explanation = ai::get_command_explanation(&request.command, config)
+        .await
+        .map_err(provider_error)?;
+
+    serde_json::to_string(&ExplainResponse { explanation }).map_err(internal_error)
+}
+
+/// Read and deserialize a JSON request body, rejecting oversized payloads
+async fn read_json<T: for<'de> Deserialize<'de>>(
+    req: Request<Body>,
+) -> Result<T, (StatusCode, String)> {
+    let too_large = || (StatusCode::PAYLOAD_TOO_LARGE, "Request body too large".to_string());
+
+    let declared = req
+        .headers()
+        .get(hyper::header::CONTENT_LENGTH)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse::<u64>().ok());
+    if declared.is_some_and(|len| len > MAX_BODY_SIZE as u64) {
+        return Err(too_large());
+    }
+
+    // Chunked bodies declare no length, so count what actually arrives
+    let mut body = req.into_body();
+    let mut bytes = Vec::new();
+    while let Some(chunk) = body.data().await {
+        let chunk = chunk.map_err(|e| (StatusCode::BAD_REQUEST, e.to_string()))?;
+        if bytes.len() + chunk.len() > MAX_BODY_SIZE {
+            return Err(too_large());
+        }
+        bytes.extend_from_slice(&chunk);
+    }
+
+    serde_json::from_slice(&bytes)
+        .map_err(|e| (StatusCode::BAD_REQUEST, format!("Invalid JSON: {}", e)))
+}
+
+/// Map provider failures to a status; missing setup is a 503 and model junk a 502
+fn provider_error(e: anyhow::Error) -> (StatusCode, String) {
+    match e.downcast_ref::<SprenError>() {
+        Some(SprenError::NotConfigured(_)) => (StatusCode::SERVICE_UNAVAILABLE, e.to_string()),
+        Some(SprenError::InvalidCommand(_)) => (StatusCode::BAD_GATEWAY, e.to_string()),
+        Some(SprenError::PolicyViolation(_)) => (StatusCode::FORBIDDEN, e.to_string()),
+        Some(SprenError::Cancelled | SprenError::TimedOut(_)) | None => internal_error(e),
+    }
+}
+
+fn internal_error(e: impl std::fmt::Display) -> (StatusCode, String) {
+    (StatusCode::INTERNAL_SERVER_ERROR, e.to_string())
+}
+
+fn json_response(status: StatusCode, body: String) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .header(hyper::header::CONTENT_TYPE, "application/json")
+        .body(Body::from(body))
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn status_of(req: Request<Body>) -> StatusCode {
+        let response = handle(req, Arc::new(Config::default())).await.unwrap();
+        response.status()
+    }
+
+    fn post(path: &str, body: impl Into<Body>) -> Request<Body> {
+        Request::post(path).body(body.into()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_unknown_routes_are_not_found() {
+        let get = Request::get("/suggest").body(Body::empty()).unwrap();
+        assert_eq!(status_of(get).await, StatusCode::NOT_FOUND);
+        assert_eq!(status_of(post("/execute", "{}")).await, StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_oversized_bodies_are_rejected() {
+        let big = format!(r#"{{"query": "{}"}}"#, "a".repeat(MAX_BODY_SIZE));
+
+        let declared = Request::post("/suggest")
+            .header(hyper::header::CONTENT_LENGTH, big.len())
+            .body(Body::from(big.clone()))
+            .unwrap();
+        assert_eq!(status_of(declared).await, StatusCode::PAYLOAD_TOO_LARGE);
+
+        // No Content-Length, as with a chunked upload
+        let (mut sender, body) = Body::channel();
+        tokio::spawn(async move {
+            for chunk in big.into_bytes().chunks(4096) {
+                if sender.send_data(chunk.to_vec().into()).await.is_err() {
+                    break;
+                }
+            }
+        });
+        assert_eq!(status_of(post("/explain", body)).await, StatusCode::PAYLOAD_TOO_LARGE);
+    }
+
+    #[tokio::test]
+    async fn test_invalid_json_is_a_bad_request() {
+        assert_eq!(status_of(post("/explain", "not json")).await, StatusCode::BAD_REQUEST);
+    }
+}
@@ -1,5 +1,6 @@
 // src/shell.rs
 use std::env;
+use std::path::PathBuf;
 
 #[derive(Debug, Clone, PartialEq)]
 pub enum ShellType {
@@ -25,6 +26,22 @@ impl ShellType {
         }
     }
 
+    /// Shell family of an explicit binary like `/bin/bash` or `pwsh.exe`
+    pub fn from_binary(path: &str) -> Self {
+        let name = path
+            .rsplit(['/', '\\'])
+            .next()
+            .unwrap_or(path)
+            .to_lowercase();
+        let name = name.strip_suffix(".exe").unwrap_or(&name);
+
+        match name {
+            "pwsh" | "powershell" => ShellType::PowerShell,
+            "cmd" => ShellType::Cmd,
+            _ => ShellType::Bash,
+        }
+    }
+
     pub fn get_shell_command(&self) -> (&str, &[&str]) {
         match self {
             ShellType::Bash => ("sh", &["-c"]),
@@ -54,4 +71,223 @@ impl ShellType {
             }
         }
     }
-}
\ No newline at end of file
+}
+
+/// Pipelines at least this long are shown one stage per line
+const DISPLAY_WRAP_WIDTH: usize = 60;
+
+/// Format a command for display in the target shell's own idiom
+///
+/// Long Bash and PowerShell pipelines are broken after each `|`, which both
+/// shells read as a line continuation, so the display stays paste-able.
+/// Only the display changes; the command that runs is untouched.
+pub fn format_command_for_display(command: &str, shell: &ShellType) -> String {
+    let escape = match shell {
+        ShellType::Bash => '\\',
+        ShellType::PowerShell => '`',
+        // cmd has no continuation after a pipe
+        ShellType::Cmd => return command.to_string(),
+    };
+
+    let stages = split_pipeline(command, escape);
+    if stages.len() < 2 || command.len() < DISPLAY_WRAP_WIDTH {
+        return command.to_string();
+    }
+
+    stages.join(" |\n    ")
+}
+
+/// Split at top-level `|` (not `||`, quotes or script blocks)
+fn split_pipeline(command: &str, escape: char) -> Vec<String> {
+    let mut stages = Vec::new();
+    let mut current = String::new();
+    let mut quote: Option<char> = None;
+    let mut depth = 0usize;
+    let mut chars = command.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match (c, quote) {
+            (c, _) if c == escape && quote != Some('\'') => {
+                current.push(c);
+                current.extend(chars.next());
+                continue;
+            }
+            (q, Some(open)) if q == open => quote = None,
+            (_, Some(_)) => {}
+            ('\'' | '"', None) => quote = Some(c),
+            ('(' | '{' | '[', None) => depth += 1,
+            (')' | '}' | ']', None) => depth = depth.saturating_sub(1),
+            ('|', None) if depth == 0 => {
+                if chars.peek() == Some(&'|') || current.ends_with('|') {
+                    current.push(c);
+                    continue;
+                }
+                stages.push(current.trim().to_string());
+                current.clear();
+                continue;
+            }
+            _ => {}
+        }
+        current.push(c);
+    }
+    stages.push(current.trim().to_string());
+
+    stages.retain(|s| !s.is_empty());
+    stages
+}
+
+/// Expand a leading `~` and `$VAR`/`${VAR}` the way a POSIX shell would
+///
+/// Unset variables are left as written rather than becoming empty, so
+/// `$UNSET/data` can never turn into `/data`.
+pub fn expand_path(s: &str) -> PathBuf {
+    let s = match s.strip_prefix('~') {
+        Some(rest) if rest.is_empty() || rest.starts_with(['/', '\\']) => {
+            match dirs::home_dir() {
+                Some(home) => format!("{}{}", home.display(), rest),
+                None => s.to_string(),
+            }
+        }
+        _ => s.to_string(),
+    };
+
+    let mut out = String::with_capacity(s.len());
+    let mut rest = s.as_str();
+    while let Some(pos) = rest.find('$') {
+        out.push_str(&rest[..pos]);
+        let after = &rest[pos + 1..];
+
+        let (name, len) = match after.strip_prefix('{') {
+            Some(braced) => match braced.find('}') {
+                Some(end) => (&braced[..end], end + 2),
+                None => ("", 0),
+            },
+            None => {
+                let end = after
+                    .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+                    .unwrap_or(after.len());
+                (&after[..end], end)
+            }
+        };
+
+        let valid = name.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_');
+        match env::var(name).ok().filter(|_| valid) {
+            Some(value) => out.push_str(&value),
+            None => out.push_str(&rest[pos..pos + 1 + len]),
+        }
+        rest = &after[len..];
+    }
+    out.push_str(rest);
+
+    PathBuf::from(out)
+}
+
+/// One command of an exported script
+#[cfg_attr(not(feature = "tui"), allow(dead_code))]
+#[derive(Debug, Clone)]
+pub struct ScriptStep {
+    pub query: String,
+    pub command: String,
+    /// One-line explanation of the command (`display.annotate_exports`)
+    pub note: Option<String>,
+}
+
+/// A runnable script of steps, each under a comment with its query (and
+/// note, if any); returns the script and its file extension
+#[cfg_attr(not(feature = "tui"), allow(dead_code))]
+pub fn session_script(steps: &[ScriptStep], shell: &ShellType) -> (String, &'static str) {
+    let (header, comment, extension) = match shell {
+        ShellType::Bash => ("#!/usr/bin/env bash\nset -e\n", "#", "sh"),
+        ShellType::PowerShell => ("$ErrorActionPreference = 'Stop'\n", "#", "ps1"),
+        ShellType::Cmd => ("@echo off\n", "REM", "cmd"),
+    };
+
+    let mut script = format!("{}{} Exported from a Spren session\n", header, comment);
+    for step in steps {
+        script.push('\n');
+        for line in step.query.lines() {
+            script.push_str(&format!("{} {}\n", comment, line));
+        }
+        if let Some(note) = &step.note {
+            script.push_str(&format!("{} {}\n", comment, note));
+        }
+        script.push_str(&step.command);
+        script.push('\n');
+        // cmd has no `set -e`
+        if *shell == ShellType::Cmd {
+            script.push_str("if errorlevel 1 exit /b %errorlevel%\n");
+        }
+    }
+    (script, extension)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_from_binary() {
+        assert_eq!(ShellType::from_binary("/bin/bash"), ShellType::Bash);
+        assert_eq!(ShellType::from_binary("/usr/local/bin/pwsh"), ShellType::PowerShell);
+        assert_eq!(ShellType::from_binary(r"C:\Windows\System32\cmd.exe"), ShellType::Cmd);
+        assert_eq!(ShellType::from_binary("PowerShell.exe"), ShellType::PowerShell);
+    }
+
+    #[test]
+    fn test_format_for_display() {
+        let ps = "Get-ChildItem -Recurse -File | Where-Object { $_.Length -gt 1MB } | Sort-Object Length -Descending";
+        assert_eq!(
+            format_command_for_display(ps, &ShellType::PowerShell),
+            "Get-ChildItem -Recurse -File |\n    Where-Object { $_.Length -gt 1MB } |\n    Sort-Object Length -Descending"
+        );
+
+        let sh = "grep -r 'a|b' src | sort | uniq -c | sort -rn || echo 'nothing found at all'";
+        let shown = format_command_for_display(sh, &ShellType::Bash);
+        assert!(shown.starts_with("grep -r 'a|b' src |\n    sort |"));
+        assert!(shown.ends_with("sort -rn || echo 'nothing found at all'"));
+
+        assert_eq!(format_command_for_display("ls | wc -l", &ShellType::Bash), "ls | wc -l");
+        assert_eq!(format_command_for_display(ps, &ShellType::Cmd), ps);
+    }
+
+    #[test]
+    fn test_expand_path() {
+        let home = dirs::home_dir().unwrap();
+        env::set_var("SPREN_TEST_DIR", "/srv/data");
+
+        assert_eq!(expand_path("~"), home);
+        assert_eq!(expand_path("~/.ssh"), home.join(".ssh"));
+        assert_eq!(expand_path("$SPREN_TEST_DIR/logs"), PathBuf::from("/srv/data/logs"));
+        assert_eq!(expand_path("${SPREN_TEST_DIR}_old"), PathBuf::from("/srv/data_old"));
+        // Left alone: other users' homes, unset variables, stray dollars
+        assert_eq!(expand_path("~bob/x"), PathBuf::from("~bob/x"));
+        assert_eq!(expand_path("$SPREN_UNSET_VAR/x"), PathBuf::from("$SPREN_UNSET_VAR/x"));
+        assert_eq!(expand_path("cost$5/${"), PathBuf::from("cost$5/${"));
+    }
+
+    #[test]
+    fn test_session_script() {
+        let step = |query: &str, command: &str, note: Option<&str>| ScriptStep {
+            query: query.to_string(),
+            command: command.to_string(),
+            note: note.map(str::to_string),
+        };
+        let steps = vec![
+            step("list big files", "du -sh * | sort -h", None),
+            step("clean up\nthe build", "cargo clean", Some("Removes the target directory")),
+        ];
+        let (script, extension) = session_script(&steps, &ShellType::Bash);
+        assert_eq!(extension, "sh");
+        assert_eq!(
+            script,
+            "#!/usr/bin/env bash\nset -e\n# Exported from a Spren session\n\
+             \n# list big files\ndu -sh * | sort -h\n\
+             \n# clean up\n# the build\n# Removes the target directory\ncargo clean\n"
+        );
+
+        let (script, extension) = session_script(&steps[..1], &ShellType::Cmd);
+        assert_eq!(extension, "cmd");
+        assert!(script.starts_with("@echo off\nREM Exported"));
+        assert!(script.ends_with("if errorlevel 1 exit /b %errorlevel%\n"));
+    }
+}
@@ -0,0 +1,122 @@
+//! Local usage statistics from the history and transcript files
+//!
+//! Everything is computed from files already on disk; nothing is collected
+//! or sent anywhere.
+
+use crate::transcript::TranscriptEntry;
+use std::collections::{BTreeMap, HashMap};
+
+/// Average suggestion latency for one provider
+#[derive(Debug, Default, PartialEq)]
+pub struct Latency {
+    pub samples: usize,
+    pub average_ms: u64,
+}
+
+/// Summary of past usage
+#[derive(Debug, Default, PartialEq)]
+pub struct UsageStats {
+    pub queries: usize,
+    /// Most frequent queries, most common first
+    pub top_queries: Vec<(String, usize)>,
+    pub executed: usize,
+    pub succeeded: usize,
+    /// Most frequently run commands, most common first
+    pub top_commands: Vec<(String, usize)>,
+    pub latency_by_provider: BTreeMap<String, Latency>,
+}
+
+impl UsageStats {
+    /// Share of executed commands that exited with 0, as a percentage
+    pub fn success_rate(&self) -> Option<f64> {
+        (self.executed > 0).then(|| self.succeeded as f64 * 100.0 / self.executed as f64)
+    }
+}
+
+/// Summarize `queries` (from history) and `entries` (from the transcript),
+/// keeping the `top` most frequent queries and commands
+pub fn usage_stats(queries: &[String], entries: &[TranscriptEntry], top: usize) -> UsageStats {
+    let mut latencies: BTreeMap<String, Vec<u64>> = BTreeMap::new();
+    for entry in entries {
+        if let (Some(provider), Some(ms)) = (&entry.provider, entry.latency_ms) {
+            latencies.entry(provider.clone()).or_default().push(ms);
+        }
+    }
+
+    UsageStats {
+        queries: queries.len(),
+        top_queries: most_common(queries.iter().map(|q| q.trim()), top),
+        executed: entries.len(),
+        succeeded: entries.iter().filter(|e| e.exit_code == Some(0)).count(),
+        top_commands: most_common(entries.iter().map(|e| e.command.trim()), top),
+        latency_by_provider: latencies
+            .into_iter()
+            .map(|(provider, samples)| {
+                let latency = Latency {
+                    samples: samples.len(),
+                    average_ms: samples.iter().sum::<u64>() / samples.len() as u64,
+                };
+                (provider, latency)
+            })
+            .collect(),
+    }
+}
+
+/// Count items and return the `top` most frequent, ties broken alphabetically
+fn most_common<'a>(items: impl Iterator<Item = &'a str>, top: usize) -> Vec<(String, usize)> {
+    let mut counts: HashMap<&str, usize> = HashMap::new();
+    for item in items.filter(|i| !i.is_empty()) {
+        *counts.entry(item).or_default() += 1;
+    }
+
+    let mut counts: Vec<(String, usize)> = counts
+        .into_iter()
+        .map(|(item, count)| (item.to_string(), count))
+        .collect();
+    counts.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+    counts.truncate(top);
+    counts
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::danger::DangerLevel;
+
+    fn entry(command: &str, exit_code: i32, latency: Option<(&str, u64)>) -> TranscriptEntry {
+        let mut entry = TranscriptEntry::new("q", command, DangerLevel::Safe, Some(exit_code));
+        if let Some((provider, ms)) = latency {
+            entry.provider = Some(provider.to_string());
+            entry.latency_ms = Some(ms);
+        }
+        entry
+    }
+
+    #[test]
+    fn test_usage_stats() {
+        let queries: Vec<String> = ["list files", "disk usage", "list files", "git status"]
+            .iter()
+            .map(|q| q.to_string())
+            .collect();
+        let entries = vec![
+            entry("ls -la", 0, Some(("openai", 400))),
+            entry("ls -la", 0, Some(("openai", 600))),
+            entry("du -sh *", 1, Some(("local", 5000))),
+            entry("git status", 0, None),
+        ];
+
+        let stats = usage_stats(&queries, &entries, 2);
+        assert_eq!(stats.queries, 4);
+        assert_eq!(
+            stats.top_queries,
+            vec![("list files".to_string(), 2), ("disk usage".to_string(), 1)]
+        );
+        assert_eq!(stats.top_commands[0], ("ls -la".to_string(), 2));
+        assert_eq!(stats.success_rate(), Some(75.0));
+        assert_eq!(
+            stats.latency_by_provider["openai"],
+            Latency { samples: 2, average_ms: 500 }
+        );
+        assert_eq!(stats.latency_by_provider["local"].samples, 1);
+    }
+}
@@ -0,0 +1,118 @@
+//! Append-only log of executed commands
+//!
+//! Each line of `~/.config/spren/transcript.jsonl` is one [`TranscriptEntry`]:
+//!
+//! ```text
+//! {"timestamp":1700000000,"query":"free up space","command":"docker system prune","danger":"destructive","exit_code":0}
+//! ```
+
+use crate::danger::DangerLevel;
+use anyhow::{anyhow, Result};
+use dirs::home_dir;
+use serde::{Deserialize, Serialize};
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// One executed command
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TranscriptEntry {
+    /// Seconds since the Unix epoch
+    pub timestamp: u64,
+    /// What the user asked for
+    pub query: String,
+    /// The command that actually ran (after edits and fixes)
+    pub command: String,
+    /// Classification at the time it ran
+    pub danger: DangerLevel,
+    /// Exit code, if the command ran to completion
+    pub exit_code: Option<i32>,
+    /// Provider that suggested the command
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub provider: Option<String>,
+    /// How long the suggestion took, in milliseconds
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub latency_ms: Option<u64>,
+}
+
+impl TranscriptEntry {
+    pub fn new(query: &str, command: &str, danger: DangerLevel, exit_code: Option<i32>) -> Self {
+        Self {
+            timestamp: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or_default(),
+            query: query.to_string(),
+            command: command.to_string(),
+            danger,
+            exit_code,
+            provider: None,
+            latency_ms: None,
+        }
+    }
+}
+
+/// Path of the transcript file
+pub fn transcript_path() -> Result<PathBuf> {
+    let home = home_dir().ok_or_else(|| anyhow!("Could not find home directory"))?;
+    Ok(home.join(".config").join("spren").join("transcript.jsonl"))
+}
+
+/// Append an entry to the transcript at `path`
+pub fn append(path: &Path, entry: &TranscriptEntry) -> Result<()> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+
+    let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+    writeln!(file, "{}", serde_json::to_string(entry)?)?;
+    Ok(())
+}
+
+/// Read every entry in a transcript, failing on the first malformed line
+pub fn read(path: &Path) -> Result<Vec<TranscriptEntry>> {
+    let contents = fs::read_to_string(path)
+        .map_err(|e| anyhow!("Could not read {}: {}", path.display(), e))?;
+
+    contents
+        .lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty())
+        .map(|(i, line)| {
+            serde_json::from_str(line)
+                .map_err(|e| anyhow!("{}:{}: invalid transcript entry: {}", path.display(), i + 1, e))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_append_and_read() -> Result<()> {
+        let dir = tempdir()?;
+        let path = dir.path().join("transcript.jsonl");
+
+        let first = TranscriptEntry::new("list files", "ls -la", DangerLevel::Safe, Some(0));
+        let second = TranscriptEntry::new("clean", "rm -rf build", DangerLevel::Destructive, None);
+        append(&path, &first)?;
+        append(&path, &second)?;
+
+        assert_eq!(read(&path)?, vec![first, second]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_read_reports_bad_line() -> Result<()> {
+        let dir = tempdir()?;
+        let path = dir.path().join("transcript.jsonl");
+        fs::write(&path, "not json\n")?;
+
+        let err = read(&path).unwrap_err().to_string();
+        assert!(err.contains(":1: invalid transcript entry"));
+        Ok(())
+    }
+}
@@ -4,7 +4,17 @@
 //! - Command editing before execution
 //! - History navigation
 //! - Visual feedback during AI processing
+//! - Resuming a typed query or staged command after quitting
+//! - Exporting the session's successful commands as a script
 
+#[cfg(feature = "tui")]
+use crate::danger::DangerLevel;
+#[cfg(feature = "tui")]
+use crate::pretty::fold_json;
+#[cfg(feature = "tui")]
+use crate::shell::{format_command_for_display, session_script, ScriptStep, ShellType};
+#[cfg(feature = "tui")]
+use ansi_to_tui::IntoText;
 #[cfg(feature = "tui")]
 use anyhow::Result;
 #[cfg(feature = "tui")]
@@ -18,12 +28,16 @@ use ratatui::{
     backend::CrosstermBackend,
     layout::{Constraint, Direction, Layout},
     style::{Color, Modifier, Style},
-    text::{Line, Span},
+    text::{Line, Span, Text},
     widgets::{Block, Borders, Paragraph, Wrap},
     Frame, Terminal,
 };
 #[cfg(feature = "tui")]
-use std::io::{self, Stdout};
+use serde::{Deserialize, Serialize};
+#[cfg(feature = "tui")]
+use std::io::{self, IsTerminal, Stdout};
+#[cfg(feature = "tui")]
+use std::path::PathBuf;
 
 /// Application state for the TUI
 #[cfg(feature = "tui")]
@@ -34,16 +48,26 @@ pub struct App {
     pub cursor: usize,
     /// Suggested command from AI
     pub command: Option<String>,
-    /// Whether command is dangerous
-    pub is_dangerous: bool,
+    /// Danger classification of the suggested command
+    pub danger_level: DangerLevel,
+    /// Token the user must retype to run a catastrophic command
+    pub confirm_token: Option<String>,
+    /// What the user has typed so far while confirming
+    pub token_input: String,
     /// Current status message
     pub status: String,
     /// Command history
     pub history: Vec<String>,
     /// History navigation index
     pub history_idx: Option<usize>,
+    /// What was typed before browsing history, restored by Down past the newest entry
+    pub draft: String,
     /// Output from last command
     pub output: String,
+    /// The output parsed as JSON, when `display.pretty_json` applies
+    pub json: Option<serde_json::Value>,
+    /// Whether JSON output is folded to its top-level keys
+    pub json_folded: bool,
     /// Whether we're in edit mode (editing the suggested command)
     pub edit_mode: bool,
     /// The command being edited
@@ -52,8 +76,28 @@ pub struct App {
     pub edit_cursor: usize,
     /// Whether app should quit
     pub should_quit: bool,
+    /// How long the AI took to suggest the staged command
+    pub latency: Option<std::time::Duration>,
+    /// `provider/model` that suggested the staged command, if shown
+    pub answered_by: Option<String>,
+    /// Whether the user has allowed the staged command's network access
+    pub network_confirmed: bool,
+    /// Input the user agreed to send despite it looking like it holds a secret
+    pub sensitive_confirmed: Option<String>,
+    /// The model's explanation of the staged command, if shown
+    pub rationale: Option<String>,
     /// Whether we're waiting for AI
     pub loading: bool,
+    /// Model output streamed so far, capped at `partial_max` characters
+    pub partial: String,
+    /// Characters of streamed output to show before eliding the rest
+    pub partial_max: usize,
+    /// Advances while a query is in flight, to animate the spinner
+    pub ticks: usize,
+    /// Every command that succeeded this session
+    pub turns: Vec<ScriptStep>,
+    /// Commands are only shown, never executed (`--dry-run`)
+    pub dry_run: bool,
 }
 
 #[cfg(feature = "tui")]
@@ -63,16 +107,31 @@ impl Default for App {
             input: String::new(),
             cursor: 0,
             command: None,
-            is_dangerous: false,
+            danger_level: DangerLevel::Safe,
+            confirm_token: None,
+            token_input: String::new(),
             status: "Type your request and press Enter".to_string(),
             history: Vec::new(),
             history_idx: None,
+            draft: String::new(),
             output: String::new(),
+            json: None,
+            json_folded: false,
             edit_mode: false,
             edited_command: String::new(),
             edit_cursor: 0,
             should_quit: false,
+            latency: None,
+            answered_by: None,
+            network_confirmed: false,
+            sensitive_confirmed: None,
+            rationale: None,
             loading: false,
+            partial: String::new(),
+            partial_max: 200,
+            ticks: 0,
+            turns: Vec::new(),
+            dry_run: false,
         }
     }
 }
@@ -92,14 +151,32 @@ impl App {
             KeyCode::Char('q') if modifiers.contains(KeyModifiers::CONTROL) => {
                 self.should_quit = true;
             }
+            KeyCode::Char('o') if modifiers.contains(KeyModifiers::CONTROL) => {
+                self.toggle_json_fold();
+            }
+            KeyCode::Char('s') if modifiers.contains(KeyModifiers::CONTROL) => {
+                self.status = match self.export_script() {
+                    Ok(Some(path)) => format!(
+                        "Saved {} command(s) to {}",
+                        self.turns.len(),
+                        path.display()
+                    ),
+                    Ok(None) => "No successful commands to export yet".to_string(),
+                    Err(e) => format!("Export failed: {}", e),
+                };
+            }
             KeyCode::Esc => {
-                if self.edit_mode {
+                if self.confirm_token.is_some() {
+                    self.cancel_token_confirm();
+                    self.status = "Cancelled. Command not executed.".to_string();
+                } else if self.edit_mode {
                     self.edit_mode = false;
                     self.status = "Edit cancelled".to_string();
                 } else {
                     self.should_quit = true;
                 }
             }
+            _ if self.confirm_token.is_some() => self.handle_token_key(key),
             _ if self.edit_mode => self.handle_edit_key(key),
             _ => self.handle_input_key(key),
         }
@@ -138,7 +215,15 @@ impl App {
                 // Navigate history
                 if !self.history.is_empty() {
                     let idx = match self.history_idx {
-                        None => self.history.len() - 1,
+                        None => {
+                            // Keep a partly typed query instead of overwriting it
+                            self.draft = std::mem::take(&mut self.input);
+                            if !self.draft.is_empty() {
+                                self.status = "Draft saved; Down past the newest entry restores it"
+                                    .to_string();
+                            }
+                            self.history.len() - 1
+                        }
                         Some(i) => i.saturating_sub(1),
                     };
                     self.history_idx = Some(idx);
@@ -154,7 +239,7 @@ impl App {
                         self.input = self.history[idx + 1].clone();
                     } else {
                         self.history_idx = None;
-                        self.input.clear();
+                        self.input = std::mem::take(&mut self.draft);
                     }
                     self.cursor = self.input.len();
                 }
@@ -205,6 +290,7 @@ impl App {
                 // Confirm edit
                 self.command = Some(self.edited_command.clone());
                 self.edit_mode = false;
+                self.network_confirmed = false;
                 self.status =
                     "Command updated. Press Enter to execute, 'y' to confirm.".to_string();
             }
@@ -212,15 +298,81 @@ impl App {
         }
     }
 
+    fn handle_token_key(&mut self, key: KeyCode) {
+        match key {
+            KeyCode::Char(c) => self.token_input.push(c),
+            KeyCode::Backspace => {
+                self.token_input.pop();
+            }
+            _ => {}
+        }
+        self.update_token_status();
+    }
+
+    /// Require the user to retype `token` before executing
+    pub fn start_token_confirm(&mut self, token: String) {
+        self.confirm_token = Some(token);
+        self.token_input.clear();
+        self.update_token_status();
+    }
+
+    pub fn cancel_token_confirm(&mut self) {
+        self.confirm_token = None;
+        self.token_input.clear();
+    }
+
+    /// Whether the typed input matches the confirmation token
+    pub fn token_matches(&self) -> bool {
+        self.confirm_token.as_deref() == Some(self.token_input.trim())
+    }
+
+    fn update_token_status(&mut self) {
+        if let Some(ref token) = self.confirm_token {
+            self.status = format!(
+                "CATASTROPHIC command! Type '{}' and press Enter to execute (Esc to cancel): {}",
+                token, self.token_input
+            );
+        }
+    }
+
+    /// Advance the spinner; called about every 100ms while a query is in flight
+    pub fn tick(&mut self) {
+        self.ticks = self.ticks.wrapping_add(1);
+    }
+
+    /// Append streamed model output, eliding anything past `partial_max`
+    pub fn push_partial(&mut self, text: &str) {
+        let shown = self.partial.chars().count();
+        if shown > self.partial_max {
+            return;
+        }
+
+        let room = self.partial_max - shown;
+        self.partial.extend(text.chars().take(room));
+        if text.chars().count() > room {
+            self.partial.push('…');
+        }
+    }
+
     /// Set the suggested command
-    pub fn set_command(&mut self, cmd: String, dangerous: bool) {
+    pub fn set_command(&mut self, cmd: String, level: DangerLevel) {
+        self.partial.clear();
+        self.network_confirmed = false;
+        self.rationale = None;
+        self.answered_by = None;
         self.command = Some(cmd.clone());
-        self.is_dangerous = dangerous;
+        self.danger_level = level;
         self.edited_command = cmd;
         self.edit_cursor = self.edited_command.len();
-        if dangerous {
+        if level == DangerLevel::Catastrophic {
+            self.status =
+                "CATASTROPHIC command! Press 'y' to confirm, Tab to edit, Esc to cancel".to_string();
+        } else if level.is_dangerous() {
             self.status =
                 "DANGEROUS command! Press 'y' to execute, Tab to edit, Esc to cancel".to_string();
+        } else if level == DangerLevel::Caution {
+            self.status =
+                "Use with caution! Press 'y' to execute, Tab to edit, Esc to cancel".to_string();
         } else {
             self.status = "Press 'y' to execute, Tab to edit, Esc to cancel".to_string();
         }
@@ -229,18 +381,77 @@ impl App {
     /// Set command output
     pub fn set_output(&mut self, output: String) {
         self.output = output;
+        self.json = None;
+    }
+
+    /// Show JSON output pretty-printed, ready to be folded
+    pub fn set_json_output(&mut self, json: serde_json::Value) {
+        self.output = serde_json::to_string_pretty(&json).unwrap_or_else(|_| json.to_string());
+        self.json = Some(json);
+        self.json_folded = false;
+    }
+
+    /// Switch JSON output between fully expanded and top-level only
+    fn toggle_json_fold(&mut self) {
+        let Some(json) = &self.json else {
+            return;
+        };
+        self.json_folded = !self.json_folded;
+        self.output = if self.json_folded {
+            fold_json(json)
+        } else {
+            serde_json::to_string_pretty(json).unwrap_or_else(|_| json.to_string())
+        };
+    }
+
+    /// Remember a command that ran successfully, for `export_script`
+    pub fn record_turn(&mut self, command: &str) {
+        self.turns.push(ScriptStep {
+            query: self.input.clone(),
+            command: command.to_string(),
+            note: None,
+        });
+    }
+
+    /// Write the session's successful commands to `spren-session-<time>.<ext>`
+    /// in the current directory; None if nothing has succeeded yet
+    pub fn export_script(&self) -> Result<Option<PathBuf>> {
+        if self.turns.is_empty() {
+            return Ok(None);
+        }
+        let (script, extension) = session_script(&self.turns, &ShellType::detect());
+        let stamp = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or_default();
+        let path = std::env::current_dir()?.join(format!("spren-session-{}.{}", stamp, extension));
+        std::fs::write(&path, script)?;
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o755))?;
+        }
+        Ok(Some(path))
     }
 
     /// Clear for new query
     pub fn clear_for_new_query(&mut self) {
-        if !self.input.is_empty() {
+        if !self.input.is_empty() && self.history.last() != Some(&self.input) {
             self.history.push(self.input.clone());
         }
         self.input.clear();
         self.cursor = 0;
         self.command = None;
-        self.is_dangerous = false;
+        self.danger_level = DangerLevel::Safe;
+        self.network_confirmed = false;
+        self.sensitive_confirmed = None;
+        self.rationale = None;
+        self.latency = None;
+        self.answered_by = None;
+        self.cancel_token_confirm();
         self.history_idx = None;
+        self.draft.clear();
         self.edit_mode = false;
         self.status = "Type your request and press Enter".to_string();
     }
@@ -251,15 +462,134 @@ impl App {
     }
 }
 
+/// Typed query and staged command saved when the TUI exits mid-task
+#[cfg(feature = "tui")]
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Session {
+    pub input: String,
+    pub command: Option<String>,
+    #[serde(default)]
+    pub danger_level: DangerLevel,
+    #[serde(default)]
+    pub edit_mode: bool,
+    #[serde(default)]
+    pub edited_command: String,
+}
+
+#[cfg(feature = "tui")]
+impl App {
+    /// State worth restoring, or None if nothing was in progress
+    pub fn session(&self) -> Option<Session> {
+        if self.input.trim().is_empty() && self.command.is_none() {
+            return None;
+        }
+
+        Some(Session {
+            input: self.input.clone(),
+            command: self.command.clone(),
+            danger_level: self.danger_level,
+            edit_mode: self.edit_mode,
+            edited_command: self.edited_command.clone(),
+        })
+    }
+
+    /// Restore a saved session; `level` is the command's current classification
+    pub fn restore_session(&mut self, session: Session, level: DangerLevel) {
+        self.input = session.input;
+        self.cursor = self.input.len();
+
+        if let Some(cmd) = session.command {
+            // Never trust a saved level to be lower than what the rules say now
+            self.set_command(cmd, level.max(session.danger_level));
+            if session.edit_mode {
+                self.edit_mode = true;
+                self.edited_command = session.edited_command;
+                self.edit_cursor = self.edited_command.len();
+                self.status = "Editing command (Tab to confirm, Esc to cancel)".to_string();
+            }
+        } else {
+            self.status = "Previous session restored. Press Enter to send".to_string();
+        }
+    }
+}
+
+/// Path of the saved TUI session
+#[cfg(feature = "tui")]
+pub fn session_path() -> Result<PathBuf> {
+    let home = dirs::home_dir().ok_or_else(|| anyhow::anyhow!("Could not find home directory"))?;
+    Ok(home.join(".config").join("spren").join("tui_session.json"))
+}
+
+/// Save the session, or remove a stale one if there's nothing to save
+#[cfg(feature = "tui")]
+pub fn save_session(session: Option<Session>) -> Result<()> {
+    let path = session_path()?;
+    match session {
+        Some(session) => {
+            if let Some(parent) = path.parent() {
+                std::fs::create_dir_all(parent)?;
+            }
+            std::fs::write(&path, serde_json::to_string(&session)?)?;
+        }
+        None if path.exists() => std::fs::remove_file(&path)?,
+        None => {}
+    }
+    Ok(())
+}
+
+/// Load and delete the saved session, so it's only ever offered once
+#[cfg(feature = "tui")]
+pub fn take_session() -> Option<Session> {
+    let path = session_path().ok()?;
+    let contents = std::fs::read_to_string(&path).ok()?;
+    let _ = std::fs::remove_file(&path);
+    serde_json::from_str(&contents).ok()
+}
+
+/// The terminal can't host the TUI (not a TTY, or no raw mode/alternate screen)
+#[cfg(feature = "tui")]
+#[derive(Debug)]
+pub struct TerminalUnsupported(pub String);
+
+#[cfg(feature = "tui")]
+impl std::fmt::Display for TerminalUnsupported {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "TUI unavailable: {}", self.0)
+    }
+}
+
+#[cfg(feature = "tui")]
+impl std::error::Error for TerminalUnsupported {}
+
+/// Fail early if stdout isn't a terminal the TUI could draw on
+#[cfg(feature = "tui")]
+pub fn ensure_terminal() -> Result<()> {
+    if !io::stdout().is_terminal() {
+        return Err(TerminalUnsupported("stdout is not a terminal".to_string()).into());
+    }
+    Ok(())
+}
+
 /// Initialize the terminal for TUI mode
+///
+/// Failures leave the terminal as it was and return [`TerminalUnsupported`].
 #[cfg(feature = "tui")]
 pub fn init_terminal() -> Result<Terminal<CrosstermBackend<Stdout>>> {
-    enable_raw_mode()?;
+    ensure_terminal()?;
+    enable_raw_mode().map_err(|e| TerminalUnsupported(format!("raw mode failed: {}", e)))?;
+
     let mut stdout = io::stdout();
-    execute!(stdout, EnterAlternateScreen)?;
+    if let Err(e) = execute!(stdout, EnterAlternateScreen) {
+        let _ = disable_raw_mode();
+        return Err(TerminalUnsupported(format!("alternate screen failed: {}", e)).into());
+    }
+
     let backend = CrosstermBackend::new(stdout);
-    let terminal = Terminal::new(backend)?;
-    Ok(terminal)
+    Terminal::new(backend).map_err(|e| {
+        let _ = disable_raw_mode();
+        let _ = execute!(io::stdout(), LeaveAlternateScreen);
+        TerminalUnsupported(e.to_string()).into()
+    })
 }
 
 /// Restore the terminal to normal mode
@@ -271,6 +601,15 @@ pub fn restore_terminal(terminal: &mut Terminal<CrosstermBackend<Stdout>>) -> Re
     Ok(())
 }
 
+/// Re-enter the TUI after handing the terminal to an interactive command
+#[cfg(feature = "tui")]
+pub fn resume_terminal(terminal: &mut Terminal<CrosstermBackend<Stdout>>) -> Result<()> {
+    enable_raw_mode()?;
+    execute!(terminal.backend_mut(), EnterAlternateScreen)?;
+    terminal.clear()?;
+    Ok(())
+}
+
 /// Draw the UI
 #[cfg(feature = "tui")]
 pub fn draw(frame: &mut Frame, app: &App) {
@@ -328,27 +667,51 @@ pub fn draw(frame: &mut Frame, app: &App) {
             cmd
         };
 
-        let cmd_style = if app.is_dangerous {
-            Style::default().fg(Color::Red).add_modifier(Modifier::BOLD)
+        let cmd_style = if let Some(style) = danger_style(app.danger_level) {
+            style
         } else if app.edit_mode {
             Style::default().fg(Color::Yellow)
         } else {
             Style::default().fg(Color::Green)
         };
 
-        let spans = if app.is_dangerous && !app.edit_mode {
-            vec![
-                Span::styled(display_cmd, cmd_style),
-                Span::styled(
-                    " [DANGEROUS]",
-                    Style::default().fg(Color::Red).add_modifier(Modifier::BOLD),
-                ),
-            ]
+        // Edits happen on the raw text; otherwise show it in the shell's own layout
+        let display_cmd = if app.edit_mode {
+            display_cmd.clone()
         } else {
-            vec![Span::styled(display_cmd, cmd_style)]
+            format_command_for_display(display_cmd, &ShellType::detect())
         };
+        let mut lines: Vec<Line> = display_cmd
+            .lines()
+            .map(|line| Line::from(Span::styled(line.to_string(), cmd_style)))
+            .collect();
+
+        if let Some(style) = danger_style(app.danger_level).filter(|_| !app.edit_mode) {
+            if let Some(last) = lines.last_mut() {
+                last.push_span(Span::styled(
+                    format!(" [{}]", app.danger_level.name().to_uppercase()),
+                    style,
+                ));
+            }
+        }
+
+        if let Some(answered_by) = app.answered_by.as_ref().filter(|_| !app.edit_mode) {
+            if let Some(last) = lines.last_mut() {
+                last.push_span(Span::styled(
+                    format!("  via {}", answered_by),
+                    Style::default().add_modifier(Modifier::DIM),
+                ));
+            }
+        }
 
-        let command = Paragraph::new(Line::from(spans))
+        if let Some(rationale) = app.rationale.as_ref().filter(|_| !app.edit_mode) {
+            lines.push(Line::from(Span::styled(
+                rationale.clone(),
+                Style::default().add_modifier(Modifier::DIM),
+            )));
+        }
+
+        let command = Paragraph::new(Text::from(lines))
             .block(cmd_block)
             .wrap(Wrap { trim: false });
         frame.render_widget(command, chunks[2]);
@@ -358,38 +721,125 @@ pub fn draw(frame: &mut Frame, app: &App) {
             frame.set_cursor_position((chunks[2].x + app.edit_cursor as u16 + 1, chunks[2].y + 1));
         }
     } else if app.loading {
-        let loading = Paragraph::new("Thinking...")
+        let spinner = SPINNER[app.ticks % SPINNER.len()];
+        let text = if app.partial.is_empty() {
+            format!("{} Thinking...", spinner)
+        } else {
+            app.partial.clone()
+        };
+        let loading = Paragraph::new(text)
             .style(
                 Style::default()
                     .fg(Color::Yellow)
                     .add_modifier(Modifier::ITALIC),
             )
-            .block(cmd_block);
+            .block(cmd_block.title(format!("Suggested Command {}", spinner)))
+            .wrap(Wrap { trim: false });
         frame.render_widget(loading, chunks[2]);
     } else {
         let empty = Paragraph::new("").block(cmd_block);
         frame.render_widget(empty, chunks[2]);
     }
 
-    // Output area
-    let output = Paragraph::new(app.output.as_str())
+    // Output area (commands like `ls --color` and `git` emit ANSI colors)
+    let output = Paragraph::new(output_text(&app.output))
         .style(Style::default().fg(Color::White))
-        .block(Block::default().borders(Borders::ALL).title("Output"))
+        .block(Block::default().borders(Borders::ALL).title(match (&app.json, app.json_folded) {
+            (None, _) => "Output",
+            (Some(_), false) => "Output (JSON, Ctrl+O to fold)",
+            (Some(_), true) => "Output (JSON folded, Ctrl+O to expand)",
+        }))
         .wrap(Wrap { trim: false });
     frame.render_widget(output, chunks[3]);
 
     // Status bar
-    let status_style = if app.is_dangerous {
-        Style::default().fg(Color::Red)
+    let level = app.danger_level;
+    let (status_style, status_title) = match danger_style(level).filter(|_| app.command.is_some()) {
+        Some(style) => (style, format!("Status: {}", level.name())),
+        None => (Style::default().fg(Color::Cyan), "Status".to_string()),
+    };
+    let status_title = if app.dry_run {
+        format!("{} (dry run)", status_title)
     } else {
-        Style::default().fg(Color::Cyan)
+        status_title
     };
     let status = Paragraph::new(app.status.as_str())
         .style(status_style)
-        .block(Block::default().borders(Borders::ALL).title("Status"));
+        .block(Block::default().borders(Borders::ALL).title(status_title));
     frame.render_widget(status, chunks[4]);
 }
 
+/// Frames of the spinner shown while a query is in flight
+#[cfg(feature = "tui")]
+const SPINNER: [&str; 10] = ["⠋", "⠙", "⠹", "⠸", "⠼", "⠴", "⠦", "⠧", "⠇", "⠏"];
+
+/// How a command of the given danger level is drawn; `None` for safe commands
+#[cfg(feature = "tui")]
+fn danger_style(level: DangerLevel) -> Option<Style> {
+    match level {
+        DangerLevel::Safe => None,
+        DangerLevel::Caution => Some(Style::default().fg(Color::Yellow).add_modifier(Modifier::BOLD)),
+        // 256-color orange; terminals without it fall back to their nearest color
+        DangerLevel::Destructive => {
+            Some(Style::default().fg(Color::Indexed(208)).add_modifier(Modifier::BOLD))
+        }
+        DangerLevel::Catastrophic => Some(
+            Style::default()
+                .fg(Color::LightRed)
+                .add_modifier(Modifier::BOLD | Modifier::SLOW_BLINK),
+        ),
+    }
+}
+
+/// Convert command output with ANSI escape codes into styled text,
+/// falling back to stripping the codes if they can't be parsed
+#[cfg(feature = "tui")]
+fn output_text(output: &str) -> Text<'static> {
+    output
+        .into_text()
+        .unwrap_or_else(|_| Text::raw(strip_ansi(output)))
+}
+
+/// Remove ANSI escape sequences (CSI and OSC) from a string
+#[cfg(feature = "tui")]
+fn strip_ansi(s: &str) -> String {
+    let mut result = String::with_capacity(s.len());
+    let mut chars = s.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        if c != '\x1b' {
+            result.push(c);
+            continue;
+        }
+
+        match chars.next() {
+            // CSI: ESC [ params... final byte in '@'..='~'
+            Some('[') => {
+                for c in chars.by_ref() {
+                    if ('@'..='~').contains(&c) {
+                        break;
+                    }
+                }
+            }
+            // OSC: ESC ] ... terminated by BEL or ESC \
+            Some(']') => {
+                while let Some(c) = chars.next() {
+                    if c == '\x07' {
+                        break;
+                    }
+                    if c == '\x1b' && chars.peek() == Some(&'\\') {
+                        chars.next();
+                        break;
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    result
+}
+
 /// Poll for events with timeout
 #[cfg(feature = "tui")]
 pub fn poll_event(timeout_ms: u64) -> Result<Option<Event>> {